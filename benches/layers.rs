@@ -0,0 +1,26 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use moz::bench::{self, Heap, Stack};
+
+type Workload = fn(&Heap, usize);
+
+const WORKLOADS: &[(&str, Workload, usize)] = &[
+    ("churn", bench::churn, 1024),
+    ("large", bench::large, 64),
+    ("long_lived", bench::long_lived, 1024),
+    ("producer_consumer", bench::producer_consumer, 1024),
+];
+
+fn layers(c: &mut Criterion) {
+    for &(name, run, ops) in WORKLOADS {
+        let mut group = c.benchmark_group(name);
+        for &stack in Stack::ALL {
+            let heap = Heap::new(stack);
+            let id = BenchmarkId::from_parameter(format!("{stack:?}"));
+            group.bench_with_input(id, &ops, |b, &ops| b.iter(|| run(&heap, ops)));
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, layers);
+criterion_main!(benches);
@@ -0,0 +1,207 @@
+//! Derives for the allocator traits of `moz`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    Data, DeriveInput, Error, Fields, Ident, Index, Member, Result, Type, parse_macro_input,
+    parse_quote,
+};
+
+/// The traits [`AllocDelegate`] can forward.
+const TRAITS: [&str; 4] = ["Alloc", "FreeAll", "Grind", "Owns"];
+
+/// Forwards `Alloc`, `FreeAll`, `Grind` and `Owns` to one field of a
+/// struct, so that a combinator only has to write the methods it changes.
+///
+/// The field is the one marked `#[delegate]`, or the only field. Traits the
+/// struct implements itself are listed in `#[delegate(skip(...))]` on the
+/// struct:
+///
+/// ```ignore
+/// #[derive(AllocDelegate)]
+/// #[delegate(skip(Alloc))]
+/// pub(crate) struct ZeroHeap<T>(T);
+/// ```
+///
+/// Every method is forwarded, including the provided ones, so the field's
+/// own `grow` or `alloc_zeroed` is used rather than the default. Each impl
+/// is bounded on the field's type implementing the trait, so generic
+/// wrappers only get the impls their inner allocator supports. The traits
+/// are named by their paths in `moz`, so the derive only works inside it.
+#[proc_macro_derive(AllocDelegate, attributes(delegate))]
+pub fn derive_alloc_delegate(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            input,
+            "AllocDelegate only supports structs",
+        ));
+    };
+    let (member, ty) = delegate(&data.fields).ok_or_else(|| {
+        Error::new_spanned(
+            &data.fields,
+            "mark the field to forward to with #[delegate], or give the struct one field",
+        )
+    })?;
+    let skip = skipped(input)?;
+    let mut out = TokenStream2::new();
+    for name in TRAITS {
+        if skip.iter().any(|x| x == name) {
+            continue;
+        }
+        let trait_ = Ident::new(name, proc_macro2::Span::call_site());
+        // Quantified so that the bound is not checked up front when the
+        // field's type is concrete and lacks the trait, which would be an
+        // error rather than an impl that never applies.
+        let mut generics = input.generics.clone();
+        generics
+            .make_where_clause()
+            .predicates
+            .push(parse_quote!(for<'__delegate> #ty: crate::core::#trait_));
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        let ident = &input.ident;
+        let body = methods(name, &member);
+        out.extend(quote! {
+            impl #impl_generics crate::core::#trait_ for #ident #ty_generics #where_clause {
+                #body
+            }
+        });
+    }
+    Ok(out)
+}
+
+/// The field marked `#[delegate]`, or the only one.
+fn delegate(fields: &Fields) -> Option<(Member, &Type)> {
+    let member = |i: usize, f: &syn::Field| match &f.ident {
+        Some(x) => Member::Named(x.clone()),
+        None => Member::Unnamed(Index::from(i)),
+    };
+    let mut marked = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.attrs.iter().any(|a| a.path().is_ident("delegate")));
+    match (marked.next(), marked.next()) {
+        (Some((i, f)), None) => Some((member(i, f), &f.ty)),
+        (Some(_), Some(_)) => None,
+        (None, _) if fields.len() == 1 => {
+            let f = fields.iter().next()?;
+            Some((member(0, f), &f.ty))
+        }
+        (None, _) => None,
+    }
+}
+
+/// The traits listed in `#[delegate(skip(...))]` on the struct.
+fn skipped(input: &DeriveInput) -> Result<Vec<String>> {
+    let mut skip = Vec::new();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("delegate")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("skip") {
+                return Err(meta.error("expected `skip(...)`"));
+            }
+            meta.parse_nested_meta(|inner| {
+                let name = inner
+                    .path
+                    .get_ident()
+                    .map(ToString::to_string)
+                    .filter(|x| TRAITS.contains(&x.as_str()))
+                    .ok_or_else(|| inner.error("expected one of Alloc, FreeAll, Grind, Owns"))?;
+                skip.push(name);
+                Ok(())
+            })
+        })?;
+    }
+    Ok(skip)
+}
+
+fn methods(name: &str, member: &Member) -> TokenStream2 {
+    let inner = quote!(&self.#member);
+    match name {
+        "Alloc" => quote! {
+            #[inline]
+            #[track_caller]
+            fn alloc(
+                &self,
+                layout: ::core::alloc::Layout,
+            ) -> ::core::result::Result<crate::core::Tag, ::core::alloc::AllocError> {
+                crate::core::Alloc::alloc(#inner, layout)
+            }
+
+            #[inline]
+            #[track_caller]
+            unsafe fn free(&self, tag: crate::core::Tag) {
+                unsafe { crate::core::Alloc::free(#inner, tag) }
+            }
+
+            #[inline]
+            #[track_caller]
+            fn alloc_zeroed(
+                &self,
+                layout: ::core::alloc::Layout,
+            ) -> ::core::result::Result<crate::core::Tag, ::core::alloc::AllocError> {
+                crate::core::Alloc::alloc_zeroed(#inner, layout)
+            }
+
+            #[inline]
+            #[track_caller]
+            fn alloc_zeroed_array(
+                &self,
+                elem: ::core::alloc::Layout,
+                n: usize,
+            ) -> ::core::result::Result<crate::core::Tag, ::core::alloc::AllocError> {
+                crate::core::Alloc::alloc_zeroed_array(#inner, elem, n)
+            }
+
+            #[inline]
+            #[track_caller]
+            unsafe fn grow(
+                &self,
+                tag: &crate::core::Tag,
+                new: ::core::alloc::Layout,
+            ) -> ::core::result::Result<crate::core::Tag, ::core::alloc::AllocError> {
+                unsafe { crate::core::Alloc::grow(#inner, tag, new) }
+            }
+
+            #[inline]
+            #[track_caller]
+            unsafe fn shrink(
+                &self,
+                tag: &crate::core::Tag,
+                new: ::core::alloc::Layout,
+            ) -> ::core::result::Result<crate::core::Tag, ::core::alloc::AllocError> {
+                unsafe { crate::core::Alloc::shrink(#inner, tag, new) }
+            }
+        },
+        "FreeAll" => quote! {
+            #[inline]
+            unsafe fn free_all(&self) {
+                unsafe { crate::core::FreeAll::free_all(#inner) }
+            }
+        },
+        "Grind" => quote! {
+            #[inline]
+            fn grind(&self) -> crate::core::GrindReport {
+                crate::core::Grind::grind(#inner)
+            }
+
+            #[inline]
+            fn grind_budgeted(&self, budget: crate::core::Budget) -> crate::core::GrindReport {
+                crate::core::Grind::grind_budgeted(#inner, budget)
+            }
+        },
+        "Owns" => quote! {
+            #[inline]
+            fn owns(&self, ptr: ::core::ptr::NonNull<u8>) -> bool {
+                crate::core::Owns::owns(#inner, ptr)
+            }
+        },
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,147 @@
+#![allow(unused)]
+
+use core::ptr::{self, NonNull};
+
+use rustix::{
+    io::Errno,
+    mm::{self, Advice, MapFlags, MprotectFlags, ProtFlags},
+};
+use thiserror::Error;
+
+use crate::sync::SpinLock;
+
+#[derive(Debug, Error)]
+pub(crate) enum AppendErr {
+    #[error("append map is full")]
+    Full,
+    #[error("append map syscall failed with {0}")]
+    Os(#[from] Errno),
+}
+
+struct State {
+    /// Offset of the first live byte.
+    head: usize,
+    /// Offset one past the last live byte.
+    tail: usize,
+    /// Committed pages span `decommitted..committed`.
+    decommitted: usize,
+    committed: usize,
+}
+
+/// A grow-only byte log over a fixed virtual reservation, for log buffers
+/// and queues.
+///
+/// The whole capacity is reserved up front without access, so the data
+/// never moves. Pages are committed as data is appended, and
+/// [`AppendMap::truncate_front`] drops data from the front, decommitting the
+/// pages it leaves behind. Offsets are absolute and never reused; once the
+/// tail reaches the capacity the map is full.
+pub(crate) struct AppendMap {
+    base: NonNull<u8>,
+    cap: usize,
+    pagesize: usize,
+    state: SpinLock<State>,
+}
+
+// SAFETY: The reservation is owned by the map and only accessed with the
+// lock held.
+unsafe impl Send for AppendMap {}
+unsafe impl Sync for AppendMap {}
+
+impl AppendMap {
+    /// Reserves `cap` bytes, rounded up to whole pages.
+    pub(crate) fn new(cap: usize) -> Result<Self, AppendErr> {
+        let pagesize = rustix::param::page_size();
+        let cap = cap
+            .max(1)
+            .checked_next_multiple_of(pagesize)
+            .ok_or(AppendErr::Full)?;
+        let flags = MapFlags::PRIVATE | MapFlags::NORESERVE;
+        // SAFETY: The kernel chooses the address.
+        let base = unsafe { mm::mmap_anonymous(ptr::null_mut(), cap, ProtFlags::empty(), flags) }?;
+        Ok(Self {
+            base: NonNull::new(base.cast()).unwrap(),
+            cap,
+            pagesize,
+            state: SpinLock::new(State {
+                head: 0,
+                tail: 0,
+                decommitted: 0,
+                committed: 0,
+            }),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Appends `data`, committing pages as needed, and returns the offset it
+    /// was written at.
+    pub(crate) fn append(&self, data: &[u8]) -> Result<usize, AppendErr> {
+        let mut state = self.state.lock();
+        let at = state.tail;
+        let end = at
+            .checked_add(data.len())
+            .filter(|&x| x <= self.cap)
+            .ok_or(AppendErr::Full)?;
+        if end > state.committed {
+            let to = end.next_multiple_of(self.pagesize);
+            let from = state.committed;
+            let rw = MprotectFlags::READ | MprotectFlags::WRITE;
+            // SAFETY: `from..to` lies within the reservation.
+            unsafe { mm::mprotect(self.base.add(from).as_ptr().cast(), to - from, rw) }?;
+            state.committed = to;
+        }
+        // SAFETY: `at..end` is committed and past the tail, so no reference
+        // to it exists and it cannot overlap `data`.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.base.add(at).as_ptr(), data.len()) };
+        state.tail = end;
+        Ok(at)
+    }
+
+    /// Drops the data before offset `to` and decommits the whole pages that
+    /// no longer hold live data. Offsets past the tail are clamped to it.
+    pub(crate) fn truncate_front(&self, to: usize) -> Result<(), AppendErr> {
+        let mut state = self.state.lock();
+        state.head = state.head.max(to.min(state.tail));
+        let to = state.head - state.head % self.pagesize;
+        if to > state.decommitted {
+            let from = state.decommitted;
+            let ptr = unsafe { self.base.add(from) }.as_ptr().cast();
+            // SAFETY: `from..to` is committed and holds no live data.
+            unsafe {
+                mm::madvise(ptr, to - from, Advice::LinuxDontNeed)?;
+                mm::mprotect(ptr, to - from, MprotectFlags::empty())?;
+            }
+            state.decommitted = to;
+        }
+        Ok(())
+    }
+
+    /// The offsets of the live data.
+    pub(crate) fn range(&self) -> core::ops::Range<usize> {
+        let state = self.state.lock();
+        state.head..state.tail
+    }
+
+    /// Calls `f` with the offset of the live data and the data itself.
+    /// Appends and truncation wait until `f` returns.
+    pub(crate) fn read<R>(&self, f: impl FnOnce(usize, &[u8]) -> R) -> R {
+        let state = self.state.lock();
+        // SAFETY: `head..tail` is committed and initialized, and cannot change
+        // while the lock is held.
+        let data = unsafe {
+            core::slice::from_raw_parts(self.base.add(state.head).as_ptr(), state.tail - state.head)
+        };
+        f(state.head, data)
+    }
+}
+
+impl Drop for AppendMap {
+    fn drop(&mut self) {
+        // SAFETY: The reservation was mapped in `new`.
+        let _ = unsafe { mm::munmap(self.base.as_ptr().cast(), self.cap) };
+    }
+}
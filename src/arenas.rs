@@ -0,0 +1,395 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    cell::{Cell, UnsafeCell},
+    mem::MaybeUninit,
+    sync::atomic::{
+        AtomicBool, AtomicU32, AtomicUsize,
+        Ordering::{AcqRel, Acquire, Relaxed, Release},
+    },
+};
+
+use rustix::thread::futex;
+
+use crate::{
+    check::{Check, Report, Violation},
+    core::{Alloc, Tag},
+    sync::thread_id,
+};
+
+/// The arena the current thread is pinned to, as the address of the
+/// [`Arenas`] and the arena id.
+#[thread_local]
+static PINNED: Cell<Option<(usize, usize)>> = Cell::new(None);
+
+/// Set in [`Slot::state`] while the slot holds an arena that can be used.
+const LIVE: usize = 1 << (usize::BITS - 1);
+/// Set in [`Slot::state`] while an arena is being created or retired.
+const BUSY: usize = 1 << (usize::BITS - 2);
+/// The bits of [`Slot::state`] counting threads using the arena.
+const USERS: usize = !(LIVE | BUSY);
+
+/// Where the arena id is kept in [`Tag::user`].
+const TAG_SHIFT: u32 = 24;
+const TAG_MASK: u32 = 0xff << TAG_SHIFT;
+
+struct Slot<A> {
+    state: AtomicUsize,
+    /// Bumped whenever the last user leaves the slot while it is `BUSY`, so
+    /// that [`Arenas::retire`] can sleep on it as a futex.
+    exits: AtomicU32,
+    arena: UnsafeCell<MaybeUninit<A>>,
+}
+
+impl<A> Slot<A> {
+    const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            exits: AtomicU32::new(0),
+            arena: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `f` on the arena if the slot is live, keeping it from being
+    /// retired until `f` returns.
+    fn with<R>(&self, f: impl FnOnce(&A) -> R) -> Option<R> {
+        let state = self.state.fetch_add(1, Acquire);
+        let res = if state & LIVE != 0 {
+            // SAFETY: The slot is live, and cannot be retired while we are
+            // counted as a user.
+            Some(f(unsafe { (*self.arena.get()).assume_init_ref() }))
+        } else {
+            None
+        };
+        let state = self.state.fetch_sub(1, Release);
+        if state & BUSY != 0 && state & USERS == 1 {
+            self.exits.fetch_add(1, Release);
+            let _ = futex::wake(&self.exits, futex::Flags::PRIVATE, u32::MAX);
+        }
+        res
+    }
+}
+
+/// Up to `N` independent allocators, created and retired at runtime, with
+/// threads spread across them to reduce contention.
+///
+/// Unpinned threads use a live arena chosen by hashing their thread id,
+/// skipping arenas that are pinned. A thread pinned with
+/// [`Arenas::pin_current_thread`] uses its arena exclusively, so its
+/// allocations never contend with other threads. Frees are routed to the
+/// arena that made the allocation, whichever thread makes them, by the id
+/// kept in the top byte of [`Tag::user`]. At most 256 arenas are supported.
+///
+/// Arenas can be added with [`Arenas::create`] and removed with
+/// [`Arenas::retire`], which gives subsystems isolated heaps that can be torn
+/// down at once.
+///
+/// Pins refer to the address of the `Arenas`, so it must not move while any
+/// thread is pinned to it.
+pub(crate) struct Arenas<A, const N: usize = 8> {
+    slots: [Slot<A>; N],
+    pinned: [AtomicBool; N],
+    deterministic: bool,
+}
+
+// SAFETY: Arenas are shared between threads through `&A`, and moved in and
+// out of their slots by `create` and `retire`.
+unsafe impl<A: Send, const N: usize> Send for Arenas<A, N> {}
+unsafe impl<A: Send + Sync, const N: usize> Sync for Arenas<A, N> {}
+
+impl<A, const N: usize> Arenas<A, N> {
+    pub(crate) const fn new() -> Self {
+        assert!(N <= 1 << (u32::BITS - TAG_SHIFT));
+        Self {
+            slots: [const { Slot::new() }; N],
+            pinned: [const { AtomicBool::new(false) }; N],
+            deterministic: false,
+        }
+    }
+
+    /// Sends unpinned threads to the first live arena that is not pinned,
+    /// rather than one picked by hashing the thread id, which varies from
+    /// run to run with the address space layout. For reproducible runs.
+    pub(crate) const fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Adds `arena` in a free slot and returns its id, or gives it back if
+    /// all `N` slots are taken.
+    pub(crate) fn create(&self, arena: A) -> Result<usize, A> {
+        for (id, slot) in self.slots.iter().enumerate() {
+            if slot
+                .state
+                .compare_exchange(0, BUSY, Acquire, Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            // SAFETY: The slot was empty, and `BUSY` keeps anyone else from
+            // touching it.
+            unsafe { (*slot.arena.get()).write(arena) };
+            // Threads that failed to enter in the meantime may still be
+            // counted, so flip the flags without touching the count.
+            slot.state.fetch_xor(BUSY | LIVE, Release);
+            return Ok(id);
+        }
+        Err(arena)
+    }
+
+    /// Creates one arena per CPU the current thread may run on, calling
+    /// `make` for each, up to the free slots. Returns how many were created.
+    ///
+    /// The count follows the thread's CPU affinity rather than the machine,
+    /// so a process confined to two CPUs of a large host, as in a container,
+    /// gets two arenas rather than dozens. Call this early, before threads
+    /// narrow their own affinity.
+    pub(crate) fn create_per_cpu(&self, mut make: impl FnMut() -> A) -> usize {
+        (0..allowed_cpus())
+            .take_while(|_| self.create(make()).is_ok())
+            .count()
+    }
+
+    /// Removes arena `id` and returns it, sleeping until no thread is using
+    /// it. Its allocations are no longer routed anywhere, so the caller
+    /// usually tears it down with `FreeAll` or by dropping it. A thread
+    /// pinned to it fails to allocate until it pins another arena.
+    ///
+    /// # SAFETY
+    ///
+    /// No allocation from the arena may be freed through `self` after this
+    /// call. Such a free panics, or once [`Arenas::create`] has reused the
+    /// slot, goes to the wrong arena.
+    pub(crate) unsafe fn retire(&self, id: usize) -> Option<A> {
+        let slot = self.slots.get(id)?;
+        slot.state
+            .fetch_update(AcqRel, Relaxed, |x| {
+                (x & (LIVE | BUSY) == LIVE).then_some(x ^ (LIVE | BUSY))
+            })
+            .ok()?;
+        loop {
+            let exits = slot.exits.load(Acquire);
+            if slot.state.load(Acquire) & USERS == 0 {
+                break;
+            }
+            // Returns at once if the last user left since `exits` was read.
+            let _ = futex::wait(&slot.exits, futex::Flags::PRIVATE, exits, None);
+        }
+        // SAFETY: The slot was live, and no one can use it any more.
+        let arena = unsafe { (*slot.arena.get()).assume_init_read() };
+        self.pinned[id].store(false, Release);
+        slot.state.fetch_and(!BUSY, Release);
+        Some(arena)
+    }
+
+    /// Runs `f` on arena `id`, if it is live.
+    pub(crate) fn with<R>(&self, id: usize, f: impl FnOnce(&A) -> R) -> Option<R> {
+        self.slots.get(id)?.with(f)
+    }
+
+    fn addr(&self) -> usize {
+        core::ptr::from_ref(self).addr()
+    }
+
+    /// Binds the current thread to arena `id`, which no other thread will use
+    /// for allocation until it is unpinned. Returns `false` if `id` is out of
+    /// range, already pinned by another thread, or if the current thread is
+    /// pinned in another `Arenas`. A thread already pinned to another arena
+    /// of `self` is moved.
+    pub(crate) fn pin_current_thread(&self, id: usize) -> bool {
+        if id >= N {
+            return false;
+        }
+        match PINNED.get() {
+            Some(pin) if pin == (self.addr(), id) => return true,
+            Some((addr, _)) if addr != self.addr() => return false,
+            _ => {}
+        }
+        if self.pinned[id]
+            .compare_exchange(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        self.unpin();
+        PINNED.set(Some((self.addr(), id)));
+        true
+    }
+
+    /// Releases the current thread's pin on an arena of `self`, if any.
+    pub(crate) fn unpin(&self) {
+        match PINNED.get() {
+            Some((addr, id)) if addr == self.addr() => {
+                self.pinned[id].store(false, Release);
+                PINNED.set(None);
+            }
+            _ => {}
+        }
+    }
+
+    /// The arena the current thread allocates from.
+    pub(crate) fn current(&self) -> usize {
+        match PINNED.get() {
+            Some((addr, id)) if addr == self.addr() => return id,
+            _ => {}
+        }
+        let start = if self.deterministic {
+            0
+        } else {
+            thread_id().wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize) % N
+        };
+        // Fall back to the hashed arena if every live arena is pinned.
+        (0..N)
+            .map(|i| (start + i) % N)
+            .find(|&i| {
+                !self.pinned[i].load(Relaxed) && self.slots[i].state.load(Relaxed) & LIVE != 0
+            })
+            .unwrap_or(start)
+    }
+}
+
+/// The number of CPUs the current thread may run on, per
+/// `sched_getaffinity`, or one if that fails.
+pub(crate) fn allowed_cpus() -> usize {
+    rustix::thread::sched_getaffinity(None).map_or(1, |x| x.count().max(1) as usize)
+}
+
+impl<A: Check, const N: usize> Check for Arenas<A, N> {
+    /// Checks that no slot is both live and being created or retired, then
+    /// checks every live arena.
+    fn check(&self, report: &mut Report) {
+        for slot in &self.slots {
+            report.checked += 1;
+            if slot.state.load(Relaxed) & (LIVE | BUSY) == LIVE | BUSY {
+                let addr = core::ptr::from_ref(&slot.state).addr();
+                report.violation(Violation::Corrupt { addr });
+            }
+            slot.with(|x| x.check(report));
+        }
+    }
+}
+
+impl<A, const N: usize> Drop for Arenas<A, N> {
+    fn drop(&mut self) {
+        for slot in &mut self.slots {
+            if *slot.state.get_mut() & LIVE != 0 {
+                // SAFETY: The slot is live and we have exclusive access.
+                unsafe { slot.arena.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
+
+impl<A: Alloc, const N: usize> Arenas<A, N> {
+    /// Allocates with `f` from the current thread's arena, and tags the
+    /// allocation with its id.
+    fn alloc_with(&self, f: impl FnOnce(&A) -> Result<Tag, AllocError>) -> Result<Tag, AllocError> {
+        let id = self.current();
+        let tag = self.with(id, f).unwrap_or(Err(AllocError))?;
+        debug_assert!(tag.user() & TAG_MASK == 0);
+        let user = tag.user() | (id as u32) << TAG_SHIFT;
+        Ok(tag.with_user(user))
+    }
+
+    /// Resizes `tag` with `f` on the arena that made it, keeping its id.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed, and
+    /// `f` must uphold the contract of [`Alloc::grow`] or [`Alloc::shrink`].
+    unsafe fn resize(
+        &self,
+        tag: &Tag,
+        f: impl FnOnce(&A, &Tag) -> Result<Tag, AllocError>,
+    ) -> Result<Tag, AllocError> {
+        let id = ((tag.user() & TAG_MASK) >> TAG_SHIFT) as usize;
+        // SAFETY: This is the tag the arena returned, with our bits cleared.
+        let inner = unsafe { Tag::new(tag.ptr(), tag.layout()) }.with_user(tag.user() & !TAG_MASK);
+        let moved = self.with(id, |x| f(x, &inner)).unwrap_or(Err(AllocError))?;
+        let user = moved.user() | (id as u32) << TAG_SHIFT;
+        Ok(moved.with_user(user))
+    }
+}
+
+impl<A: Alloc, const N: usize> Alloc for Arenas<A, N> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.alloc_with(|x| x.alloc(layout))
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let id = ((tag.user() & TAG_MASK) >> TAG_SHIFT) as usize;
+        let (ptr, user) = (tag.ptr(), tag.user() & !TAG_MASK);
+        let res = self.with(id, |x| unsafe { x.free(tag.with_user(user)) });
+        // The arena is gone, and may have been dropped with the block, so
+        // there is nowhere to send it.
+        assert!(res.is_some(), "free of {ptr:p} to retired arena {id}");
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.alloc_with(|x| x.alloc_zeroed(layout))
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, |x, tag| x.grow(tag, new)) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, |x, tag| x.shrink(tag, new)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::Mmap;
+
+    const PAGE: Layout = Layout::new::<[u8; 4096]>();
+
+    #[test]
+    fn retire_returns_arena() {
+        let arenas = Arenas::<Mmap, 2>::new();
+        let id = arenas.create(Mmap::new()).ok().unwrap();
+        assert!(arenas.pin_current_thread(id));
+        let a = arenas.alloc(PAGE).unwrap();
+        unsafe { arenas.free(a) };
+        let arena = unsafe { arenas.retire(id) }.unwrap();
+        assert_eq!(arena.stats().mapped(), 0);
+        assert!(unsafe { arenas.retire(id) }.is_none());
+        // The pin went with the arena.
+        assert!(arenas.alloc(PAGE).is_err());
+        arenas.unpin();
+    }
+
+    #[test]
+    #[should_panic = "retired arena"]
+    fn free_to_retired_arena() {
+        let arenas = Arenas::<Mmap, 1>::new();
+        let id = arenas.create(Mmap::new()).ok().unwrap();
+        let a = arenas.alloc(PAGE).unwrap();
+        let _arena = unsafe { arenas.retire(id) };
+        unsafe { arenas.free(a) };
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn retire_waits_for_users() {
+        use std::{sync::Barrier, thread, time::Duration};
+
+        let arenas = Arenas::<Mmap, 1>::new();
+        let id = arenas.create(Mmap::new()).ok().unwrap();
+        let (entered, done) = (Barrier::new(2), AtomicBool::new(false));
+        thread::scope(|s| {
+            s.spawn(|| {
+                arenas.with(id, |_| {
+                    entered.wait();
+                    thread::sleep(Duration::from_millis(50));
+                    done.store(true, Relaxed);
+                })
+            });
+            entered.wait();
+            let _arena = unsafe { arenas.retire(id) }.unwrap();
+            assert!(done.load(Relaxed));
+        });
+    }
+}
@@ -0,0 +1,139 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicU64, AtomicUsize,
+        Ordering::{Relaxed, SeqCst},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+/// An async front end to `A`, for servers that would rather wait for memory
+/// than fail: [`Backpressure::alloc_async`] retries a failed allocation
+/// whenever memory may have become available, instead of returning an
+/// error.
+///
+/// Memory may become available when an allocation is freed through this
+/// layer, or when something else, such as a background trim or a quota
+/// being raised, calls [`Backpressure::notify`]. Up to `N` tasks wait at
+/// once; further waiters are re-polled immediately rather than parked.
+///
+/// Frees cost an extra atomic add, plus a lock round trip while tasks are
+/// waiting. Synchronous allocations through [`Alloc`] are unaffected.
+pub(crate) struct Backpressure<A, const N: usize = 64> {
+    inner: A,
+    waiters: SpinLock<[Option<Waker>; N]>,
+    /// The number of parked wakers, so that `notify` is a single load when
+    /// there are none.
+    waiting: AtomicUsize,
+    /// Bumped by every `notify`, so that a waiter can tell whether memory
+    /// was released between its failed attempt and parking.
+    epoch: AtomicU64,
+}
+
+impl<A, const N: usize> Backpressure<A, N> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            waiters: SpinLock::new([const { None }; N]),
+            waiting: AtomicUsize::new(0),
+            epoch: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Wakes every waiting allocation to retry. Call this after releasing
+    /// memory by means this layer cannot see.
+    pub(crate) fn notify(&self) {
+        self.epoch.fetch_add(1, SeqCst);
+        // Sequentially consistent, so that either a waiter sees the new
+        // epoch after parking, or this sees it parked.
+        if self.waiting.load(SeqCst) == 0 {
+            return;
+        }
+        loop {
+            let waker = self.waiters.lock().iter_mut().find_map(Option::take);
+            let Some(waker) = waker else { break };
+            self.waiting.fetch_sub(1, Relaxed);
+            waker.wake();
+        }
+    }
+
+    /// Parks `waker` until the next `notify`. Returns `false` if all `N`
+    /// slots are taken.
+    fn park(&self, waker: &Waker) -> bool {
+        let mut waiters = self.waiters.lock();
+        if waiters.iter().flatten().any(|x| x.will_wake(waker)) {
+            return true;
+        }
+        let Some(slot) = waiters.iter_mut().find(|x| x.is_none()) else {
+            return false;
+        };
+        *slot = Some(waker.clone());
+        self.waiting.fetch_add(1, SeqCst);
+        true
+    }
+}
+
+impl<A: Alloc, const N: usize> Backpressure<A, N> {
+    /// Allocates memory for `layout`, waiting until the inner allocator can
+    /// serve it.
+    ///
+    /// A layout the inner allocator can never serve waits forever, so bound
+    /// the wait with a timeout where that is possible.
+    pub(crate) fn alloc_async(&self, layout: Layout) -> AllocAsync<'_, A, N> {
+        AllocAsync { heap: self, layout }
+    }
+}
+
+impl<A: Alloc, const N: usize> Alloc for Backpressure<A, N> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        unsafe { self.inner.free(tag) };
+        self.notify();
+    }
+}
+
+/// The future returned by [`Backpressure::alloc_async`].
+pub(crate) struct AllocAsync<'a, A, const N: usize> {
+    heap: &'a Backpressure<A, N>,
+    layout: Layout,
+}
+
+impl<A: Alloc, const N: usize> Future for AllocAsync<'_, A, N> {
+    type Output = Tag;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Tag> {
+        let heap = self.heap;
+        loop {
+            let epoch = heap.epoch.load(SeqCst);
+            if let Ok(tag) = heap.inner.alloc(self.layout) {
+                return Poll::Ready(tag);
+            }
+            if !heap.park(cx.waker()) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            // Memory released after the failed attempt but before parking
+            // would not wake us, so retry.
+            if heap.epoch.load(SeqCst) == epoch {
+                return Poll::Pending;
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use rustix::mm::{MapFlags, ProtFlags, mmap_anonymous, munmap};
+
+use crate::{
+    core::{Alloc, Tag},
+    registry::{Region, RegionState},
+    stats::{Stats, Syscall},
+    sync::SpinLock,
+};
+
+/// Written at the start of every mapping, linking the mappings of a base.
+struct Chunk {
+    next: Option<NonNull<Chunk>>,
+    len: usize,
+}
+
+const HEADER: usize = mem::size_of::<Chunk>();
+
+struct State {
+    /// The most recent mapping.
+    head: Option<NonNull<Chunk>>,
+    /// Bytes of `head` in use, including the header.
+    used: usize,
+}
+
+// SAFETY: The mappings are owned by the base and only accessed with the lock
+// held or through `&mut Base`.
+unsafe impl Send for State {}
+
+/// The allocator for allocator metadata, such as extent nodes and arena
+/// structs.
+///
+/// It bump-allocates from mappings of its own, made with raw `mmap` calls, so
+/// that allocating metadata can never recurse into the heap being built. Its
+/// memory is never reused: `free` is a no-op, and mappings are only released
+/// when the base is dropped. It keeps its own [`Stats`], so that metadata
+/// overhead is reported apart from the heap it describes.
+pub(crate) struct Base {
+    pagesize: usize,
+    chunk_size: usize,
+    stats: Stats,
+    state: SpinLock<State>,
+}
+
+impl Base {
+    /// Creates a base that maps at least `chunk_size` bytes at a time.
+    pub(crate) fn new(chunk_size: usize) -> Self {
+        Self {
+            pagesize: rustix::param::page_size(),
+            chunk_size,
+            stats: Stats::new(),
+            state: SpinLock::new(State {
+                head: None,
+                used: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Calls `f` on every mapping, each as one active region.
+    pub(crate) fn regions(&self, f: &mut dyn FnMut(Region)) {
+        let state = self.state.lock();
+        let mut next = state.head;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk { next: n, len } = unsafe { chunk.read() };
+            next = n;
+            f(Region {
+                addr: chunk.addr().get(),
+                len,
+                state: RegionState::Active,
+            });
+        }
+    }
+
+    /// Tries to carve `layout` out of the current mapping.
+    fn bump(state: &mut State, layout: Layout) -> Option<NonNull<u8>> {
+        let head = state.head?;
+        // SAFETY: `head` points to the header of a live mapping.
+        let cap = unsafe { head.as_ref() }.len;
+        let base = head.cast::<u8>();
+        let start = base
+            .addr()
+            .get()
+            .checked_add(state.used)?
+            .checked_next_multiple_of(layout.align())?
+            - base.addr().get();
+        let end = start.checked_add(layout.size())?;
+        if end > cap {
+            return None;
+        }
+        state.used = end;
+        // SAFETY: `start <= end <= cap`, and the mapping is valid for `cap`
+        // bytes.
+        Some(unsafe { base.add(start) })
+    }
+
+    /// Maps a new chunk large enough for `layout` and makes it current.
+    fn grow(&self, state: &mut State, layout: Layout) -> Result<(), AllocError> {
+        let len = HEADER
+            .checked_add(layout.size())
+            .and_then(|x| x.checked_add(layout.align()))
+            .map(|x| x.max(self.chunk_size))
+            .and_then(|x| x.checked_next_multiple_of(self.pagesize))
+            .ok_or(AllocError)?;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        self.stats.record_syscall(Syscall::Mmap);
+        // SAFETY: The kernel chooses the address.
+        let ptr = unsafe { mmap_anonymous(ptr::null_mut(), len, rw, MapFlags::PRIVATE) }
+            .map_err(|_| AllocError)?;
+        self.stats.record_map(len);
+        self.stats.record_commit(len);
+        self.stats.record_mapping();
+        let head = NonNull::new(ptr.cast::<Chunk>()).unwrap();
+        // SAFETY: The mapping is page-aligned and at least `HEADER` bytes.
+        unsafe {
+            head.write(Chunk {
+                next: state.head,
+                len,
+            })
+        };
+        state.head = Some(head);
+        state.used = HEADER;
+        Ok(())
+    }
+}
+
+impl Alloc for Base {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let mut state = self.state.lock();
+        let ptr = match Self::bump(&mut state, layout) {
+            Some(ptr) => ptr,
+            None => {
+                self.grow(&mut state, layout)?;
+                Self::bump(&mut state, layout).ok_or(AllocError)?
+            }
+        };
+        self.stats.record_alloc(layout.size());
+        // SAFETY: `bump` returns a pointer aligned to `layout.align()` and
+        // valid for `layout.size()` bytes.
+        Ok(unsafe { Tag::new(ptr, layout) })
+    }
+
+    /// Metadata lives as long as the base.
+    unsafe fn free(&self, tag: Tag) {}
+}
+
+impl Drop for Base {
+    fn drop(&mut self) {
+        let mut next = self.state.get_mut().head.take();
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk { next: n, len } = unsafe { chunk.read() };
+            next = n;
+            // SAFETY: The chunk was mapped in `grow` and nothing refers to it
+            // once the base is gone.
+            let _ = unsafe { munmap(chunk.as_ptr().cast(), len) };
+        }
+    }
+}
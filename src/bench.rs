@@ -0,0 +1,167 @@
+//! Workloads driven by the criterion benchmarks in `benches/`.
+//!
+//! This module is only compiled with the `bench` feature. It is the one
+//! public entry point into the crate's layers, and exists so that the
+//! benchmarks can compare layer stacks without the layers themselves being
+//! public.
+
+use core::alloc::Layout;
+use std::{boxed::Box, sync::mpsc, thread, vec::Vec};
+
+use crate::{
+    core::{Alloc, Tag},
+    fit::{Fit, FitConfig, Placement},
+    mmap::Mmap,
+    tracked::Tracked,
+};
+
+/// A layer configuration to benchmark.
+#[derive(Clone, Copy, Debug)]
+pub enum Stack {
+    /// The bare mmap backend.
+    Mmap,
+    /// The mmap backend with call-site tracking.
+    Tracked,
+    /// The extent allocator over mmap, taking the first fitting extent.
+    FirstFit,
+    /// The extent allocator over mmap, taking the shortest fitting extent.
+    BestFit,
+    /// The extent allocator over mmap, taking the lowest fitting extent.
+    LowestAddress,
+}
+
+impl Stack {
+    pub const ALL: &[Stack] = &[
+        Stack::Mmap,
+        Stack::Tracked,
+        Stack::FirstFit,
+        Stack::BestFit,
+        Stack::LowestAddress,
+    ];
+}
+
+fn fit(placement: Placement) -> Box<dyn Alloc + Sync> {
+    // The parent has to outlive the heap, and heaps are made once per
+    // benchmark, so leaking it costs little.
+    let parent = Box::leak(Box::new(Mmap::new()));
+    let config = FitConfig {
+        chunk_size: 16 << 20,
+        placement,
+        ..Default::default()
+    };
+    Box::new(Fit::with_config(parent, config))
+}
+
+/// An allocator built from a [`Stack`].
+pub struct Heap(Box<dyn Alloc + Sync>);
+
+impl Heap {
+    pub fn new(stack: Stack) -> Self {
+        match stack {
+            Stack::Mmap => Self(Box::new(Mmap::new())),
+            Stack::Tracked => Self(Box::new(Tracked::<_>::new(Mmap::new()))),
+            Stack::FirstFit => Self(fit(Placement::FirstFit)),
+            Stack::BestFit => Self(fit(Placement::BestFit)),
+            Stack::LowestAddress => Self(fit(Placement::LowestAddress)),
+        }
+    }
+
+    fn alloc(&self, size: usize, align: usize) -> Tag {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let tag = self.0.alloc(layout).unwrap();
+        // Touch the allocation so the cost of faulting it in is measured too.
+        // SAFETY: `tag` is valid for at least `size` bytes, and `size > 0`.
+        unsafe { tag.ptr().write(1) };
+        tag
+    }
+
+    fn free(&self, tag: Tag) {
+        // SAFETY: Every tag passed here was returned by `self.alloc`.
+        unsafe { self.0.free(tag) }
+    }
+}
+
+/// A xorshift generator, so that workloads are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Performs `ops` random frees and allocations of 1 byte to 64 KiB over a
+/// window of 64 live slots.
+pub fn churn(heap: &Heap, ops: usize) {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut slots: [Option<Tag>; 64] = [const { None }; 64];
+    for _ in 0..ops {
+        let i = rng.below(slots.len());
+        match slots[i].take() {
+            Some(tag) => heap.free(tag),
+            None => slots[i] = Some(heap.alloc(1 + rng.below(64 << 10), 8)),
+        }
+    }
+    slots.into_iter().flatten().for_each(|tag| heap.free(tag));
+}
+
+/// Allocates and immediately frees `ops` objects of 1 to 16 MiB, every
+/// fourth of them aligned to 2 MiB.
+pub fn large(heap: &Heap, ops: usize) {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for i in 0..ops {
+        let align = if i % 4 == 0 { 2 << 20 } else { 8 };
+        let tag = heap.alloc(1 + rng.below(16 << 20), align);
+        heap.free(tag);
+    }
+}
+
+/// Keeps every eighth of `ops` objects of 256 bytes to 64 KiB alive for
+/// the whole run and frees the rest in a random order, which fragments
+/// allocators that cannot coalesce or that place blocks carelessly.
+pub fn long_lived(heap: &Heap, ops: usize) {
+    let mut rng = Rng(0xbf58_476d_1ce4_e5b9);
+    let mut kept = Vec::new();
+    let mut slots: [Option<Tag>; 32] = [const { None }; 32];
+    for i in 0..ops {
+        let tag = heap.alloc(256 + rng.below(64 << 10), 16);
+        if i % 8 == 0 {
+            kept.push(tag);
+            continue;
+        }
+        let j = rng.below(slots.len());
+        if let Some(old) = slots[j].replace(tag) {
+            heap.free(old);
+        }
+    }
+    slots.into_iter().flatten().for_each(|tag| heap.free(tag));
+    kept.into_iter().for_each(|tag| heap.free(tag));
+}
+
+struct SendTag(Tag);
+
+// SAFETY: A `Tag` uniquely owns its allocation, and every `Alloc` used here
+// is `Sync`, so the allocation may be freed from any thread.
+unsafe impl Send for SendTag {}
+
+/// Allocates `ops` objects of 64 bytes to 4 KiB on one thread and frees them
+/// on another.
+pub fn producer_consumer(heap: &Heap, ops: usize) {
+    let (tx, rx) = mpsc::sync_channel::<SendTag>(256);
+    thread::scope(|s| {
+        s.spawn(move || rx.iter().for_each(|tag| heap.free(tag.0)));
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        for _ in 0..ops {
+            let tag = heap.alloc(64 + rng.below(4032), 16);
+            tx.send(SendTag(tag)).unwrap();
+        }
+        drop(tx);
+    });
+}
@@ -0,0 +1,280 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    hint,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+
+use crate::core::{Alloc, Tag};
+
+/// Marks in [`Tag::user`] an allocation that holds a permit.
+const COUNTED: u32 = 1 << 15;
+
+/// Limits how many large allocations made through `A` are live at once,
+/// and how many bytes they take in total, so that a burst of big requests
+/// cannot map an unbounded amount of memory at the same time.
+///
+/// An allocation of at least `large` bytes takes a permit before it reaches
+/// `A` and returns it when freed. While no permit is available it spins
+/// until another large allocation is freed, so only bound allocations that
+/// are released promptly. An allocation larger than the byte bound alone
+/// fails instead, since it could never proceed. Smaller allocations pass
+/// straight through. Growing an allocation that already holds a permit
+/// fails rather than waits if the extra bytes do not fit.
+pub(crate) struct Bounded<A> {
+    inner: A,
+    large: usize,
+    max_count: usize,
+    max_bytes: usize,
+    count: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl<A> Bounded<A> {
+    /// Bounds allocations of at least `large` bytes, initially without any
+    /// limit.
+    pub(crate) const fn new(inner: A, large: usize) -> Self {
+        Self {
+            inner,
+            large,
+            max_count: usize::MAX,
+            max_bytes: usize::MAX,
+            count: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allows at most `n` large allocations at once.
+    pub(crate) const fn with_max_count(mut self, n: usize) -> Self {
+        self.max_count = n;
+        self
+    }
+
+    /// Allows at most `n` bytes of large allocations at once. Inner
+    /// allocators that round sizes up can overshoot this by the rounding.
+    pub(crate) const fn with_max_bytes(mut self, n: usize) -> Self {
+        self.max_bytes = n;
+        self
+    }
+
+    /// The number of large allocations currently live, and their bytes.
+    pub(crate) fn in_flight(&self) -> (usize, usize) {
+        (self.count.load(Relaxed), self.bytes.load(Relaxed))
+    }
+
+    /// Takes a permit for `size` bytes if one is available.
+    fn try_acquire(&self, size: usize) -> bool {
+        let max_count = self.max_count;
+        if self
+            .count
+            .fetch_update(Acquire, Relaxed, |x| (x < max_count).then_some(x + 1))
+            .is_err()
+        {
+            return false;
+        }
+        let max_bytes = self.max_bytes;
+        if self
+            .bytes
+            .fetch_update(Acquire, Relaxed, |x| {
+                x.checked_add(size).filter(|&x| x <= max_bytes)
+            })
+            .is_err()
+        {
+            self.count.fetch_sub(1, Release);
+            return false;
+        }
+        true
+    }
+
+    fn release(&self, size: usize) {
+        self.bytes.fetch_sub(size, Release);
+        self.count.fetch_sub(1, Release);
+    }
+
+    /// Adds `size` bytes to a permit already held, if they fit.
+    fn try_extend(&self, size: usize) -> bool {
+        let max_bytes = self.max_bytes;
+        self.bytes
+            .fetch_update(Acquire, Relaxed, |x| {
+                x.checked_add(size).filter(|&x| x <= max_bytes)
+            })
+            .is_ok()
+    }
+
+    /// Moves the bytes counted for an allocation from `from` to `to`.
+    fn adjust(&self, from: usize, to: usize) {
+        if to > from {
+            self.bytes.fetch_add(to - from, Relaxed);
+        } else {
+            self.bytes.fetch_sub(from - to, Release);
+        }
+    }
+}
+
+impl<A: Alloc> Bounded<A> {
+    /// Allocates `layout` with `f` on the inner allocator, taking a permit
+    /// first if it is large.
+    fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A) -> Result<Tag, AllocError>,
+    ) -> Result<Tag, AllocError> {
+        let size = layout.size();
+        if size < self.large {
+            return f(&self.inner);
+        }
+        if size > self.max_bytes || self.max_count == 0 {
+            return Err(AllocError);
+        }
+        while !self.try_acquire(size) {
+            hint::spin_loop();
+        }
+        let tag = match f(&self.inner) {
+            Ok(tag) => tag,
+            Err(e) => {
+                self.release(size);
+                return Err(e);
+            }
+        };
+        // Account for what the inner allocator actually handed out, which
+        // is what `free` releases.
+        self.bytes.fetch_add(tag.layout().size() - size, Relaxed);
+        debug_assert!(tag.user() & COUNTED == 0);
+        let user = tag.user() | COUNTED;
+        Ok(tag.with_user(user))
+    }
+
+    /// Resizes `tag` to `new` with `f` on the inner allocator, carrying its
+    /// permit along. An allocation that becomes large takes a permit,
+    /// spinning as `alloc` does, and one that becomes small returns it. One
+    /// that stays large grows its permit only if the bytes are free right
+    /// away, and fails otherwise, since it may hold the bytes others are
+    /// waiting for.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed, and
+    /// `f` must uphold the contract of [`Alloc::grow`] or [`Alloc::shrink`].
+    unsafe fn resize(
+        &self,
+        tag: &Tag,
+        new: Layout,
+        f: impl FnOnce(&A, &Tag) -> Result<Tag, AllocError>,
+    ) -> Result<Tag, AllocError> {
+        let counted = tag.user() & COUNTED != 0;
+        let (old, size) = (tag.layout().size(), new.size());
+        let large = size >= self.large;
+        // The bytes the permit holds while `f` runs.
+        let held = match (counted, large) {
+            (false, false) => 0,
+            (false, true) => {
+                if size > self.max_bytes || self.max_count == 0 {
+                    return Err(AllocError);
+                }
+                while !self.try_acquire(size) {
+                    hint::spin_loop();
+                }
+                size
+            }
+            (true, true) if size > old => {
+                if !self.try_extend(size - old) {
+                    return Err(AllocError);
+                }
+                size
+            }
+            (true, _) => old,
+        };
+        // SAFETY: This is the tag the inner allocator returned, with our bit
+        // cleared.
+        let inner = unsafe { Tag::new(tag.ptr(), tag.layout()) }.with_user(tag.user() & !COUNTED);
+        let moved = match f(&self.inner, &inner) {
+            Ok(x) => x,
+            Err(e) => {
+                match (counted, large) {
+                    (false, false) => {}
+                    (false, true) => self.release(size),
+                    (true, _) => self.adjust(held, old),
+                }
+                return Err(e);
+            }
+        };
+        if !large {
+            if counted {
+                self.release(held);
+            }
+            return Ok(moved);
+        }
+        // Account for what the inner allocator actually handed out, which
+        // is what `free` releases.
+        self.adjust(held, moved.layout().size());
+        debug_assert!(moved.user() & COUNTED == 0);
+        let user = moved.user() | COUNTED;
+        Ok(moved.with_user(user))
+    }
+}
+
+impl<A: Alloc> Alloc for Bounded<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.alloc_with(layout, |x| x.alloc(layout))
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        if tag.user() & COUNTED == 0 {
+            return unsafe { self.inner.free(tag) };
+        }
+        let size = tag.layout().size();
+        let user = tag.user() & !COUNTED;
+        unsafe { self.inner.free(tag.with_user(user)) };
+        self.release(size);
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.alloc_with(layout, |x| x.alloc_zeroed(layout))
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, new, |inner, tag| inner.grow(tag, new)) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, new, |inner, tag| inner.shrink(tag, new)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::Mmap;
+
+    fn pages(n: usize) -> Layout {
+        Layout::from_size_align(n * rustix::param::page_size(), 8).unwrap()
+    }
+
+    #[test]
+    fn resize_carries_permit() {
+        let heap = Bounded::new(Mmap::new(), pages(4).size());
+        let tag = heap.alloc(pages(1)).unwrap();
+        assert_eq!(heap.in_flight(), (0, 0));
+        let tag = unsafe { heap.grow(&tag, pages(8)) }.unwrap();
+        assert_eq!(heap.in_flight(), (1, pages(8).size()));
+        let tag = unsafe { heap.grow(&tag, pages(16)) }.unwrap();
+        assert_eq!(heap.in_flight(), (1, pages(16).size()));
+        let tag = unsafe { heap.shrink(&tag, pages(1)) }.unwrap();
+        assert_eq!(heap.in_flight(), (0, 0));
+        unsafe { heap.free(tag) };
+    }
+
+    #[test]
+    fn grow_past_max_bytes_fails() {
+        let heap = Bounded::new(Mmap::new(), pages(4).size()).with_max_bytes(pages(8).size());
+        let tag = heap.alloc(pages(8)).unwrap();
+        assert!(unsafe { heap.grow(&tag, pages(16)) }.is_err());
+        assert_eq!(heap.in_flight(), (1, pages(8).size()));
+        unsafe { heap.free(tag) };
+        assert_eq!(heap.in_flight(), (0, 0));
+    }
+}
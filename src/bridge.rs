@@ -0,0 +1,212 @@
+#![allow(unused)]
+
+use alloc::boxed::Box;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    mem::MaybeUninit,
+    ptr::NonNull,
+};
+
+use crate::core::{Alloc, Tag};
+
+/// Implements [`Alloc`] on top of a [`core::alloc::Allocator`], such as
+/// `alloc::alloc::Global`, so that this crate's layers can wrap it while the
+/// mmap backend is not yet in use everywhere.
+pub(crate) struct FromAllocator<T>(pub(crate) T);
+
+impl<T: Allocator> Alloc for FromAllocator<T> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let ptr = self.0.allocate(layout)?;
+        // SAFETY: `allocate` returns a block that fits `layout`.
+        Ok(unsafe { Tag::new(ptr.cast(), layout) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        // SAFETY: `tag` was returned by `self.alloc`, so it describes a block
+        // allocated by `self.0` with this layout.
+        unsafe { self.0.deallocate(tag.ptr(), tag.layout()) }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let ptr = self.0.allocate_zeroed(layout)?;
+        // SAFETY: As in `alloc`.
+        Ok(unsafe { Tag::new(ptr.cast(), layout) })
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        // SAFETY: The caller upholds the contract of `Alloc::grow`, which
+        // implies that of `Allocator::grow`.
+        let ptr = unsafe { self.0.grow(tag.ptr(), tag.layout(), new) }?;
+        Ok(unsafe { Tag::new(ptr.cast(), new) })
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        // SAFETY: As in `grow`.
+        let ptr = unsafe { self.0.shrink(tag.ptr(), tag.layout(), new) }?;
+        Ok(unsafe { Tag::new(ptr.cast(), new) })
+    }
+}
+
+/// Implements [`core::alloc::Allocator`] on top of an allocator stack, so
+/// that standard collections can allocate from it.
+///
+/// `Allocator::deallocate` only gets the pointer and layout back, so the
+/// stack must not rely on [`Tag::user`] bits to route frees. Layers such as
+/// `Arenas` and `Categorized` do, and cannot be used below this adapter:
+/// every allocation through it fails.
+pub(crate) struct ToAllocator<A>(pub(crate) A);
+
+impl<A: Alloc> ToAllocator<A> {
+    /// Hands out a fresh allocation, or frees it and fails if the stack set
+    /// user bits that `deallocate` could not give back.
+    fn slice(&self, tag: Tag) -> Result<NonNull<[u8]>, AllocError> {
+        if tag.user() != 0 {
+            // SAFETY: `tag` was just returned by the stack and is unused.
+            unsafe { self.0.free(tag) };
+            return Err(AllocError);
+        }
+        Ok(NonNull::slice_from_raw_parts(
+            tag.ptr(),
+            tag.layout().size(),
+        ))
+    }
+
+    /// Hands out a grown or shrunk allocation. The old block is gone by now,
+    /// so user bits cannot be refused as in `slice`. Only a stack that sets
+    /// them on `grow` or `shrink` but not on `alloc` gets here.
+    fn moved(tag: Tag) -> NonNull<[u8]> {
+        assert!(tag.user() == 0, "user bits would be lost");
+        NonNull::slice_from_raw_parts(tag.ptr(), tag.layout().size())
+    }
+}
+
+// SAFETY: Blocks stay valid until they are passed back to `deallocate`,
+// `grow` or `shrink`, as the `Alloc` contract guarantees, and the adapter
+// can be moved or shared exactly as the stack can.
+unsafe impl<A: Alloc> Allocator for ToAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.slice(self.0.alloc(layout)?)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.slice(self.0.alloc_zeroed(layout)?)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // SAFETY: `ptr` was allocated with `layout` by `allocate`, which got
+        // it from `self.0`.
+        unsafe { self.0.free(Tag::new(ptr, layout)) }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: As in `deallocate`, and the caller upholds the contract of
+        // `Allocator::grow`, which implies that of `Alloc::grow`.
+        unsafe { self.0.grow(&Tag::new(ptr, old), new) }.map(Self::moved)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: As in `grow`.
+        unsafe { self.0.shrink(&Tag::new(ptr, old), new) }.map(Self::moved)
+    }
+}
+
+/// The allocator of a box made by [`into_boxed`]: frees through the stack
+/// under [`ToAllocator`] with the layout the stack handed out, which may be
+/// larger or more aligned than the slice the box holds.
+pub(crate) struct Boxed<'a, A> {
+    alloc: &'a ToAllocator<A>,
+    layout: Layout,
+}
+
+// SAFETY: The box never allocates through its allocator, and the one block
+// it frees is the one `into_boxed` was given, with its original layout.
+unsafe impl<A: Alloc> Allocator for Boxed<'_, A> {
+    fn allocate(&self, _: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Err(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _: Layout) {
+        // SAFETY: `ptr` is the block `into_boxed` took from `self.alloc.0`,
+        // which allocated it with `self.layout`.
+        unsafe { self.alloc.0.free(Tag::new(ptr, self.layout)) }
+    }
+}
+
+/// Hands the allocation described by `tag` to a boxed slice of `T`, which
+/// frees it through `alloc` when dropped, so that memory from the stack can
+/// flow into standard containers.
+///
+/// The slice is as long as fits in the allocation, which must hold at least
+/// one `T`, be aligned for `T` and have no [`Tag::user`] bits. Otherwise
+/// `tag` is given back.
+///
+/// # SAFETY
+///
+/// `tag` must have been returned by `alloc.0.alloc` and not yet freed.
+pub(crate) unsafe fn into_boxed<'a, T, A: Alloc>(
+    tag: Tag,
+    alloc: &'a ToAllocator<A>,
+) -> Result<Box<[MaybeUninit<T>], Boxed<'a, A>>, Tag> {
+    let layout = tag.layout();
+    let size = size_of::<T>();
+    if size == 0 || layout.size() < size || layout.align() < align_of::<T>() || tag.user() != 0 {
+        return Err(tag);
+    }
+    let ptr = NonNull::slice_from_raw_parts(tag.ptr().cast(), layout.size() / size);
+    // SAFETY: The allocation is aligned for `T` and holds the slice, and the
+    // box frees it through the stack under `alloc` with its own layout.
+    Ok(unsafe { Box::from_raw_in(ptr.as_ptr(), Boxed { alloc, layout }) })
+}
+
+/// The counterpart of [`into_boxed`]: takes the memory of `boxed` back from
+/// the standard library as the tag it was made from, to be freed through
+/// `alloc.0` or passed on.
+pub(crate) fn from_boxed<T, A: Alloc>(boxed: Box<[MaybeUninit<T>], Boxed<'_, A>>) -> Tag {
+    let (ptr, Boxed { layout, .. }) = Box::into_raw_with_allocator(boxed);
+    // SAFETY: The box owns the allocation `into_boxed` was given, which is
+    // `layout` long and came from the stack under its allocator.
+    unsafe { Tag::new(NonNull::new(ptr).unwrap().cast(), layout) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::Mmap;
+
+    #[test]
+    fn box_mmap_tag() {
+        let alloc = ToAllocator(Mmap::new());
+        let tag = alloc
+            .0
+            .alloc(Layout::from_size_align(100, 4).unwrap())
+            .unwrap();
+        // The backend rounds the layout up to whole pages.
+        let layout = tag.layout();
+        assert!(layout.size() > 100);
+        let Ok(mut boxed) = (unsafe { into_boxed::<u32, _>(tag, &alloc) }) else {
+            panic!("a page-aligned tag holds a slice of u32");
+        };
+        assert_eq!(boxed.len(), layout.size() / 4);
+        for (i, x) in boxed.iter_mut().enumerate() {
+            x.write(i as u32);
+        }
+        let tag = from_boxed(boxed);
+        assert_eq!(tag.layout(), layout);
+        let Ok(boxed) = (unsafe { into_boxed::<u32, _>(tag, &alloc) }) else {
+            panic!("the tag came back unchanged");
+        };
+        assert_eq!(unsafe { boxed[7].assume_init() }, 7);
+        drop(boxed);
+        assert_eq!(alloc.0.stats().allocated(), 0);
+    }
+}
@@ -0,0 +1,254 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::{self, NonNull},
+};
+
+use rustix::{
+    io::Errno,
+    mm::{self, Advice, MapFlags, MprotectFlags, ProtFlags},
+};
+
+use crate::{
+    core::{Alloc, Budget, FreeAll, Grind, GrindReport, Owns, Tag},
+    registry::{Region, RegionState},
+    sync::SpinLock,
+};
+
+struct State {
+    /// Offset of the break: everything below it may be in use.
+    brk: usize,
+    /// Offset of the end of the committed pages.
+    committed: usize,
+}
+
+/// A single contiguous heap that grows by moving a break pointer, for
+/// consumers such as conservative GCs that want all memory in one range.
+///
+/// The whole capacity is reserved up front without access, and pages are
+/// committed as the break moves past them. Only the allocation just below
+/// the break can be given back by `free`, which moves the break down; other
+/// frees are left to the layers above. [`Grind::grind`] decommits the pages
+/// above the break.
+pub(crate) struct Brk {
+    base: NonNull<u8>,
+    cap: usize,
+    pagesize: usize,
+    probe: bool,
+    state: SpinLock<State>,
+}
+
+// SAFETY: The reservation is owned by the heap, and the break is only moved
+// with the lock held.
+unsafe impl Send for Brk {}
+unsafe impl Sync for Brk {}
+
+impl Brk {
+    /// Reserves `cap` bytes of address space, rounded up to whole pages.
+    pub(crate) fn new(cap: usize) -> Result<Self, Errno> {
+        let pagesize = rustix::param::page_size();
+        let cap = cap
+            .max(1)
+            .checked_next_multiple_of(pagesize)
+            .ok_or(Errno::NOMEM)?;
+        let flags = MapFlags::PRIVATE | MapFlags::NORESERVE;
+        // SAFETY: The kernel chooses the address.
+        let base = unsafe { mm::mmap_anonymous(ptr::null_mut(), cap, ProtFlags::empty(), flags) }?;
+        Ok(Self {
+            base: NonNull::new(base.cast()).unwrap(),
+            cap,
+            pagesize,
+            probe: false,
+            state: SpinLock::new(State {
+                brk: 0,
+                committed: 0,
+            }),
+        })
+    }
+
+    /// Populates pages with `MADV_POPULATE_WRITE` as they are committed, so
+    /// that running out of memory fails the allocation rather than raising
+    /// `SIGBUS` or waking the OOM killer on first touch. Needs Linux 5.14.
+    pub(crate) fn with_probe(mut self) -> Self {
+        self.probe = true;
+        self
+    }
+
+    /// The start of the heap.
+    #[inline]
+    pub(crate) fn base(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Bytes of the reservation that are committed, i.e. accessible. The
+    /// rest of [`Brk::capacity`] is only reserved address space.
+    pub(crate) fn committed(&self) -> usize {
+        self.state.lock().committed
+    }
+
+    /// Like [`Brk::committed`], but `None` rather than waiting if the heap is
+    /// locked.
+    pub(crate) fn try_committed(&self) -> Option<usize> {
+        self.state.try_lock().map(|x| x.committed)
+    }
+
+    /// The current break, as an offset from [`Brk::base`].
+    pub(crate) fn brk(&self) -> usize {
+        self.state.lock().brk
+    }
+
+    /// Whether `ptr` lies below the current break.
+    pub(crate) fn contains(&self, ptr: NonNull<u8>) -> bool {
+        let brk = self.brk();
+        (self.base.addr().get()..self.base.addr().get() + brk).contains(&ptr.addr().get())
+    }
+
+    /// Calls `f` on the part of the reservation below the break, the
+    /// committed pages above it, and the uncommitted rest, skipping any that
+    /// are empty.
+    pub(crate) fn regions(&self, f: &mut dyn FnMut(Region)) {
+        let (brk, committed) = {
+            let state = self.state.lock();
+            (state.brk, state.committed)
+        };
+        let base = self.base.addr().get();
+        let parts = [
+            (0, brk, RegionState::Active),
+            (brk, committed, RegionState::Dirty),
+            (committed, self.cap, RegionState::Reserved),
+        ];
+        for (start, end, state) in parts {
+            if start < end {
+                f(Region {
+                    addr: base + start,
+                    len: end - start,
+                    state,
+                });
+            }
+        }
+    }
+
+    /// Decommits the committed pages that lie wholly at or above offset `to`.
+    fn decommit(&self, state: &mut State, to: usize) -> GrindReport {
+        let from = to.next_multiple_of(self.pagesize);
+        if from >= state.committed {
+            return GrindReport::default();
+        }
+        let len = state.committed - from;
+        // SAFETY: `from..committed` lies within the reservation and above the
+        // break, so it holds no live allocations.
+        let ptr = unsafe { self.base.add(from) }.as_ptr().cast();
+        let ok = unsafe {
+            mm::madvise(ptr, len, Advice::LinuxDontNeed).is_ok()
+                && mm::mprotect(ptr, len, MprotectFlags::empty()).is_ok()
+        };
+        if !ok {
+            return GrindReport {
+                duration_hint: 2,
+                ..GrindReport::default()
+            };
+        }
+        state.committed = from;
+        GrindReport {
+            purged_bytes: len,
+            unmapped_bytes: 0,
+            duration_hint: 2,
+        }
+    }
+}
+
+impl Alloc for Brk {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let base = self.base.addr().get();
+        let mut state = self.state.lock();
+        let start = base
+            .checked_add(state.brk)
+            .and_then(|x| x.checked_next_multiple_of(layout.align()))
+            .ok_or(AllocError)?
+            - base;
+        let end = start
+            .checked_add(layout.size())
+            .filter(|&x| x <= self.cap)
+            .ok_or(AllocError)?;
+        if end > state.committed {
+            let to = end.next_multiple_of(self.pagesize);
+            let from = state.committed;
+            let rw = MprotectFlags::READ | MprotectFlags::WRITE;
+            // SAFETY: `from..to` lies within the reservation.
+            let ptr = unsafe { self.base.add(from) }.as_ptr().cast();
+            unsafe { mm::mprotect(ptr, to - from, rw) }.map_err(|_| AllocError)?;
+            state.committed = to;
+            // SAFETY: `from..to` is committed and above the break.
+            if self.probe
+                && unsafe { mm::madvise(ptr, to - from, Advice::LinuxPopulateWrite) }.is_err()
+            {
+                self.decommit(&mut state, from);
+                return Err(AllocError);
+            }
+        }
+        state.brk = end;
+        // SAFETY: `start..end` is committed, below the break, and aligned.
+        Ok(unsafe { Tag::new(self.base.add(start), layout) })
+    }
+
+    /// Moves the break down if `tag` is the allocation just below it, and
+    /// otherwise does nothing.
+    unsafe fn free(&self, tag: Tag) {
+        let start = tag.ptr().addr().get() - self.base.addr().get();
+        let mut state = self.state.lock();
+        if start + tag.layout().size() == state.brk {
+            state.brk = start;
+        }
+    }
+}
+
+impl Owns for Brk {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.contains(ptr)
+    }
+}
+
+impl Grind for Brk {
+    fn grind(&self) -> GrindReport {
+        let mut state = self.state.lock();
+        let brk = state.brk;
+        self.decommit(&mut state, brk)
+    }
+
+    /// Decommits the highest pages above the break, counting them as one
+    /// extent, until `budget` is spent.
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        let mut state = self.state.lock();
+        let to = match budget {
+            Budget::Bytes(max) => {
+                let max = max.next_multiple_of(self.pagesize);
+                state.brk.max(state.committed.saturating_sub(max))
+            }
+            Budget::Extents(0) => return GrindReport::default(),
+            Budget::Extents(_) => state.brk,
+        };
+        self.decommit(&mut state, to)
+    }
+}
+
+impl FreeAll for Brk {
+    /// Resets the break to the base and decommits the whole heap.
+    unsafe fn free_all(&self) {
+        let mut state = self.state.lock();
+        state.brk = 0;
+        self.decommit(&mut state, 0);
+    }
+}
+
+impl Drop for Brk {
+    fn drop(&mut self) {
+        // SAFETY: The reservation was mapped in `new`.
+        let _ = unsafe { mm::munmap(self.base.as_ptr().cast(), self.cap) };
+    }
+}
@@ -0,0 +1,248 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    cell::Cell,
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use rustix::io::Errno;
+
+use crate::{
+    core::{Alloc, GrindReport, Tag},
+    registry::{Fault, Member, Region},
+    stats::Stats,
+};
+
+/// The category set by the innermost [`CategoryScope`] on this thread.
+#[thread_local]
+static CURRENT: Cell<Category> = Cell::new(Category::Other);
+
+/// Where the category is kept in [`Tag::user`].
+const TAG_SHIFT: u32 = 16;
+const TAG_MASK: u32 = 0xff << TAG_SHIFT;
+
+/// The subsystem an allocation is attributed to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Category {
+    /// Allocations made without a category or scope.
+    #[default]
+    Other,
+    Network,
+    Parser,
+    Storage,
+    Cache,
+    Metadata,
+}
+
+impl Category {
+    pub(crate) const ALL: [Category; 6] = [
+        Category::Other,
+        Category::Network,
+        Category::Parser,
+        Category::Storage,
+        Category::Cache,
+        Category::Metadata,
+    ];
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Category::Other => "other",
+            Category::Network => "network",
+            Category::Parser => "parser",
+            Category::Storage => "storage",
+            Category::Cache => "cache",
+            Category::Metadata => "metadata",
+        }
+    }
+}
+
+/// Counts live bytes per [`Category`], so that one shared heap can still
+/// answer who is using the memory.
+///
+/// Allocations are attributed to the category passed to
+/// [`Categorized::alloc_tagged`], or else to the one set by the innermost
+/// [`Categorized::category_scope`] on the current thread. The category of
+/// each allocation is kept in bits 16 to 23 of [`Tag::user`], so frees are
+/// attributed without a side table.
+pub(crate) struct Categorized<A> {
+    inner: A,
+    live: [AtomicUsize; Category::ALL.len()],
+}
+
+impl<A> Categorized<A> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: [const { AtomicUsize::new(0) }; Category::ALL.len()],
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Bytes currently allocated under `category`.
+    #[inline]
+    pub(crate) fn live(&self, category: Category) -> usize {
+        self.live[category as usize].load(Relaxed)
+    }
+
+    /// Returns a report of live bytes per category.
+    pub(crate) fn report(&self) -> Report<'_, A> {
+        Report(self)
+    }
+
+    /// Attributes untagged allocations made on this thread to `category`
+    /// until the guard is dropped, so that it need not be passed down
+    /// through every call. Scopes nest, and dropping a guard restores the
+    /// category it replaced.
+    pub(crate) fn category_scope(&self, category: Category) -> CategoryScope {
+        CategoryScope {
+            prev: CURRENT.replace(category),
+            _thread: PhantomData,
+        }
+    }
+}
+
+/// Restores the previous category of the thread on drop.
+#[must_use]
+pub(crate) struct CategoryScope {
+    prev: Category,
+    /// The scope belongs to the thread that made it.
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for CategoryScope {
+    fn drop(&mut self) {
+        CURRENT.set(self.prev);
+    }
+}
+
+impl<A: Alloc> Categorized<A> {
+    /// Allocates `layout` and attributes it to `category`.
+    pub(crate) fn alloc_tagged(
+        &self,
+        layout: Layout,
+        category: Category,
+    ) -> Result<Tag, AllocError> {
+        self.inner
+            .alloc(layout)
+            .map(|x| self.attribute(x, category))
+    }
+
+    /// Counts a new allocation from the inner allocator towards `category`
+    /// and tags it with it.
+    fn attribute(&self, tag: Tag, category: Category) -> Tag {
+        debug_assert!(tag.user() & TAG_MASK == 0);
+        self.live[category as usize].fetch_add(tag.layout().size(), Relaxed);
+        let user = tag.user() | (category as u32) << TAG_SHIFT;
+        tag.with_user(user)
+    }
+
+    /// Resizes `tag` with `f` on the inner allocator, keeping its category.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed, and
+    /// `f` must uphold the contract of [`Alloc::grow`] or [`Alloc::shrink`].
+    unsafe fn resize(
+        &self,
+        tag: &Tag,
+        f: impl FnOnce(&A, &Tag) -> Result<Tag, AllocError>,
+    ) -> Result<Tag, AllocError> {
+        let category = ((tag.user() & TAG_MASK) >> TAG_SHIFT) as usize;
+        // SAFETY: This is the tag the inner allocator returned, with our bits
+        // cleared.
+        let inner = unsafe { Tag::new(tag.ptr(), tag.layout()) }.with_user(tag.user() & !TAG_MASK);
+        let moved = f(&self.inner, &inner)?;
+        self.live[category].fetch_add(moved.layout().size(), Relaxed);
+        self.live[category].fetch_sub(tag.layout().size(), Relaxed);
+        let user = moved.user() | (category as u32) << TAG_SHIFT;
+        Ok(moved.with_user(user))
+    }
+}
+
+impl<A: Alloc> Alloc for Categorized<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.alloc_tagged(layout, CURRENT.get())
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let category = ((tag.user() & TAG_MASK) >> TAG_SHIFT) as usize;
+        self.live[category].fetch_sub(tag.layout().size(), Relaxed);
+        let user = tag.user() & !TAG_MASK;
+        unsafe { self.inner.free(tag.with_user(user)) }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let category = CURRENT.get();
+        self.inner
+            .alloc_zeroed(layout)
+            .map(|x| self.attribute(x, category))
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, |inner, tag| inner.grow(tag, new)) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.resize(tag, |inner, tag| inner.shrink(tag, new)) }
+    }
+}
+
+impl<A: Member> Member for Categorized<A> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.inner.stats()
+    }
+
+    fn grind(&self) -> GrindReport {
+        self.inner.grind()
+    }
+
+    fn trim(&self, keep: usize) -> GrindReport {
+        self.inner.trim(keep)
+    }
+
+    fn pre_dump(&self) -> GrindReport {
+        self.inner.pre_dump()
+    }
+
+    fn post_restore(&self) -> Result<(), Errno> {
+        self.inner.post_restore()
+    }
+
+    /// Live bytes by category, followed by those of the inner heap.
+    fn leaks(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.report())?;
+        self.inner.leaks(out)
+    }
+
+    fn fault(&self, addr: usize) -> Option<Fault> {
+        self.inner.fault(addr)
+    }
+
+    fn regions(&self, f: &mut dyn FnMut(Region)) {
+        self.inner.regions(f)
+    }
+}
+
+/// Live bytes per category, one `name bytes` line per category.
+pub(crate) struct Report<'a, A>(&'a Categorized<A>);
+
+impl<A> fmt::Display for Report<'_, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for category in Category::ALL {
+            writeln!(f, "{:<10} {:>15}", category.name(), self.0.live(category))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    sync::atomic::{AtomicU8, AtomicU64, Ordering::Relaxed},
+};
+
+use crate::{
+    core::{Alloc, Budget, Grind, GrindReport, Tag},
+    heap, registry,
+};
+
+/// Maintenance that [`Chaos`] can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// [`Grind::grind`] on the wrapped allocator.
+    Grind,
+    /// [`Grind::grind_budgeted`] on the wrapped allocator, releasing a
+    /// single extent.
+    GrindOne,
+    /// [`heap::flush_scratch`] on the calling thread.
+    FlushScratch,
+    /// [`heap::reclaim_idle_scratch`] across all threads.
+    ReclaimScratch,
+    /// [`registry::trim`] of every registered heap, keeping nothing.
+    Trim,
+}
+
+impl Action {
+    const ALL: [Action; 5] = [
+        Action::Grind,
+        Action::GrindOne,
+        Action::FlushScratch,
+        Action::ReclaimScratch,
+        Action::Trim,
+    ];
+}
+
+/// A testing layer that runs maintenance at random points among the
+/// allocations and frees made through `A`, to shake out ordering bugs
+/// between maintenance and the hot paths before they ship.
+///
+/// Before or after roughly one in `one_in` calls, it picks an [`Action`]
+/// and runs it on the calling thread. The choices come from a generator
+/// seeded by `seed`, so a failing single-threaded run can be replayed with
+/// the same seed; with several threads, the interleaving still varies. This
+/// is far too slow, and too disruptive to other heaps, for production.
+pub(crate) struct Chaos<A> {
+    inner: A,
+    one_in: u64,
+    state: AtomicU64,
+    runs: AtomicU64,
+    /// The index in [`Action::ALL`] of the last action run.
+    last: AtomicU8,
+}
+
+impl<A> Chaos<A> {
+    /// # Panics
+    ///
+    /// Panics if `one_in` is zero.
+    pub(crate) const fn new(inner: A, seed: u64, one_in: u64) -> Self {
+        assert!(one_in != 0);
+        // xorshift has a fixed point at zero.
+        let seed = if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        };
+        Self {
+            inner,
+            one_in,
+            state: AtomicU64::new(seed),
+            runs: AtomicU64::new(0),
+            last: AtomicU8::new(u8::MAX),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// The number of actions run so far, and the last of them, for
+    /// reporting alongside a failure.
+    pub(crate) fn last(&self) -> Option<(u64, Action)> {
+        let action = Action::ALL.get(self.last.load(Relaxed) as usize)?;
+        Some((self.runs.load(Relaxed), *action))
+    }
+
+    fn next(&self) -> u64 {
+        let mut x = self.state.load(Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Relaxed);
+        x
+    }
+}
+
+impl<A: Grind> Chaos<A> {
+    /// Rolls for an action, and runs it if `before` matches the roll.
+    fn roll(&self, before: bool) {
+        let x = self.next();
+        if !x.is_multiple_of(self.one_in) || (x >> 32) & 1 != before as u64 {
+            return;
+        }
+        let i = (x >> 33) as usize % Action::ALL.len();
+        self.runs.fetch_add(1, Relaxed);
+        self.last.store(i as u8, Relaxed);
+        match Action::ALL[i] {
+            Action::Grind => {
+                self.inner.grind();
+            }
+            Action::GrindOne => {
+                self.inner.grind_budgeted(Budget::Extents(1));
+            }
+            Action::FlushScratch => heap::flush_scratch(),
+            Action::ReclaimScratch => {
+                heap::reclaim_idle_scratch(0);
+            }
+            Action::Trim => {
+                registry::trim(0);
+            }
+        }
+    }
+}
+
+impl<A: Alloc + Grind> Alloc for Chaos<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.roll(true);
+        let res = self.inner.alloc(layout);
+        self.roll(false);
+        res
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        self.roll(true);
+        unsafe { self.inner.free(tag) };
+        self.roll(false);
+    }
+}
+
+impl<A: Grind> Grind for Chaos<A> {
+    fn grind(&self) -> GrindReport {
+        self.inner.grind()
+    }
+
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        self.inner.grind_budgeted(budget)
+    }
+
+    fn compact(&self) -> GrindReport {
+        self.inner.compact()
+    }
+}
@@ -0,0 +1,64 @@
+#![allow(unused)]
+
+/// A broken heap invariant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Violation {
+    /// Two live allocations, or two free extents, starting at the given
+    /// addresses, overlap.
+    Overlap { a: usize, b: usize },
+    /// The block or extent at `addr` is not aligned to `align` bytes, or
+    /// its length is not a multiple of them.
+    Misaligned { addr: usize, align: usize },
+    /// The free extent at `b` comes after the one at `a` in an index
+    /// ordered by address, but lies below it or directly against it, where
+    /// the two should have been merged.
+    Disordered { a: usize, b: usize },
+    /// The free extent at `addr` is not within the space for blocks of any
+    /// chunk of its allocator.
+    Stray { addr: usize },
+    /// The structure at `addr` keeps a count or sum of `kept`, but counting
+    /// what it covers gives `found`.
+    Miscount {
+        addr: usize,
+        kept: usize,
+        found: usize,
+    },
+    /// The metadata at `addr` holds a value that no operation writes.
+    Corrupt { addr: usize },
+}
+
+/// The outcome of a [`Check`] pass.
+///
+/// Only the first violation is kept, so that checking never allocates. The
+/// total count tells the caller whether there were more.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Report {
+    /// The number of objects inspected.
+    pub(crate) checked: usize,
+    /// The number of violations found.
+    pub(crate) violations: usize,
+    /// The first violation found.
+    pub(crate) first: Option<Violation>,
+}
+
+impl Report {
+    #[inline]
+    pub(crate) fn is_ok(&self) -> bool {
+        self.violations == 0
+    }
+
+    pub(crate) fn violation(&mut self, v: Violation) {
+        self.violations += 1;
+        self.first.get_or_insert(v);
+    }
+}
+
+/// Validates the internal invariants of an allocator layer.
+///
+/// Implementations walk their own metadata and then check the layer they
+/// wrap, so that checking the outermost layer checks the whole stack.
+/// Checking takes the same locks as allocation and may be slow; it is meant
+/// for watchdogs and debugging, not hot paths.
+pub(crate) trait Check {
+    fn check(&self, report: &mut Report);
+}
@@ -0,0 +1,100 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    num::NonZero,
+    ptr::NonNull,
+};
+
+use rustix::io::Errno;
+
+use crate::{
+    brk::Brk,
+    core::{Alloc, Budget, Grind, GrindReport, Owns, Tag},
+};
+
+/// A heap confined to one contiguous reservation of `4 GiB << SHIFT`, whose
+/// allocations can be named by 32-bit offsets from its base.
+///
+/// Every allocation is aligned to `1 << SHIFT` bytes, so a compressed
+/// pointer is the byte offset shifted right by `SHIFT`: `SHIFT = 3` gives a
+/// 32 GiB heap of 8-byte aligned objects. The first granule is never handed
+/// out, so compressed pointers are non-zero and `Option<NonZero<u32>>` can
+/// stand for a nullable one. Memory comes from a [`Brk`] over the
+/// reservation.
+pub(crate) struct Compressed<const SHIFT: u32 = 3> {
+    heap: Brk,
+}
+
+impl<const SHIFT: u32> Compressed<SHIFT> {
+    const GRANULE: usize = 1 << SHIFT;
+
+    pub(crate) fn new() -> Result<Self, Errno> {
+        let cap = (1usize << 32).checked_shl(SHIFT).ok_or(Errno::NOMEM)?;
+        let heap = Brk::new(cap)?;
+        // Burn the first granule, so that no allocation is at offset zero.
+        let first = Layout::from_size_align(Self::GRANULE, Self::GRANULE).unwrap();
+        heap.alloc(first).map_err(|_| Errno::NOMEM)?;
+        Ok(Self { heap })
+    }
+
+    /// The start of the reservation, which compressed pointers are relative
+    /// to.
+    #[inline]
+    pub(crate) fn base(&self) -> NonNull<u8> {
+        self.heap.base()
+    }
+
+    /// Compresses a pointer to an allocation from this heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` does not point into the heap at a granule boundary.
+    pub(crate) fn encode(&self, ptr: NonNull<u8>) -> NonZero<u32> {
+        let ost = ptr.addr().get().wrapping_sub(self.base().addr().get());
+        assert!(ost < self.heap.capacity() && ost.is_multiple_of(Self::GRANULE));
+        NonZero::new((ost >> SHIFT) as u32).expect("pointer to the reserved granule")
+    }
+
+    /// Expands a compressed pointer. The result has the provenance of the
+    /// whole reservation.
+    #[inline]
+    pub(crate) fn decode(&self, ptr: NonZero<u32>) -> NonNull<u8> {
+        // SAFETY: Every `u32` shifted by `SHIFT` is within the reservation.
+        unsafe { self.base().add((ptr.get() as usize) << SHIFT) }
+    }
+}
+
+impl<const SHIFT: u32> Alloc for Compressed<SHIFT> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let layout = layout
+            .align_to(Self::GRANULE)
+            .map_err(|_| AllocError)?
+            .pad_to_align();
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        unsafe { self.heap.free(tag) }
+    }
+}
+
+impl<const SHIFT: u32> Owns for Compressed<SHIFT> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.heap.owns(ptr)
+    }
+}
+
+impl<const SHIFT: u32> Grind for Compressed<SHIFT> {
+    fn grind(&self) -> GrindReport {
+        self.heap.grind()
+    }
+
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        self.heap.grind_budgeted(budget)
+    }
+
+    fn compact(&self) -> GrindReport {
+        self.heap.compact()
+    }
+}
@@ -2,13 +2,19 @@
 
 use core::{
     alloc::{AllocError, Layout},
+    mem,
     num::NonZero,
     ptr::{self, NonNull},
 };
 
+use rustix::{io::Errno, mm};
+
+use crate::layout::LayoutExt;
+
 pub(crate) struct Tag {
     ptr: NonNull<u8>,
     layout: Layout,
+    user: u32,
 }
 
 impl Tag {
@@ -17,7 +23,11 @@ impl Tag {
     /// TODO@safety
     /// `ptr` must be aligned to `layout.align()` and valid for `layout.size()`.
     pub(crate) unsafe fn new(ptr: NonNull<u8>, layout: Layout) -> Self {
-        Self { ptr, layout }
+        Self {
+            ptr,
+            layout,
+            user: 0,
+        }
     }
 
     #[inline]
@@ -29,19 +39,269 @@ impl Tag {
     pub(crate) fn layout(&self) -> Layout {
         self.layout
     }
+
+    /// Bits that layers can use to remember routing decisions, such as which
+    /// inner allocator or pool served the allocation, so that they need no
+    /// side table to route the free. Fresh tags have no bits set.
+    ///
+    /// The bits are shared by the whole stack, so each layer should claim a
+    /// disjoint range, and clear it before passing the tag back down.
+    #[inline]
+    pub(crate) fn user(&self) -> u32 {
+        self.user
+    }
+
+    #[inline]
+    pub(crate) fn with_user(self, user: u32) -> Self {
+        Self { user, ..self }
+    }
+
+    /// Tells the kernel how the allocation will be accessed, so that it can
+    /// read ahead or release cache accordingly. The advice applies to every
+    /// page the allocation touches, including parts shared with neighbouring
+    /// allocations, and `Sequential` and `Random` may split the mapping.
+    pub(crate) fn advise(&self, advice: Advice) -> Result<(), Errno> {
+        let advice = match advice {
+            Advice::WillNeed => mm::Advice::WillNeed,
+            Advice::Sequential => mm::Advice::Sequential,
+            Advice::Random => mm::Advice::Random,
+        };
+        let pagesize = rustix::param::page_size();
+        let start = self.ptr.addr().get() & !(pagesize - 1);
+        let end = (self.ptr.addr().get() + self.layout.size()).next_multiple_of(pagesize);
+        let ptr = self.ptr.with_addr(NonZero::new(start).unwrap());
+        // SAFETY: This advice does not change the contents of the range, and
+        // the pages are mapped since the allocation lies on them.
+        unsafe { mm::madvise(ptr.as_ptr().cast(), end - start, advice) }
+    }
+
+    /// Releases the physical pages wholly inside the allocation, with
+    /// `MADV_DONTNEED` or the lazier `MADV_FREE`. Partial pages at either end
+    /// are left alone.
+    ///
+    /// # SAFETY
+    ///
+    /// Nothing may rely on the contents of the released pages: afterwards
+    /// they read as zeroes, or with `Free`, as either zeroes or their old
+    /// contents. The allocation must come from private anonymous memory.
+    pub(crate) unsafe fn discard(&self, discard: Discard) -> Result<(), Errno> {
+        let advice = match discard {
+            Discard::DontNeed => mm::Advice::LinuxDontNeed,
+            Discard::Free => mm::Advice::LinuxFree,
+        };
+        let pagesize = rustix::param::page_size();
+        let ost = self.ptr.align_offset(pagesize);
+        let Some(len) = self.layout.size().checked_sub(ost) else {
+            return Ok(());
+        };
+        let len = len & !(pagesize - 1);
+        if len == 0 {
+            return Ok(());
+        }
+        // SAFETY: The range lies wholly inside the allocation, and the caller
+        // does not need its contents.
+        unsafe { mm::madvise(self.ptr.add(ost).as_ptr().cast(), len, advice) }
+    }
+}
+
+/// Access patterns that [`Tag::advise`] can pass on to the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Advice {
+    /// The allocation will be read soon, so start faulting it in.
+    WillNeed,
+    /// The allocation will be read front to back.
+    Sequential,
+    /// The allocation will be read in no particular order.
+    Random,
+}
+
+/// How [`Tag::discard`] releases pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Discard {
+    /// Release the pages now.
+    DontNeed,
+    /// Let the kernel reclaim the pages when it needs memory, which is
+    /// cheaper if they are written again soon.
+    Free,
 }
 
 pub(crate) trait Alloc {
     fn alloc(&self, layout: Layout) -> Result<Tag, AllocError>;
     unsafe fn free(&self, tag: Tag);
+
+    /// Like [`Alloc::alloc`], but the memory is zeroed. Implementations
+    /// that know their memory is already zero can skip the zeroing.
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.alloc(layout)?;
+        // SAFETY: `tag` is valid for `tag.layout().size()` bytes.
+        unsafe { tag.ptr().write_bytes(0, tag.layout().size()) };
+        Ok(tag)
+    }
+
+    /// Allocates zeroed memory for `n` elements of layout `elem`, failing
+    /// rather than wrapping if the total size overflows.
+    fn alloc_zeroed_array(&self, elem: Layout, n: usize) -> Result<Tag, AllocError> {
+        let layout = Layout::array_of(elem, n).map_err(|_| AllocError)?;
+        self.alloc_zeroed(layout)
+    }
+
+    /// Resizes the allocation described by `tag` to at least `new.size()`
+    /// bytes, preserving its contents. The returned allocation is aligned to
+    /// `new.align()`, even when the memory had to move.
+    ///
+    /// On success, `tag` no longer describes a live allocation and must not
+    /// be freed. On failure, it is left untouched.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed, and
+    /// `new.size()` must be at least `tag.layout().size()`.
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        let old = tag.layout().size();
+        debug_assert!(new.size() >= old);
+        let moved = self.alloc(new)?;
+        // SAFETY: Both allocations are valid for `old` bytes and distinct.
+        unsafe { ptr::copy_nonoverlapping(tag.ptr().as_ptr(), moved.ptr().as_ptr(), old) };
+        unsafe { self.free(Tag::new(tag.ptr(), tag.layout()).with_user(tag.user())) };
+        Ok(moved)
+    }
+
+    /// The counterpart of [`Alloc::grow`] for `new.size()` at most
+    /// `tag.layout().size()`, with the same alignment guarantee.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed, and
+    /// `new.size()` must be at most `tag.layout().size()`.
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        debug_assert!(new.size() <= tag.layout().size());
+        let moved = self.alloc(new)?;
+        // SAFETY: Both allocations are valid for `new.size()` bytes and
+        // distinct.
+        unsafe { ptr::copy_nonoverlapping(tag.ptr().as_ptr(), moved.ptr().as_ptr(), new.size()) };
+        unsafe { self.free(Tag::new(tag.ptr(), tag.layout()).with_user(tag.user())) };
+        Ok(moved)
+    }
+}
+
+/// Moves the allocation described by `tag` from `from` to `to`: allocates
+/// the same layout in `to`, copies the contents, and frees the original.
+/// For moving data between a scratch arena and a long-lived heap.
+///
+/// On failure, `tag` is given back untouched.
+///
+/// # SAFETY
+///
+/// `tag` must have been returned by `from.alloc` and not yet freed.
+pub(crate) unsafe fn migrate<A, B>(tag: Tag, from: &A, to: &B) -> Result<Tag, Tag>
+where
+    A: Alloc + ?Sized,
+    B: Alloc + ?Sized,
+{
+    let Ok(moved) = to.alloc(tag.layout()) else {
+        return Err(tag);
+    };
+    // SAFETY: Both allocations are valid for the size of `tag`, and distinct.
+    unsafe {
+        ptr::copy_nonoverlapping(
+            tag.ptr().as_ptr(),
+            moved.ptr().as_ptr(),
+            tag.layout().size(),
+        )
+    };
+    unsafe { from.free(tag) };
+    Ok(moved)
+}
+
+/// [`migrate`] for every allocation in `tags`, replacing each tag with its
+/// new one. Stops at the first allocation that fails, and returns how many
+/// were moved: the rest of `tags` still describe allocations from `from`.
+///
+/// # SAFETY
+///
+/// As for [`migrate`], for every tag in `tags`.
+pub(crate) unsafe fn migrate_all<A, B>(tags: &mut [Tag], from: &A, to: &B) -> usize
+where
+    A: Alloc + ?Sized,
+    B: Alloc + ?Sized,
+{
+    for (i, tag) in tags.iter_mut().enumerate() {
+        // SAFETY: The placeholder is never freed: it is replaced by the
+        // moved allocation, or by the original again.
+        let empty = unsafe { Tag::new(NonNull::dangling(), Layout::new::<()>()) };
+        let old = mem::replace(tag, empty);
+        match unsafe { migrate(old, from, to) } {
+            Ok(moved) => *tag = moved,
+            Err(old) => {
+                *tag = old;
+                return i;
+            }
+        }
+    }
+    tags.len()
+}
+
+/// Allocators that can tell whether a pointer lies in memory they hand out,
+/// so that combinators can route frees without a side table.
+pub(crate) trait Owns {
+    fn owns(&self, ptr: NonNull<u8>) -> bool;
 }
 
 pub(crate) trait FreeAll {
     unsafe fn free_all(&self);
 }
 
+/// What a call to [`Grind::grind`] released, so that callers driving
+/// background maintenance can pace themselves.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct GrindReport {
+    /// Bytes whose physical pages were released, keeping the address space.
+    pub(crate) purged_bytes: usize,
+    /// Bytes of address space unmapped.
+    pub(crate) unmapped_bytes: usize,
+    /// The number of syscalls made, as a rough measure of how long the call
+    /// took.
+    pub(crate) duration_hint: usize,
+}
+
+/// A limit on the work done by [`Grind::grind_budgeted`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Budget {
+    /// Stop once at least this many bytes have been released. Extents are
+    /// not split, so the last one may overshoot.
+    Bytes(usize),
+    /// Stop after releasing this many extents.
+    Extents(usize),
+}
+
+impl Budget {
+    /// Whether work is left after releasing `bytes` in `extents` extents.
+    #[inline]
+    pub(crate) fn allows(self, bytes: usize, extents: usize) -> bool {
+        match self {
+            Budget::Bytes(max) => bytes < max,
+            Budget::Extents(max) => extents < max,
+        }
+    }
+}
+
 pub(crate) trait Grind {
-    fn grind(&self);
+    fn grind(&self) -> GrindReport;
+
+    /// Like [`Grind::grind`], but stops once `budget` is spent, so that
+    /// maintenance can be sliced into small increments on latency-sensitive
+    /// threads. Call it again to continue. Allocators that cannot slice
+    /// their work do all of it at once.
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        self.grind()
+    }
+
+    /// Like [`Grind::grind`], but also tidies what is kept for reuse into as
+    /// few pieces as possible, for right before the process is forked or
+    /// checkpointed. Allocators that keep nothing in pieces just grind.
+    fn compact(&self) -> GrindReport {
+        self.grind()
+    }
 }
 
 pub struct ZeroHeap<T>(T);
@@ -49,14 +309,14 @@ pub struct ZeroHeap<T>(T);
 impl<T: Alloc> Alloc for ZeroHeap<T> {
     fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
         if layout.size() == 0 {
-            Ok(unsafe { Tag::new(layout.dangling(), layout) })
+            Ok(unsafe { Tag::new(layout.dangling_ptr(), layout) })
         } else {
             self.0.alloc(layout)
         }
     }
 
     unsafe fn free(&self, tag: Tag) {
-        if layout.size() != 0 {
+        if tag.layout().size() != 0 {
             unsafe { self.0.free(tag) }
         }
     }
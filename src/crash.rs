@@ -0,0 +1,74 @@
+#![allow(unused)]
+
+use core::fmt::{self, Write};
+
+use rustix::fd::BorrowedFd;
+
+use crate::{stats::Stats, sync::SpinLock};
+
+const BUF_LEN: usize = 4096;
+
+/// The buffer reports are formatted into. It is static so that reporting
+/// does not need much stack, which may be a small alternate signal stack.
+static BUF: SpinLock<Buf> = SpinLock::new(Buf {
+    bytes: [0; BUF_LEN],
+    len: 0,
+});
+
+/// A fixed-size `fmt::Write` sink that silently truncates.
+struct Buf {
+    bytes: [u8; BUF_LEN],
+    len: usize,
+}
+
+impl Write for Buf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(BUF_LEN - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Writes a best-effort summary of `stats` to `fd`, e.g. standard error,
+/// followed by `events` if given, typically an [`EventRing`] of the most
+/// recent allocations and frees.
+///
+/// This is meant to be called from a panic hook or a signal handler while
+/// the process is going down, so it never allocates, never blocks, and makes
+/// no syscalls other than `write(2)`. The report is formatted into a static
+/// buffer; if another thread is already reporting, this returns `false`
+/// without writing anything. Output longer than the buffer is truncated.
+///
+/// [`EventRing`]: crate::ring::EventRing
+pub(crate) fn report(fd: BorrowedFd<'_>, stats: &Stats, events: Option<&dyn fmt::Display>) -> bool {
+    emit(fd, |buf| {
+        writeln!(buf, "moz: heap state at crash")?;
+        write!(buf, "{stats}")?;
+        if let Some(events) = events {
+            writeln!(buf, "recent events:")?;
+            write!(buf, "{events}")?;
+        }
+        Ok(())
+    })
+}
+
+/// Formats a report with `f` into the static buffer and writes it to `fd`,
+/// under the same rules as [`report`].
+pub(crate) fn emit(fd: BorrowedFd<'_>, f: impl FnOnce(&mut dyn Write) -> fmt::Result) -> bool {
+    let Some(mut buf) = BUF.try_lock() else {
+        return false;
+    };
+    buf.len = 0;
+    let _ = f(&mut *buf);
+    let mut out = &buf.bytes[..buf.len];
+    while !out.is_empty() {
+        match rustix::io::write(fd, out) {
+            Ok(0) => break,
+            Ok(n) => out = &out[n..],
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(_) => break,
+        }
+    }
+    true
+}
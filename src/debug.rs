@@ -0,0 +1,198 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::NonNull,
+};
+
+use crate::{
+    check::{self, Check},
+    core::{Alloc, FreeAll, Owns, Tag},
+};
+
+/// The byte fresh allocations are filled with, so that reads of
+/// uninitialized memory stand out.
+pub(crate) const ALLOC_BYTE: u8 = 0xab;
+
+/// The byte freed allocations are filled with, so that reads after free
+/// stand out.
+pub(crate) const FREE_BYTE: u8 = 0xdf;
+
+/// Trailer word of a live allocation, mixed with its address.
+const LIVE: u64 = 0x6c69_7665_d00d_f00d;
+
+/// Trailer word of a freed allocation, mixed with its address.
+const FREED: u64 = 0x6672_6565_dead_beef;
+
+/// Debugging checks around `A`, each enabled by a const parameter:
+///
+/// - `FILL` fills fresh memory with [`ALLOC_BYTE`] and freed memory with
+///   [`FREE_BYTE`].
+/// - `CANARY` appends a word to every allocation and panics on free if a
+///   write ran past the end and clobbered it. The end is that of the
+///   returned tag's layout, which may be past the requested size if the
+///   inner allocator rounds up.
+/// - `CHECKED` panics on misaligned frees, and on double frees as long as
+///   the memory has not been reused or unmapped in between.
+///
+/// The checks are resolved at compile time, so a disabled check costs
+/// nothing, and [`Release`] is a plain pass-through. Pick the parameters
+/// per build profile, e.g. with `cfg(debug_assertions)`, rather than
+/// checking a flag at runtime.
+pub(crate) struct Debugged<
+    A,
+    const FILL: bool = true,
+    const CANARY: bool = true,
+    const CHECKED: bool = true,
+> {
+    inner: A,
+}
+
+/// [`Debugged`] with every check disabled.
+pub(crate) type Release<A> = Debugged<A, false, false, false>;
+
+impl<A, const FILL: bool, const CANARY: bool, const CHECKED: bool>
+    Debugged<A, FILL, CANARY, CHECKED>
+{
+    /// The bytes appended to every allocation. Both canaries and double
+    /// free checks keep their state in the trailer.
+    const TRAILER: usize = if CANARY || CHECKED {
+        size_of::<u64>()
+    } else {
+        0
+    };
+
+    pub(crate) const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Hands out an allocation of the inner allocator, with the trailer
+    /// cut off the end of its layout.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must be a fresh allocation of at least `Self::TRAILER` bytes.
+    unsafe fn wrap(&self, tag: Tag, fill: bool) -> Tag {
+        let size = tag.layout().size() - Self::TRAILER;
+        if FILL && fill {
+            // SAFETY: `tag` is valid for `size` bytes.
+            unsafe { tag.ptr().write_bytes(ALLOC_BYTE, size) };
+        }
+        if Self::TRAILER == 0 {
+            return tag;
+        }
+        // SAFETY: The trailer lies inside the allocation.
+        unsafe { trailer(tag.ptr(), size).write_unaligned(LIVE ^ addr(&tag)) };
+        // SAFETY: The layout is smaller than the allocation, with the same
+        // alignment.
+        let layout = unsafe { Layout::from_size_align_unchecked(size, tag.layout().align()) };
+        unsafe { Tag::new(tag.ptr(), layout) }.with_user(tag.user())
+    }
+
+    fn outer(layout: Layout) -> Result<Layout, AllocError> {
+        if Self::TRAILER == 0 {
+            return Ok(layout);
+        }
+        let size = layout.size().checked_add(Self::TRAILER).ok_or(AllocError)?;
+        Layout::from_size_align(size, layout.align()).map_err(|_| AllocError)
+    }
+}
+
+#[inline]
+fn addr(tag: &Tag) -> u64 {
+    tag.ptr().addr().get() as u64
+}
+
+/// # SAFETY
+///
+/// `ptr + size` must lie inside the allocation at `ptr`.
+#[inline]
+unsafe fn trailer(ptr: NonNull<u8>, size: usize) -> *mut u64 {
+    unsafe { ptr.add(size).cast::<u64>().as_ptr() }
+}
+
+impl<A: Alloc, const FILL: bool, const CANARY: bool, const CHECKED: bool> Alloc
+    for Debugged<A, FILL, CANARY, CHECKED>
+{
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(Self::outer(layout)?)?;
+        // SAFETY: `tag` is fresh, and at least the trailer larger than
+        // `layout`.
+        Ok(unsafe { self.wrap(tag, true) })
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc_zeroed(Self::outer(layout)?)?;
+        // SAFETY: As above. The memory stays zeroed.
+        Ok(unsafe { self.wrap(tag, false) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let size = tag.layout().size();
+        if CHECKED {
+            assert!(
+                tag.ptr().is_aligned_to(tag.layout().align()),
+                "free of misaligned pointer {:p}",
+                tag.ptr()
+            );
+        }
+        if Self::TRAILER != 0 {
+            // SAFETY: The trailer lies inside the allocation, which the
+            // caller guarantees is live, or, for the double free check,
+            // freed but still mapped.
+            let t = unsafe { trailer(tag.ptr(), size) };
+            let word = unsafe { t.read_unaligned() };
+            if CHECKED && word == FREED ^ addr(&tag) {
+                panic!("double free of {:p}", tag.ptr());
+            }
+            if CANARY && word != LIVE ^ addr(&tag) {
+                panic!("canary of {:p} ({size} bytes) overwritten", tag.ptr());
+            }
+            if CHECKED {
+                // SAFETY: As above.
+                unsafe { t.write_unaligned(FREED ^ addr(&tag)) };
+            }
+        }
+        if FILL {
+            // SAFETY: The allocation is valid for `size` bytes and no longer
+            // in use.
+            unsafe { tag.ptr().write_bytes(FREE_BYTE, size) };
+        }
+        // The layout is known to be valid, since `alloc` made it.
+        let layout = Self::outer(tag.layout()).unwrap();
+        unsafe {
+            self.inner
+                .free(Tag::new(tag.ptr(), layout).with_user(tag.user()))
+        }
+    }
+}
+
+impl<A: Owns, const FILL: bool, const CANARY: bool, const CHECKED: bool> Owns
+    for Debugged<A, FILL, CANARY, CHECKED>
+{
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.inner.owns(ptr)
+    }
+}
+
+impl<A: FreeAll, const FILL: bool, const CANARY: bool, const CHECKED: bool> FreeAll
+    for Debugged<A, FILL, CANARY, CHECKED>
+{
+    /// Frees everything held by the inner allocator, without checks.
+    unsafe fn free_all(&self) {
+        unsafe { self.inner.free_all() }
+    }
+}
+
+impl<A: Check, const FILL: bool, const CANARY: bool, const CHECKED: bool> Check
+    for Debugged<A, FILL, CANARY, CHECKED>
+{
+    fn check(&self, report: &mut check::Report) {
+        self.inner.check(report);
+    }
+}
@@ -0,0 +1,193 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::{self, NonNull},
+    sync::atomic::{
+        AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+
+use crate::{
+    core::{Alloc, Owns, Tag},
+    reserve::Reserve,
+};
+
+/// Sets aside a small reserve from `A` when created, and serves
+/// allocations from it only once `A` fails, so that error handling and
+/// logging have room to run and report before the process dies of
+/// exhaustion.
+///
+/// The reserve is a bump region: frees into it are not reused, so once it
+/// is used up it stays used up. [`Emergency::tapped`] tells how many
+/// allocations it has served, which the application can poll to shed load
+/// or report.
+///
+/// Dropping the value gives the reserve back to `A` only if every
+/// allocation served from it has been freed. Otherwise the reserve is
+/// leaked, so that the survivors stay valid.
+pub(crate) struct Emergency<A: Alloc> {
+    inner: A,
+    /// The allocation from `inner` backing `reserve`, unless `inner` could
+    /// not spare it.
+    block: Option<Tag>,
+    reserve: Reserve,
+    tapped: AtomicUsize,
+    /// Allocations served from the reserve and not freed yet.
+    live: AtomicUsize,
+}
+
+// SAFETY: `block` is owned by the value and only freed on drop.
+unsafe impl<A: Alloc + Send> Send for Emergency<A> {}
+unsafe impl<A: Alloc + Sync> Sync for Emergency<A> {}
+
+impl<A: Alloc> Emergency<A> {
+    /// Takes `len` bytes for the reserve from `inner`, touching every page
+    /// so that using the reserve never faults memory in. If `inner` cannot
+    /// spare them, the reserve is left empty, which
+    /// [`Emergency::remaining`] shows.
+    pub(crate) fn new(inner: A, len: usize) -> Self {
+        let pagesize = rustix::param::page_size();
+        let block = Layout::from_size_align(len, pagesize)
+            .ok()
+            .and_then(|x| inner.alloc(x).ok());
+        let reserve = match &block {
+            Some(block) => {
+                let len = block.layout().size();
+                for ost in (0..len).step_by(pagesize) {
+                    // SAFETY: `ost < len` and `block` is valid for `len`
+                    // bytes.
+                    unsafe { block.ptr().add(ost).write_volatile(0) };
+                }
+                // SAFETY: `block` stays allocated, and its pages committed,
+                // until the value is dropped, which also drops the reserve.
+                unsafe { Reserve::new(block.ptr(), len) }
+            }
+            // SAFETY: An empty region needs no memory.
+            None => unsafe { Reserve::new(NonNull::dangling(), 0) },
+        };
+        Self {
+            inner,
+            block,
+            reserve,
+            tapped: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// The number of allocations served from the reserve.
+    #[inline]
+    pub(crate) fn tapped(&self) -> usize {
+        self.tapped.load(Relaxed)
+    }
+
+    /// Bytes of the reserve not handed out yet.
+    #[inline]
+    pub(crate) fn remaining(&self) -> usize {
+        self.reserve.capacity() - self.reserve.used()
+    }
+
+    /// Counts an allocation served from the reserve.
+    fn tap(&self, tag: Tag) -> Tag {
+        self.tapped.fetch_add(1, Relaxed);
+        self.live.fetch_add(1, Relaxed);
+        tag
+    }
+
+    /// Moves `tag` to a new allocation of `new`, from `inner` if it can
+    /// spare one and from the reserve otherwise, for resizes that `inner`
+    /// cannot do in place.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed.
+    unsafe fn relocate(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        let moved = self.alloc(new)?;
+        let len = tag.layout().size().min(new.size());
+        // SAFETY: Both allocations are valid for `len` bytes and distinct.
+        unsafe { ptr::copy_nonoverlapping(tag.ptr().as_ptr(), moved.ptr().as_ptr(), len) };
+        // SAFETY: The caller guarantees `tag` is live, and it is freed once.
+        unsafe { self.free(Tag::new(tag.ptr(), tag.layout()).with_user(tag.user())) };
+        Ok(moved)
+    }
+}
+
+impl<A: Alloc> Alloc for Emergency<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.inner
+            .alloc(layout)
+            .or_else(|_| self.reserve.alloc(layout).map(|x| self.tap(x)))
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        // An empty reserve owns no pointers, so zero-sized allocations from
+        // `inner` are never mistaken for its own.
+        if self.reserve.owns(tag.ptr()) {
+            self.live.fetch_sub(1, Release);
+        } else {
+            unsafe { self.inner.free(tag) }
+        }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.inner
+            .alloc_zeroed(layout)
+            .or_else(|_| self.reserve.alloc_zeroed(layout).map(|x| self.tap(x)))
+    }
+
+    /// Allocations from `inner` grow in place when `inner` can, and move,
+    /// possibly into the reserve, when it cannot. Those from the reserve
+    /// always move.
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if !self.reserve.owns(tag.ptr())
+            && let Ok(moved) = unsafe { self.inner.grow(tag, new) }
+        {
+            return Ok(moved);
+        }
+        unsafe { self.relocate(tag, new) }
+    }
+
+    /// Allocations from the reserve shrink in place when aligned, leaving
+    /// the tail unused, so that shrinking never takes more of the reserve.
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if self.reserve.owns(tag.ptr()) {
+            if tag.ptr().is_aligned_to(new.align()) {
+                // SAFETY: The block is valid for `tag.layout().size()` bytes,
+                // which is at least `new.size()`, and aligned as `new` asks.
+                return Ok(unsafe { Tag::new(tag.ptr(), new) });
+            }
+            return unsafe { self.relocate(tag, new) };
+        }
+        match unsafe { self.inner.shrink(tag, new) } {
+            Ok(moved) => Ok(moved),
+            Err(_) => unsafe { self.relocate(tag, new) },
+        }
+    }
+}
+
+impl<A: Alloc + Owns> Owns for Emergency<A> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.reserve.owns(ptr) || self.inner.owns(ptr)
+    }
+}
+
+impl<A: Alloc> Drop for Emergency<A> {
+    fn drop(&mut self) {
+        let Some(block) = self.block.take() else {
+            return;
+        };
+        // Leak the reserve if anything served from it is still in use.
+        if self.live.load(Acquire) != 0 {
+            return;
+        }
+        // SAFETY: `block` was allocated from `inner` in `new`, and every
+        // allocation served from the reserve has been freed.
+        unsafe { self.inner.free(block) }
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(unused)]
+
+use core::ptr::{self, NonNull};
+
+use crate::check::{Check, Report, Violation};
+
+/// A run of whole pages owned by the backend but not handed out.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Extent {
+    pub(crate) ptr: NonNull<u8>,
+    pub(crate) len: usize,
+    /// Whether the pages may still be resident. Clean extents have been
+    /// purged and read back as zeroes.
+    pub(crate) dirty: bool,
+    /// A tick of the caller's clock at which the extent was freed, or zero
+    /// without a clock.
+    pub(crate) freed: u64,
+}
+
+/// A fixed-capacity cache of retained extents.
+///
+/// Extents are only reused for requests of exactly their length. Each
+/// insertion is stamped with a sequence number so that reuse can prefer the
+/// most recently freed (and most likely resident) extent, and eviction can
+/// prefer the oldest.
+pub(crate) struct ExtentCache<const N: usize> {
+    slots: [Option<(u64, Extent)>; N],
+    seq: u64,
+    bytes: usize,
+    dirty: usize,
+}
+
+// SAFETY: The cache exclusively owns the extents it holds, so they may be
+// handed out on any thread.
+unsafe impl<const N: usize> Send for ExtentCache<N> {}
+
+impl<const N: usize> ExtentCache<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: [None; N],
+            seq: 0,
+            bytes: 0,
+            dirty: 0,
+        }
+    }
+
+    /// Total bytes held.
+    #[inline]
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Bytes held in dirty extents.
+    #[inline]
+    pub(crate) fn dirty(&self) -> usize {
+        self.dirty
+    }
+
+    /// The extents held, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Extent> {
+        self.slots.iter().flatten().map(|(_, ext)| ext)
+    }
+
+    /// Whether an extent of `len` bytes would fit without the total
+    /// exceeding `max` bytes.
+    pub(crate) fn has_room(&self, len: usize, max: usize) -> bool {
+        self.slots.iter().any(Option::is_none) && self.bytes.saturating_add(len) <= max
+    }
+
+    pub(crate) fn insert(&mut self, ext: Extent) -> Result<(), Extent> {
+        let Some(slot) = self.slots.iter_mut().find(|x| x.is_none()) else {
+            return Err(ext);
+        };
+        self.seq += 1;
+        *slot = Some((self.seq, ext));
+        self.bytes += ext.len;
+        if ext.dirty {
+            self.dirty += ext.len;
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the most recently inserted extent of exactly `len`
+    /// bytes that is aligned to `align`.
+    pub(crate) fn take(&mut self, len: usize, align: usize) -> Option<Extent> {
+        self.take_by(
+            |ext| ext.len == len && ext.ptr.is_aligned_to(align),
+            |seq| seq,
+        )
+    }
+
+    /// Removes and returns the oldest dirty extent.
+    pub(crate) fn take_oldest_dirty(&mut self) -> Option<Extent> {
+        self.take_by(|ext| ext.dirty, |seq| u64::MAX - seq)
+    }
+
+    /// Removes and returns the oldest extent.
+    pub(crate) fn take_oldest(&mut self) -> Option<Extent> {
+        self.take_by(|_| true, |seq| u64::MAX - seq)
+    }
+
+    /// Removes and returns the oldest extent freed at or before `tick`.
+    pub(crate) fn take_freed_by(&mut self, tick: u64) -> Option<Extent> {
+        self.take_by(|ext| ext.freed <= tick, |seq| u64::MAX - seq)
+    }
+
+    /// Removes and returns two clean extents that are adjacent in memory,
+    /// the lower one first.
+    pub(crate) fn take_adjacent(&mut self) -> Option<(Extent, Extent)> {
+        let (i, j) = self.slots.iter().enumerate().find_map(|(i, a)| {
+            let (_, a) = a.as_ref().filter(|(_, a)| !a.dirty)?;
+            let end = a.ptr.addr().get() + a.len;
+            let j = self
+                .slots
+                .iter()
+                .position(|b| b.is_some_and(|(_, b)| !b.dirty && b.ptr.addr().get() == end))?;
+            Some((i, j))
+        })?;
+        Some((self.remove(i)?, self.remove(j)?))
+    }
+
+    fn take_by(
+        &mut self,
+        pred: impl Fn(&Extent) -> bool,
+        key: impl Fn(u64) -> u64,
+    ) -> Option<Extent> {
+        let slot = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.is_some_and(|(_, ext)| pred(&ext)))
+            .max_by_key(|(_, x)| x.map_or(0, |(seq, _)| key(seq)))?
+            .0;
+        self.remove(slot)
+    }
+
+    fn remove(&mut self, slot: usize) -> Option<Extent> {
+        let (_, ext) = self.slots[slot].take()?;
+        self.bytes -= ext.len;
+        if ext.dirty {
+            self.dirty -= ext.len;
+        }
+        Some(ext)
+    }
+}
+
+impl<const N: usize> Check for ExtentCache<N> {
+    /// Checks that no two extents overlap and that the byte counts match
+    /// the extents held.
+    fn check(&self, report: &mut Report) {
+        let (mut bytes, mut dirty) = (0, 0);
+        for (i, a) in self.iter().enumerate() {
+            report.checked += 1;
+            bytes += a.len;
+            if a.dirty {
+                dirty += a.len;
+            }
+            for b in self.iter().skip(i + 1) {
+                let (x, y) = (a.ptr.addr().get(), b.ptr.addr().get());
+                if x < y + b.len && y < x + a.len {
+                    report.violation(Violation::Overlap { a: x, b: y });
+                }
+            }
+        }
+        let addr = ptr::from_ref(self).addr();
+        for (kept, found) in [(self.bytes, bytes), (self.dirty, dirty)] {
+            if kept != found {
+                report.violation(Violation::Miscount { addr, kept, found });
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+#![allow(unused)]
+
+use core::{
+    ffi::{c_int, c_void},
+    fmt::Write,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+use std::os::fd::AsFd;
+
+use crate::{crash, registry, sync::SpinLock};
+
+const SIGBUS: c_int = 7;
+const SIGSEGV: c_int = 11;
+
+const SA_SIGINFO: c_int = 4;
+const SA_ONSTACK: c_int = 0x0800_0000;
+
+/// `struct sigaction` as laid out by glibc and musl on 64-bit Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigAction {
+    /// Null for `SIG_DFL`.
+    handler: Option<extern "C" fn(c_int, *const SigInfo, *mut c_void)>,
+    mask: [u64; 16],
+    flags: c_int,
+    restorer: Option<extern "C" fn()>,
+}
+
+impl SigAction {
+    const DEFAULT: Self = Self {
+        handler: None,
+        mask: [0; 16],
+        flags: 0,
+        restorer: None,
+    };
+}
+
+/// The head of `siginfo_t` on 64-bit Linux, up to `si_addr`.
+#[repr(C)]
+struct SigInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    _pad: c_int,
+    addr: *mut c_void,
+}
+
+unsafe extern "C" {
+    fn sigaction(sig: c_int, act: *const SigAction, old: *mut SigAction) -> c_int;
+}
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The handlers ours replaced, for `SIGBUS` and `SIGSEGV`.
+static PREVIOUS: SpinLock<[SigAction; 2]> = SpinLock::new([SigAction::DEFAULT; 2]);
+
+fn slot(sig: c_int) -> usize {
+    usize::from(sig == SIGSEGV)
+}
+
+/// Installs `SIGSEGV` and `SIGBUS` handlers that recognize faults inside the
+/// inaccessible parts of [registered](crate::registry) heaps, such as the
+/// uncommitted tail of a [`Brk`](crate::brk::Brk), and write a report to
+/// standard error saying which heap and which region, rather than leaving
+/// an anonymous segfault. Returns `false` if the handlers could not be
+/// installed. Calling this more than once installs them once.
+///
+/// After reporting, or if the fault is not the allocator's, the handler puts
+/// back the one it replaced and returns, so the fault is raised again and
+/// handled as before, e.g. by Rust's stack overflow handler, or by dumping
+/// core. Reports are written with the same rules as [`crash::report`].
+pub(crate) fn install_fault_handler() -> bool {
+    if INSTALLED.swap(true, Relaxed) {
+        return true;
+    }
+    let act = SigAction {
+        handler: Some(handle),
+        flags: SA_SIGINFO | SA_ONSTACK,
+        ..SigAction::DEFAULT
+    };
+    let mut previous = PREVIOUS.lock();
+    for sig in [SIGBUS, SIGSEGV] {
+        // SAFETY: Both actions are valid, and `handle` only does what is
+        // safe in a signal handler.
+        if unsafe { sigaction(sig, &act, &mut previous[slot(sig)]) } != 0 {
+            INSTALLED.store(false, Relaxed);
+            return false;
+        }
+    }
+    true
+}
+
+extern "C" fn handle(sig: c_int, info: *const SigInfo, _: *mut c_void) {
+    // SAFETY: The kernel passes a valid `siginfo_t` with `SA_SIGINFO`.
+    let addr = unsafe { (*info).addr }.addr();
+    let name = if sig == SIGSEGV { "SIGSEGV" } else { "SIGBUS" };
+    // Neither lock can be waited for here: the thread that faulted may be
+    // holding it.
+    registry::try_for_each(|heap| {
+        let Some(fault) = heap.fault(addr) else {
+            return;
+        };
+        crash::emit(std::io::stderr().as_fd(), |out| {
+            writeln!(out, "moz: {name} in {}: {fault}", heap.name())
+        });
+    });
+    let previous = PREVIOUS
+        .try_lock()
+        .map_or(SigAction::DEFAULT, |x| x[slot(sig)]);
+    // SAFETY: The action was returned by `sigaction`, or is the default.
+    unsafe { sigaction(sig, &previous, ptr::null_mut()) };
+}
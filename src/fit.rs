@@ -0,0 +1,1184 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    mem,
+    ops::Range,
+    ptr::{self, NonNull},
+    slice,
+    sync::atomic::{AtomicU8, Ordering::Relaxed},
+};
+
+use crate::{
+    check::{Check, Report, Violation},
+    core::{Alloc, Budget, Grind, GrindReport, Tag},
+    mmap::{self, MmapErr},
+    sync::SpinLock,
+};
+
+type Link = Option<NonNull<Node>>;
+
+/// Written at the start of every free extent. The nodes form a treap ordered
+/// by address, where each node also knows the longest extent in its
+/// subtree, so that a fit can be found without visiting every node.
+struct Node {
+    left: Link,
+    right: Link,
+    len: usize,
+    max: usize,
+}
+
+/// The unit extents are measured in, so that every free extent can hold a
+/// node.
+const GRANULE: usize = mem::size_of::<Node>().next_power_of_two();
+
+/// The treap priority of a node, derived from its address so that no
+/// random state is needed.
+fn prio(node: NonNull<Node>) -> usize {
+    node.addr()
+        .get()
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize)
+        .rotate_left(usize::BITS / 2)
+}
+
+// The functions below operate on trees of nodes written into free extents.
+// Their callers must pass links to live nodes only.
+
+unsafe fn max(t: Link) -> usize {
+    t.map_or(0, |n| unsafe { n.as_ref() }.max)
+}
+
+unsafe fn fix(n: NonNull<Node>) {
+    let node = unsafe { &mut *n.as_ptr() };
+    node.max = node
+        .len
+        .max(unsafe { max(node.left) })
+        .max(unsafe { max(node.right) });
+}
+
+/// Splits `t` into the nodes below `key` and those at or above it.
+unsafe fn split(t: Link, key: usize) -> (Link, Link) {
+    let Some(n) = t else { return (None, None) };
+    let node = unsafe { &mut *n.as_ptr() };
+    if n.addr().get() < key {
+        let (l, r) = unsafe { split(node.right, key) };
+        node.right = l;
+        unsafe { fix(n) };
+        (Some(n), r)
+    } else {
+        let (l, r) = unsafe { split(node.left, key) };
+        node.left = r;
+        unsafe { fix(n) };
+        (l, Some(n))
+    }
+}
+
+/// Joins two trees, where every node of `a` is below every node of `b`.
+unsafe fn merge(a: Link, b: Link) -> Link {
+    let (x, y) = match (a, b) {
+        (None, t) | (t, None) => return t,
+        (Some(x), Some(y)) => (x, y),
+    };
+    if prio(x) > prio(y) {
+        let node = unsafe { &mut *x.as_ptr() };
+        node.right = unsafe { merge(node.right, b) };
+        unsafe { fix(x) };
+        Some(x)
+    } else {
+        let node = unsafe { &mut *y.as_ptr() };
+        node.left = unsafe { merge(a, node.left) };
+        unsafe { fix(y) };
+        Some(y)
+    }
+}
+
+/// Removes the lowest node of `t`, leaving it as a tree of its own.
+unsafe fn pop_first(t: Link) -> (Link, Link) {
+    let Some(n) = t else { return (None, None) };
+    let node = unsafe { &mut *n.as_ptr() };
+    if node.left.is_none() {
+        let rest = node.right.take();
+        unsafe { fix(n) };
+        return (rest, Some(n));
+    }
+    let (rest, first) = unsafe { pop_first(node.left) };
+    node.left = rest;
+    unsafe { fix(n) };
+    (Some(n), first)
+}
+
+/// Removes the highest node of `t`, leaving it as a tree of its own.
+unsafe fn pop_last(t: Link) -> (Link, Link) {
+    let Some(n) = t else { return (None, None) };
+    let node = unsafe { &mut *n.as_ptr() };
+    if node.right.is_none() {
+        let rest = node.left.take();
+        unsafe { fix(n) };
+        return (rest, Some(n));
+    }
+    let (rest, last) = unsafe { pop_last(node.right) };
+    node.right = rest;
+    unsafe { fix(n) };
+    (Some(n), last)
+}
+
+/// The first node of `t` at least `need` bytes long met on the way down
+/// from the root.
+unsafe fn any_fit(mut t: Link, need: usize) -> Link {
+    while let Some(n) = t {
+        let node = unsafe { n.as_ref() };
+        if node.max < need {
+            return None;
+        }
+        if node.len >= need {
+            return Some(n);
+        }
+        t = if unsafe { max(node.left) } >= need {
+            node.left
+        } else {
+            node.right
+        };
+    }
+    None
+}
+
+/// The lowest node of `t` at least `need` bytes long.
+unsafe fn lowest_fit(mut t: Link, need: usize) -> Link {
+    while let Some(n) = t {
+        let node = unsafe { n.as_ref() };
+        if node.max < need {
+            return None;
+        }
+        if unsafe { max(node.left) } >= need {
+            t = node.left;
+        } else if node.len >= need {
+            return Some(n);
+        } else {
+            t = node.right;
+        }
+    }
+    None
+}
+
+/// The shortest node of `t` at least `need` bytes long, and the lowest of
+/// those. Every subtree holding a fitting node is visited, until an exact
+/// fit is found.
+unsafe fn best_fit(t: Link, need: usize, best: &mut Link) {
+    let Some(n) = t else { return };
+    let node = unsafe { n.as_ref() };
+    if node.max < need {
+        return;
+    }
+    let len = |b: Link| b.map_or(usize::MAX, |b| unsafe { b.as_ref() }.len);
+    unsafe { best_fit(node.left, need, best) };
+    if len(*best) == need {
+        return;
+    }
+    if node.len >= need && node.len < len(*best) {
+        *best = Some(n);
+    }
+    unsafe { best_fit(node.right, need, best) };
+}
+
+/// Checks the nodes of `t` in address order, calling `f` on each extent.
+/// `prev` is the extent visited last. Returns the total length.
+unsafe fn walk(
+    t: Link,
+    prev: &mut Option<(usize, usize)>,
+    report: &mut Report,
+    f: &mut dyn FnMut(usize, usize, &mut Report),
+) -> usize {
+    let Some(n) = t else { return 0 };
+    let node = unsafe { n.as_ref() };
+    let mut bytes = unsafe { walk(node.left, prev, report, f) };
+    let (addr, len) = (n.addr().get(), node.len);
+    report.checked += 1;
+    if len == 0 || !addr.is_multiple_of(GRANULE) || !len.is_multiple_of(GRANULE) {
+        report.violation(Violation::Misaligned {
+            addr,
+            align: GRANULE,
+        });
+    }
+    let found = len
+        .max(unsafe { max(node.left) })
+        .max(unsafe { max(node.right) });
+    if node.max != found {
+        report.violation(Violation::Miscount {
+            addr,
+            kept: node.max,
+            found,
+        });
+    }
+    match *prev {
+        Some((a, end)) if a >= addr || end == addr => {
+            report.violation(Violation::Disordered { a, b: addr });
+        }
+        Some((a, end)) if end > addr => report.violation(Violation::Overlap { a, b: addr }),
+        _ => {}
+    }
+    f(addr, len, report);
+    *prev = Some((addr, addr.saturating_add(len)));
+    bytes += len;
+    bytes + unsafe { walk(node.right, prev, report, f) }
+}
+
+/// How a [`FreeIndex`] chooses among the free extents that can hold a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) enum Placement {
+    /// The first fitting extent found, which is the cheapest to search for
+    /// but follows no order.
+    FirstFit,
+    /// The shortest fitting extent, which leaves the least behind but may
+    /// visit every fitting extent to find it.
+    BestFit,
+    /// The fitting extent at the lowest address, which packs live blocks
+    /// towards the start of each chunk.
+    #[default]
+    LowestAddress,
+}
+
+/// An address-ordered index of free extents that coalesces adjacent extents
+/// as they are inserted.
+///
+/// Nodes live in the free extents themselves, so the index needs no memory
+/// of its own. Extents are whole multiples of [`GRANULE`] bytes and aligned
+/// to it.
+pub(crate) struct FreeIndex {
+    root: Link,
+    bytes: usize,
+}
+
+// SAFETY: The index only refers to free memory owned by whoever owns it.
+unsafe impl Send for FreeIndex {}
+
+impl FreeIndex {
+    pub(crate) const fn new() -> Self {
+        Self {
+            root: None,
+            bytes: 0,
+        }
+    }
+
+    /// Total bytes in free extents.
+    #[inline]
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds `len` bytes at `ptr`, merging them with the free extents directly
+    /// before and after.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` and `len` must be multiples of [`GRANULE`], and the range must be
+    /// valid for writes, unused, and not already in the index.
+    pub(crate) unsafe fn insert(&mut self, ptr: NonNull<u8>, len: usize) {
+        let (addr, end) = (ptr.addr().get(), ptr.addr().get() + len);
+        self.bytes += len;
+        let (mut start, mut len) = (ptr, len);
+        // SAFETY: The tree only holds live nodes, and `ptr..ptr + len` is
+        // disjoint from every extent in it.
+        unsafe {
+            let (mut l, mut r) = split(self.root, addr);
+            let (rest, last) = pop_last(l);
+            match last {
+                Some(p) if p.addr().get() + p.as_ref().len == addr => {
+                    start = p.cast();
+                    len += p.as_ref().len;
+                    l = rest;
+                }
+                _ => l = merge(rest, last),
+            }
+            let (rest, first) = pop_first(r);
+            match first {
+                Some(s) if s.addr().get() == end => {
+                    len += s.as_ref().len;
+                    r = rest;
+                }
+                _ => r = merge(first, rest),
+            }
+            let node = start.cast::<Node>();
+            node.write(Node {
+                left: None,
+                right: None,
+                len,
+                max: len,
+            });
+            self.root = merge(merge(l, Some(node)), r);
+        }
+    }
+
+    /// Checks the tree and the byte count, calling `f` on every extent in
+    /// address order.
+    fn check(&self, report: &mut Report, f: &mut dyn FnMut(usize, usize, &mut Report)) {
+        // SAFETY: The tree only holds live nodes.
+        let found = unsafe { walk(self.root, &mut None, report, f) };
+        if found != self.bytes {
+            report.violation(Violation::Miscount {
+                addr: ptr::from_ref(self).addr(),
+                kept: self.bytes,
+                found,
+            });
+        }
+    }
+
+    /// Removes a block of `size` bytes aligned to `align` from a free extent
+    /// that can hold it, chosen by `placement`. The block is carved from the
+    /// end of the extent, and what is left on either side stays in the index.
+    ///
+    /// `size` must be a multiple of [`GRANULE`].
+    pub(crate) fn take(
+        &mut self,
+        size: usize,
+        align: usize,
+        placement: Placement,
+    ) -> Option<NonNull<u8>> {
+        let align = align.max(GRANULE);
+        let need = size.checked_add(align - GRANULE)?;
+        // SAFETY: The tree only holds live nodes.
+        unsafe {
+            let n = match placement {
+                Placement::FirstFit => any_fit(self.root, need),
+                Placement::LowestAddress => lowest_fit(self.root, need),
+                Placement::BestFit => {
+                    let mut best = None;
+                    best_fit(self.root, need, &mut best);
+                    best
+                }
+            }?;
+            let (addr, len) = (n.addr().get(), n.as_ref().len);
+            let (l, r) = split(self.root, addr);
+            let (_, r) = split(r, addr + 1);
+            self.root = merge(l, r);
+            self.bytes -= len;
+            let end = addr + len;
+            let start = (end - size) & !(align - 1);
+            let base = n.cast::<u8>();
+            if start > addr {
+                self.insert(base, start - addr);
+            }
+            if start + size < end {
+                self.insert(base.add(start + size - addr), end - start - size);
+            }
+            Some(base.add(start - addr))
+        }
+    }
+}
+
+/// Written at the start of every chunk, linking the chunks of a [`Fit`].
+struct Chunk {
+    next: Option<NonNull<Chunk>>,
+    layout: Layout,
+    /// The user bits of the parent's tag, restored when the chunk is freed.
+    user: u32,
+    /// The tag of the chunk's card table, if the allocator keeps them.
+    cards: Option<Tag>,
+}
+
+/// The space reserved for the chunk header.
+const HEADER: usize = mem::size_of::<Chunk>().next_multiple_of(GRANULE);
+
+/// The words in each allocation bitmap of a chunk of `len` bytes.
+fn map_words(len: usize) -> usize {
+    (len / GRANULE).div_ceil(usize::BITS as usize)
+}
+
+/// The space reserved after the header for the allocation bitmaps of a
+/// chunk of `len` bytes: one for the first granule of every live block and
+/// one for the last.
+fn map_len(len: usize) -> usize {
+    (2 * map_words(len) * mem::size_of::<usize>()).next_multiple_of(GRANULE)
+}
+
+/// The lowest set bit of `bits` at or after bit `i`.
+fn next_set(bits: &[usize], i: usize) -> Option<usize> {
+    let b = usize::BITS as usize;
+    let mut w = i / b;
+    let mut word = *bits.get(w)? & (usize::MAX << (i % b));
+    loop {
+        if word != 0 {
+            return Some(w * b + word.trailing_zeros() as usize);
+        }
+        w += 1;
+        word = *bits.get(w)?;
+    }
+}
+
+/// The highest set bit of `bits` at or before bit `i`.
+fn prev_set(bits: &[usize], i: usize) -> Option<usize> {
+    let b = usize::BITS as usize;
+    let mut w = i / b;
+    let mut word = *bits.get(w)? & (usize::MAX >> (b - 1 - i % b));
+    loop {
+        if word != 0 {
+            return Some(w * b + (b - 1 - word.leading_zeros() as usize));
+        }
+        w = w.checked_sub(1)?;
+        word = bits[w];
+    }
+}
+
+/// The allocation bitmaps of one chunk of a [`Fit`], for a garbage
+/// collector built on top to enumerate live blocks and to test whether a
+/// word points into one.
+///
+/// Bit `i` of [`starts`](Self::starts) is set if a live block starts at
+/// granule `i` of the chunk, counting from its base, and bit `i` of
+/// [`ends`](Self::ends) if one ends there. Blocks are whole granules, so
+/// their lengths are rounded up from the sizes they were allocated with.
+pub(crate) struct ChunkMap<'a> {
+    base: NonNull<u8>,
+    len: usize,
+    starts: &'a [usize],
+    ends: &'a [usize],
+}
+
+impl<'a> ChunkMap<'a> {
+    /// # SAFETY
+    ///
+    /// `chunk` must be a live chunk with allocation bitmaps, which are not
+    /// written to for `'a`.
+    unsafe fn new(chunk: NonNull<Chunk>) -> Self {
+        // SAFETY: The caller guarantees the chunk and its bitmaps are live.
+        unsafe {
+            let len = chunk.as_ref().layout.size();
+            let words = map_words(len);
+            let bits = chunk.cast::<u8>().add(HEADER).cast::<usize>();
+            Self {
+                base: chunk.cast(),
+                len,
+                starts: slice::from_raw_parts(bits.as_ptr(), words),
+                ends: slice::from_raw_parts(bits.add(words).as_ptr(), words),
+            }
+        }
+    }
+
+    /// The addresses the chunk spans.
+    pub(crate) fn range(&self) -> Range<usize> {
+        let base = self.base.addr().get();
+        base..base + self.len
+    }
+
+    /// A bit for every granule that starts a live block.
+    pub(crate) fn starts(&self) -> &'a [usize] {
+        self.starts
+    }
+
+    /// A bit for every granule that ends a live block.
+    pub(crate) fn ends(&self) -> &'a [usize] {
+        self.ends
+    }
+
+    /// The granule of the chunk holding `addr`.
+    fn granule(&self, addr: usize) -> Option<usize> {
+        self.range()
+            .contains(&addr)
+            .then(|| (addr - self.base.addr().get()) / GRANULE)
+    }
+
+    /// The start and length of the block starting at granule `i`.
+    fn block(&self, i: usize) -> (NonNull<u8>, usize) {
+        let end = next_set(self.ends, i).expect("a live block has an end");
+        // SAFETY: Both granules are within the chunk.
+        let ptr = unsafe { self.base.add(i * GRANULE) };
+        (ptr, (end + 1 - i) * GRANULE)
+    }
+
+    /// Whether a live block starts at `addr`.
+    pub(crate) fn is_object_start(&self, addr: usize) -> bool {
+        let Some(i) = self.granule(addr) else {
+            return false;
+        };
+        addr.is_multiple_of(GRANULE)
+            && self.starts[i / usize::BITS as usize] >> (i % usize::BITS as usize) & 1 != 0
+    }
+
+    /// The start and length of the live block holding `addr`, which may
+    /// point anywhere inside it.
+    pub(crate) fn object_containing(&self, addr: usize) -> Option<(NonNull<u8>, usize)> {
+        let i = self.granule(addr)?;
+        let (ptr, len) = self.block(prev_set(self.starts, i)?);
+        (addr < ptr.addr().get() + len).then_some((ptr, len))
+    }
+
+    /// The start and length of every live block, in address order.
+    pub(crate) fn objects(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        let mut i = 0;
+        core::iter::from_fn(move || {
+            let (ptr, len) = self.block(next_set(self.starts, i)?);
+            i = (ptr.addr().get() - self.base.addr().get() + len) / GRANULE;
+            Some((ptr, len))
+        })
+    }
+}
+
+/// The card table of one chunk of a [`Fit`]: a byte for every
+/// [`card_size`](FitConfig::card_size) bytes of the chunk, counting from its
+/// base, kept in a mapping of its own.
+///
+/// A write barrier sets a card when it stores into it, through
+/// [`Fit::card`], and a collector or incremental snapshot clears it once it
+/// has scanned the card. The allocator itself never writes a card.
+pub(crate) struct CardTable<'a> {
+    base: NonNull<u8>,
+    len: usize,
+    card_size: usize,
+    cards: &'a [AtomicU8],
+}
+
+impl<'a> CardTable<'a> {
+    /// # SAFETY
+    ///
+    /// `chunk` must be a live chunk, which outlives `'a`.
+    unsafe fn new(chunk: NonNull<Chunk>, card_size: usize) -> Option<Self> {
+        // SAFETY: The caller guarantees the chunk is live.
+        let Chunk { layout, cards, .. } = unsafe { chunk.read() };
+        let cards = cards?.ptr().cast::<AtomicU8>();
+        let len = layout.size();
+        Some(Self {
+            base: chunk.cast(),
+            len,
+            card_size,
+            // SAFETY: The table has a byte per card of the chunk, and the
+            // caller guarantees it outlives `'a`.
+            cards: unsafe { slice::from_raw_parts(cards.as_ptr(), len.div_ceil(card_size)) },
+        })
+    }
+
+    /// The addresses the chunk spans.
+    pub(crate) fn range(&self) -> Range<usize> {
+        let base = self.base.addr().get();
+        base..base + self.len
+    }
+
+    /// The bytes of the chunk each card covers.
+    pub(crate) fn card_size(&self) -> usize {
+        self.card_size
+    }
+
+    /// A byte for every card of the chunk, nonzero if the card is dirty.
+    pub(crate) fn cards(&self) -> &'a [AtomicU8] {
+        self.cards
+    }
+
+    /// The card covering `addr`, if it lies in the chunk.
+    pub(crate) fn card(&self, addr: usize) -> Option<&'a AtomicU8> {
+        let offset = addr.checked_sub(self.base.addr().get())?;
+        self.cards.get(offset / self.card_size)
+    }
+
+    /// The address ranges of every run of dirty cards, in address order.
+    pub(crate) fn dirty(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let Range { start: base, end } = self.range();
+        let mut i = 0;
+        core::iter::from_fn(move || {
+            let dirty = |c: &AtomicU8| c.load(Relaxed) != 0;
+            let start = i + self.cards[i..].iter().position(dirty)?;
+            let stop = start
+                + self.cards[start..]
+                    .iter()
+                    .position(|c| !dirty(c))
+                    .unwrap_or(self.cards.len() - start);
+            i = stop;
+            Some(base + start * self.card_size..(base + stop * self.card_size).min(end))
+        })
+    }
+
+    /// Marks every card clean.
+    pub(crate) fn clear(&self) {
+        for card in self.cards {
+            card.store(0, Relaxed);
+        }
+    }
+}
+
+/// Sets or clears the bits of the block of `size` bytes at `ptr` in the
+/// bitmaps of `chunk`.
+///
+/// # SAFETY
+///
+/// `chunk` must be a live chunk with allocation bitmaps that holds the
+/// block, and no [`ChunkMap`] of it may be live.
+unsafe fn mark(chunk: NonNull<Chunk>, ptr: NonNull<u8>, size: usize, live: bool) {
+    let b = usize::BITS as usize;
+    let first = (ptr.addr().get() - chunk.addr().get()) / GRANULE;
+    let last = first + size / GRANULE - 1;
+    // SAFETY: The caller guarantees the bitmaps are live and not borrowed.
+    unsafe {
+        let len = chunk.as_ref().layout.size();
+        let words = map_words(len);
+        let starts = chunk.cast::<u8>().add(HEADER).cast::<usize>();
+        let ends = starts.add(words);
+        let (s, e) = (starts.add(first / b).as_ptr(), ends.add(last / b).as_ptr());
+        if live {
+            *s |= 1 << (first % b);
+            *e |= 1 << (last % b);
+        } else {
+            *s &= !(1 << (first % b));
+            *e &= !(1 << (last % b));
+        }
+    }
+}
+
+struct State {
+    index: FreeIndex,
+    chunks: Option<NonNull<Chunk>>,
+}
+
+// SAFETY: The chunks are owned by the allocator and only accessed with the
+// lock held or through `&mut Fit`.
+unsafe impl Send for State {}
+
+impl State {
+    /// The chunk holding `addr`, found by walking the list.
+    fn chunk_of(&self, addr: usize) -> Option<NonNull<Chunk>> {
+        let mut next = self.chunks;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk {
+                next: n, layout, ..
+            } = unsafe { chunk.read() };
+            let base = chunk.addr().get();
+            if (base..base + layout.size()).contains(&addr) {
+                return Some(chunk);
+            }
+            next = n;
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) struct FitConfig {
+    /// Bytes to request from the parent at a time. Larger objects get a
+    /// chunk of their own.
+    pub(crate) chunk_size: usize,
+    pub(crate) placement: Placement,
+    /// Keep a card table for every chunk, with a byte per this many bytes of
+    /// the chunk, for [`Fit::card`] and [`Fit::card_tables`]. Each table is
+    /// allocated from the parent alongside its chunk, and freed with it.
+    pub(crate) card_size: Option<usize>,
+    /// Keep allocation bitmaps in every chunk, for [`Fit::chunk_maps`] and
+    /// the object queries. Costs a bit per granule twice over, and a walk of
+    /// the chunk list on every allocation and free.
+    pub(crate) bitmaps: bool,
+}
+
+/// An allocator for large objects within chunks obtained from a parent,
+/// which coalesces adjacent free extents on free to bound fragmentation in
+/// long-running processes.
+///
+/// Chunks are only returned to the parent when the allocator is dropped.
+pub(crate) struct Fit<'p, A: Alloc> {
+    parent: &'p A,
+    config: FitConfig,
+    state: SpinLock<State>,
+}
+
+impl<'p, A: Alloc> Fit<'p, A> {
+    pub(crate) const fn new(parent: &'p A, chunk_size: usize) -> Self {
+        Self::with_config(
+            parent,
+            FitConfig {
+                chunk_size,
+                placement: Placement::LowestAddress,
+                bitmaps: false,
+                card_size: None,
+            },
+        )
+    }
+
+    pub(crate) const fn with_config(parent: &'p A, config: FitConfig) -> Self {
+        Self {
+            parent,
+            config,
+            state: SpinLock::new(State {
+                index: FreeIndex::new(),
+                chunks: None,
+            }),
+        }
+    }
+
+    /// Total bytes in free extents.
+    pub(crate) fn free_bytes(&self) -> usize {
+        self.state.lock().index.bytes()
+    }
+
+    /// Calls `f` on the allocation bitmaps of every chunk, with the lock held
+    /// so that no block is allocated or freed meanwhile. Does nothing unless
+    /// [`FitConfig::bitmaps`] is set.
+    pub(crate) fn chunk_maps(&self, f: &mut dyn FnMut(&ChunkMap<'_>)) {
+        if !self.config.bitmaps {
+            return;
+        }
+        let state = self.state.lock();
+        let mut next = state.chunks;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and has bitmaps, which
+            // the lock keeps from changing.
+            let map = unsafe { ChunkMap::new(chunk) };
+            f(&map);
+            // SAFETY: As above.
+            next = unsafe { chunk.as_ref() }.next;
+        }
+    }
+
+    /// The card covering `addr`, for a write barrier to set. Always `None`
+    /// unless [`FitConfig::card_size`] is set.
+    ///
+    /// Walks the chunk list, so it costs more the more chunks there are.
+    pub(crate) fn card(&self, addr: usize) -> Option<&AtomicU8> {
+        let card_size = self.config.card_size?;
+        // Chunks are only ever pushed onto the list until the allocator is
+        // dropped, so the list can be walked without the lock.
+        let mut next = self.state.lock().chunks;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live until the allocator is
+            // dropped, and has a card table.
+            let table = unsafe { CardTable::new(chunk, card_size) }?;
+            if let Some(card) = table.card(addr) {
+                return Some(card);
+            }
+            // SAFETY: As above.
+            next = unsafe { chunk.as_ref() }.next;
+        }
+        None
+    }
+
+    /// Calls `f` on the card table of every chunk. Does nothing unless
+    /// [`FitConfig::card_size`] is set.
+    pub(crate) fn card_tables(&self, f: &mut dyn FnMut(&CardTable<'_>)) {
+        let Some(card_size) = self.config.card_size else {
+            return;
+        };
+        let mut next = self.state.lock().chunks;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and has a card table.
+            if let Some(table) = unsafe { CardTable::new(chunk, card_size) } {
+                f(&table);
+            }
+            // SAFETY: As above.
+            next = unsafe { chunk.as_ref() }.next;
+        }
+    }
+
+    /// Whether a live block starts at `addr`. Always `false` unless
+    /// [`FitConfig::bitmaps`] is set.
+    pub(crate) fn is_object_start(&self, addr: usize) -> bool {
+        self.with_map(addr, |map| map.is_object_start(addr))
+            .unwrap_or(false)
+    }
+
+    /// The start and length, in whole granules, of the live block holding
+    /// `addr`. Always `None` unless [`FitConfig::bitmaps`] is set.
+    pub(crate) fn object_containing(&self, addr: usize) -> Option<(NonNull<u8>, usize)> {
+        self.with_map(addr, |map| map.object_containing(addr))
+            .flatten()
+    }
+
+    fn with_map<R>(&self, addr: usize, f: impl FnOnce(&ChunkMap<'_>) -> R) -> Option<R> {
+        if !self.config.bitmaps {
+            return None;
+        }
+        let state = self.state.lock();
+        let chunk = state.chunk_of(addr)?;
+        // SAFETY: The chunk is live and has bitmaps, which the lock keeps
+        // from changing.
+        Some(f(&unsafe { ChunkMap::new(chunk) }))
+    }
+
+    /// Sets or clears the bits of a block taken from or returned to the
+    /// index, if the allocator keeps bitmaps.
+    fn mark(&self, state: &State, ptr: NonNull<u8>, size: usize, live: bool) {
+        if !self.config.bitmaps {
+            return;
+        }
+        let chunk = state
+            .chunk_of(ptr.addr().get())
+            .expect("a block lies in a chunk");
+        // SAFETY: The chunk holds the block and has bitmaps, and the caller
+        // holds the lock, so no map of them is live.
+        unsafe { mark(chunk, ptr, size, live) };
+    }
+
+    /// The space reserved for allocation bitmaps in a chunk of `len` bytes.
+    fn map_len(&self, len: usize) -> usize {
+        if self.config.bitmaps { map_len(len) } else { 0 }
+    }
+
+    /// Upgrades every chunk to transparent huge pages, as
+    /// [`Mmap::collapse_huge_pages`](crate::mmap::Mmap::collapse_huge_pages)
+    /// does for one allocation. Stops at the first chunk that cannot be
+    /// collapsed, such as one that was never touched.
+    ///
+    /// The parent must hand out private anonymous memory, as the mmap
+    /// backend does.
+    pub(crate) fn collapse_huge_pages(&self) -> Result<(), MmapErr> {
+        // Chunks are only ever pushed onto the list until the allocator is
+        // dropped, so the list can be walked without the lock.
+        let mut next = self.state.lock().chunks;
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk {
+                next: n, layout, ..
+            } = unsafe { chunk.read() };
+            next = n;
+            // SAFETY: The chunk is valid for `layout.size()` bytes, and the
+            // parent's memory is anonymous.
+            unsafe { mmap::collapse(chunk.cast(), layout.size()) }?;
+        }
+        Ok(())
+    }
+
+    /// Obtains a chunk large enough for `size` bytes aligned to `align`, and
+    /// adds it to the index.
+    fn grow(&self, state: &mut State, size: usize, align: usize) -> Result<(), AllocError> {
+        let len = HEADER
+            .checked_add(size)
+            .and_then(|x| x.checked_add(align))
+            .ok_or(AllocError)?
+            .max(self.config.chunk_size);
+        // Bitmaps for twice the length cover their own space too.
+        let len = len
+            .checked_add(self.map_len(len.saturating_mul(2)))
+            .ok_or(AllocError)?
+            .next_multiple_of(GRANULE);
+        let layout = Layout::from_size_align(len, GRANULE).map_err(|_| AllocError)?;
+        let tag = self.parent.alloc(layout)?;
+        let cards = match self.config.card_size {
+            Some(card_size) => {
+                let n = tag.layout().size().div_ceil(card_size);
+                match Layout::array::<AtomicU8>(n)
+                    .map_err(|_| AllocError)
+                    .and_then(|x| self.parent.alloc_zeroed(x))
+                {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        // SAFETY: The chunk was just allocated and is unused.
+                        unsafe { self.parent.free(tag) };
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+        let chunk = tag.ptr().cast::<Chunk>();
+        let map = self.map_len(tag.layout().size());
+        // SAFETY: The chunk is at least `HEADER + map` bytes and aligned to
+        // `GRANULE`, and the rest of it is unused.
+        unsafe {
+            chunk.write(Chunk {
+                next: state.chunks,
+                layout: tag.layout(),
+                user: tag.user(),
+                cards,
+            });
+            tag.ptr().add(HEADER).write_bytes(0, map);
+            let body = tag.ptr().add(HEADER + map);
+            let len = (tag.layout().size() - HEADER - map) & !(GRANULE - 1);
+            state.index.insert(body, len);
+        }
+        state.chunks = Some(chunk);
+        Ok(())
+    }
+}
+
+fn granules(layout: Layout) -> Option<usize> {
+    layout.size().max(1).checked_next_multiple_of(GRANULE)
+}
+
+impl<A: Alloc> Alloc for Fit<'_, A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let size = granules(layout).ok_or(AllocError)?;
+        let mut state = self.state.lock();
+        let (align, placement) = (layout.align(), self.config.placement);
+        let ptr = match state.index.take(size, align, placement) {
+            Some(ptr) => ptr,
+            None => {
+                self.grow(&mut state, size, align)?;
+                state.index.take(size, align, placement).ok_or(AllocError)?
+            }
+        };
+        self.mark(&state, ptr, size, true);
+        // SAFETY: `take` returns a block of `size` bytes aligned to
+        // `layout.align()`.
+        Ok(unsafe { Tag::new(ptr, layout) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let size = granules(tag.layout()).unwrap();
+        let mut state = self.state.lock();
+        self.mark(&state, tag.ptr(), size, false);
+        // SAFETY: The block was taken from the index in `alloc`, so it is a
+        // multiple of `GRANULE` in size and alignment.
+        unsafe { state.index.insert(tag.ptr(), size) };
+    }
+}
+
+/// Chunks are kept until the allocator is dropped, so there is nothing of
+/// its own to release. Grinding passes through to the parent.
+impl<A: Alloc + Grind> Grind for Fit<'_, A> {
+    fn grind(&self) -> GrindReport {
+        self.parent.grind()
+    }
+
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        self.parent.grind_budgeted(budget)
+    }
+
+    fn compact(&self) -> GrindReport {
+        self.parent.compact()
+    }
+}
+
+impl<A: Alloc + Check> Check for Fit<'_, A> {
+    /// Checks the chunk headers, that the free extents are ordered, apart
+    /// and inside the chunks, and with bitmaps, that no live block starts
+    /// in a free extent and that every block that starts also ends. Then
+    /// checks the parent.
+    fn check(&self, report: &mut Report) {
+        {
+            let state = self.state.lock();
+            let mut next = state.chunks;
+            while let Some(chunk) = next {
+                // SAFETY: Every chunk in the list is live and starts with its
+                // header.
+                let header = unsafe { chunk.as_ref() };
+                report.checked += 1;
+                let addr = chunk.addr().get();
+                if !addr.is_multiple_of(GRANULE) || header.layout.align() < GRANULE {
+                    report.violation(Violation::Misaligned {
+                        addr,
+                        align: GRANULE,
+                    });
+                }
+                if self.config.bitmaps {
+                    // SAFETY: The chunk has bitmaps, which the lock keeps
+                    // from changing.
+                    let map = unsafe { ChunkMap::new(chunk) };
+                    let count = |x: &[usize]| x.iter().map(|x| x.count_ones() as usize).sum();
+                    let (kept, found) = (count(map.starts()), count(map.ends()));
+                    if kept != found {
+                        report.violation(Violation::Miscount { addr, kept, found });
+                    }
+                }
+                next = header.next;
+            }
+            state.index.check(report, &mut |addr, len, report| {
+                let Some(chunk) = state.chunk_of(addr) else {
+                    return report.violation(Violation::Stray { addr });
+                };
+                // SAFETY: The chunk is live.
+                let size = unsafe { chunk.as_ref() }.layout.size();
+                let body = chunk.addr().get() + HEADER + self.map_len(size);
+                if addr < body || addr.saturating_add(len) > chunk.addr().get() + size {
+                    return report.violation(Violation::Stray { addr });
+                }
+                if !self.config.bitmaps {
+                    return;
+                }
+                // SAFETY: As above, and the chunk has bitmaps, which the
+                // lock keeps from changing.
+                let map = unsafe { ChunkMap::new(chunk) };
+                let first = (addr - chunk.addr().get()) / GRANULE;
+                if let Some(i) = next_set(map.starts(), first)
+                    && i < first + len / GRANULE
+                {
+                    let a = chunk.addr().get() + i * GRANULE;
+                    report.violation(Violation::Overlap { a, b: addr });
+                }
+            });
+        }
+        self.parent.check(report);
+    }
+}
+
+impl<A: Alloc> Drop for Fit<'_, A> {
+    fn drop(&mut self) {
+        let mut next = self.state.get_mut().chunks.take();
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk {
+                next: n,
+                layout,
+                user,
+                cards,
+            } = unsafe { chunk.read() };
+            next = n;
+            // SAFETY: `chunk`, `layout` and `user` describe a tag returned by
+            // the parent, which is freed exactly once here, and so does the
+            // card table.
+            unsafe {
+                if let Some(cards) = cards {
+                    self.parent.free(cards);
+                }
+                self.parent
+                    .free(Tag::new(chunk.cast(), layout).with_user(user));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    use crate::mmap::Mmap;
+
+    /// The extents in `index` as offsets from `base`, in address order.
+    fn extents(index: &FreeIndex, base: NonNull<u8>) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        let mut report = Report::default();
+        index.check(&mut report, &mut |addr, len, _| {
+            out.push((addr - base.addr().get(), len));
+        });
+        assert!(report.is_ok(), "{report:?}");
+        out
+    }
+
+    #[test]
+    fn free_index_coalesces() {
+        let mmap = Mmap::new();
+        let page = mmap
+            .alloc(Layout::from_size_align(4096, 4096).unwrap())
+            .unwrap();
+        let base = page.ptr();
+        let g = GRANULE;
+        let mut index = FreeIndex::new();
+        unsafe {
+            index.insert(base, g);
+            index.insert(base.add(2 * g), g);
+            index.insert(base.add(4 * g), g);
+        }
+        assert_eq!(extents(&index, base), [(0, g), (2 * g, g), (4 * g, g)]);
+        // Filling a gap merges with the extents on both sides.
+        unsafe { index.insert(base.add(g), g) };
+        assert_eq!(extents(&index, base), [(0, 3 * g), (4 * g, g)]);
+        unsafe { index.insert(base.add(3 * g), g) };
+        assert_eq!(extents(&index, base), [(0, 5 * g)]);
+        assert_eq!(index.bytes(), 5 * g);
+        // Taking a block from the middle splits the extent again.
+        let size = 2 * g;
+        let ptr = index.take(size, 2 * g, Placement::LowestAddress).unwrap();
+        assert_eq!(ptr, unsafe { base.add(2 * g) });
+        assert_eq!(extents(&index, base), [(0, 2 * g), (4 * g, g)]);
+        unsafe { index.insert(ptr, size) };
+        assert_eq!(extents(&index, base), [(0, 5 * g)]);
+        unsafe { mmap.free(page) };
+    }
+
+    #[test]
+    fn placement() {
+        let mmap = Mmap::new();
+        let page = mmap
+            .alloc(Layout::from_size_align(4096, 4096).unwrap())
+            .unwrap();
+        let base = page.ptr();
+        let g = GRANULE;
+        // Free extents of 4, 2 and 3 granules, in address order.
+        let index = || {
+            let mut index = FreeIndex::new();
+            unsafe {
+                index.insert(base, 4 * g);
+                index.insert(base.add(6 * g), 2 * g);
+                index.insert(base.add(10 * g), 3 * g);
+            }
+            index
+        };
+        let take = |placement| {
+            let mut index = index();
+            let ptr = index.take(2 * g, g, placement).unwrap();
+            assert!(index.take(5 * g, g, placement).is_none());
+            (ptr.addr().get() - base.addr().get(), extents(&index, base))
+        };
+        // The lowest extent that fits, carved from its end.
+        let (at, rest) = take(Placement::LowestAddress);
+        assert_eq!(at, 2 * g);
+        assert_eq!(rest, [(0, 2 * g), (6 * g, 2 * g), (10 * g, 3 * g)]);
+        // The shortest extent that fits, which is used up.
+        let (at, rest) = take(Placement::BestFit);
+        assert_eq!(at, 6 * g);
+        assert_eq!(rest, [(0, 4 * g), (10 * g, 3 * g)]);
+        // Any extent that fits.
+        let (at, rest) = take(Placement::FirstFit);
+        assert!([2 * g, 6 * g, 11 * g].contains(&at));
+        assert_eq!(rest.iter().map(|x| x.1).sum::<usize>(), 7 * g);
+        unsafe { mmap.free(page) };
+    }
+
+    #[test]
+    fn frees_coalesce() {
+        let mmap = Mmap::new();
+        let fit = Fit::with_config(
+            &mmap,
+            FitConfig {
+                chunk_size: 64 << 10,
+                ..Default::default()
+            },
+        );
+        let layout = Layout::from_size_align(3 * GRANULE, GRANULE).unwrap();
+        let tags = [(); 4].map(|_| fit.alloc(layout).unwrap());
+        let free = fit.state.lock().index.bytes();
+        let [a, b, c, d] = tags;
+        unsafe {
+            fit.free(b);
+            fit.free(d);
+            fit.free(a);
+            fit.free(c);
+        }
+        // The chunk is one free extent again.
+        let state = fit.state.lock();
+        let mut report = Report::default();
+        state.index.check(&mut report, &mut |_, _, _| {});
+        assert_eq!(report.checked, 1);
+        assert_eq!(state.index.bytes(), free + 4 * layout.size());
+    }
+
+    fn check(fit: &Fit<'_, Mmap>) -> Report {
+        let mut report = Report::default();
+        fit.check(&mut report);
+        report
+    }
+
+    #[test]
+    fn check_finds_corrupt_extent() {
+        let mmap = Mmap::new();
+        let fit = Fit::with_config(
+            &mmap,
+            FitConfig {
+                chunk_size: 64 << 10,
+                bitmaps: true,
+                ..Default::default()
+            },
+        );
+        let layout = Layout::from_size_align(2 * GRANULE, GRANULE).unwrap();
+        let a = fit.alloc(layout).unwrap();
+        let b = fit.alloc(layout).unwrap();
+        let c = fit.alloc(layout).unwrap();
+        // Blocks are carved from the top of the free extent.
+        assert_eq!(b.ptr().addr().get() + 2 * GRANULE, a.ptr().addr().get());
+        let node = b.ptr().cast::<Node>();
+        unsafe { fit.free(b) };
+        let report = check(&fit);
+        assert!(report.is_ok(), "{report:?}");
+        assert!(report.checked >= 3);
+        // A write after free that stretches the free extent over `a`.
+        unsafe { (*node.as_ptr()).len += GRANULE };
+        let report = check(&fit);
+        assert!(report.violations >= 2);
+        assert!(matches!(
+            report.first,
+            Some(Violation::Miscount { .. } | Violation::Overlap { .. })
+        ));
+        unsafe { (*node.as_ptr()).len -= GRANULE };
+        assert!(check(&fit).is_ok());
+        unsafe { fit.free(a) };
+        unsafe { fit.free(c) };
+    }
+}
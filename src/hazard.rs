@@ -0,0 +1,232 @@
+#![allow(unused)]
+
+use core::{
+    hint, ptr,
+    sync::atomic::{
+        AtomicBool, AtomicPtr,
+        Ordering::{Acquire, Relaxed, Release, SeqCst},
+        fence,
+    },
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+/// Nodes replaced by writers and waiting for readers to move on.
+struct Retired<const R: usize> {
+    tags: [Option<Tag>; R],
+}
+
+// SAFETY: Retired nodes are no longer reachable, and are only freed with the
+// lock held.
+unsafe impl<const R: usize> Send for Retired<R> {}
+
+/// Hazard pointers protecting nodes of shared metadata, such as an extent
+/// map, so that readers never take a lock while writers replace nodes.
+///
+/// A reader announces the node it is about to read with
+/// [`Hazards::protect`]. A writer that unlinks a node hands it to
+/// [`Hazards::retire`], and it is freed through `alloc`, usually the
+/// [`Base`](crate::base::Base), once no reader has it protected. Up to `N`
+/// nodes can be protected at once, and up to `R` retired nodes wait before
+/// a writer stops to reclaim them.
+pub(crate) struct Hazards<'a, A: Alloc, const N: usize = 64, const R: usize = 128> {
+    alloc: &'a A,
+    slots: [AtomicPtr<u8>; N],
+    owned: [AtomicBool; N],
+    retired: SpinLock<Retired<R>>,
+}
+
+impl<'a, A: Alloc, const N: usize, const R: usize> Hazards<'a, A, N, R> {
+    pub(crate) const fn new(alloc: &'a A) -> Self {
+        // Otherwise every retired node could be protected, and `retire`
+        // would wait for readers forever.
+        assert!(N < R);
+        Self {
+            alloc,
+            slots: [const { AtomicPtr::new(ptr::null_mut()) }; N],
+            owned: [const { AtomicBool::new(false) }; N],
+            retired: SpinLock::new(Retired {
+                tags: [const { None }; R],
+            }),
+        }
+    }
+
+    /// Claims a hazard slot, spinning while all `N` are in use.
+    fn claim(&self) -> usize {
+        loop {
+            for (i, owned) in self.owned.iter().enumerate() {
+                if !owned.load(Relaxed)
+                    && owned
+                        .compare_exchange(false, true, Acquire, Relaxed)
+                        .is_ok()
+                {
+                    return i;
+                }
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Loads `src` and keeps the node it points to from being freed until
+    /// the guard is dropped.
+    pub(crate) fn protect<T>(&self, src: &AtomicPtr<T>) -> Guard<'_, 'a, T, A, N, R> {
+        let slot = self.claim();
+        let mut ptr = src.load(Acquire);
+        loop {
+            self.slots[slot].store(ptr.cast(), SeqCst);
+            // The node may have been retired before the hazard was visible,
+            // in which case `src` no longer points to it.
+            let again = src.load(SeqCst);
+            if again == ptr {
+                break;
+            }
+            ptr = again;
+        }
+        Guard {
+            hazards: self,
+            slot,
+            ptr,
+        }
+    }
+
+    /// Frees the node described by `tag` once no reader has it protected.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc`, and the node must no
+    /// longer be reachable from any shared pointer that readers protect.
+    pub(crate) unsafe fn retire(&self, tag: Tag) {
+        let mut tag = Some(tag);
+        loop {
+            {
+                let mut retired = self.retired.lock();
+                if let Some(free) = retired.tags.iter_mut().find(|x| x.is_none()) {
+                    *free = tag.take();
+                    return;
+                }
+            }
+            self.reclaim();
+            hint::spin_loop();
+        }
+    }
+
+    /// Frees every retired node that no reader has protected.
+    pub(crate) fn reclaim(&self) {
+        // Pairs with the fence implied by the `SeqCst` store in `protect`, so
+        // that either we see the hazard or the reader sees the node unlinked.
+        fence(SeqCst);
+        let mut retired = self.retired.lock();
+        for entry in &mut retired.tags {
+            let Some(tag) = entry else { continue };
+            let addr = tag.ptr().addr().get();
+            if self.slots.iter().any(|x| x.load(SeqCst).addr() == addr) {
+                continue;
+            }
+            let tag = entry.take().unwrap();
+            // SAFETY: The node is unreachable and unprotected, and the caller
+            // of `retire` vouched that it came from `self.alloc`.
+            unsafe { self.alloc.free(tag) };
+        }
+    }
+}
+
+impl<A: Alloc, const N: usize, const R: usize> Drop for Hazards<'_, A, N, R> {
+    fn drop(&mut self) {
+        for tag in self
+            .retired
+            .get_mut()
+            .tags
+            .iter_mut()
+            .filter_map(Option::take)
+        {
+            // SAFETY: No guard can outlive `self`, so nothing is protected.
+            unsafe { self.alloc.free(tag) };
+        }
+    }
+}
+
+/// A node protected by a hazard pointer.
+pub(crate) struct Guard<'h, 'a, T, A: Alloc, const N: usize, const R: usize> {
+    hazards: &'h Hazards<'a, A, N, R>,
+    slot: usize,
+    ptr: *mut T,
+}
+
+impl<T, A: Alloc, const N: usize, const R: usize> Guard<'_, '_, T, A, N, R> {
+    /// The protected pointer, which may be null.
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// The protected node, if the pointer was not null.
+    ///
+    /// # SAFETY
+    ///
+    /// The pointer must point to a valid `T` whenever it is reachable from
+    /// the shared pointer it was loaded from.
+    #[inline]
+    pub(crate) unsafe fn as_ref(&self) -> Option<&T> {
+        // SAFETY: The node was reachable when it was protected, so it cannot
+        // be freed until the guard is dropped.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, A: Alloc, const N: usize, const R: usize> Drop for Guard<'_, '_, T, A, N, R> {
+    fn drop(&mut self) {
+        self.hazards.slots[self.slot].store(ptr::null_mut(), Release);
+        self.hazards.owned[self.slot].store(false, Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::*;
+    use crate::mmap::Mmap;
+
+    #[test]
+    fn protected_nodes_outlive_retire() {
+        let mmap = Mmap::new();
+        let hazards = Hazards::<_, 2, 4>::new(&mmap);
+        let page = Layout::from_size_align(4096, 4096).unwrap();
+        let node = mmap.alloc(page).unwrap();
+        let shared = AtomicPtr::new(node.ptr().as_ptr());
+        let guard = hazards.protect(&shared);
+        assert_eq!(guard.as_ptr(), node.ptr().as_ptr());
+        // A writer unlinks the node while the reader holds it.
+        shared.store(ptr::null_mut(), Release);
+        unsafe { hazards.retire(node) };
+        hazards.reclaim();
+        assert_eq!(mmap.stats().mapped(), page.size());
+        drop(guard);
+        hazards.reclaim();
+        assert_eq!(mmap.stats().mapped(), 0);
+    }
+
+    #[test]
+    fn retire_reclaims_when_full() {
+        let mmap = Mmap::new();
+        let hazards = Hazards::<_, 2, 4>::new(&mmap);
+        let page = Layout::from_size_align(4096, 4096).unwrap();
+        let kept = mmap.alloc(page).unwrap();
+        let shared = AtomicPtr::new(kept.ptr().as_ptr());
+        let guard = hazards.protect(&shared);
+        shared.store(ptr::null_mut(), Release);
+        unsafe { hazards.retire(kept) };
+        for _ in 0..4 {
+            unsafe { hazards.retire(mmap.alloc(page).unwrap()) };
+        }
+        // Retiring the fifth node reclaimed the unprotected ones to make
+        // room, leaving the protected node and the last one.
+        assert_eq!(mmap.stats().mapped(), 2 * page.size());
+        drop(guard);
+        drop(hazards);
+        assert_eq!(mmap.stats().mapped(), 0);
+    }
+}
@@ -0,0 +1,480 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    cell::Cell,
+    ptr::NonNull,
+    slice,
+    sync::atomic::{
+        AtomicBool, AtomicU64,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+
+use crate::{
+    core::{Alloc, FreeAll, Grind, GrindReport, Tag},
+    mmap::MmapErr,
+    sync::SpinLock,
+};
+
+/// The alignment of [`Heap::scratch`] buffers, enough for any SIMD load.
+pub(crate) const SCRATCH_ALIGN: usize = 64;
+
+/// The most threads that can cache a scratch buffer at once. Threads beyond
+/// that get a fresh buffer on every call.
+const SCRATCH_SLOTS: usize = 256;
+
+/// A thread's cached scratch buffer, kept where other threads can reach it
+/// so that the buffers of idle threads can be reclaimed.
+struct Slot {
+    /// Whether a thread has claimed the slot.
+    owned: AtomicBool,
+    /// The value of [`EPOCH`] when the buffer was last used.
+    used: AtomicU64,
+    scratch: SpinLock<Option<Scratch>>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            owned: AtomicBool::new(false),
+            used: AtomicU64::new(0),
+            scratch: SpinLock::new(None),
+        }
+    }
+}
+
+static SLOTS: [Slot; SCRATCH_SLOTS] = [const { Slot::new() }; SCRATCH_SLOTS];
+
+/// Counts calls to [`reclaim_idle_scratch`], which is how idleness is
+/// measured.
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The slot this thread has claimed, if any.
+#[thread_local]
+static SLOT: Cell<Option<&'static Slot>> = Cell::new(None);
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// Calls [`on_thread_exit`] when the thread exits, once the thread has
+    /// claimed a slot.
+    static EXIT: ExitHook = const { ExitHook };
+}
+
+#[cfg(feature = "std")]
+struct ExitHook;
+
+#[cfg(feature = "std")]
+impl Drop for ExitHook {
+    fn drop(&mut self) {
+        on_thread_exit();
+    }
+}
+
+/// The calling thread's slot, claimed on first use.
+fn slot() -> Option<&'static Slot> {
+    if let Some(slot) = SLOT.get() {
+        return Some(slot);
+    }
+    let slot = SLOTS.iter().find(|x| {
+        x.owned
+            .compare_exchange(false, true, Acquire, Relaxed)
+            .is_ok()
+    })?;
+    SLOT.set(Some(slot));
+    // Registers the destructor. This fails if the thread is already exiting,
+    // in which case the slot stays claimed.
+    #[cfg(feature = "std")]
+    let _ = EXIT.try_with(|_| {});
+    Some(slot)
+}
+
+/// Frees the calling thread's scratch buffer and gives up its slot for
+/// another thread to claim, as a thread that is about to exit should.
+///
+/// With `std`, this runs on its own when the thread exits. Without it, call
+/// it last thing on every thread that used a scratch buffer, or threads
+/// spawned in a loop will use up the slots and leak their buffers.
+pub(crate) fn on_thread_exit() {
+    let Some(slot) = SLOT.take() else { return };
+    let scratch = slot.scratch.lock().take();
+    if let Some(x) = scratch {
+        x.release();
+    }
+    slot.owned.store(false, Release);
+}
+
+/// A scratch buffer, and the heap it came from.
+struct Scratch {
+    heap: NonNull<()>,
+    tag: Tag,
+    /// Frees `tag` through `heap`, which is a `Heap<A>` for the `A` that
+    /// made the buffer.
+    release: unsafe fn(NonNull<()>, Tag),
+}
+
+impl Scratch {
+    fn size(&self) -> usize {
+        self.tag.layout().size()
+    }
+
+    fn fits(&self, layout: Layout) -> bool {
+        let have = self.tag.layout();
+        have.size() >= layout.size() && have.align() >= layout.align()
+    }
+
+    fn release(self) {
+        // SAFETY: `heap` is a `&'static Heap`, and `release` was picked for
+        // its allocator type.
+        unsafe { (self.release)(self.heap, self.tag) }
+    }
+
+    /// Takes the buffer out of `cached` if it came from `heap`.
+    fn take_from(cached: &mut Option<Scratch>, heap: NonNull<()>) -> Option<Scratch> {
+        match cached.take() {
+            Some(x) if x.heap == heap => Some(x),
+            other => {
+                *cached = other;
+                None
+            }
+        }
+    }
+}
+
+// SAFETY: The buffer is only freed through its heap, which `Heap::scratch`
+// requires to be `Sync`.
+unsafe impl Send for Scratch {}
+
+/// # SAFETY
+///
+/// `heap` must point to a `Heap<A>` that made `tag`.
+unsafe fn release<A: Alloc>(heap: NonNull<()>, tag: Tag) {
+    unsafe { heap.cast::<Heap<A>>().as_ref().free(tag) }
+}
+
+/// An owning handle to an allocator stack with an explicit end of life.
+///
+/// After [`Heap::shutdown`], the handle is poisoned: allocations fail and
+/// frees are ignored, since the memory they refer to has already been
+/// released.
+pub(crate) struct Heap<A> {
+    inner: A,
+    live: AtomicBool,
+}
+
+impl<A> Heap<A> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: AtomicBool::new(true),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    #[inline]
+    pub(crate) fn is_live(&self) -> bool {
+        self.live.load(Relaxed)
+    }
+}
+
+impl<A: Alloc> Heap<A> {
+    /// Runs `f` on a scratch buffer of `len` bytes aligned to
+    /// [`SCRATCH_ALIGN`], for codecs and math kernels that need a temporary
+    /// workspace on every call. With `len` zero, `f` gets an empty slice
+    /// and nothing is allocated.
+    ///
+    /// Each thread keeps its buffer between calls, so the allocator is only
+    /// reached when the buffer has to grow, which at least doubles it. The
+    /// buffer is never shrunk, except by [`Heap::purge_scratch`]. Its
+    /// contents are whatever the last call left in it. A thread caches one
+    /// buffer at a time, so calls on another heap, and nested calls, which
+    /// get a buffer of their own, may replace it.
+    ///
+    /// A thread's buffer is freed when the thread exits, with `std`, or when
+    /// it calls [`on_thread_exit`]. [`reclaim_idle_scratch`] also frees it
+    /// once it has gone unused for long enough.
+    pub(crate) fn scratch<R>(
+        &'static self,
+        len: usize,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R, AllocError>
+    where
+        Self: Sync,
+    {
+        self.scratch_aligned(len, SCRATCH_ALIGN, f)
+    }
+
+    /// Like [`Heap::scratch`], but with the buffer aligned to `align`.
+    pub(crate) fn scratch_aligned<R>(
+        &'static self,
+        len: usize,
+        align: usize,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R, AllocError>
+    where
+        Self: Sync,
+    {
+        // `shutdown` freed the buffers cached from this heap.
+        if !self.is_live() {
+            return Err(AllocError);
+        }
+        if len == 0 {
+            return Ok(f(&mut []));
+        }
+        let layout = Layout::from_size_align(len, align).map_err(|_| AllocError)?;
+        let heap = NonNull::from(self).cast();
+        // Taking the buffer out of the slot lets a nested call, e.g. from
+        // `f`, allocate its own rather than alias ours, and keeps it from
+        // being reclaimed while in use.
+        let slot = slot();
+        let cached = slot.and_then(|x| x.scratch.lock().take());
+        let scratch = match cached {
+            Some(x) if x.heap == heap && x.fits(layout) => x,
+            cached => {
+                let size = match &cached {
+                    Some(x) if x.heap == heap => x
+                        .size()
+                        .checked_mul(2)
+                        .map_or(layout.size(), |x| x.max(layout.size())),
+                    _ => layout.size(),
+                };
+                if let Some(x) = cached {
+                    x.release();
+                }
+                let layout = Layout::from_size_align(size, align).map_err(|_| AllocError)?;
+                // Zeroed, so that the buffer is always initialized and can be
+                // handed out as bytes.
+                Scratch {
+                    heap,
+                    tag: self.alloc_zeroed(layout)?,
+                    release: release::<A>,
+                }
+            }
+        };
+        // SAFETY: The buffer is initialized, holds at least `len` bytes, and
+        // is out of the cell, so nothing else refers to it.
+        let buf = unsafe { slice::from_raw_parts_mut(scratch.tag.ptr().as_ptr(), len) };
+        let res = f(buf);
+        let Some(slot) = slot else {
+            scratch.release();
+            return Ok(res);
+        };
+        slot.used.store(EPOCH.load(Relaxed), Relaxed);
+        // Keep the larger buffer if a nested call cached one meanwhile.
+        let mut cached = slot.scratch.lock();
+        let (keep, spare) = match cached.take() {
+            Some(x) if x.size() > scratch.size() => (x, Some(scratch)),
+            other => (scratch, other),
+        };
+        *cached = Some(keep);
+        drop(cached);
+        if let Some(x) = spare {
+            x.release();
+        }
+        Ok(res)
+    }
+
+    /// Frees the calling thread's scratch buffer, if this heap made it.
+    pub(crate) fn purge_scratch(&'static self) {
+        let Some(slot) = SLOT.get() else { return };
+        let heap = NonNull::from(self).cast();
+        let scratch = Scratch::take_from(&mut slot.scratch.lock(), heap);
+        if let Some(x) = scratch {
+            x.release();
+        }
+    }
+}
+
+/// Upgrades the memory of an allocator stack to huge pages, for
+/// [`Heap::compact_for_snapshot`].
+pub(crate) type Collapse<A> = fn(&A) -> Result<(), MmapErr>;
+
+impl<A: Alloc + Grind> Heap<A> {
+    /// Shrinks the heap as far as it goes, to be called right before `fork`
+    /// or a CRIU checkpoint so that the child or checkpoint carries as
+    /// little of it as possible: frees every thread's scratch buffer from
+    /// this heap, except those in use, then purges dirty pages and
+    /// coalesces retained extents with [`Grind::compact`].
+    ///
+    /// With `collapse`, such as [`Fit::collapse_huge_pages`], the pages
+    /// left are then upgraded to huge pages, so that there are fewer page
+    /// table entries to copy. An error from it is returned once the rest of
+    /// the work is done.
+    ///
+    /// [`Fit::collapse_huge_pages`]: crate::fit::Fit::collapse_huge_pages
+    pub(crate) fn compact_for_snapshot(
+        &'static self,
+        collapse: Option<Collapse<A>>,
+    ) -> Result<GrindReport, MmapErr> {
+        let heap = NonNull::from(self).cast();
+        for slot in &SLOTS {
+            // The owner holds the lock only to take or return its buffer.
+            let scratch = match slot.scratch.try_lock() {
+                Some(mut cached) => Scratch::take_from(&mut cached, heap),
+                None => continue,
+            };
+            if let Some(x) = scratch {
+                x.release();
+            }
+        }
+        let report = self.inner.compact();
+        if let Some(collapse) = collapse {
+            collapse(&self.inner)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Frees the calling thread's scratch buffer, whichever heap made it.
+pub(crate) fn flush_scratch() {
+    let scratch = SLOT.get().and_then(|x| x.scratch.lock().take());
+    if let Some(x) = scratch {
+        x.release();
+    }
+}
+
+/// Frees the scratch buffers of threads that have not used theirs in the
+/// last `passes` calls, counting this one, so that idle workers in a thread
+/// pool do not strand them. Call it periodically, such as from a background
+/// worker. Buffers in use are skipped. Returns the bytes freed.
+pub(crate) fn reclaim_idle_scratch(passes: u64) -> usize {
+    let epoch = EPOCH.fetch_add(1, Relaxed) + 1;
+    let mut freed = 0;
+    for slot in &SLOTS {
+        if epoch.saturating_sub(slot.used.load(Relaxed)) < passes {
+            continue;
+        }
+        // The owner holds the lock only to take or return its buffer.
+        let scratch = match slot.scratch.try_lock() {
+            Some(mut cached) => cached.take(),
+            None => continue,
+        };
+        if let Some(x) = scratch {
+            freed += x.size();
+            x.release();
+        }
+    }
+    freed
+}
+
+impl<A: Alloc + FreeAll> Heap<A> {
+    /// Poisons the handle, passes the allocator stack to `report` so that it
+    /// can render leak reports or final stats, and then releases all memory
+    /// held by the stack, starting with every thread's cached scratch buffer
+    /// from this heap. Returns `false` if the heap was already shut down.
+    ///
+    /// # SAFETY
+    ///
+    /// No allocation made through this heap may be used after this call, and
+    /// no other thread may be allocating or freeing through it concurrently.
+    pub(crate) unsafe fn shutdown(&self, report: impl FnOnce(&A)) -> bool {
+        if !self.live.swap(false, Relaxed) {
+            return false;
+        }
+        let heap = NonNull::from(self).cast();
+        for slot in &SLOTS {
+            let scratch = Scratch::take_from(&mut slot.scratch.lock(), heap);
+            if let Some(x) = scratch {
+                // SAFETY: The buffer came from this heap, which no longer
+                // passes frees through now that it is poisoned.
+                unsafe { self.inner.free(x.tag) };
+            }
+        }
+        report(&self.inner);
+        unsafe { self.inner.free_all() };
+        true
+    }
+}
+
+impl<A: Alloc> Alloc for Heap<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        if !self.is_live() {
+            return Err(AllocError);
+        }
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        if self.is_live() {
+            unsafe { self.inner.free(tag) }
+        }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        if !self.is_live() {
+            return Err(AllocError);
+        }
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if !self.is_live() {
+            return Err(AllocError);
+        }
+        unsafe { self.inner.grow(tag, new) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if !self.is_live() {
+            return Err(AllocError);
+        }
+        unsafe { self.inner.shrink(tag, new) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::mmap::{Mmap, MmapConfig, Retain};
+
+    fn leak() -> &'static Heap<Mmap> {
+        Box::leak(Box::new(Heap::new(Mmap::new())))
+    }
+
+    #[test]
+    fn empty_scratch() {
+        let heap = leak();
+        assert_eq!(heap.scratch(0, |x| x.len()), Ok(0));
+        assert_eq!(heap.inner().stats().mappings(), 0);
+    }
+
+    #[test]
+    fn shutdown_frees_scratch() {
+        let heap = leak();
+        heap.scratch(100, |x| x.fill(1)).unwrap();
+        assert_ne!(heap.inner().stats().allocated(), 0);
+        assert!(unsafe { heap.shutdown(|_| {}) });
+        assert_eq!(heap.inner().stats().allocated(), 0);
+        assert!(SLOT.get().is_none_or(|x| x.scratch.lock().is_none()));
+    }
+
+    #[test]
+    fn compact_for_snapshot() {
+        let mmap = Mmap::with_config(MmapConfig {
+            retain: Retain::Dirty { max: usize::MAX },
+            base: Some(0x2200_0000_0000),
+            ..Default::default()
+        });
+        let heap = Box::leak(Box::new(Heap::new(mmap)));
+        heap.scratch(100, |x| x.fill(1)).unwrap();
+        let page = Layout::from_size_align(rustix::param::page_size(), 1).unwrap();
+        let a = heap.inner().alloc(page).unwrap();
+        let b = heap.inner().alloc(page).unwrap();
+        unsafe { heap.inner().free(a) };
+        unsafe { heap.inner().free(b) };
+        let mappings = heap.inner().stats().mappings();
+        // The collapse error comes back once the rest is done.
+        let res = heap.compact_for_snapshot(Some(|_| Err(MmapErr::Overflow)));
+        assert!(matches!(res, Err(MmapErr::Overflow)));
+        assert_eq!(heap.inner().stats().allocated(), 0);
+        assert!(heap.inner().stats().mappings() < mappings);
+        let report = heap.compact_for_snapshot(None).unwrap();
+        assert_eq!(report.purged_bytes, 0);
+    }
+}
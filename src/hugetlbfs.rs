@@ -0,0 +1,166 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ffi::CStr,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use rustix::{
+    fd::OwnedFd,
+    fs::{self, AtFlags, FsWord, Mode, OFlags},
+    io::Errno,
+    mm::{self, MapFlags, ProtFlags},
+    process,
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    mmap::MmapErr,
+    stats::{Stats, Syscall},
+};
+
+/// Where distributions mount hugetlbfs by default.
+pub(crate) const DEFAULT_MOUNT: &CStr = c"/dev/hugepages";
+
+/// What `statfs(2)` reports as the type of a hugetlbfs mount.
+const HUGETLBFS_MAGIC: FsWord = 0x9584_58f6;
+
+/// Numbers the files created by this process.
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A backend that maps files in a hugetlbfs mount, for deployments that
+/// reserve huge pages at boot and hand them out through a mount rather than
+/// rely on transparent huge pages or `MAP_HUGETLB`. Meant as the parent of
+/// a chunked allocator such as [`Fit`](crate::fit::Fit).
+///
+/// Every allocation is a private mapping of a file of its own, rounded up
+/// to whole huge pages of the mount's size. The file is unlinked as soon as
+/// it is mapped, so nothing is left behind in the mount if the process
+/// dies. The huge pages are reserved when the file is mapped, so running
+/// out of them, or past the mount's `size=` limit, fails the allocation
+/// rather than raising `SIGBUS` on first touch. A forked child copies
+/// huge pages on write, and is killed if none are left.
+pub(crate) struct HugetlbFs {
+    dir: OwnedFd,
+    pagesize: usize,
+    stats: Stats,
+}
+
+impl HugetlbFs {
+    /// Opens the hugetlbfs mount at `mount`, such as [`DEFAULT_MOUNT`], and
+    /// reads its huge page size. Fails with `EINVAL` if `mount` is not a
+    /// hugetlbfs mount.
+    pub(crate) fn new(mount: &CStr) -> Result<Self, MmapErr> {
+        let flags = OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC;
+        let dir = fs::open(mount, flags, Mode::empty())?;
+        let statfs = fs::fstatfs(&dir)?;
+        if statfs.f_type != HUGETLBFS_MAGIC {
+            return Err(Errno::INVAL.into());
+        }
+        Ok(Self {
+            dir,
+            pagesize: statfs.f_bsize as usize,
+            stats: Stats::new(),
+        })
+    }
+
+    /// The size of the mount's huge pages, to which every allocation is
+    /// rounded up and aligned.
+    #[inline]
+    pub(crate) fn pagesize(&self) -> usize {
+        self.pagesize
+    }
+
+    #[inline]
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Creates a file in the mount, named after the process so that no
+    /// other process picks the same name, and unlinks it again, leaving only
+    /// the descriptor.
+    fn create(&self) -> Result<OwnedFd, Errno> {
+        let flags = OFlags::RDWR | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC;
+        let pid = process::getpid().as_raw_nonzero().get() as u32;
+        let name = name(pid, NEXT.fetch_add(1, Relaxed));
+        let name = CStr::from_bytes_until_nul(&name).unwrap();
+        let fd = fs::openat(&self.dir, name, flags, Mode::RUSR | Mode::WUSR)?;
+        fs::unlinkat(&self.dir, name, AtFlags::empty())?;
+        Ok(fd)
+    }
+
+    fn alloc(&self, layout: Layout) -> Result<Tag, MmapErr> {
+        if layout.align() > self.pagesize {
+            return Err(MmapErr::NoAlign);
+        }
+        let len = layout
+            .size()
+            .max(1)
+            .checked_next_multiple_of(self.pagesize)
+            .ok_or(MmapErr::Overflow)?;
+        let fd = self.create()?;
+        fs::ftruncate(&fd, len as u64)?;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        self.stats.record_syscall(Syscall::Mmap);
+        // SAFETY: The kernel chooses the address, and the file is `len`
+        // bytes long. The mapping keeps the file alive once `fd` is closed.
+        let ptr = unsafe { mm::mmap(core::ptr::null_mut(), len, rw, MapFlags::PRIVATE, &fd, 0) }?;
+        self.stats.record_map(len);
+        self.stats.record_mapping();
+        self.stats.record_commit(len);
+        self.stats.record_alloc(len);
+        // SAFETY: The mapping is `len` bytes, and aligned to a huge page,
+        // which is at least `layout.align()`.
+        Ok(unsafe { Tag::new(NonNull::new(ptr.cast()).unwrap(), layout) })
+    }
+
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed.
+    unsafe fn free(&self, tag: Tag) -> Result<(), MmapErr> {
+        let len = tag.layout().size().max(1).next_multiple_of(self.pagesize);
+        self.stats.record_syscall(Syscall::Munmap);
+        // SAFETY: The caller guarantees that the mapping is ours and unused.
+        unsafe { mm::munmap(tag.ptr().as_ptr().cast(), len) }?;
+        self.stats.record_unmap(len);
+        self.stats.record_unmapping();
+        self.stats.record_decommit(len);
+        self.stats.record_free(len);
+        Ok(())
+    }
+}
+
+/// `moz-<pid>-<n>` in hex, nul-terminated.
+fn name(pid: u32, n: u64) -> [u8; 32] {
+    let mut buf = [0; 32];
+    buf[..4].copy_from_slice(b"moz-");
+    hex(&mut buf[4..12], pid.into());
+    buf[12] = b'-';
+    hex(&mut buf[13..29], n);
+    buf
+}
+
+/// Writes the low `out.len()` nibbles of `n` to `out` as hex digits.
+fn hex(out: &mut [u8], n: u64) {
+    for (i, x) in out.iter_mut().rev().enumerate() {
+        *x = b"0123456789abcdef"[((n >> (4 * i)) & 0xf) as usize];
+    }
+}
+
+impl Alloc for HugetlbFs {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        HugetlbFs::alloc(self, layout).map_err(|_| AllocError)
+    }
+
+    /// Huge pages of a fresh file read as zero.
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        HugetlbFs::alloc(self, layout).map_err(|_| AllocError)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let res = unsafe { HugetlbFs::free(self, tag) };
+        debug_assert!(res.is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    mem::ManuallyDrop,
+    ptr::{self, NonNull},
+    slice,
+};
+
+use rustix::{
+    fd::{AsFd, BorrowedFd, OwnedFd},
+    fs::{MemfdFlags, fstat, ftruncate, memfd_create},
+    io::Errno,
+    mm::{self, MapFlags, ProtFlags},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    mmap::MmapErr,
+    sync::SpinLock,
+};
+
+/// A bump arena for large immutable tables, such as interned strings, that
+/// can be shared between processes once built.
+///
+/// The arena is a shared mapping of a memfd, so its contents live in the
+/// memfd rather than in anonymous memory. [`Intern::freeze`] re-maps the
+/// used pages read-only and releases the rest, after which processes forked
+/// from the owner, or given [`Frozen::fd`], share one physical copy of the
+/// table. Memory is never reused: `free` is a no-op.
+///
+/// # Checkpoint and restore
+///
+/// CRIU saves the memfd's contents with the process and maps the restored
+/// memfd back in place, so the arena survives as long as the memfd is
+/// dumped with it. A process sharing the table through [`Frozen::fd`]
+/// must be dumped along with the owner, or each is restored with a copy of
+/// its own. Check a restored table with [`Frozen::post_restore`].
+pub(crate) struct Intern {
+    fd: OwnedFd,
+    ptr: NonNull<u8>,
+    cap: usize,
+    used: SpinLock<usize>,
+}
+
+// SAFETY: The mapping is owned by the arena, and space is handed out with
+// the lock held.
+unsafe impl Send for Intern {}
+unsafe impl Sync for Intern {}
+
+impl Intern {
+    /// Creates an arena of up to `cap` bytes, rounded up to whole pages. The
+    /// memfd only uses memory for the pages that are written.
+    pub(crate) fn new(cap: usize) -> Result<Self, MmapErr> {
+        let cap = cap
+            .max(1)
+            .checked_next_multiple_of(rustix::param::page_size())
+            .ok_or(MmapErr::Overflow)?;
+        let fd = memfd_create(c"moz-intern", MemfdFlags::CLOEXEC)?;
+        ftruncate(&fd, cap as u64)?;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        // SAFETY: The kernel chooses the address, and the file is `cap` bytes
+        // long.
+        let ptr = unsafe { mm::mmap(core::ptr::null_mut(), cap, rw, MapFlags::SHARED, &fd, 0) }?;
+        Ok(Self {
+            fd,
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            cap,
+            used: SpinLock::new(0),
+        })
+    }
+
+    /// Copies `bytes` into the arena and returns their offset, which stays
+    /// valid in the [`Frozen`] table.
+    pub(crate) fn push(&self, bytes: &[u8]) -> Result<usize, AllocError> {
+        let tag = self.alloc(Layout::for_value(bytes))?;
+        // SAFETY: The allocation is valid for `bytes.len()` bytes, and cannot
+        // overlap `bytes`, which the arena has not handed out.
+        unsafe {
+            tag.ptr()
+                .as_ptr()
+                .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len())
+        };
+        Ok(tag.ptr().addr().get() - self.ptr.addr().get())
+    }
+
+    /// Makes the table read-only and shareable. The used pages are re-mapped
+    /// read-only in place, and the memfd is truncated to them.
+    pub(crate) fn freeze(self) -> Result<Frozen, MmapErr> {
+        let pagesize = rustix::param::page_size();
+        let len = (*self.used.lock()).next_multiple_of(pagesize).max(pagesize);
+        let this = ManuallyDrop::new(self);
+        let (ptr, cap) = (this.ptr, this.cap);
+        // SAFETY: `this` is never used or dropped again, so the fd is moved
+        // out exactly once. The lock holds no resources.
+        let fd = unsafe { ptr::read(&this.fd) };
+        // From here on, the table owns the mapping and unmaps it on failure.
+        let mut table = Frozen { fd, ptr, len: cap };
+        if len < cap {
+            // SAFETY: `len..cap` is part of the mapping, and nothing in it
+            // was handed out.
+            unsafe { mm::munmap(ptr.add(len).as_ptr().cast(), cap - len) }?;
+            table.len = len;
+        }
+        ftruncate(&table.fd, len as u64)?;
+        let flags = MapFlags::SHARED | MapFlags::FIXED;
+        // SAFETY: `MAP_FIXED` replaces our own mapping of the same pages of
+        // the same file, so the contents are unchanged.
+        unsafe {
+            mm::mmap(
+                ptr.as_ptr().cast(),
+                len,
+                ProtFlags::READ,
+                flags,
+                &table.fd,
+                0,
+            )
+        }?;
+        Ok(table)
+    }
+}
+
+impl Alloc for Intern {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let base = self.ptr.addr().get();
+        let mut used = self.used.lock();
+        let start = base
+            .checked_add(*used)
+            .and_then(|x| x.checked_next_multiple_of(layout.align()))
+            .ok_or(AllocError)?
+            - base;
+        let end = start
+            .checked_add(layout.size())
+            .filter(|&x| x <= self.cap)
+            .ok_or(AllocError)?;
+        *used = end;
+        // SAFETY: `start..end` lies within the mapping and is aligned.
+        Ok(unsafe { Tag::new(self.ptr.add(start), layout) })
+    }
+
+    /// The table is immutable once built, so nothing is reclaimed.
+    unsafe fn free(&self, tag: Tag) {}
+}
+
+impl Drop for Intern {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was made in `new`.
+        let _ = unsafe { mm::munmap(self.ptr.as_ptr().cast(), self.cap) };
+    }
+}
+
+/// The read-only table produced by [`Intern::freeze`].
+pub(crate) struct Frozen {
+    fd: OwnedFd,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: The mapping is read-only.
+unsafe impl Send for Frozen {}
+unsafe impl Sync for Frozen {}
+
+impl Frozen {
+    /// The whole table, padded to a page with zeroes.
+    #[inline]
+    pub(crate) fn bytes(&self) -> &[u8] {
+        // SAFETY: The mapping is valid and immutable for `len` bytes.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// The `len` bytes pushed at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    #[inline]
+    pub(crate) fn get(&self, offset: usize, len: usize) -> &[u8] {
+        &self.bytes()[offset..offset + len]
+    }
+
+    /// The memfd holding the table, which other processes can map
+    /// read-only to share it.
+    #[inline]
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Checks, after a restore from a checkpoint, that the memfd was
+    /// restored with the table, and still covers the mapping. A mapping past
+    /// the end of its file faults rather than reads.
+    pub(crate) fn post_restore(&self) -> Result<(), Errno> {
+        let stat = fstat(&self.fd)?;
+        if (stat.st_size as u64) < self.len as u64 {
+            return Err(Errno::NXIO);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was made in `Intern::freeze`.
+        let _ = unsafe { mm::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
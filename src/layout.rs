@@ -0,0 +1,53 @@
+#![allow(unused)]
+
+use core::alloc::{Layout, LayoutError};
+
+/// Checked constructors for composite layouts.
+pub(crate) trait LayoutExt: Sized {
+    /// Returns the layout of a header of type `H` followed by `len` elements
+    /// of type `T`, along with the offset of the first element. The returned
+    /// layout is padded to its alignment.
+    fn for_header_and_slice<H, T>(len: usize) -> Result<(Self, usize), LayoutError>;
+
+    /// Returns the layout of `header` followed by `len` elements of layout
+    /// `elem`, along with the offset of the first element. The returned layout
+    /// is padded to its alignment.
+    fn from_header_and_slice(
+        header: Layout,
+        elem: Layout,
+        len: usize,
+    ) -> Result<(Self, usize), LayoutError>;
+
+    /// Returns the layout of `len` elements of layout `elem`, where each
+    /// element is padded to `elem.align()`.
+    fn array_of(elem: Layout, len: usize) -> Result<Self, LayoutError>;
+}
+
+impl LayoutExt for Layout {
+    #[inline]
+    fn for_header_and_slice<H, T>(len: usize) -> Result<(Self, usize), LayoutError> {
+        Self::from_header_and_slice(Layout::new::<H>(), Layout::new::<T>(), len)
+    }
+
+    fn from_header_and_slice(
+        header: Layout,
+        elem: Layout,
+        len: usize,
+    ) -> Result<(Self, usize), LayoutError> {
+        let slice = Self::array_of(elem, len)?;
+        // `Layout::extend` checks that the padded offset and the total size
+        // do not exceed `isize::MAX` when rounded up to the alignment.
+        let (layout, ost) = header.extend(slice)?;
+        Ok((layout.pad_to_align(), ost))
+    }
+
+    fn array_of(elem: Layout, len: usize) -> Result<Self, LayoutError> {
+        // `Layout::pad_to_align` cannot overflow: the size of a valid layout
+        // rounded up to its alignment never exceeds `isize::MAX`.
+        let stride = elem.pad_to_align().size();
+        // `usize::MAX` can never be a valid size, so saturating on overflow
+        // lets `Layout::from_size_align` produce the `LayoutError` for us.
+        let size = stride.saturating_mul(len);
+        Layout::from_size_align(size, elem.align())
+    }
+}
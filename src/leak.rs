@@ -0,0 +1,70 @@
+#![allow(unused)]
+
+use core::{
+    ffi::c_int,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+use std::string::String;
+
+use crate::registry;
+
+unsafe extern "C" {
+    fn atexit(f: extern "C" fn()) -> c_int;
+}
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Registers a hook that prints a leak summary to standard error when the
+/// process exits, in the spirit of LeakSanitizer but from the allocator's own
+/// metadata. Returns `false` if the hook could not be registered. Calling
+/// this more than once registers the hook once.
+///
+/// Only heaps still in the [registry](crate::registry) at exit are checked,
+/// so heaps dropped before then, which unregister themselves, are not.
+pub(crate) fn check_at_exit() -> bool {
+    if INSTALLED.swap(true, Relaxed) {
+        return true;
+    }
+    // SAFETY: `report` is a plain function that stays valid for the life of
+    // the process.
+    if unsafe { atexit(report) } != 0 {
+        INSTALLED.store(false, Relaxed);
+        return false;
+    }
+    true
+}
+
+extern "C" fn report() {
+    let mut out = String::new();
+    let _ = summary(&mut out);
+    if !out.is_empty() {
+        std::eprint!("{out}");
+    }
+}
+
+/// Writes one line for every registered heap with live allocations, each
+/// followed by whatever detail the heap keeps about them, such as live
+/// bytes by call site or category. Heaps without stats are listed if they
+/// have any detail to give.
+pub(crate) fn summary(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut res = Ok(());
+    registry::for_each(|heap| {
+        if res.is_err() {
+            return;
+        }
+        let live = heap.stats().map(|x| x.allocated());
+        if live == Some(0) {
+            return;
+        }
+        let mut detail = String::new();
+        let _ = heap.leaks(&mut detail);
+        res = match live {
+            Some(live) => writeln!(out, "moz: {} leaked {live} bytes", heap.name()),
+            None if detail.is_empty() => return,
+            None => writeln!(out, "moz: {} has live allocations", heap.name()),
+        }
+        .and_then(|()| out.write_str(&detail));
+    });
+    res
+}
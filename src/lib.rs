@@ -1,6 +1,71 @@
 #![no_std]
 #![feature(allocator_api)]
 #![feature(pointer_is_aligned_to)]
+#![feature(strict_provenance_lints)]
+#![feature(thread_local)]
+#![deny(fuzzy_provenance_casts, lossy_provenance_casts)]
 
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+mod append;
+mod arenas;
+#[cfg(feature = "async")]
+mod backpressure;
+mod base;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod bounded;
+mod bridge;
+mod brk;
+mod category;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod check;
+mod compressed;
 mod core;
+mod crash;
+mod debug;
+mod emergency;
+mod extent;
+#[cfg(feature = "std")]
+mod fault;
+mod fit;
+mod hazard;
+mod heap;
+mod hugetlbfs;
+mod intern;
+mod layout;
+#[cfg(feature = "std")]
+mod leak;
+mod massif;
+mod mirror;
 mod mmap;
+mod observer;
+#[cfg(feature = "std")]
+mod pagemap;
+mod pages;
+mod pinned;
+#[cfg(feature = "pmem")]
+mod pmem;
+mod presets;
+#[cfg(feature = "backtrace")]
+mod profile;
+mod prometheus;
+mod ratelimit;
+mod reentry;
+mod registry;
+mod replay;
+mod reserve;
+mod ring;
+mod sampler;
+mod scope;
+#[cfg(feature = "std")]
+mod softdirty;
+mod stats;
+mod sync;
+mod threshold;
+mod tracked;
+mod uffd;
+mod uring;
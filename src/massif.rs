@@ -0,0 +1,120 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    fmt::{self, Write},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+struct State<W> {
+    out: W,
+    /// Bytes allocated plus bytes freed so far, i.e. `--time-unit=B`.
+    time: usize,
+    /// Bytes currently live.
+    heap: usize,
+    events: usize,
+    snapshots: usize,
+    failed: bool,
+}
+
+impl<W: Write> State<W> {
+    fn snapshot(&mut self) {
+        if self.failed {
+            return;
+        }
+        if self.snapshots == 0 {
+            self.failed |= self.header().is_err();
+        }
+        self.failed |= self.body().is_err();
+        self.snapshots += 1;
+    }
+
+    fn header(&mut self) -> fmt::Result {
+        writeln!(self.out, "desc: (none)")?;
+        writeln!(self.out, "cmd: moz")?;
+        writeln!(self.out, "time_unit: B")
+    }
+
+    fn body(&mut self) -> fmt::Result {
+        writeln!(self.out, "#-----------")?;
+        writeln!(self.out, "snapshot={}", self.snapshots)?;
+        writeln!(self.out, "#-----------")?;
+        writeln!(self.out, "time={}", self.time)?;
+        writeln!(self.out, "mem_heap_B={}", self.heap)?;
+        writeln!(self.out, "mem_heap_extra_B=0")?;
+        writeln!(self.out, "mem_stacks_B=0")?;
+        writeln!(self.out, "heap_tree=empty")
+    }
+
+    fn record(&mut self, every: usize) {
+        self.events += 1;
+        if self.events.is_multiple_of(every) {
+            self.snapshot();
+        }
+    }
+}
+
+/// Writes the heap usage of `A` over time in the `massif.out` format read
+/// by `ms_print` and massif-visualizer.
+///
+/// A snapshot is taken every `every` allocation or free events. Write errors
+/// never fail an allocation: once `out` returns an error, tracing stops and
+/// [`Massif::failed`] returns `true`.
+pub(crate) struct Massif<A, W> {
+    inner: A,
+    every: usize,
+    state: SpinLock<State<W>>,
+}
+
+impl<A, W: Write> Massif<A, W> {
+    pub(crate) fn new(inner: A, out: W, every: usize) -> Self {
+        Self {
+            inner,
+            every: every.max(1),
+            state: SpinLock::new(State {
+                out,
+                time: 0,
+                heap: 0,
+                events: 0,
+                snapshots: 0,
+                failed: false,
+            }),
+        }
+    }
+
+    pub(crate) fn failed(&self) -> bool {
+        self.state.lock().failed
+    }
+
+    /// Takes a final snapshot and returns the inner allocator and writer.
+    pub(crate) fn finish(self) -> (A, W) {
+        let mut state = self.state.into_inner();
+        state.snapshot();
+        (self.inner, state.out)
+    }
+}
+
+impl<A: Alloc, W: Write> Alloc for Massif<A, W> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        let size = tag.layout().size();
+        let mut state = self.state.lock();
+        state.time = state.time.saturating_add(size);
+        state.heap += size;
+        state.record(self.every);
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let size = tag.layout().size();
+        unsafe { self.inner.free(tag) };
+        let mut state = self.state.lock();
+        state.time = state.time.saturating_add(size);
+        state.heap -= size;
+        state.record(self.every);
+    }
+}
@@ -0,0 +1,102 @@
+#![allow(unused)]
+
+use core::{ptr::NonNull, slice};
+
+use rustix::{
+    fs::{MemfdFlags, ftruncate, memfd_create},
+    mm::{self, MapFlags, ProtFlags},
+};
+
+use crate::mmap::MmapErr;
+
+/// A ring buffer whose pages are mapped twice, back to back, so that any
+/// window of up to `len` bytes starting anywhere in the buffer is contiguous
+/// in memory, wrapping around included.
+///
+/// The backing memory is a memfd mapped shared at both halves of a single
+/// reservation, so a write through one half is visible through the other.
+///
+/// # Checkpoint and restore
+///
+/// CRIU restores both halves as mappings of one memfd, so they stay
+/// mirrored. The memfd has no descriptor of its own once the buffer is
+/// made, which CRIU handles, but tools that dump only anonymous memory
+/// would restore two unrelated copies.
+pub(crate) struct MirroredBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: The mappings are owned by the value; access follows `&`/`&mut`.
+unsafe impl Send for MirroredBuf {}
+unsafe impl Sync for MirroredBuf {}
+
+impl MirroredBuf {
+    /// Creates a mirrored buffer of at least `len` bytes, rounded up to whole
+    /// pages.
+    pub(crate) fn new(len: usize) -> Result<Self, MmapErr> {
+        let len = len
+            .max(1)
+            .checked_next_multiple_of(rustix::param::page_size())
+            .ok_or(MmapErr::Overflow)?;
+        let total = len.checked_mul(2).ok_or(MmapErr::Overflow)?;
+        let fd = memfd_create(c"moz-mirror", MemfdFlags::CLOEXEC)?;
+        ftruncate(&fd, len as u64)?;
+        // Reserve both halves at once, so that nothing else can be mapped in
+        // between, then replace each half with a view of the memfd.
+        let flags = MapFlags::PRIVATE | MapFlags::NORESERVE;
+        // SAFETY: The kernel chooses the address.
+        let base =
+            unsafe { mm::mmap_anonymous(core::ptr::null_mut(), total, ProtFlags::empty(), flags) }?;
+        let this = Self {
+            ptr: NonNull::new(base.cast()).unwrap(),
+            len,
+        };
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::SHARED | MapFlags::FIXED;
+        for half in [0, len] {
+            // SAFETY: `half..half + len` lies within our reservation, which
+            // `MAP_FIXED` replaces. On failure, `this` unmaps the whole range.
+            unsafe { mm::mmap(this.ptr.add(half).as_ptr().cast(), len, rw, flags, &fd, 0) }?;
+        }
+        // The mappings keep the memfd alive.
+        Ok(this)
+    }
+
+    /// The capacity of the ring, i.e. the size of one half.
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The `n` bytes starting at offset `start` of the ring, which may wrap
+    /// around its end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start >= self.len()` or `n > self.len()`.
+    pub(crate) fn window(&self, start: usize, n: usize) -> &[u8] {
+        assert!(start < self.len && n <= self.len);
+        // SAFETY: `start + n < 2 * len`, which is mapped and initialized.
+        unsafe { slice::from_raw_parts(self.ptr.add(start).as_ptr(), n) }
+    }
+
+    /// The mutable counterpart of [`MirroredBuf::window`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start >= self.len()` or `n > self.len()`.
+    pub(crate) fn window_mut(&mut self, start: usize, n: usize) -> &mut [u8] {
+        assert!(start < self.len && n <= self.len);
+        // SAFETY: As above. Since `n <= len`, the window never covers the same
+        // byte through both halves, and `&mut self` makes it unique.
+        unsafe { slice::from_raw_parts_mut(self.ptr.add(start).as_ptr(), n) }
+    }
+}
+
+impl Drop for MirroredBuf {
+    fn drop(&mut self) {
+        // SAFETY: Both halves were mapped in `new` as one range.
+        let _ = unsafe { mm::munmap(self.ptr.as_ptr().cast(), 2 * self.len) };
+    }
+}
@@ -1,24 +1,319 @@
 #![allow(unused)]
 
 use core::{
-    alloc::{Layout, LayoutError},
+    alloc::{AllocError, Layout, LayoutError},
     ptr::{self, NonNull},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed},
 };
 
 use rustix::{
     io::Errno,
-    mm::{MapFlags, ProtFlags, mmap_anonymous},
+    mm::{
+        Advice, MapFlags, MprotectFlags, MremapFlags, ProtFlags, madvise, mmap_anonymous, mprotect,
+        mremap, mremap_fixed,
+    },
 };
 use thiserror::Error;
 
-use crate::core::Tag;
+use crate::{
+    check::{Check, Report, Violation},
+    core::{Alloc, Budget, FreeAll, Grind, GrindReport, Tag},
+    extent::{Extent, ExtentCache},
+    observer::{Charge, ExtentObserver},
+    registry::{Fault, FaultKind, Region, RegionState},
+    reserve::Reserve,
+    stats::{Stats, Syscall},
+    sync::SpinLock,
+    threshold::Thresholds,
+};
+
+/// The maximum number of freed extents retained for reuse.
+const RETAIN_SLOTS: usize = 64;
+
+/// The maximum number of freed allocations held in quarantine. See
+/// [`MmapConfig::quarantine`].
+const QUARANTINE_SLOTS: usize = 64;
+
+/// The maximum number of live over-mapped allocations. See
+/// [`AlignStrategy::OverMap`].
+const OVERMAP_SLOTS: usize = 64;
+
+/// How many allocations of each alignment [`AlignStrategy::Auto`] samples
+/// before settling on a strategy for it.
+const AUTO_SAMPLES: u32 = 8;
+
+/// The maximum number of registered threshold callbacks.
+const THRESHOLD_SLOTS: usize = 8;
+
+/// The end of the low address space kept to by [`MmapConfig::low32`].
+const LOW32_END: usize = 1 << 32;
+
+/// `MAP_32BIT`, which rustix does not expose. It places the mapping in the
+/// first 2 GiB of the address space.
+#[cfg(target_arch = "x86_64")]
+const MAP_32BIT: MapFlags = MapFlags::from_bits_retain(0x40);
+
+/// Where mappings are hinted to go when [`MmapConfig::low32`] is set and
+/// `MAP_32BIT` is not available.
+#[cfg(not(target_arch = "x86_64"))]
+const LOW32_HINT: usize = 1 << 28;
+
+/// How many places after [`MmapConfig::base`] a fresh mapping tries, past
+/// ranges that are already taken, before the kernel picks one.
+const BASE_TRIES: usize = 16;
+
+/// Alignments from which allocations always take
+/// [`AlignStrategy::Reserve`], whatever the configured strategy: their
+/// padding is so large that mapping it accessible, even briefly, could
+/// exceed the commit limit or the overcommit heuristic.
+///
+/// Alignments up to `isize::MAX / 2` are supported in principle, but the
+/// padded range must fit in the user address space, 128 TiB with 4-level
+/// page tables, so in practice alignments past a few TiB fail with
+/// [`MmapErr::Os`]. Larger alignments, and padded sizes past `isize::MAX`,
+/// fail with [`MmapErr::Overflow`].
+pub(crate) const JUMBO_ALIGN: usize = 64 << 20;
+
+/// The size of a transparent huge page on x86-64 and most aarch64 kernels.
+pub(crate) const HUGE_PAGE: usize = 2 << 20;
+
+/// `MADV_COLLAPSE`, which rustix does not expose.
+const MADV_COLLAPSE: usize = 25;
+
+/// `MPOL_INTERLEAVE`, for `mbind(2)`, which rustix does not expose.
+const MPOL_INTERLEAVE: usize = 3;
+
+/// `MPOL_F_MEMS_ALLOWED`, for `get_mempolicy(2)`.
+const MPOL_F_MEMS_ALLOWED: usize = 1 << 2;
+
+/// The most NUMA nodes a kernel can be built for, which node masks passed
+/// to the kernel must cover.
+const MAX_NODES: usize = 1 << 10;
+
+/// A mask of NUMA nodes, as `mbind(2)` and `get_mempolicy(2)` take it.
+type NodeMask = [usize; MAX_NODES / usize::BITS as usize];
+
+/// What the backend does with the pages of a freed allocation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) enum Retain {
+    /// Unmap immediately.
+    #[default]
+    None,
+    /// Keep up to `max` bytes mapped, and resident, for reuse.
+    Dirty { max: usize },
+    /// Keep up to `max` bytes of address space for reuse, releasing the
+    /// physical pages with `MADV_DONTNEED`.
+    Purged { max: usize },
+}
+
+/// What the backend does with a purged extent it hands out again, whose
+/// pages will fault on first touch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) enum Revive {
+    /// Nothing: pages are faulted in as they are touched.
+    #[default]
+    Lazy,
+    /// Advise the pages with `MADV_WILLNEED`, which reads back any that
+    /// were swapped out without waiting for them.
+    WillNeed,
+    /// Fault every page in with `MADV_POPULATE_WRITE` before returning, so
+    /// the consumer does not take a fault storm on first access. Needs
+    /// Linux 5.14; failures are ignored.
+    Populate,
+}
+
+/// How the backend carves an allocation aligned to more than a page out of
+/// a larger mapping, when the kernel does not return an aligned address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) enum AlignStrategy {
+    /// Unmap the padding before and after the aligned block, which takes
+    /// up to two extra `munmap` calls.
+    #[default]
+    Trim,
+    /// Leave the padding mapped but untouched, so it is never resident,
+    /// and unmap the whole mapping on free. This saves the `munmap` calls,
+    /// and the VMA splits they cause, for a little address space and commit
+    /// charge. Up to 64 such allocations are live at once; beyond that,
+    /// allocations are trimmed. Resizing an allocation trims it first.
+    OverMap,
+    /// Measure, over the first few allocations of each alignment, whether
+    /// the kernel tends to return aligned addresses, either by chance or
+    /// when given an aligned hint, and settle on mapping directly, mapping
+    /// at a hint, or over-mapping and trimming accordingly. This skips the
+    /// wasted first `mmap` for alignments that never come back aligned.
+    Auto,
+    /// Reserve the padded range as inaccessible address space, unmap the
+    /// slack at both ends, and only then commit the aligned window by making
+    /// it accessible. The slack never counts against the commit limit or the
+    /// [`Charge`], so peak commit is the size of the allocation rather than
+    /// that plus the alignment, at the cost of an extra `mprotect`.
+    /// Alignments of at least [`JUMBO_ALIGN`] always use this.
+    Reserve,
+}
+
+/// What [`AlignStrategy::Auto`] has seen for one alignment.
+struct AlignClass {
+    /// Allocations sampled so far, up to [`AUTO_SAMPLES`].
+    tries: AtomicU32,
+    /// Sampled allocations that a plain `mmap` returned aligned.
+    direct: AtomicU32,
+    /// Sampled allocations that a hinted `mmap` returned aligned.
+    hinted: AtomicU32,
+    /// Where to hint the next mapping, or zero.
+    hint: AtomicUsize,
+}
+
+impl AlignClass {
+    const fn new() -> Self {
+        Self {
+            tries: AtomicU32::new(0),
+            direct: AtomicU32::new(0),
+            hinted: AtomicU32::new(0),
+            hint: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) struct MmapConfig {
+    pub(crate) retain: Retain,
+    /// When retained dirty bytes exceed this watermark, the thread freeing
+    /// memory purges the oldest dirty extents until they no longer do. This
+    /// bounds the RSS held by retention without a background thread.
+    pub(crate) dirty_watermark: Option<usize>,
+    /// Keep every mapping below 4 GiB, for JITs that need rel32 calls and
+    /// for compressed-pointer heaps. Uses `MAP_32BIT` on x86-64, which
+    /// limits the heap to 2 GiB, and an address hint elsewhere. Allocations
+    /// fail rather than land above the limit.
+    pub(crate) low32: bool,
+    /// Allocations of at least this many bytes are aligned to [`HUGE_PAGE`]
+    /// and rounded up to whole huge pages, and fresh mappings for them are
+    /// advised with `MADV_HUGEPAGE`, so that transparent huge pages can back
+    /// them fully and save TLB misses on big buffers. Below
+    /// [`HUGE_PAGE`] this is treated as [`HUGE_PAGE`]; `None` disables it.
+    pub(crate) huge_threshold: Option<usize>,
+    /// Interleave the pages of fresh mappings of at least this many bytes
+    /// across NUMA nodes with `mbind(2)`, so that a large structure shared
+    /// by threads on every node is not placed wholly on the node of
+    /// whichever thread touched it first. The nodes are those the thread
+    /// that creates the backend may allocate from, read once then. Smaller
+    /// allocations keep the default policy, which places pages on the node
+    /// of the thread that first touches them. Retained extents keep the
+    /// policy they were mapped with. Targets without a raw syscall path
+    /// ignore it.
+    pub(crate) interleave_threshold: Option<usize>,
+    /// Populate fresh mappings with `MADV_POPULATE_WRITE` when they are
+    /// allocated, so that running out of memory fails the allocation rather
+    /// than raising `SIGBUS` or waking the OOM killer on first touch. This
+    /// costs the page faults up front, and needs Linux 5.14. Allocations
+    /// served from retained extents, and growth, are not probed.
+    pub(crate) probe: bool,
+    /// The most mappings the backend may own at once, to stay clear of
+    /// `vm.max_map_count` (see [`max_map_count`]). When a new mapping would
+    /// exceed it, retained extents are unmapped, oldest first, and if none
+    /// are left the allocation fails. Under concurrent allocation the limit
+    /// may be overshot by one mapping per thread.
+    pub(crate) max_mappings: Option<usize>,
+    pub(crate) align: AlignStrategy,
+    pub(crate) revive: Revive,
+    /// Hardening: hold up to this many bytes of freed allocations in
+    /// quarantine, purged and protected `PROT_NONE` rather than unmapped or
+    /// retained, so that a use after free traps deterministically instead
+    /// of hitting whatever reuses the range. The oldest are unmapped, and
+    /// their addresses recycled, once the quarantine is full.
+    pub(crate) quarantine: Option<usize>,
+    /// Keep quarantined address ranges reserved until this long after they
+    /// were freed, rather than only until the quarantine is full, so that
+    /// a stale pointer faults for at least that long instead of aliasing a
+    /// new allocation. Expired ranges are unmapped on later frees and by
+    /// [`Grind::grind`]. The byte bound of [`MmapConfig::quarantine`] still
+    /// applies, and evicts ranges early when reached. Each quarantined range
+    /// is a mapping of its own, counted against
+    /// [`MmapConfig::max_mappings`], which also evicts them early.
+    pub(crate) quarantine_delay: Option<Delay>,
+    /// Place fresh mappings back to back from this address, with
+    /// `MAP_FIXED_NOREPLACE`, rather than wherever address space layout
+    /// randomization puts them, so that a single-threaded program sees the
+    /// same addresses from run to run. Ranges that are taken are skipped,
+    /// and after a few tries the kernel picks the address after all. The
+    /// address space of freed mappings is not reused, except through
+    /// retention. Ignored with [`MmapConfig::low32`].
+    pub(crate) base: Option<usize>,
+}
+
+/// A span of time on a caller-supplied clock. The crate has no clock of its
+/// own, so the caller picks the source and the unit, such as a monotonic
+/// clock in milliseconds.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub(crate) struct Delay {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) clock: fn() -> u64,
+    pub(crate) ticks: u64,
+}
+
+/// A live allocation that lies inside a larger mapping, whose padding was
+/// left in place by [`AlignStrategy::OverMap`].
+#[derive(Clone, Copy)]
+struct OverMapped {
+    ptr: NonNull<u8>,
+    base: NonNull<u8>,
+    len: usize,
+}
+
+struct OverMaps {
+    slots: [Option<OverMapped>; OVERMAP_SLOTS],
+}
+
+// SAFETY: The table only records where allocations lie, and is accessed
+// with the lock held.
+unsafe impl Send for OverMaps {}
+
+impl OverMaps {
+    fn insert(&mut self, entry: OverMapped) -> bool {
+        let Some(slot) = self.slots.iter_mut().find(|x| x.is_none()) else {
+            return false;
+        };
+        *slot = Some(entry);
+        true
+    }
+
+    fn find(&mut self, ptr: NonNull<u8>) -> Option<&mut OverMapped> {
+        self.slots.iter_mut().flatten().find(|x| x.ptr == ptr)
+    }
+
+    fn take(&mut self, ptr: NonNull<u8>) -> Option<OverMapped> {
+        self.slots
+            .iter_mut()
+            .find(|x| x.is_some_and(|x| x.ptr == ptr))?
+            .take()
+    }
+}
 
 pub struct Mmap {
     pagesize: usize,
+    config: MmapConfig,
+    stats: Stats,
+    cache: SpinLock<ExtentCache<RETAIN_SLOTS>>,
+    quarantine: SpinLock<ExtentCache<QUARANTINE_SLOTS>>,
+    overmapped: SpinLock<OverMaps>,
+    classes: [AlignClass; usize::BITS as usize],
+    thresholds: Thresholds<THRESHOLD_SLOTS>,
+    observer: Option<&'static dyn ExtentObserver>,
+    charge: Option<&'static dyn Charge>,
+    /// Where the next fresh mapping goes, with [`MmapConfig::base`].
+    cursor: AtomicUsize,
+    /// The nodes to interleave across, with
+    /// [`MmapConfig::interleave_threshold`].
+    nodes: Option<NodeMask>,
 }
 
 #[derive(Debug, Error)]
-enum MmapErr {
+pub(crate) enum MmapErr {
     #[error("mmap failed with {0}")]
     Os(#[from] rustix::io::Errno),
     #[error("overflow")]
@@ -29,35 +324,719 @@ enum MmapErr {
     Layout(#[from] LayoutError),
 }
 
-fn map(len: usize) -> Result<NonNull<u8>, Errno> {
-    let nil = ptr::null_mut();
-    let rw = ProtFlags::READ | ProtFlags::WRITE;
-    // SAFETY: passsing `ptr::null_mut()` means the kernel will choose a
-    // page-aligned address at which to create the mapping. See mmap(2).
-    let ptr = unsafe { mmap_anonymous(nil, len, rw, MapFlags::PRIVATE) }?;
-    Ok(NonNull::new(ptr.cast()).unwrap())
+/// Reads the process-wide limit on mappings from
+/// `/proc/sys/vm/max_map_count`, for sizing [`MmapConfig::max_mappings`].
+/// Thread stacks, shared libraries and other allocators count towards the
+/// same limit, so leave headroom.
+#[cfg(feature = "std")]
+pub(crate) fn max_map_count() -> std::io::Result<usize> {
+    use std::io::{Error, ErrorKind};
+
+    std::fs::read_to_string("/proc/sys/vm/max_map_count")?
+        .trim()
+        .parse()
+        .map_err(|_| Error::from(ErrorKind::InvalidData))
+}
+
+/// Maps `len` bytes, preferably at `at`, or with `fixed`, only at `at`.
+/// Without `prot`, the range is only reserved, with `MAP_NORESERVE`.
+fn map(
+    len: usize,
+    prot: ProtFlags,
+    low32: bool,
+    at: Option<usize>,
+    fixed: bool,
+) -> Result<NonNull<u8>, Errno> {
+    let mut hint = at.map_or(ptr::null_mut(), ptr::without_provenance_mut);
+    let mut flags = MapFlags::PRIVATE;
+    if prot.is_empty() {
+        flags |= MapFlags::NORESERVE;
+    }
+    if fixed {
+        flags |= MapFlags::FIXED_NOREPLACE;
+    }
+    if low32 {
+        #[cfg(target_arch = "x86_64")]
+        {
+            flags |= MAP_32BIT;
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            hint = ptr::without_provenance_mut(LOW32_HINT);
+        }
+    }
+    // SAFETY: Without `MAP_FIXED`, the address is only a hint, and the kernel
+    // will choose a page-aligned address at which to create the mapping that
+    // does not overlap existing mappings. `MAP_FIXED_NOREPLACE` fails rather
+    // than overlap them. See mmap(2).
+    let ptr = unsafe { mmap_anonymous(hint, len, prot, flags) };
+    #[cfg(feature = "tracing")]
+    match ptr {
+        Ok(ptr) => tracing::trace!(len, ?ptr, "mmap"),
+        Err(err) => tracing::warn!(len, %err, "mmap failed"),
+    }
+    #[cfg(feature = "defmt")]
+    match ptr {
+        Ok(ptr) => defmt::trace!("mmap len={=usize} ptr={=usize:#x}", len, ptr.addr()),
+        Err(err) => defmt::warn!(
+            "mmap failed len={=usize} errno={=i32}",
+            len,
+            err.raw_os_error()
+        ),
+    }
+    let ptr = NonNull::new(ptr?.cast::<u8>()).unwrap();
+    if low32
+        && ptr
+            .addr()
+            .get()
+            .checked_add(len)
+            .is_none_or(|x| x > LOW32_END)
+    {
+        // SAFETY: The mapping was just created and is not in use.
+        let _ = unsafe { rustix::mm::munmap(ptr.as_ptr().cast(), len) };
+        return Err(Errno::NOMEM);
+    }
+    Ok(ptr)
+}
+
+/// Asks the kernel to back the whole huge pages within `len` bytes at `ptr`
+/// with transparent huge pages now, with `MADV_COLLAPSE`, rather than waiting
+/// for khugepaged. Ranges that were never touched cannot be collapsed and
+/// fail with `EINVAL`, as do kernels before 6.1. Targets without a raw
+/// syscall path fail with `ENOSYS`.
+///
+/// # SAFETY
+///
+/// `ptr` must be valid for `len` bytes of private anonymous memory.
+pub(crate) unsafe fn collapse(ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+    let pagesize = rustix::param::page_size();
+    let ost = ptr.align_offset(pagesize);
+    let Some(len) = len.checked_sub(ost).map(|x| x & !(pagesize - 1)) else {
+        return Ok(());
+    };
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: The range is within the caller's, and collapsing does not
+    // change its contents.
+    unsafe { madvise_raw(ptr.add(ost).as_ptr(), len, MADV_COLLAPSE) }
+}
+
+/// `madvise(2)` with advice that rustix has no variant for.
+///
+/// # SAFETY
+///
+/// As for `madvise(2)` with `advice`.
+unsafe fn madvise_raw(ptr: *mut u8, len: usize, advice: usize) -> Result<(), Errno> {
+    let args = [ptr.addr(), len, advice, 0, 0, 0];
+    unsafe { syscall_raw(nr::MADVISE, args) }.map(drop)
+}
+
+/// `mbind(2)`, which rustix does not expose, with the policy `mode` over
+/// the nodes in `mask`.
+///
+/// # SAFETY
+///
+/// As for `mbind(2)`. Without `MPOL_MF_MOVE`, the contents of the range
+/// are not touched.
+unsafe fn mbind_raw(ptr: *mut u8, len: usize, mode: usize, mask: &NodeMask) -> Result<(), Errno> {
+    // The kernel drops the last bit of `maxnode`.
+    let maxnode = MAX_NODES + 1;
+    let mask = ptr::from_ref(mask).expose_provenance();
+    unsafe { syscall_raw(nr::MBIND, [ptr.addr(), len, mode, mask, maxnode, 0]) }.map(drop)
+}
+
+/// The NUMA nodes the current thread may allocate from, from
+/// `get_mempolicy(2)`, which rustix does not expose.
+fn allowed_nodes() -> Result<NodeMask, Errno> {
+    let mut mode = 0i32;
+    let mut mask = [0; MAX_NODES / usize::BITS as usize];
+    let args = [
+        ptr::from_mut(&mut mode).expose_provenance(),
+        ptr::from_mut(&mut mask).expose_provenance(),
+        MAX_NODES,
+        0,
+        MPOL_F_MEMS_ALLOWED,
+        0,
+    ];
+    // SAFETY: The kernel writes an `int` to `mode` and `MAX_NODES` bits to
+    // `mask`, both of which are ours.
+    unsafe { syscall_raw(nr::GET_MEMPOLICY, args) }?;
+    Ok(mask)
+}
+
+/// System call numbers for [`syscall_raw`].
+#[cfg(target_arch = "x86_64")]
+mod nr {
+    pub(super) const MADVISE: usize = 28;
+    pub(super) const MBIND: usize = 237;
+    pub(super) const GET_MEMPOLICY: usize = 239;
+}
+
+#[cfg(target_arch = "aarch64")]
+mod nr {
+    pub(super) const MADVISE: usize = 233;
+    pub(super) const MBIND: usize = 235;
+    pub(super) const GET_MEMPOLICY: usize = 236;
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod nr {
+    pub(super) const MADVISE: usize = 0;
+    pub(super) const MBIND: usize = 0;
+    pub(super) const GET_MEMPOLICY: usize = 0;
+}
+
+/// Makes the system call `nr` with `args`, for calls that rustix does not
+/// expose. Pointers among `args` must have their provenance exposed if the
+/// kernel accesses memory through them.
+///
+/// # SAFETY
+///
+/// As for the system call.
+#[cfg(target_arch = "x86_64")]
+unsafe fn syscall_raw(nr: usize, args: [usize; 6]) -> Result<usize, Errno> {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") nr as isize => ret,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        )
+    };
+    match ret {
+        -4095..0 => Err(Errno::from_raw_os_error(-ret as i32)),
+        ret => Ok(ret as usize),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn syscall_raw(nr: usize, args: [usize; 6]) -> Result<usize, Errno> {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "svc 0",
+            in("x8") nr,
+            inlateout("x0") args[0] => ret,
+            in("x1") args[1],
+            in("x2") args[2],
+            in("x3") args[3],
+            in("x4") args[4],
+            in("x5") args[5],
+            options(nostack),
+        )
+    };
+    match ret {
+        -4095..0 => Err(Errno::from_raw_os_error(-ret as i32)),
+        ret => Ok(ret as usize),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn syscall_raw(nr: usize, args: [usize; 6]) -> Result<usize, Errno> {
+    Err(Errno::NOSYS)
 }
 
 impl Mmap {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
+        Self::with_config(MmapConfig::default())
+    }
+
+    pub(crate) fn with_config(config: MmapConfig) -> Self {
         Self {
             pagesize: rustix::param::page_size(),
+            config,
+            stats: Stats::new(),
+            cache: SpinLock::new(ExtentCache::new()),
+            quarantine: SpinLock::new(ExtentCache::new()),
+            overmapped: SpinLock::new(OverMaps {
+                slots: [None; OVERMAP_SLOTS],
+            }),
+            classes: [const { AlignClass::new() }; usize::BITS as usize],
+            thresholds: Thresholds::new(),
+            observer: None,
+            charge: None,
+            cursor: AtomicUsize::new(config.base.unwrap_or(0)),
+            nodes: config
+                .interleave_threshold
+                .and_then(|_| allowed_nodes().ok()),
         }
     }
 
+    /// Reports every change to the backend's mappings to `observer`.
+    pub(crate) fn with_observer(mut self, observer: &'static dyn ExtentObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Charges every byte the backend maps to `charge`, and fails
+    /// allocations it refuses.
+    pub(crate) fn with_charge(mut self, charge: &'static dyn Charge) -> Self {
+        self.charge = Some(charge);
+        self
+    }
+
     fn pagesize(&self) -> usize {
         self.pagesize
     }
 
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Callbacks on allocated bytes crossing a threshold. They are evaluated
+    /// whenever the backend maps or releases memory, but not when an
+    /// allocation is served from retained extents, so a crossing may be
+    /// reported late.
+    pub(crate) fn thresholds(&self) -> &Thresholds<THRESHOLD_SLOTS> {
+        &self.thresholds
+    }
+
+    fn mmap(&self, len: usize) -> Result<NonNull<u8>, Errno> {
+        self.mmap_at(len, None)
+    }
+
+    /// Maps `len` bytes, preferably at the address `hint`, which the kernel
+    /// may ignore.
+    fn mmap_at(&self, len: usize, hint: Option<usize>) -> Result<NonNull<u8>, Errno> {
+        self.make_room()?;
+        self.charge(len)?;
+        self.stats.record_syscall(Syscall::Mmap);
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        let ptr = self
+            .map(len, rw, hint)
+            .inspect_err(|_| self.uncharge(len))?;
+        self.stats.record_map(len);
+        self.stats.record_commit(len);
+        self.stats.record_mapping();
+        if let Some(observer) = self.observer {
+            observer.on_map(ptr, len);
+        }
+        Ok(ptr)
+    }
+
+    /// Maps `len` bytes with `prot` where [`MmapConfig::low32`] and
+    /// [`MmapConfig::base`] say, or else preferably at `hint`.
+    fn map(&self, len: usize, prot: ProtFlags, hint: Option<usize>) -> Result<NonNull<u8>, Errno> {
+        match self.config.base {
+            Some(_) if !self.config.low32 => self.map_next(len, prot),
+            _ => map(len, prot, self.config.low32, hint, false),
+        }
+    }
+
+    /// Maps `len` bytes at the cursor of [`MmapConfig::base`], skipping
+    /// ranges that are taken.
+    fn map_next(&self, len: usize, prot: ProtFlags) -> Result<NonNull<u8>, Errno> {
+        for _ in 0..BASE_TRIES {
+            let at = self.cursor.fetch_add(len, Relaxed);
+            if at.checked_add(len).is_none() {
+                break;
+            }
+            if let Ok(ptr) = map(len, prot, false, Some(at), true) {
+                return Ok(ptr);
+            }
+        }
+        map(len, prot, false, None, false)
+    }
+
+    fn charge(&self, len: usize) -> Result<(), Errno> {
+        match self.charge {
+            Some(charge) if !charge.charge(len) => Err(Errno::NOMEM),
+            _ => Ok(()),
+        }
+    }
+
+    fn uncharge(&self, len: usize) {
+        if let Some(charge) = self.charge {
+            charge.uncharge(len);
+        }
+    }
+
+    /// Unmaps retained extents until there is room for one more mapping
+    /// under [`MmapConfig::max_mappings`].
+    fn make_room(&self) -> Result<(), Errno> {
+        let Some(max) = self.config.max_mappings else {
+            return Ok(());
+        };
+        while self.stats.mappings() >= max {
+            // Quarantined ranges are the next to go once nothing is retained,
+            // since they only serve to catch stale pointers.
+            let ext = self.cache.lock().take_oldest();
+            let ext = ext.or_else(|| self.quarantine.lock().take_oldest());
+            let Some(ext) = ext else {
+                return Err(Errno::NOMEM);
+            };
+            // SAFETY: Retained and quarantined extents are mapped by `self`
+            // and unused.
+            let _ = unsafe { self.unmap(ext.ptr, ext.len) };
+        }
+        Ok(())
+    }
+
+    /// Unmaps a whole mapping.
+    ///
+    /// # SAFETY
+    ///
+    /// As for [`Mmap::unmap_part`], and the range must be a whole mapping.
+    unsafe fn unmap(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        unsafe { self.unmap_part(ptr, len) }?;
+        self.stats.record_unmapping();
+        Ok(())
+    }
+
+    /// Reserves `len` bytes of inaccessible address space, which counts as
+    /// mapped but neither as committed nor against the [`Charge`]. It is
+    /// placed as [`Mmap::mmap`] places mappings, and fails with `ENOMEM`
+    /// when it cannot fit below 4 GiB under [`MmapConfig::low32`].
+    fn reserve(&self, len: usize) -> Result<NonNull<u8>, Errno> {
+        if self.config.low32 && len > LOW32_END {
+            return Err(Errno::NOMEM);
+        }
+        self.make_room()?;
+        self.stats.record_syscall(Syscall::Mmap);
+        let ptr = self.map(len, ProtFlags::empty(), None)?;
+        self.stats.record_map(len);
+        self.stats.record_mapping();
+        if let Some(observer) = self.observer {
+            observer.on_map(ptr, len);
+        }
+        Ok(ptr)
+    }
+
+    /// Unmaps part of a reservation made by [`Mmap::reserve`] that was never
+    /// committed.
+    ///
+    /// # SAFETY
+    ///
+    /// As for [`Mmap::unmap_part`].
+    unsafe fn unreserve(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.stats.record_syscall(Syscall::Munmap);
+        unsafe { rustix::mm::munmap(ptr.as_ptr().cast(), len) }?;
+        self.stats.record_unmap(len);
+        if let Some(observer) = self.observer {
+            observer.on_unmap(ptr, len);
+        }
+        Ok(())
+    }
+
+    /// Unmaps part of a mapping, such as the padding cut off by
+    /// [`Mmap::trim`], which leaves the mapping count unchanged.
+    ///
     /// # SAFETY
     ///
     /// TODO@safety
     /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
-    unsafe fn unmap(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+    unsafe fn unmap_part(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
         assert!(ptr.is_aligned_to(self.pagesize));
-        assert!(len % self.pagesize == 0);
+        assert!(len.is_multiple_of(self.pagesize));
         //assert!(round_up(len, self.pagesize) == len);
-        unsafe { rustix::mm::munmap(ptr.as_ptr().cast(), len) }
+        self.stats.record_syscall(Syscall::Munmap);
+        unsafe { rustix::mm::munmap(ptr.as_ptr().cast(), len) }?;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(len, ?ptr, "munmap");
+        #[cfg(feature = "defmt")]
+        defmt::trace!("munmap len={=usize} ptr={=usize:#x}", len, ptr.addr().get());
+        self.stats.record_unmap(len);
+        self.stats.record_decommit(len);
+        self.uncharge(len);
+        if let Some(observer) = self.observer {
+            observer.on_unmap(ptr, len);
+        }
+        Ok(())
+    }
+
+    /// Changes the protection of `len` bytes at `ptr` to `prot`.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`, and no
+    /// live reference into the range may be used in a way `prot` forbids.
+    unsafe fn protect(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        prot: MprotectFlags,
+    ) -> Result<(), Errno> {
+        self.stats.record_syscall(Syscall::Mprotect);
+        unsafe { mprotect(ptr.as_ptr().cast(), len, prot) }?;
+        if let Some(observer) = self.observer {
+            observer.on_protect(ptr, len, prot);
+        }
+        Ok(())
+    }
+
+    /// Releases the physical pages backing `len` bytes at `ptr` while keeping
+    /// the mapping. Subsequent reads return zeroes.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`, and the
+    /// contents of the range must no longer be needed.
+    unsafe fn purge(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        self.stats.record_syscall(Syscall::Madvise);
+        unsafe { madvise(ptr.as_ptr().cast(), len, Advice::LinuxDontNeed) }
+    }
+
+    /// Faults in `len` bytes at `ptr` for writing, reporting failure as an
+    /// error rather than a signal.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
+    unsafe fn populate(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        self.stats.record_syscall(Syscall::Madvise);
+        unsafe { madvise(ptr.as_ptr().cast(), len, Advice::LinuxPopulateWrite) }
+    }
+
+    /// Prepares the pages of a purged extent for reuse as
+    /// [`MmapConfig::revive`] says.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
+    unsafe fn revive(&self, ptr: NonNull<u8>, len: usize) {
+        let advice = match self.config.revive {
+            Revive::Lazy => return,
+            Revive::WillNeed => Advice::WillNeed,
+            Revive::Populate => Advice::LinuxPopulateWrite,
+        };
+        self.stats.record_syscall(Syscall::Madvise);
+        // SAFETY: Neither advice changes the contents of the range.
+        let _ = unsafe { madvise(ptr.as_ptr().cast(), len, advice) };
+    }
+
+    /// Tries to keep the freed range of `len` bytes at `ptr` for reuse,
+    /// according to the retain policy. Returns `false` if the caller should
+    /// unmap it instead.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`, and the
+    /// range must have been mapped by `self` and no longer be in use.
+    unsafe fn retain(&self, ptr: NonNull<u8>, len: usize) -> bool {
+        let (max, dirty) = match self.config.retain {
+            Retain::None => return false,
+            Retain::Dirty { max } => (max, true),
+            Retain::Purged { max } => (max, false),
+        };
+        // Check for room before purging so that we don't waste a syscall in
+        // the common case, but don't hold the lock across the syscall.
+        if !self.cache.lock().has_room(len, max) {
+            return false;
+        }
+        if !dirty && unsafe { self.purge(ptr, len) }.is_err() {
+            return false;
+        }
+        if self
+            .cache
+            .lock()
+            .insert(Extent {
+                ptr,
+                len,
+                dirty,
+                freed: 0,
+            })
+            .is_err()
+        {
+            return false;
+        }
+        if let Some(watermark) = self.config.dirty_watermark {
+            self.purge_to(watermark, None);
+        }
+        true
+    }
+
+    /// Tries to hold the freed mapping of `len` bytes at `ptr` in
+    /// quarantine, unmapping the oldest quarantined mappings to make room.
+    /// Returns `false` if the caller should unmap it instead.
+    ///
+    /// # SAFETY
+    ///
+    /// As for [`Mmap::retain`], and the range must be a whole mapping.
+    unsafe fn quarantine(&self, ptr: NonNull<u8>, len: usize) -> bool {
+        let Some(max) = self.config.quarantine else {
+            return false;
+        };
+        if len > max {
+            return false;
+        }
+        // SAFETY: The range is unused, so it can be released and protected.
+        let protected = unsafe {
+            self.purge(ptr, len).is_ok() && self.protect(ptr, len, MprotectFlags::empty()).is_ok()
+        };
+        if !protected {
+            return false;
+        }
+        self.expire_quarantine();
+        let freed = self.config.quarantine_delay.map_or(0, |x| (x.clock)());
+        loop {
+            let oldest = {
+                let mut quarantine = self.quarantine.lock();
+                if quarantine.has_room(len, max) {
+                    let ext = Extent {
+                        ptr,
+                        len,
+                        dirty: false,
+                        freed,
+                    };
+                    return quarantine.insert(ext).is_ok();
+                }
+                quarantine.take_oldest()
+            };
+            let Some(ext) = oldest else { return false };
+            // SAFETY: Quarantined extents are whole mappings owned by `self`.
+            let _ = unsafe { self.unmap(ext.ptr, ext.len) };
+        }
+    }
+
+    /// Unmaps the quarantined ranges whose [`MmapConfig::quarantine_delay`]
+    /// has passed, and returns how many bytes were unmapped.
+    fn expire_quarantine(&self) -> usize {
+        let Some(delay) = self.config.quarantine_delay else {
+            return 0;
+        };
+        let Some(tick) = (delay.clock)().checked_sub(delay.ticks) else {
+            return 0;
+        };
+        let mut unmapped = 0;
+        loop {
+            let ext = self.quarantine.lock().take_freed_by(tick);
+            let Some(ext) = ext else { break };
+            // SAFETY: Quarantined extents are whole mappings owned by `self`.
+            if unsafe { self.unmap(ext.ptr, ext.len) }.is_ok() {
+                unmapped += ext.len;
+            }
+        }
+        unmapped
+    }
+
+    /// Replaces two clean retained extents that are adjacent in memory,
+    /// `lo` below `hi`, with a single fresh mapping of both, which gets the
+    /// advice a fresh allocation of its length would. Unmapping the merged
+    /// extent later then unmaps exactly one mapping, and `mremap` can move
+    /// or resize it as a whole.
+    ///
+    /// # SAFETY
+    ///
+    /// Both extents must be mapped by `self`, out of the cache, and unused.
+    unsafe fn merge(&self, lo: Extent, hi: Extent) -> Result<Extent, Errno> {
+        debug_assert_eq!(lo.ptr.addr().get() + lo.len, hi.ptr.addr().get());
+        let len = lo.len + hi.len;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::PRIVATE | MapFlags::FIXED;
+        self.stats.record_syscall(Syscall::Mmap);
+        // SAFETY: `MAP_FIXED` replaces both mappings, which belong to `self`
+        // and hold nothing. The pages were purged, so they were zero-filled
+        // on next touch already, and stay charged and counted as committed.
+        unsafe { mmap_anonymous(lo.ptr.as_ptr().cast(), len, rw, flags) }?;
+        self.stats.record_unmapping();
+        if let Some(observer) = self.observer {
+            observer.on_unmap(lo.ptr, lo.len);
+            observer.on_unmap(hi.ptr, hi.len);
+            observer.on_map(lo.ptr, len);
+        }
+        // SAFETY: The mapping is fresh and valid for `len` bytes.
+        unsafe { self.advise(lo.ptr, len) };
+        Ok(Extent {
+            ptr: lo.ptr,
+            len,
+            dirty: false,
+            freed: lo.freed.max(hi.freed),
+        })
+    }
+
+    /// Returns `ext` to the cache, or unmaps it if the cache is full.
+    ///
+    /// # SAFETY
+    ///
+    /// `ext` must be a whole mapping owned by `self`, out of the cache, and
+    /// unused.
+    unsafe fn recache(&self, ext: Extent) {
+        let res = self.cache.lock().insert(ext);
+        if let Err(ext) = res {
+            let _ = unsafe { self.unmap(ext.ptr, ext.len) };
+        }
+    }
+
+    /// Purges the oldest dirty retained extents until at most `target` dirty
+    /// bytes remain, or `budget` is spent.
+    fn purge_to(&self, target: usize, budget: Option<Budget>) -> GrindReport {
+        let mut report = GrindReport::default();
+        let mut extents = 0;
+        // Take each extent out of the cache so that the syscall happens
+        // without the lock held. Purged extents go back in clean, so this
+        // terminates.
+        loop {
+            let released = report.purged_bytes + report.unmapped_bytes;
+            if budget.is_some_and(|x| !x.allows(released, extents)) {
+                break;
+            }
+            let ext = {
+                let mut cache = self.cache.lock();
+                if cache.dirty() <= target {
+                    break;
+                }
+                cache.take_oldest_dirty()
+            };
+            let Some(mut ext) = ext else { break };
+            // SAFETY: Extents in the cache are mapped by `self` and unused.
+            let purged = unsafe { self.purge(ext.ptr, ext.len) }.is_ok();
+            report.duration_hint += 1;
+            extents += 1;
+            if purged {
+                report.purged_bytes += ext.len;
+            }
+            ext.dirty = !purged;
+            let res = self.cache.lock().insert(ext);
+            if let Err(ext) = res {
+                // SAFETY: As above.
+                let unmapped = unsafe { self.unmap(ext.ptr, ext.len) }.is_ok();
+                report.duration_hint += 1;
+                if unmapped {
+                    report.unmapped_bytes += ext.len;
+                }
+            }
+            // Give up rather than spin on an extent that cannot be purged.
+            if !purged {
+                break;
+            }
+        }
+        report
+    }
+
+    /// Purges every dirty retained extent, then unmaps the oldest retained
+    /// extents until at most `keep` bytes remain. Quarantined extents are
+    /// left alone.
+    pub(crate) fn trim_retained(&self, keep: usize) -> GrindReport {
+        let mut report = self.purge_to(0, None);
+        loop {
+            let ext = {
+                let mut cache = self.cache.lock();
+                if cache.bytes() <= keep {
+                    break;
+                }
+                cache.take_oldest()
+            };
+            let Some(ext) = ext else { break };
+            // SAFETY: Extents in the cache are mapped by `self` and unused.
+            let unmapped = unsafe { self.unmap(ext.ptr, ext.len) }.is_ok();
+            report.duration_hint += 1;
+            if unmapped {
+                report.unmapped_bytes += ext.len;
+            }
+        }
+        report
     }
 
     /// Cuts a cookie of shape `layout` from an allocation of size `alloc_size`
@@ -96,7 +1075,7 @@ impl Mmap {
         //   beginning at `alloc + align_ost`.
         let aligned = unsafe { alloc.add(align_ost) };
         if align_ost > 0 {
-            unsafe { self.unmap(alloc, align_ost) }?;
+            unsafe { self.unmap_part(alloc, align_ost) }?;
         }
         if trim_end > 0 {
             // SAFETY: As above, the checked arithmetic implies
@@ -106,24 +1085,149 @@ impl Mmap {
             // from `alloc`. Therefore `aligned` is valid for the entire range
             // of length `layout.size()` beginning at `alloc + align_ost`.
             let end = unsafe { aligned.add(layout.size()) };
-            unsafe { self.unmap(end, trim_end) }?;
+            unsafe { self.unmap_part(end, trim_end) }?;
         }
         Ok(aligned)
     }
 
-    // https://github.com/jemalloc/jemalloc/blob/22440a0207cd7d7c624c78723ca1eeb8a4353e79/src/pages.c#L312-L336
     fn alloc(&self, layout: Layout) -> Result<Tag, MmapErr> {
-        let layout = layout.align_to(self.pagesize)?.pad_to_align();
-        let ptr = map(layout.size())?;
-        if ptr.is_aligned_to(layout.align()) {
-            Ok(unsafe { Tag::new(ptr, layout) })
+        self.alloc_inner(layout, false)
+    }
+
+    /// Fresh mappings and purged extents already read as zero, so only dirty
+    /// retained extents need to be cleared.
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, MmapErr> {
+        self.alloc_inner(layout, true)
+    }
+
+    // https://github.com/jemalloc/jemalloc/blob/22440a0207cd7d7c624c78723ca1eeb8a4353e79/src/pages.c#L312-L336
+    fn alloc_inner(&self, layout: Layout, zero: bool) -> Result<Tag, MmapErr> {
+        let layout = self.pad(layout)?;
+        if self.config.retain != Retain::None {
+            let ext = self.cache.lock().take(layout.size(), layout.align());
+            if let Some(ext) = ext {
+                if zero && ext.dirty {
+                    // SAFETY: The extent is valid for `ext.len` bytes.
+                    unsafe { ext.ptr.write_bytes(0, ext.len) };
+                }
+                if !ext.dirty {
+                    // SAFETY: As above, and the extent is not in use.
+                    unsafe { self.revive(ext.ptr, ext.len) };
+                }
+                self.stats.record_alloc(layout.size());
+                // SAFETY: The cache only holds whole extents previously
+                // mapped by `self`, and `take` checked the length and
+                // alignment.
+                return Ok(unsafe { Tag::new(ext.ptr, layout) });
+            }
+        }
+        let tag = if layout.align() > self.pagesize
+            && (self.config.align == AlignStrategy::Reserve || layout.align() >= JUMBO_ALIGN)
+        {
+            self.alloc_reserved(layout)?
+        } else if self.config.align == AlignStrategy::Auto && layout.align() > self.pagesize {
+            self.alloc_auto(layout)?
         } else {
-            unsafe { self.unmap(ptr, layout.size()) }?;
-            self.alloc_slow(layout)
+            let ptr = self.mmap(layout.size())?;
+            if ptr.is_aligned_to(layout.align()) {
+                self.fresh(ptr, layout)
+            } else {
+                unsafe { self.unmap(ptr, layout.size()) }?;
+                self.alloc_slow(layout)?
+            }
+        };
+        // Retained extents were advised when they were first mapped.
+        // SAFETY: The mapping is valid for `layout.size()` bytes, and no page
+        // of it has been touched.
+        unsafe { self.advise(tag.ptr(), layout.size()) };
+        if self.config.probe {
+            // SAFETY: As above, and the allocation is not in use yet.
+            if let Err(err) = unsafe { self.populate(tag.ptr(), layout.size()) } {
+                // Unmap rather than retain, so that the pages are uncharged.
+                let _ = match self.take_overmapped(tag.ptr()) {
+                    Some(entry) => unsafe { self.unmap(entry.base, entry.len) },
+                    None => unsafe { self.unmap(tag.ptr(), layout.size()) },
+                };
+                self.stats.record_free(layout.size());
+                return Err(err.into());
+            }
+        }
+        Ok(tag)
+    }
+
+    /// Gives `len` bytes freshly mapped at `ptr` the huge page advice and
+    /// NUMA policy that their length calls for.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
+    unsafe fn advise(&self, ptr: NonNull<u8>, len: usize) {
+        if self.is_huge(len) {
+            unsafe { self.advise_huge(ptr, len) };
+        }
+        if self.config.interleave_threshold.is_some_and(|x| len >= x) {
+            // SAFETY: A policy does not change the contents of the range.
+            unsafe { self.interleave(ptr, len) };
+        }
+    }
+
+    /// Counts a fresh mapping of `layout` at `ptr` as allocated.
+    fn fresh(&self, ptr: NonNull<u8>, layout: Layout) -> Tag {
+        self.stats.record_alloc(layout.size());
+        self.thresholds.poll(self.stats.allocated());
+        // SAFETY: `ptr` is a fresh mapping of `layout.size()` bytes, aligned
+        // to `layout.align()`.
+        unsafe { Tag::new(ptr, layout) }
+    }
+
+    /// Allocates `layout`, aligned to more than a page, as
+    /// [`AlignStrategy::Auto`] has found to work for its alignment.
+    fn alloc_auto(&self, layout: Layout) -> Result<Tag, MmapErr> {
+        let (size, align) = (layout.size(), layout.align());
+        let class = &self.classes[align.trailing_zeros() as usize];
+        let tries = class.tries.load(Relaxed);
+        let sampling = tries < AUTO_SAMPLES;
+        if sampling {
+            class.tries.fetch_add(1, Relaxed);
+        }
+        // While sampling, try everything, so that each way gets counted.
+        let direct = sampling || class.direct.load(Relaxed) * 2 >= tries;
+        let hinted = sampling || class.hinted.load(Relaxed) * 2 >= tries;
+        let mut hint = class.hint.load(Relaxed);
+        if direct {
+            let ptr = self.mmap(size)?;
+            if ptr.is_aligned_to(align) {
+                if sampling {
+                    class.direct.fetch_add(1, Relaxed);
+                }
+                return Ok(self.fresh(ptr, layout));
+            }
+            // The aligned address just above the miss is likely free.
+            hint = ptr.addr().get().next_multiple_of(align);
+            unsafe { self.unmap(ptr, size) }?;
         }
+        if hinted && hint != 0 {
+            let ptr = self.mmap_at(size, Some(hint))?;
+            if ptr.is_aligned_to(align) {
+                if sampling {
+                    class.hinted.fetch_add(1, Relaxed);
+                }
+                // The kernel places mappings top-down, so hint the next one
+                // just below this one.
+                let next = ptr.addr().get().saturating_sub(size) & !(align - 1);
+                class.hint.store(next, Relaxed);
+                return Ok(self.fresh(ptr, layout));
+            }
+            unsafe { self.unmap(ptr, size) }?;
+        }
+        self.alloc_slow(layout)
     }
 
     fn alloc_slow(&self, layout: Layout) -> Result<Tag, MmapErr> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("alloc_slow", size = layout.size(), align = layout.align())
+                .entered();
         // Any pointer returned by `mmap` is guaranteed to be page-aligned, so
         // we should be at most `align - pagesize` bytes away from an address
         // aligned to `align`. Reserving `align - pagesize` extra bytes ensures
@@ -131,14 +1235,942 @@ impl Mmap {
         // inside the allocation of `alloc_size` bytes beginning at `alloc`.
         let pad = layout.align().checked_sub(self.pagesize).unwrap();
         let alloc_size = layout.size().checked_add(pad).ok_or(MmapErr::Overflow)?;
-        let alloc = map(alloc_size)?;
-        // SAFETY: `alloc` points to the beginning of the freshly mmap'd region
-        // of `alloc_size` bytes.
-        let ptr = unsafe { self.trim(alloc, alloc_size, layout) }?;
+        let alloc = self.mmap(alloc_size)?;
+        let ptr = match self.config.align {
+            AlignStrategy::OverMap => {
+                // SAFETY: As in `trim`, the aligned block fits in the mapping.
+                let ptr = unsafe { alloc.add(alloc.align_offset(layout.align())) };
+                let entry = OverMapped {
+                    ptr,
+                    base: alloc,
+                    len: alloc_size,
+                };
+                self.overmapped.lock().insert(entry).then_some(ptr)
+            }
+            AlignStrategy::Trim | AlignStrategy::Auto | AlignStrategy::Reserve => None,
+        };
+        let ptr = match ptr {
+            Some(ptr) => ptr,
+            // SAFETY: `alloc` points to the beginning of the freshly mmap'd
+            // region of `alloc_size` bytes.
+            None => unsafe { self.trim(alloc, alloc_size, layout) }?,
+        };
+        self.stats.record_alloc(layout.size());
+        self.thresholds.poll(self.stats.allocated());
         Ok(unsafe { Tag::new(ptr, layout) })
     }
 
+    /// Allocates `layout`, aligned to more than a page, as
+    /// [`AlignStrategy::Reserve`] does.
+    fn alloc_reserved(&self, layout: Layout) -> Result<Tag, MmapErr> {
+        let size = layout.size();
+        let ptr = self.reserve_aligned(layout)?;
+        let rw = MprotectFlags::READ | MprotectFlags::WRITE;
+        // SAFETY: The window is what is left of the reservation, and is not in
+        // use yet.
+        let committed = self.charge(size).and_then(|()| {
+            unsafe { self.protect(ptr, size, rw) }.inspect_err(|_| self.uncharge(size))
+        });
+        if let Err(err) = committed {
+            let _ = unsafe { self.unreserve(ptr, size) };
+            self.stats.record_unmapping();
+            return Err(err.into());
+        }
+        self.stats.record_commit(size);
+        Ok(self.fresh(ptr, layout))
+    }
+
+    /// Reserves a window of address space of `layout`, aligned to more than
+    /// a page, with nothing around it: the padding needed to align it is cut
+    /// off while it is still only reserved. The window counts as a mapping,
+    /// but is not committed.
+    fn reserve_aligned(&self, layout: Layout) -> Result<NonNull<u8>, MmapErr> {
+        if layout.align() > isize::MAX as usize / 2 {
+            return Err(MmapErr::Overflow);
+        }
+        let pad = layout.align() - self.pagesize;
+        let alloc_size = layout
+            .size()
+            .checked_add(pad)
+            .filter(|&x| x <= isize::MAX as usize)
+            .ok_or(MmapErr::Overflow)?;
+        let size = layout.size();
+        let alloc = self.reserve(alloc_size)?;
+        // Cut the slack off both ends while it is still just address space,
+        // so that only the aligned window is ever committed.
+        let ost = alloc.align_offset(layout.align());
+        // SAFETY: `ost + size <= alloc_size`, since the reservation was padded
+        // by `align - pagesize` and `alloc` is page-aligned. The slack is not
+        // in use.
+        let ptr = unsafe { alloc.add(ost) };
+        unsafe { self.unreserve(alloc, ost) }?;
+        unsafe { self.unreserve(ptr.add(size), alloc_size - ost - size) }?;
+        Ok(ptr)
+    }
+
+    /// Whether an allocation of `size` bytes is backed by huge pages under
+    /// [`MmapConfig::huge_threshold`].
+    #[inline]
+    fn is_huge(&self, size: usize) -> bool {
+        self.config
+            .huge_threshold
+            .is_some_and(|x| size >= x.max(HUGE_PAGE))
+    }
+
+    /// The layout of the mapping for an allocation of `layout`: padded to
+    /// whole pages, or to whole huge pages for large allocations. The size
+    /// is not padded to the alignment, so that a small allocation with a
+    /// jumbo alignment does not map and commit the whole alignment.
+    fn pad(&self, layout: Layout) -> Result<Layout, MmapErr> {
+        let mut unit = self.pagesize;
+        let mut size = layout
+            .size()
+            .checked_next_multiple_of(unit)
+            .ok_or(MmapErr::Overflow)?;
+        if self.is_huge(size) {
+            unit = HUGE_PAGE;
+            size = size
+                .checked_next_multiple_of(unit)
+                .ok_or(MmapErr::Overflow)?;
+        }
+        Ok(Layout::from_size_align(size, layout.align().max(unit))?)
+    }
+
+    /// Asks the kernel to back `len` bytes at `ptr` with transparent huge
+    /// pages. Kernels without THP refuse, which is harmless.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
+    unsafe fn advise_huge(&self, ptr: NonNull<u8>, len: usize) {
+        self.stats.record_syscall(Syscall::Madvise);
+        // SAFETY: The advice does not change the contents of the range.
+        let _ = unsafe { madvise(ptr.as_ptr().cast(), len, Advice::LinuxHugepage) };
+    }
+
+    /// Interleaves `len` bytes at `ptr` across the nodes read when the
+    /// backend was created. Failures are harmless, and ignored.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`.
+    unsafe fn interleave(&self, ptr: NonNull<u8>, len: usize) {
+        let Some(mask) = &self.nodes else {
+            return;
+        };
+        self.stats.record_syscall(Syscall::Mbind);
+        // SAFETY: A policy does not change the contents of the range.
+        let _ = unsafe { mbind_raw(ptr.as_ptr(), len, MPOL_INTERLEAVE, mask) };
+    }
+
+    /// The length of the mapping behind `tag`. Allocations are padded as by
+    /// [`Mmap::pad`], but callers such as `ToAllocator` hand back the layout
+    /// they asked for rather than the padded one. Padding is idempotent, so
+    /// this gives the same length either way.
+    #[inline]
+    fn mapped_len(&self, tag: &Tag) -> usize {
+        let len = tag.layout().size().next_multiple_of(self.pagesize);
+        if self.is_huge(len) {
+            len.next_multiple_of(HUGE_PAGE)
+        } else {
+            len
+        }
+    }
+
+    /// Takes `ptr` out of the over-mapped table if it is there. Only
+    /// allocations aligned to more than a page can be over-mapped.
+    fn take_overmapped(&self, ptr: NonNull<u8>) -> Option<OverMapped> {
+        if !self.may_overmap(ptr) {
+            return None;
+        }
+        self.overmapped.lock().take(ptr)
+    }
+
+    /// Whether an allocation at `ptr` can be in the over-mapped table.
+    fn may_overmap(&self, ptr: NonNull<u8>) -> bool {
+        self.config.align == AlignStrategy::OverMap && ptr.is_aligned_to(self.pagesize * 2)
+    }
+
+    /// Unmaps the padding around an over-mapped allocation, so that it can
+    /// be resized like any other.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed.
+    unsafe fn settle(&self, tag: &Tag) -> Result<(), MmapErr> {
+        let ptr = tag.ptr();
+        if !self.may_overmap(ptr) {
+            return Ok(());
+        }
+        let Some(entry) = self.overmapped.lock().find(ptr).copied() else {
+            return Ok(());
+        };
+        let head = ptr.addr().get() - entry.base.addr().get();
+        let tail = entry.len - head - self.mapped_len(tag);
+        // The entry stays in the table until the padding is gone, so that if
+        // unmapping fails, freeing the allocation still unmaps all of it.
+        // SAFETY: The padding is part of the mapping and was never handed
+        // out.
+        if head > 0 {
+            unsafe { self.unmap_part(entry.base, head) }?;
+            if let Some(entry) = self.overmapped.lock().find(ptr) {
+                entry.base = ptr;
+                entry.len -= head;
+            }
+        }
+        if tail > 0 {
+            let end = unsafe { ptr.add(self.mapped_len(tag)) };
+            unsafe { self.unmap_part(end, tail) }?;
+        }
+        self.overmapped.lock().take(ptr);
+        Ok(())
+    }
+
     unsafe fn free(&self, tag: Tag) -> Result<(), MmapErr> {
-        unsafe { self.unmap(tag.ptr(), tag.layout().size()) }.map_err(Into::into)
+        let (ptr, len) = (tag.ptr(), self.mapped_len(&tag));
+        if let Some(entry) = self.take_overmapped(ptr) {
+            // Not retained: the cache only holds extents that start where
+            // their mapping does.
+            if !unsafe { self.quarantine(entry.base, entry.len) } {
+                unsafe { self.unmap(entry.base, entry.len) }?;
+            }
+        } else if !unsafe { self.quarantine(ptr, len) || self.retain(ptr, len) } {
+            unsafe { self.unmap(ptr, len) }?;
+        }
+        self.stats.record_free(len);
+        self.thresholds.poll(self.stats.allocated());
+        Ok(())
+    }
+
+    /// Resizes the `len` bytes mapped at `ptr` to `new_len` with `mremap`.
+    /// With `to`, the pages are moved there, replacing whatever was mapped.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` must be aligned to `self.pagesize` and valid for `len`, and if
+    /// the mapping may move, nothing may refer to it any longer. `to` must be
+    /// a page-aligned mapping of `new_len` bytes owned by `self`.
+    unsafe fn remap(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        new_len: usize,
+        flags: MremapFlags,
+        to: Option<NonNull<u8>>,
+    ) -> Result<NonNull<u8>, Errno> {
+        // Moving onto `to` replaces a mapping that is already charged, so
+        // only resizing in place charges or uncharges the difference.
+        let (more, less) = match to {
+            None => (new_len.saturating_sub(len), len.saturating_sub(new_len)),
+            Some(_) => (0, len),
+        };
+        self.charge(more)?;
+        self.stats.record_syscall(Syscall::Mremap);
+        let old = ptr.as_ptr().cast();
+        let new = match to {
+            None => unsafe { mremap(old, len, new_len, flags) },
+            Some(to) => unsafe { mremap_fixed(old, len, new_len, flags, to.as_ptr().cast()) },
+        }
+        .inspect_err(|_| self.uncharge(more))?;
+        let new = NonNull::new(new.cast()).unwrap();
+        self.uncharge(less);
+        self.stats.record_unmap(len);
+        self.stats.record_decommit(len);
+        self.stats.record_map(new_len);
+        self.stats.record_commit(new_len);
+        if let Some(observer) = self.observer {
+            observer.on_remap(ptr, len, new, new_len);
+        }
+        Ok(new)
+    }
+
+    /// Grows an allocation, in place if the kernel can extend the mapping,
+    /// and otherwise by moving its pages with `mremap`. When `new` asks for
+    /// more than page alignment, the pages are moved into an aligned window
+    /// reserved like in [`Mmap::alloc_reserved`], rather than wherever the
+    /// kernel would put them, so the padding is never committed.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed.
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, MmapErr> {
+        unsafe { self.settle(tag) }?;
+        let new = self.pad(new)?;
+        let (ptr, len) = (tag.ptr(), self.mapped_len(tag));
+        let empty = MremapFlags::empty();
+        let in_place = if ptr.is_aligned_to(new.align()) {
+            unsafe { self.remap(ptr, len, new.size(), empty, None) }.ok()
+        } else {
+            None
+        };
+        let moved = if let Some(ptr) = in_place {
+            ptr
+        } else if new.align() <= self.pagesize {
+            unsafe { self.remap(ptr, len, new.size(), MremapFlags::MAYMOVE, None) }?
+        } else {
+            let to = self.reserve_aligned(new)?;
+            // The moved pages commit the window, so charge it as a fresh
+            // mapping would be.
+            if let Err(err) = self.charge(new.size()) {
+                let _ = unsafe { self.unreserve(to, new.size()) };
+                self.stats.record_unmapping();
+                return Err(err.into());
+            }
+            self.stats.record_commit(new.size());
+            let flags = MremapFlags::MAYMOVE;
+            match unsafe { self.remap(ptr, len, new.size(), flags, Some(to)) } {
+                // The destination mapping was replaced by the moved pages.
+                Ok(moved) => {
+                    self.stats.record_unmap(new.size());
+                    self.stats.record_decommit(new.size());
+                    self.stats.record_unmapping();
+                    moved
+                }
+                Err(err) => {
+                    let _ = unsafe { self.unmap(to, new.size()) };
+                    return Err(err.into());
+                }
+            }
+        };
+        debug_assert!(moved.is_aligned_to(new.align()));
+        if self.is_huge(new.size()) {
+            // SAFETY: The mapping is valid for `new.size()` bytes.
+            unsafe { self.advise_huge(moved, new.size()) };
+        }
+        self.stats.record_free(len);
+        self.stats.record_alloc(new.size());
+        self.thresholds.poll(self.stats.allocated());
+        Ok(unsafe { Tag::new(moved, new) })
+    }
+
+    /// Shrinks an allocation in place by releasing its trailing pages. If
+    /// `new` asks for a stricter alignment than the allocation has, it is
+    /// copied instead.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc` and not yet freed.
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, MmapErr> {
+        unsafe { self.settle(tag) }?;
+        let new = self.pad(new)?;
+        let (ptr, len) = (tag.ptr(), self.mapped_len(tag));
+        if !ptr.is_aligned_to(new.align()) {
+            let moved = Mmap::alloc(self, new)?;
+            // SAFETY: Both allocations are valid for `new.size()` bytes and
+            // distinct.
+            unsafe { ptr::copy_nonoverlapping(ptr.as_ptr(), moved.ptr().as_ptr(), new.size()) };
+            unsafe { Mmap::free(self, Tag::new(ptr, tag.layout())) }?;
+            return Ok(moved);
+        }
+        if new.size() < len {
+            unsafe { self.remap(ptr, len, new.size(), MremapFlags::empty(), None) }?;
+            self.stats.record_free(len - new.size());
+            self.thresholds.poll(self.stats.allocated());
+        }
+        Ok(unsafe { Tag::new(ptr, new) })
+    }
+}
+
+impl Mmap {
+    /// Maps and pre-faults `len` bytes (rounded up to whole pages) as a
+    /// [`Reserve`] that can be allocated from inside signal handlers.
+    pub(crate) fn signal_safe(&self, len: usize) -> Result<Reserve, MmapErr> {
+        let tag = Mmap::alloc(self, Layout::from_size_align(len, self.pagesize)?)?;
+        let (ptr, len) = (tag.ptr(), tag.layout().size());
+        // Touch every page now, so that allocating from the reserve never
+        // has to fault memory in.
+        for ost in (0..len).step_by(self.pagesize) {
+            // SAFETY: `ost < len` and `tag` is valid for `len` bytes.
+            unsafe { ptr.add(ost).write_volatile(0) };
+        }
+        // SAFETY: The mapping is valid for `len` bytes until it is returned
+        // with `free_signal_safe`, and every page has been touched.
+        Ok(unsafe { Reserve::new(ptr, len) })
+    }
+
+    /// Unmaps a reserve created by [`Mmap::signal_safe`].
+    ///
+    /// # SAFETY
+    ///
+    /// `reserve` must have been returned by `self.signal_safe`, and no
+    /// allocation made from it may be used after this call.
+    pub(crate) unsafe fn free_signal_safe(&self, reserve: Reserve) -> Result<(), MmapErr> {
+        let layout = Layout::from_size_align(reserve.capacity(), self.pagesize)?;
+        unsafe { Mmap::free(self, Tag::new(reserve.base(), layout)) }
+    }
+
+    /// Opts the pages of an allocation in or out of kernel same-page merging
+    /// with `MADV_MERGEABLE`, so that many near-identical allocations, such
+    /// as copies of one config blob across arenas, can share physical pages.
+    /// Merging only happens while KSM is enabled in
+    /// `/sys/kernel/mm/ksm/run`, and merged pages are copied again on write.
+    ///
+    /// The advice stays with the pages, so an extent retained after `free`
+    /// may still be scanned when it is reused.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` does not start on a page boundary, which every
+    /// allocation from `self` does.
+    pub(crate) fn set_mergeable(&self, tag: &Tag, mergeable: bool) -> Result<(), MmapErr> {
+        assert!(tag.ptr().is_aligned_to(self.pagesize));
+        let len = self.mapped_len(tag);
+        let advice = if mergeable {
+            Advice::LinuxMergeable
+        } else {
+            Advice::LinuxUnmergeable
+        };
+        self.stats.record_syscall(Syscall::Madvise);
+        // SAFETY: The advice does not change the contents of the range.
+        unsafe { madvise(tag.ptr().as_ptr().cast(), len, advice) }?;
+        Ok(())
+    }
+}
+
+impl Mmap {
+    /// Upgrades the pages of a long-lived allocation that was faulted in with
+    /// small pages to transparent huge pages, e.g. after warmup. See
+    /// [`collapse`].
+    pub(crate) fn collapse_huge_pages(&self, tag: &Tag) -> Result<(), MmapErr> {
+        self.stats.record_syscall(Syscall::Madvise);
+        // SAFETY: Allocations from `self` are private anonymous mappings of
+        // `mapped_len` bytes.
+        unsafe { collapse(tag.ptr(), self.mapped_len(tag)) }?;
+        Ok(())
+    }
+}
+
+impl Mmap {
+    /// Calls `f` on every retained extent, as a dirty or retained region,
+    /// and every quarantined one.
+    pub(crate) fn regions(&self, f: &mut dyn FnMut(Region)) {
+        for ext in self.cache.lock().iter() {
+            f(Region {
+                addr: ext.ptr.addr().get(),
+                len: ext.len,
+                state: if ext.dirty {
+                    RegionState::Dirty
+                } else {
+                    RegionState::Retained
+                },
+            });
+        }
+        for ext in self.quarantine.lock().iter() {
+            f(Region {
+                addr: ext.ptr.addr().get(),
+                len: ext.len,
+                state: RegionState::Quarantined,
+            });
+        }
+    }
+
+    /// Explains a fault inside a quarantined allocation, as a use after
+    /// free. Returns `None` if the quarantine is locked.
+    pub(crate) fn fault(&self, addr: usize) -> Option<Fault> {
+        let quarantine = self.quarantine.try_lock()?;
+        let ext = quarantine
+            .iter()
+            .find(|x| (x.ptr.addr().get()..x.ptr.addr().get() + x.len).contains(&addr))?;
+        Some(Fault {
+            kind: FaultKind::Quarantined,
+            base: ext.ptr.addr().get(),
+            len: ext.len,
+            addr,
+        })
+    }
+}
+
+impl Grind for Mmap {
+    /// Purges every dirty retained extent, and unmaps expired quarantined
+    /// ranges.
+    fn grind(&self) -> GrindReport {
+        let mut report = self.purge_to(0, None);
+        report.unmapped_bytes += self.expire_quarantine();
+        report
+    }
+
+    /// Purges the oldest dirty retained extents until `budget` is spent.
+    fn grind_budgeted(&self, budget: Budget) -> GrindReport {
+        self.purge_to(0, Some(budget))
+    }
+
+    /// Grinds, then merges retained extents that are adjacent in memory,
+    /// so that each run of them takes one slot and one mapping. A merged
+    /// extent is only reused for a request of its whole length.
+    fn compact(&self) -> GrindReport {
+        let report = self.grind();
+        loop {
+            let pair = self.cache.lock().take_adjacent();
+            let Some((lo, hi)) = pair else { break };
+            // SAFETY: Extents in the cache are mapped by `self` and unused.
+            match unsafe { self.merge(lo, hi) } {
+                Ok(ext) => unsafe { self.recache(ext) },
+                Err(_) => {
+                    unsafe { self.recache(lo) };
+                    unsafe { self.recache(hi) };
+                    break;
+                }
+            }
+        }
+        report
+    }
+}
+
+impl FreeAll for Mmap {
+    /// Unmaps every retained and quarantined extent. The backend does not
+    /// keep track of live allocations, so those must be freed by the layers
+    /// above.
+    unsafe fn free_all(&self) {
+        loop {
+            let ext = self.quarantine.lock().take_oldest();
+            let Some(ext) = ext else { break };
+            // SAFETY: Quarantined extents are mapped by `self` and unused.
+            let _ = unsafe { self.unmap(ext.ptr, ext.len) };
+        }
+        loop {
+            let ext = self.cache.lock().take_oldest();
+            let Some(ext) = ext else { break };
+            // SAFETY: Extents in the cache are mapped by `self` and unused.
+            let _ = unsafe { self.unmap(ext.ptr, ext.len) };
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: `free_all` only releases memory owned by the backend.
+        unsafe { self.free_all() }
+    }
+}
+
+impl Alloc for Mmap {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        Mmap::alloc(self, layout).map_err(|_| AllocError)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let res = unsafe { Mmap::free(self, tag) };
+        debug_assert!(res.is_ok());
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        Mmap::alloc_zeroed(self, layout).map_err(|_| AllocError)
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { Mmap::grow(self, tag, new) }.map_err(|_| AllocError)
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { Mmap::shrink(self, tag, new) }.map_err(|_| AllocError)
+    }
+}
+
+impl Check for Mmap {
+    /// Checks the retained and quarantined extents, which must also be whole
+    /// pages. The backend does not keep track of live allocations.
+    fn check(&self, report: &mut Report) {
+        for cache in [&self.cache, &self.quarantine] {
+            let cache = cache.lock();
+            cache.check(report);
+            for ext in cache.iter() {
+                if !ext.ptr.is_aligned_to(self.pagesize) || !ext.len.is_multiple_of(self.pagesize) {
+                    let (addr, align) = (ext.ptr.addr().get(), self.pagesize);
+                    report.violation(Violation::Misaligned { addr, align });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    fn jumbo(align: usize) {
+        let mmap = Mmap::new();
+        let layout = Layout::from_size_align(4096, align).unwrap();
+        let tag = mmap.alloc(layout).unwrap();
+        assert!(tag.ptr().is_aligned_to(align));
+        // SAFETY: The allocation is at least a page long.
+        unsafe { tag.ptr().write_bytes(0xa5, 4096) };
+        // Only the window counts, not the padding it was carved from.
+        assert_eq!(mmap.stats().committed(), tag.layout().size());
+        unsafe { Mmap::free(&mmap, tag) }.unwrap();
+        assert_eq!(mmap.stats().mapped(), 0);
+    }
+
+    #[test]
+    fn jumbo_256m() {
+        jumbo(256 << 20);
+    }
+
+    #[test]
+    fn jumbo_1g() {
+        jumbo(1 << 30);
+    }
+
+    #[test]
+    fn jumbo_overflow() {
+        let mmap = Mmap::new();
+        let layout = Layout::from_size_align(4096, (isize::MAX as usize / 2) + 1).unwrap();
+        assert!(matches!(mmap.alloc(layout), Err(MmapErr::Overflow)));
+    }
+
+    #[test]
+    fn jumbo_low32() {
+        let mmap = Mmap::with_config(MmapConfig {
+            low32: true,
+            ..Default::default()
+        });
+        let tag = mmap
+            .alloc(Layout::from_size_align(4096, 256 << 20).unwrap())
+            .unwrap();
+        assert!(tag.ptr().is_aligned_to(256 << 20));
+        assert!(tag.ptr().addr().get() + tag.layout().size() <= LOW32_END);
+        unsafe { Mmap::free(&mmap, tag) }.unwrap();
+        // The padding alone would not fit below 4 GiB.
+        let layout = Layout::from_size_align(4096, 4 << 30).unwrap();
+        assert!(matches!(mmap.alloc(layout), Err(MmapErr::Os(Errno::NOMEM))));
+    }
+
+    #[test]
+    fn jumbo_base() {
+        let base = 0x2000_0000_0000;
+        let mmap = Mmap::with_config(MmapConfig {
+            base: Some(base),
+            ..Default::default()
+        });
+        let tag = mmap
+            .alloc(Layout::from_size_align(4096, 256 << 20).unwrap())
+            .unwrap();
+        assert!(tag.ptr().is_aligned_to(256 << 20));
+        assert!((base..base + (512 << 20)).contains(&tag.ptr().addr().get()));
+        unsafe { Mmap::free(&mmap, tag) }.unwrap();
+    }
+
+    #[test]
+    fn compact_merges_mappings() {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            retain: Retain::Purged { max: usize::MAX },
+            max_mappings: Some(2),
+            base: Some(0x2100_0000_0000),
+            ..Default::default()
+        });
+        let page = Layout::from_size_align(pagesize, pagesize).unwrap();
+        let a = mmap.alloc(page).unwrap();
+        let b = mmap.alloc(page).unwrap();
+        let ptr = a.ptr();
+        assert_eq!(b.ptr().addr().get(), ptr.addr().get() + pagesize);
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        assert_eq!(mmap.stats().mappings(), 2);
+        mmap.compact();
+        assert_eq!(mmap.stats().mappings(), 1);
+        // The merged extent is one mapping, which `mremap` can resize.
+        let two = Layout::from_size_align(2 * pagesize, pagesize).unwrap();
+        let c = mmap.alloc(two).unwrap();
+        assert_eq!(c.ptr(), ptr);
+        let three = Layout::from_size_align(3 * pagesize, pagesize).unwrap();
+        let c = unsafe { Mmap::grow(&mmap, &c, three) }.unwrap();
+        let c = unsafe { Mmap::shrink(&mmap, &c, page) }.unwrap();
+        let d = mmap.alloc(page).unwrap();
+        unsafe { Mmap::free(&mmap, c) }.unwrap();
+        unsafe { Mmap::free(&mmap, d) }.unwrap();
+        assert_eq!(mmap.stats().mappings(), 2);
+        unsafe { mmap.free_all() };
+        assert_eq!(mmap.stats().mappings(), 0);
+        // No mapping is left counted, so the limit admits two again.
+        let e = mmap.alloc(page).unwrap();
+        let f = mmap.alloc(page).unwrap();
+        unsafe { Mmap::free(&mmap, e) }.unwrap();
+        unsafe { Mmap::free(&mmap, f) }.unwrap();
+    }
+
+    /// Frees a two-page allocation filled with `0xa5` under `retain`, and
+    /// returns the backend and what reusing the range reads, if it was kept.
+    fn retain(retain: Retain) -> (Mmap, Option<u8>) {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            retain,
+            ..Default::default()
+        });
+        let layout = Layout::from_size_align(2 * pagesize, pagesize).unwrap();
+        let a = mmap.alloc(layout).unwrap();
+        let ptr = a.ptr();
+        // SAFETY: The allocation is two pages long.
+        unsafe { ptr.write_bytes(0xa5, layout.size()) };
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        if mmap.stats().mapped() == 0 {
+            return (mmap, None);
+        }
+        let b = mmap.alloc(layout).unwrap();
+        assert_eq!(b.ptr(), ptr);
+        let byte = unsafe { ptr.read() };
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        (mmap, Some(byte))
+    }
+
+    #[test]
+    fn retain_policies() {
+        let pagesize = rustix::param::page_size();
+        let (_, kept) = retain(Retain::None);
+        assert_eq!(kept, None);
+        // Dirty extents keep their pages, and their contents.
+        let max = 4 * pagesize;
+        let (mmap, kept) = retain(Retain::Dirty { max });
+        assert_eq!(kept, Some(0xa5));
+        assert_eq!(mmap.stats().syscalls(Syscall::Madvise), 0);
+        // Anything that would take the cache over `max` is unmapped.
+        let layout = Layout::from_size_align(3 * pagesize, pagesize).unwrap();
+        let c = mmap.alloc(layout).unwrap();
+        unsafe { Mmap::free(&mmap, c) }.unwrap();
+        assert_eq!(mmap.stats().mapped(), 2 * pagesize);
+        unsafe { mmap.free_all() };
+        // Purged extents keep the address space, but not the pages.
+        let (mmap, kept) = retain(Retain::Purged { max });
+        assert_eq!(kept, Some(0));
+        assert_eq!(mmap.stats().syscalls(Syscall::Madvise), 2);
+        assert_eq!(mmap.cache.lock().dirty(), 0);
+        unsafe { mmap.free_all() };
+    }
+
+    #[test]
+    fn dirty_watermark() {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            retain: Retain::Dirty { max: usize::MAX },
+            dirty_watermark: Some(2 * pagesize),
+            ..Default::default()
+        });
+        let alloc = |pages| {
+            let layout = Layout::from_size_align(pages * pagesize, pagesize).unwrap();
+            let tag = mmap.alloc(layout).unwrap();
+            // SAFETY: The allocation is `pages` pages long.
+            unsafe { tag.ptr().write_bytes(0xa5, layout.size()) };
+            tag
+        };
+        let (a, b, c) = (alloc(2), alloc(2), alloc(1));
+        let (pa, pb) = (a.ptr(), b.ptr());
+        let dirty = |ptr| {
+            mmap.cache
+                .lock()
+                .iter()
+                .find(|x| x.ptr == ptr)
+                .unwrap()
+                .dirty
+        };
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        assert_eq!(mmap.stats().syscalls(Syscall::Madvise), 0);
+        // Over the watermark: the oldest dirty extent is purged.
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        assert_eq!(mmap.stats().syscalls(Syscall::Madvise), 1);
+        assert!(!dirty(pa) && dirty(pb));
+        unsafe { Mmap::free(&mmap, c) }.unwrap();
+        assert_eq!(mmap.stats().syscalls(Syscall::Madvise), 2);
+        assert!(!dirty(pb));
+        assert_eq!(mmap.cache.lock().dirty(), pagesize);
+        // Everything is still retained.
+        assert_eq!(mmap.cache.lock().bytes(), 5 * pagesize);
+        unsafe { mmap.free_all() };
+    }
+
+    /// The permissions of the mapping holding `ptr`, such as `rw-p`, as
+    /// `/proc/self/maps` lists them.
+    fn perms(ptr: NonNull<u8>) -> Option<[u8; 4]> {
+        use rustix::fs::{Mode, OFlags, open};
+        let fd = open("/proc/self/maps", OFlags::RDONLY, Mode::empty()).unwrap();
+        let mut maps = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = rustix::io::read(&fd, &mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            maps.extend_from_slice(&buf[..n]);
+        }
+        let addr = ptr.addr().get();
+        let maps = core::str::from_utf8(&maps).unwrap();
+        maps.lines().find_map(|line| {
+            let mut fields = line.split(' ');
+            let (start, end) = fields.next()?.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            let perms = fields.next()?.as_bytes().try_into().ok()?;
+            (start..end).contains(&addr).then_some(perms)
+        })
+    }
+
+    #[test]
+    fn quarantine_protects() {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            quarantine: Some(4 * pagesize),
+            ..Default::default()
+        });
+        let layout = Layout::from_size_align(2 * pagesize, pagesize).unwrap();
+        let alloc = || {
+            let tag = mmap.alloc(layout).unwrap();
+            // SAFETY: The allocation is two pages long.
+            unsafe { tag.ptr().write_bytes(0xa5, layout.size()) };
+            tag
+        };
+        let a = alloc();
+        let pa = a.ptr();
+        assert_eq!(perms(pa), Some(*b"rw-p"));
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        // The range stays mapped, but any access to it faults.
+        assert_eq!(perms(pa), Some(*b"---p"));
+        assert_eq!(mmap.stats().mapped(), layout.size());
+        assert_eq!(mmap.stats().syscalls(Syscall::Mprotect), 1);
+        // Quarantined ranges are not reused.
+        let b = alloc();
+        assert_ne!(b.ptr(), pa);
+        let c = alloc();
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        assert_eq!(mmap.stats().mapped(), 3 * layout.size());
+        // A full quarantine unmaps its oldest range to make room.
+        unsafe { Mmap::free(&mmap, c) }.unwrap();
+        assert_eq!(mmap.stats().mapped(), 2 * layout.size());
+        assert!(mmap.quarantine.lock().iter().all(|x| x.ptr != pa));
+        unsafe { mmap.free_all() };
+        assert_eq!(mmap.stats().mapped(), 0);
+    }
+
+    #[test]
+    fn quarantine_delay() {
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            quarantine: Some(usize::MAX),
+            quarantine_delay: Some(Delay {
+                clock: || NOW.load(Relaxed),
+                ticks: 10,
+            }),
+            ..Default::default()
+        });
+        let layout = Layout::from_size_align(pagesize, pagesize).unwrap();
+        let a = mmap.alloc(layout).unwrap();
+        let b = mmap.alloc(layout).unwrap();
+        let c = mmap.alloc(layout).unwrap();
+        let held = |ptr| mmap.quarantine.lock().iter().any(|x| x.ptr == ptr);
+        let (pa, pb) = (a.ptr(), b.ptr());
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        NOW.store(5, Relaxed);
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        assert!(held(pa) && held(pb));
+        // Nothing has been held for the delay yet.
+        NOW.store(9, Relaxed);
+        assert_eq!(mmap.grind().unmapped_bytes, 0);
+        NOW.store(10, Relaxed);
+        assert_eq!(mmap.grind().unmapped_bytes, pagesize);
+        assert!(!held(pa) && held(pb));
+        // Later frees unmap expired ranges too.
+        NOW.store(15, Relaxed);
+        unsafe { Mmap::free(&mmap, c) }.unwrap();
+        assert!(!held(pb));
+        assert_eq!(mmap.stats().mapped(), pagesize);
+        unsafe { mmap.free_all() };
+    }
+
+    #[test]
+    fn grind_reports_purge() {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            retain: Retain::Dirty { max: usize::MAX },
+            ..Default::default()
+        });
+        let layout = Layout::from_size_align(4 * pagesize, pagesize).unwrap();
+        let a = mmap.alloc(layout).unwrap();
+        let ptr = a.ptr();
+        // SAFETY: The allocation is four pages long.
+        unsafe { ptr.write_bytes(0xa5, layout.size()) };
+        unsafe { Mmap::free(&mmap, a) }.unwrap();
+        let report = mmap.grind();
+        assert_eq!(report.purged_bytes, layout.size());
+        assert_eq!(report.unmapped_bytes, 0);
+        assert_eq!(report.duration_hint, 1);
+        // Nothing is left to purge.
+        assert_eq!(mmap.grind(), GrindReport::default());
+        // The extent is reused, and purging zeroed it.
+        let b = mmap.alloc(layout).unwrap();
+        assert_eq!(b.ptr(), ptr);
+        assert_eq!(unsafe { ptr.add(layout.size() - 1).read() }, 0);
+        unsafe { Mmap::free(&mmap, b) }.unwrap();
+        unsafe { mmap.free_all() };
+        assert_eq!(mmap.stats().mapped(), 0);
+    }
+
+    /// The policy of the page at `ptr`, from `get_mempolicy(2)`.
+    fn policy(ptr: NonNull<u8>) -> usize {
+        // `MPOL_F_ADDR`.
+        const ADDR: usize = 1 << 1;
+        let mut mode = 0i32;
+        let args = [
+            ptr::from_mut(&mut mode).expose_provenance(),
+            0,
+            0,
+            ptr.addr().get(),
+            ADDR,
+            0,
+        ];
+        unsafe { syscall_raw(nr::GET_MEMPOLICY, args) }.unwrap();
+        mode as usize
+    }
+
+    #[test]
+    fn interleave_threshold() {
+        let pagesize = rustix::param::page_size();
+        let mmap = Mmap::with_config(MmapConfig {
+            interleave_threshold: Some(16 * pagesize),
+            ..Default::default()
+        });
+        // Kernels built without NUMA support have no policies to set.
+        if mmap.nodes.is_none() {
+            return;
+        }
+        let small = mmap
+            .alloc(Layout::from_size_align(pagesize, pagesize).unwrap())
+            .unwrap();
+        assert_eq!(mmap.stats().syscalls(Syscall::Mbind), 0);
+        assert_ne!(policy(small.ptr()), MPOL_INTERLEAVE);
+        let large = mmap
+            .alloc(Layout::from_size_align(16 * pagesize, pagesize).unwrap())
+            .unwrap();
+        assert_eq!(mmap.stats().syscalls(Syscall::Mbind), 1);
+        assert_eq!(policy(large.ptr()), MPOL_INTERLEAVE);
+        unsafe { Mmap::free(&mmap, small) }.unwrap();
+        unsafe { Mmap::free(&mmap, large) }.unwrap();
+    }
+
+    #[test]
+    fn grow_aligned_across_move() {
+        let mmap = Mmap::new();
+        let pagesize = rustix::param::page_size();
+        let align = 16 * pagesize;
+        let tag = mmap
+            .alloc(Layout::from_size_align(pagesize, align).unwrap())
+            .unwrap();
+        // SAFETY: The allocation is a page long.
+        unsafe { tag.ptr().write_bytes(0xa5, pagesize) };
+        // Take the page after the allocation, so that it cannot grow in place.
+        // A test running alongside may hold it already.
+        let after = tag.ptr().addr().get() + tag.layout().size();
+        let blocker = map(pagesize, ProtFlags::empty(), false, Some(after), true).ok();
+        let new = Layout::from_size_align(64 * pagesize, align).unwrap();
+        let grown = unsafe { Mmap::grow(&mmap, &tag, new) }.unwrap();
+        assert_ne!(grown.ptr(), tag.ptr());
+        assert!(grown.ptr().is_aligned_to(align));
+        // SAFETY: The first page was moved along with the mapping.
+        assert_eq!(unsafe { grown.ptr().add(pagesize - 1).read() }, 0xa5);
+        assert_eq!(mmap.stats().committed(), new.size());
+        unsafe { Mmap::free(&mmap, grown) }.unwrap();
+        if let Some(blocker) = blocker {
+            let _ = unsafe { rustix::mm::munmap(blocker.as_ptr().cast(), pagesize) };
+        }
     }
 }
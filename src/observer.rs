@@ -0,0 +1,46 @@
+#![allow(unused)]
+
+use core::ptr::NonNull;
+
+use rustix::mm::MprotectFlags;
+
+/// Notified of every change the backend makes to its address space, so that
+/// external systems such as GC write barriers, GPU pinning or profilers can
+/// mirror it.
+///
+/// Methods are called after the change has succeeded, on the thread that
+/// made it, possibly with allocator locks held. They must not allocate
+/// through the observed backend. All methods default to doing nothing.
+pub(crate) trait ExtentObserver: Sync {
+    /// `len` bytes at `ptr` were mapped.
+    fn on_map(&self, ptr: NonNull<u8>, len: usize) {}
+
+    /// `len` bytes at `ptr` were unmapped.
+    fn on_unmap(&self, ptr: NonNull<u8>, len: usize) {}
+
+    /// The protection of `len` bytes at `ptr` was changed to `prot`.
+    fn on_protect(&self, ptr: NonNull<u8>, len: usize, prot: MprotectFlags) {}
+
+    /// The `old_len` bytes at `old` were moved or resized to `new_len` bytes
+    /// at `new`.
+    fn on_remap(&self, old: NonNull<u8>, old_len: usize, new: NonNull<u8>, new_len: usize) {}
+}
+
+/// Charges the backend's mappings to an account the embedder keeps, such as
+/// a tenant's budget in a multi-tenant runtime, the way the kernel charges a
+/// memory cgroup for the pages it faults in.
+///
+/// Bytes are charged before they are mapped, so that a refused charge fails
+/// the allocation, and uncharged once they are unmapped. Retained and
+/// quarantined extents stay charged until they are unmapped. Methods may be
+/// called with allocator locks held, so they must not allocate through the
+/// charged backend.
+pub(crate) trait Charge: Sync {
+    /// Charges `len` bytes about to be mapped. Returns `false` to refuse
+    /// them.
+    fn charge(&self, len: usize) -> bool;
+
+    /// Uncharges `len` bytes that were unmapped, or that could not be
+    /// mapped after all.
+    fn uncharge(&self, len: usize);
+}
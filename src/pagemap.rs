@@ -0,0 +1,151 @@
+#![allow(unused)]
+
+use core::{fmt, ops::Range};
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use crate::core::Tag;
+
+/// Set in a pagemap entry if the page is resident. See the kernel's
+/// `pagemap.rst`.
+const PRESENT: u64 = 1 << 63;
+/// Set in a pagemap entry if the page is swapped out.
+const SWAPPED: u64 = 1 << 62;
+/// The page frame number of a resident page, which reads as zero without
+/// `CAP_SYS_ADMIN`.
+const PFN: u64 = (1 << 55) - 1;
+
+/// `/proc/kpageflags` bits for a page that is part of a transparent huge
+/// page, or of a hugetlbfs page.
+const KPF_HUGE: u64 = 1 << 17;
+const KPF_THP: u64 = 1 << 22;
+
+/// Pagemap entries read per call.
+const BATCH: usize = 512;
+
+/// Calls `f` with the number and pagemap entry of every page overlapping
+/// `range`, in address order.
+pub(crate) fn for_each_entry(
+    pagemap: &File,
+    range: Range<usize>,
+    pagesize: usize,
+    f: &mut dyn FnMut(usize, u64),
+) -> io::Result<()> {
+    let end = range.end.div_ceil(pagesize);
+    let mut page = range.start / pagesize;
+    let mut bytes = [0u8; BATCH * 8];
+    while page < end {
+        let n = (end - page).min(BATCH);
+        pagemap.read_exact_at(&mut bytes[..n * 8], (page * 8) as u64)?;
+        for (i, entry) in bytes[..n * 8].chunks_exact(8).enumerate() {
+            f(page + i, u64::from_ne_bytes(entry.try_into().unwrap()));
+        }
+        page += n;
+    }
+    Ok(())
+}
+
+/// Where one page of an allocation is, according to pagemap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PageState {
+    /// In memory.
+    Resident,
+    /// Written out to swap.
+    Swapped,
+    /// Never touched, or discarded since.
+    Absent,
+}
+
+/// One page reported by [`Tag::pages`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Page {
+    pub(crate) addr: usize,
+    pub(crate) state: PageState,
+    /// Whether the page is part of a huge page. Always `false` without
+    /// `CAP_SYS_ADMIN`, which the kernel needs to reveal page frames.
+    pub(crate) huge: bool,
+}
+
+/// The pages of an allocation counted by state, from [`Tag::residency`].
+/// Pages are counted whole, including those the allocation only partly
+/// covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Residency {
+    pub(crate) pages: usize,
+    pub(crate) resident: usize,
+    pub(crate) swapped: usize,
+    pub(crate) huge: usize,
+}
+
+impl fmt::Display for Residency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pages: {} resident ({} huge), {} swapped, {} absent",
+            self.pages,
+            self.resident,
+            self.huge,
+            self.swapped,
+            self.pages - self.resident - self.swapped
+        )
+    }
+}
+
+impl Tag {
+    /// Calls `f` on every page the allocation overlaps, in address order,
+    /// for debugging why the resident set size does not match expectations.
+    pub(crate) fn pages(&self, f: &mut dyn FnMut(Page)) -> io::Result<()> {
+        let pagesize = rustix::param::page_size();
+        let pagemap = File::open("/proc/self/pagemap")?;
+        // Only readable with `CAP_SYS_ADMIN`, like the page frames it is
+        // indexed by.
+        let kpageflags = File::open("/proc/kpageflags").ok();
+        let start = self.ptr().addr().get();
+        let range = start..start + self.layout().size();
+        let mut res = Ok(());
+        for_each_entry(&pagemap, range, pagesize, &mut |page, entry| {
+            let state = if entry & PRESENT != 0 {
+                PageState::Resident
+            } else if entry & SWAPPED != 0 {
+                PageState::Swapped
+            } else {
+                PageState::Absent
+            };
+            let pfn = entry & PFN;
+            let huge = match &kpageflags {
+                Some(flags) if state == PageState::Resident && pfn != 0 => {
+                    let mut buf = [0u8; 8];
+                    match flags.read_exact_at(&mut buf, pfn * 8) {
+                        Ok(()) => u64::from_ne_bytes(buf) & (KPF_HUGE | KPF_THP) != 0,
+                        Err(e) => {
+                            res = Err(e);
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            f(Page {
+                addr: page * pagesize,
+                state,
+                huge,
+            });
+        })?;
+        res
+    }
+
+    /// Counts the pages of the allocation that are resident, swapped or
+    /// huge. See [`Tag::pages`].
+    pub(crate) fn residency(&self) -> io::Result<Residency> {
+        let mut res = Residency::default();
+        self.pages(&mut |page| {
+            res.pages += 1;
+            match page.state {
+                PageState::Resident => res.resident += 1,
+                PageState::Swapped => res.swapped += 1,
+                PageState::Absent => {}
+            }
+            res.huge += page.huge as usize;
+        })?;
+        Ok(res)
+    }
+}
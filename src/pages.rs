@@ -0,0 +1,345 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    mem,
+    ptr::NonNull,
+};
+
+use crate::{
+    check::{Check, Report, Violation},
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+/// Written in the first page of every chunk. Bit `i` of `free` is set while
+/// page `i` of the chunk is free.
+struct Chunk<const WORDS: usize> {
+    next: Option<NonNull<Chunk<WORDS>>>,
+    layout: Layout,
+    /// The user bits of the parent's tag, restored when the chunk is freed.
+    user: u32,
+    free: [u64; WORDS],
+}
+
+impl<const WORDS: usize> Chunk<WORDS> {
+    const PAGES: usize = WORDS * u64::BITS as usize;
+
+    /// The first run of `n` free pages whose index is a multiple of `align`.
+    fn find(&self, n: usize, align: usize) -> Option<usize> {
+        let mut i = 0;
+        while i + n <= Self::PAGES {
+            let (w, b) = (i / 64, i % 64);
+            let free = self.free[w] >> b;
+            if free == 0 {
+                i = (w + 1) * 64;
+                continue;
+            }
+            i += free.trailing_zeros() as usize;
+            // Measure the run starting at `i`, a word at a time.
+            let start = i;
+            while i < Self::PAGES {
+                let (w, b) = (i / 64, i % 64);
+                let run = ((!self.free[w]) >> b).trailing_zeros() as usize;
+                let run = run.min(64 - b);
+                i += run;
+                if run < 64 - b {
+                    break;
+                }
+            }
+            let at = start.next_multiple_of(align);
+            if at + n <= i {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    /// Sets or clears the bits of pages `start..start + n`.
+    fn mark(&mut self, start: usize, n: usize, free: bool) {
+        let mut i = start;
+        let end = start + n;
+        while i < end {
+            let (w, b) = (i / 64, i % 64);
+            let len = (end - i).min(64 - b);
+            let mask = (u64::MAX >> (64 - len)) << b;
+            if free {
+                self.free[w] |= mask;
+            } else {
+                self.free[w] &= !mask;
+            }
+            i += len;
+        }
+    }
+}
+
+struct State<const WORDS: usize> {
+    chunks: Option<NonNull<Chunk<WORDS>>>,
+}
+
+// SAFETY: The chunks are owned by the allocator and only accessed with the
+// lock held or through `&mut Pages`.
+unsafe impl<const WORDS: usize> Send for State<WORDS> {}
+
+/// An allocator of page runs within chunks of `64 * WORDS` pages obtained
+/// from a parent.
+///
+/// Each chunk keeps a bitmap of its free pages in its first page, and runs
+/// are found by scanning the bitmap a word at a time with `trailing_zeros`,
+/// so the metadata for a chunk of 512 pages is 64 bytes. Requests larger
+/// than a chunk go straight to the parent. Chunks are only returned to the
+/// parent when the allocator is dropped.
+pub(crate) struct Pages<'p, A: Alloc, const WORDS: usize = 8> {
+    parent: &'p A,
+    pagesize: usize,
+    state: SpinLock<State<WORDS>>,
+}
+
+impl<'p, A: Alloc, const WORDS: usize> Pages<'p, A, WORDS> {
+    pub(crate) fn new(parent: &'p A) -> Self {
+        let pagesize = rustix::param::page_size();
+        assert!(mem::size_of::<Chunk<WORDS>>() <= pagesize);
+        Self {
+            parent,
+            pagesize,
+            state: SpinLock::new(State { chunks: None }),
+        }
+    }
+
+    /// The layout of a chunk, which is aligned to its size so that page
+    /// indices within it are as aligned as the addresses they stand for.
+    fn chunk_layout(&self) -> Layout {
+        let len = Chunk::<WORDS>::PAGES * self.pagesize;
+        Layout::from_size_align(len, len).unwrap()
+    }
+
+    /// The chunk containing `ptr`, if any.
+    fn find_chunk(&self, state: &State<WORDS>, ptr: NonNull<u8>) -> Option<NonNull<Chunk<WORDS>>> {
+        let len = self.chunk_layout().size();
+        let mut next = state.chunks;
+        while let Some(chunk) = next {
+            if (chunk.addr().get()..chunk.addr().get() + len).contains(&ptr.addr().get()) {
+                return Some(chunk);
+            }
+            // SAFETY: Every chunk in the list is live.
+            next = unsafe { chunk.as_ref() }.next;
+        }
+        None
+    }
+
+    /// Obtains a chunk from the parent, with every page but the first free.
+    fn grow(&self, state: &mut State<WORDS>) -> Result<NonNull<Chunk<WORDS>>, AllocError> {
+        let tag = self.parent.alloc(self.chunk_layout())?;
+        let chunk = tag.ptr().cast::<Chunk<WORDS>>();
+        let mut header = Chunk {
+            next: state.chunks,
+            layout: tag.layout(),
+            user: tag.user(),
+            free: [u64::MAX; WORDS],
+        };
+        header.mark(0, 1, false);
+        // SAFETY: The chunk is at least a page, which holds the header.
+        unsafe { chunk.write(header) };
+        state.chunks = Some(chunk);
+        Ok(chunk)
+    }
+}
+
+impl<A: Alloc, const WORDS: usize> Alloc for Pages<'_, A, WORDS> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let n = layout.size().max(1).div_ceil(self.pagesize);
+        let align = layout.align().div_ceil(self.pagesize);
+        // The first page of a chunk holds its header, so the first aligned
+        // run starts at page `align`.
+        if align
+            .checked_add(n)
+            .is_none_or(|x| x > Chunk::<WORDS>::PAGES)
+        {
+            return self.parent.alloc(layout);
+        }
+        let mut state = self.state.lock();
+        let mut next = state.chunks;
+        let (chunk, start) = loop {
+            let (chunk, fresh) = match next {
+                Some(chunk) => (chunk, false),
+                None => (self.grow(&mut state)?, true),
+            };
+            // SAFETY: Every chunk in the list is live, and the lock is held.
+            let header = unsafe { &mut *chunk.as_ptr() };
+            match header.find(n, align) {
+                Some(start) => {
+                    header.mark(start, n, false);
+                    break (chunk, start);
+                }
+                None if fresh => return Err(AllocError),
+                None => next = header.next,
+            }
+        };
+        // SAFETY: Page `start` lies within the chunk.
+        let ptr = unsafe { chunk.cast::<u8>().add(start * self.pagesize) };
+        // SAFETY: The run of `n` pages at `ptr` was free, and its index is a
+        // multiple of the alignment in pages, in a chunk aligned to its size.
+        Ok(unsafe { Tag::new(ptr, layout) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let mut state = self.state.lock();
+        let Some(chunk) = self.find_chunk(&state, tag.ptr()) else {
+            drop(state);
+            // SAFETY: The allocation did not fit in a chunk, so it came from
+            // the parent.
+            return unsafe { self.parent.free(tag) };
+        };
+        let start = (tag.ptr().addr().get() - chunk.addr().get()) / self.pagesize;
+        let n = tag.layout().size().max(1).div_ceil(self.pagesize);
+        // SAFETY: The chunk is live, and the lock is held.
+        unsafe { &mut *chunk.as_ptr() }.mark(start, n, true);
+    }
+}
+
+impl<A: Alloc + Check, const WORDS: usize> Check for Pages<'_, A, WORDS> {
+    /// Checks that every chunk is aligned to its size, apart from the
+    /// others, and has its header page in use. Then checks the parent.
+    fn check(&self, report: &mut Report) {
+        {
+            let state = self.state.lock();
+            let layout = self.chunk_layout();
+            let mut next = state.chunks;
+            while let Some(chunk) = next {
+                // SAFETY: Every chunk in the list is live, and the lock is
+                // held.
+                let header = unsafe { chunk.as_ref() };
+                report.checked += 1;
+                let addr = chunk.addr().get();
+                if !addr.is_multiple_of(layout.align()) {
+                    let align = layout.align();
+                    report.violation(Violation::Misaligned { addr, align });
+                }
+                let short = header.layout.size() < layout.size();
+                if short || header.layout.align() < layout.align() || header.free[0] & 1 != 0 {
+                    report.violation(Violation::Corrupt { addr });
+                }
+                let mut other = header.next;
+                while let Some(chunk) = other {
+                    let b = chunk.addr().get();
+                    if addr < b + layout.size() && b < addr + layout.size() {
+                        report.violation(Violation::Overlap { a: addr, b });
+                    }
+                    // SAFETY: As above.
+                    other = unsafe { chunk.as_ref() }.next;
+                }
+                next = header.next;
+            }
+        }
+        self.parent.check(report);
+    }
+}
+
+impl<A: Alloc, const WORDS: usize> Drop for Pages<'_, A, WORDS> {
+    fn drop(&mut self) {
+        let mut next = self.state.get_mut().chunks.take();
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk {
+                next: n,
+                layout,
+                user,
+                ..
+            } = unsafe { chunk.read() };
+            next = n;
+            // SAFETY: `chunk`, `layout` and `user` describe a tag returned by
+            // the parent, which is freed exactly once here.
+            unsafe {
+                self.parent
+                    .free(Tag::new(chunk.cast(), layout).with_user(user))
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::Mmap;
+
+    #[test]
+    fn find_runs() {
+        let mut chunk = Chunk::<2> {
+            next: None,
+            layout: Layout::new::<u8>(),
+            user: 0,
+            free: [u64::MAX; 2],
+        };
+        chunk.mark(0, 1, false);
+        assert_eq!(chunk.find(1, 1), Some(1));
+        assert_eq!(chunk.find(4, 4), Some(4));
+        // A run that crosses a word boundary.
+        chunk.mark(1, 70, false);
+        assert_eq!(chunk.find(1, 1), Some(71));
+        assert_eq!(chunk.find(57, 1), Some(71));
+        assert_eq!(chunk.find(58, 1), None);
+        // A hole too short once aligned is skipped.
+        chunk.mark(10, 5, true);
+        assert_eq!(chunk.find(5, 1), Some(10));
+        assert_eq!(chunk.find(5, 2), Some(10));
+        assert_eq!(chunk.find(5, 4), Some(72));
+        assert_eq!(chunk.find(64, 8), None);
+    }
+
+    #[test]
+    fn alloc_reuses_pages() {
+        let mmap = Mmap::new();
+        let pages = Pages::<_, 1>::new(&mmap);
+        let size = pages.pagesize;
+        let run = Layout::from_size_align(2 * size, size).unwrap();
+        let a = pages.alloc(run).unwrap();
+        let b = pages
+            .alloc(Layout::from_size_align(size, 4 * size).unwrap())
+            .unwrap();
+        let chunk = pages.state.lock().chunks.unwrap().addr().get();
+        // Page 0 holds the header, and `b` skips page 3 to stay aligned.
+        assert_eq!(a.ptr().addr().get(), chunk + size);
+        assert_eq!(b.ptr().addr().get(), chunk + 4 * size);
+        let c = pages
+            .alloc(Layout::from_size_align(size, size).unwrap())
+            .unwrap();
+        assert_eq!(c.ptr().addr().get(), chunk + 3 * size);
+        let ptr = a.ptr();
+        unsafe { pages.free(a) };
+        let a = pages.alloc(run).unwrap();
+        assert_eq!(a.ptr(), ptr);
+        // Runs longer than a chunk come from the parent.
+        let big = pages
+            .alloc(Layout::from_size_align(64 * size, size).unwrap())
+            .unwrap();
+        assert!(pages.find_chunk(&pages.state.lock(), big.ptr()).is_none());
+        unsafe {
+            pages.free(a);
+            pages.free(b);
+            pages.free(c);
+            pages.free(big);
+        }
+    }
+
+    #[test]
+    fn check_finds_freed_header() {
+        let mmap = Mmap::new();
+        let pages = Pages::<_, 1>::new(&mmap);
+        let layout = Layout::from_size_align(pages.pagesize, pages.pagesize).unwrap();
+        let a = pages.alloc(layout).unwrap();
+        let mut report = Report::default();
+        pages.check(&mut report);
+        assert!(report.is_ok(), "{report:?}");
+        assert!(report.checked >= 1);
+        let chunk = pages.state.lock().chunks.unwrap();
+        // A stray free of the header page.
+        unsafe { (*chunk.as_ptr()).free[0] |= 1 };
+        let mut report = Report::default();
+        pages.check(&mut report);
+        let addr = chunk.addr().get();
+        assert_eq!(report.first, Some(Violation::Corrupt { addr }));
+        unsafe { (*chunk.as_ptr()).free[0] &= !1 };
+        unsafe { pages.free(a) };
+    }
+}
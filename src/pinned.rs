@@ -0,0 +1,134 @@
+#![allow(unused)]
+
+use core::ptr::NonNull;
+
+use rustix::{
+    fd::{AsFd, BorrowedFd, OwnedFd},
+    fs::{MemfdFlags, ftruncate, memfd_create},
+    io::Errno,
+    mm::{self, Advice, MapFlags, ProtFlags},
+};
+
+use crate::mmap::MmapErr;
+
+/// The huge page size tried for hugetlb-backed regions.
+const HUGEPAGE: usize = 2 << 20;
+
+/// A region of memory that stays resident at a fixed physical location, for
+/// registration with RDMA verbs or vfio.
+///
+/// The region is a shared mapping of a memfd, so it can be identified by
+/// [`PinnedRegion::fd`] and [`PinnedRegion::offset`]. It is backed by
+/// hugetlb pages when the system has them available, and otherwise by
+/// regular pages with `MADV_HUGEPAGE` as a hint. Either way, it is faulted
+/// in and mlocked up front, and is never purged or moved by the allocator.
+///
+/// # Checkpoint and restore
+///
+/// A restore puts the region back at the same virtual address, but not at
+/// the same physical pages, so it must be registered with the device again
+/// afterwards, and deregistered before the dump. A hugetlb-backed region
+/// can only be restored where enough huge pages of the same size are
+/// reserved. Call [`PinnedRegion::post_restore`] before registering it
+/// again.
+pub(crate) struct PinnedRegion {
+    fd: OwnedFd,
+    ptr: NonNull<u8>,
+    len: usize,
+    huge: bool,
+}
+
+// SAFETY: The region is owned by the value and not aliased by the allocator.
+unsafe impl Send for PinnedRegion {}
+unsafe impl Sync for PinnedRegion {}
+
+impl PinnedRegion {
+    /// Creates a pinned region of at least `len` bytes.
+    pub(crate) fn new(len: usize) -> Result<Self, MmapErr> {
+        let this = match Self::map(len, true) {
+            Ok(this) => this,
+            Err(_) => Self::map(len, false)?,
+        };
+        if !this.huge {
+            // SAFETY: The region is valid for `len` bytes. This is only a
+            // hint, so failure is fine.
+            let _ =
+                unsafe { mm::madvise(this.ptr.as_ptr().cast(), this.len, Advice::LinuxHugepage) };
+        }
+        // SAFETY: As above. On failure, `this` is dropped and unmapped.
+        unsafe { mm::mlock(this.ptr.as_ptr().cast(), this.len) }?;
+        Ok(this)
+    }
+
+    fn map(len: usize, huge: bool) -> Result<Self, MmapErr> {
+        let (flags, unit) = if huge {
+            (MemfdFlags::CLOEXEC | MemfdFlags::HUGETLB, HUGEPAGE)
+        } else {
+            (MemfdFlags::CLOEXEC, rustix::param::page_size())
+        };
+        let len = len
+            .max(1)
+            .checked_next_multiple_of(unit)
+            .ok_or(MmapErr::Overflow)?;
+        let fd = memfd_create(c"moz-pinned", flags)?;
+        ftruncate(&fd, len as u64)?;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::SHARED | MapFlags::POPULATE;
+        // SAFETY: Passing `ptr::null_mut()` lets the kernel choose the
+        // address, and the file is at least `len` bytes long.
+        let ptr = unsafe { mm::mmap(core::ptr::null_mut(), len, rw, flags, &fd, 0) }?;
+        Ok(Self {
+            fd,
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+            huge,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn ptr(&self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the region is backed by hugetlb pages.
+    #[inline]
+    pub(crate) fn is_huge(&self) -> bool {
+        self.huge
+    }
+
+    /// The memfd backing the region.
+    #[inline]
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Faults the region back in and locks it again after a restore from a
+    /// checkpoint, so that it is resident before it is registered with a
+    /// device.
+    pub(crate) fn post_restore(&self) -> Result<(), Errno> {
+        // SAFETY: The region is valid for `len` bytes, and locking does not
+        // change its contents.
+        unsafe { mm::mlock(self.ptr.as_ptr().cast(), self.len) }
+    }
+
+    /// The offset of the region within [`PinnedRegion::fd`].
+    #[inline]
+    pub(crate) fn offset(&self) -> u64 {
+        0
+    }
+}
+
+impl Drop for PinnedRegion {
+    fn drop(&mut self) {
+        // SAFETY: The region was mapped in `map` and is not used after this.
+        unsafe {
+            let _ = mm::munlock(self.ptr.as_ptr().cast(), self.len);
+            let _ = mm::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
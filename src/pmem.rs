@@ -0,0 +1,295 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ffi::CStr,
+    ptr::NonNull,
+    sync::atomic::{
+        AtomicU64,
+        Ordering::{AcqRel, Acquire, Relaxed},
+    },
+};
+
+use rustix::{
+    fd::OwnedFd,
+    fs::{self, FallocateFlags, Mode, OFlags},
+    io::Errno,
+    mm::{self, MapFlags, ProtFlags},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    mmap::MmapErr,
+};
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("the `pmem` feature supports x86_64 and aarch64 only");
+
+/// Marks a file laid out by [`Pmem`].
+const MAGIC: u64 = u64::from_le_bytes(*b"moz-pmem");
+
+/// The persistent state of a [`Pmem`] file, kept in its first cache line.
+#[repr(C, align(64))]
+struct Header {
+    magic: AtomicU64,
+    /// The offset up to which the file has been handed out.
+    used: AtomicU64,
+}
+
+/// A backend over a file on a DAX filesystem, such as ext4 or xfs mounted
+/// with `-o dax` on persistent memory, for building persistent data
+/// structures on the allocation layers.
+///
+/// The file is mapped shared with `MAP_SYNC`, so stores reach the media
+/// without the page cache, and the filesystem's metadata for every page is
+/// durable by the time the page can be written: making data durable only
+/// takes [`Tag::persist`], never `msync`. Opening fails with `EOPNOTSUPP`
+/// where the file is not on DAX.
+///
+/// Space is handed out by bumping an offset kept at the start of the file,
+/// which is persisted on every allocation, so reopening the file resumes
+/// where the last run left off. Memory is never reused: `free` is a no-op.
+/// The file may be mapped at a different address each time, so persistent
+/// structures should link with [`Pmem::offset`] rather than pointers.
+pub(crate) struct Pmem {
+    fd: OwnedFd,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: The mapping is owned by the value, and space is claimed with
+// atomic operations on the header.
+unsafe impl Send for Pmem {}
+unsafe impl Sync for Pmem {}
+
+impl Pmem {
+    /// Maps the file at `path`, creating it and allocating its blocks up to
+    /// `len` bytes, rounded up to whole pages, if it is shorter.
+    pub(crate) fn open(path: &CStr, len: usize) -> Result<Self, MmapErr> {
+        let pagesize = rustix::param::page_size();
+        let flags = OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC;
+        let fd = fs::open(path, flags, Mode::RUSR | Mode::WUSR)?;
+        let len = (fs::fstat(&fd)?.st_size as usize)
+            .max(len)
+            .max(2 * pagesize)
+            .checked_next_multiple_of(pagesize)
+            .ok_or(MmapErr::Overflow)?;
+        // Allocate the blocks now, so that running out of space fails here
+        // rather than raising `SIGBUS` on a store.
+        fs::fallocate(&fd, FallocateFlags::empty(), 0, len as u64)?;
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::SHARED_VALIDATE | MapFlags::SYNC;
+        // SAFETY: The kernel chooses the address, and the file is `len`
+        // bytes long.
+        let ptr = unsafe { mm::mmap(core::ptr::null_mut(), len, rw, flags, &fd, 0) }?;
+        let this = Self {
+            fd,
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+        };
+        let header = this.header();
+        if header.magic.load(Acquire) != MAGIC {
+            // The data starts a page in, so that chunks can be page-aligned.
+            header.used.store(pagesize as u64, Relaxed);
+            persist(this.ptr, size_of::<Header>());
+            header.magic.store(MAGIC, Relaxed);
+            persist(this.ptr, size_of::<Header>());
+        }
+        Ok(this)
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: The file starts with a page-aligned header, which is only
+        // accessed atomically.
+        unsafe { self.ptr.cast::<Header>().as_ref() }
+    }
+
+    #[inline]
+    pub(crate) fn base(&self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The offset of `ptr` from the start of the file, which stays the same
+    /// however the file is mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` is outside the mapping.
+    pub(crate) fn offset(&self, ptr: NonNull<u8>) -> usize {
+        let ost = ptr.addr().get().wrapping_sub(self.ptr.addr().get());
+        assert!(ost < self.len);
+        ost
+    }
+
+    /// The address of `offset` in the mapping.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is outside the mapping.
+    pub(crate) fn at(&self, offset: usize) -> NonNull<u8> {
+        assert!(offset < self.len);
+        // SAFETY: The offset lies within the mapping.
+        unsafe { self.ptr.add(offset) }
+    }
+}
+
+impl Alloc for Pmem {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let header = self.header();
+        let base = self.ptr.addr().get();
+        let mut used = header.used.load(Relaxed) as usize;
+        let start = loop {
+            let start = base
+                .checked_add(used)
+                .and_then(|x| x.checked_next_multiple_of(layout.align()))
+                .ok_or(AllocError)?
+                - base;
+            let end = start
+                .checked_add(layout.size())
+                .filter(|&x| x <= self.len)
+                .ok_or(AllocError)?;
+            match header
+                .used
+                .compare_exchange_weak(used as u64, end as u64, AcqRel, Relaxed)
+            {
+                Ok(_) => break start,
+                Err(x) => used = x as usize,
+            }
+        };
+        // Persisted before the caller can link the allocation into anything
+        // persistent, so that it is never handed out twice across a crash.
+        persist(self.ptr, size_of::<Header>());
+        // SAFETY: `start..start + layout.size()` lies within the mapping, is
+        // aligned, and was claimed above.
+        Ok(unsafe { Tag::new(self.ptr.add(start), layout) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {}
+}
+
+impl Drop for Pmem {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was made in `open`.
+        let _ = unsafe { mm::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+impl Tag {
+    /// Writes the cache lines under the allocation back to memory, without
+    /// waiting for them. Follow with [`fence`], or use [`Tag::persist`].
+    pub(crate) fn flush(&self) {
+        flush(self.ptr(), self.layout().size());
+    }
+
+    /// Makes stores to the allocation durable, on memory mapped by
+    /// [`Pmem`] or another `MAP_SYNC` mapping of persistent memory.
+    pub(crate) fn persist(&self) {
+        persist(self.ptr(), self.layout().size());
+    }
+}
+
+/// Makes stores to the `len` bytes at `ptr` durable: writes their cache
+/// lines back and waits for the writes to complete.
+pub(crate) fn persist(ptr: NonNull<u8>, len: usize) {
+    flush(ptr, len);
+    fence();
+}
+
+/// Writes back the cache lines under the `len` bytes at `ptr`, without
+/// waiting. Several ranges can be flushed before one [`fence`].
+pub(crate) fn flush(ptr: NonNull<u8>, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let line = line_size();
+    let start = ptr.addr().get() & !(line - 1);
+    let end = ptr.addr().get() + len;
+    for addr in (start..end).step_by(line) {
+        // SAFETY: Writing a line back does not change memory, and the line
+        // is mapped since the range lies on it.
+        unsafe { write_back(ptr.as_ptr().with_addr(addr)) };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn line_size() -> usize {
+    64
+}
+
+/// Writes back a cache line with the weakest instruction the CPU has:
+/// `clwb` keeps the line cached, `clflushopt` evicts it but is not ordered
+/// with other flushes, and `clflush` is both evicting and ordered.
+///
+/// # SAFETY
+///
+/// `ptr` must be mapped.
+#[cfg(target_arch = "x86_64")]
+unsafe fn write_back(ptr: *mut u8) {
+    use core::arch::x86_64::__cpuid_count;
+    use core::sync::atomic::AtomicU8;
+    /// Bit 0 set once detected, bit 1 for `clwb`, bit 2 for `clflushopt`.
+    static FEATURES: AtomicU8 = AtomicU8::new(0);
+    let mut features = FEATURES.load(Relaxed);
+    if features == 0 {
+        let ebx = __cpuid_count(7, 0).ebx;
+        features = 1 | (((ebx >> 24) & 1) << 1) as u8 | (((ebx >> 23) & 1) << 2) as u8;
+        FEATURES.store(features, Relaxed);
+    }
+    unsafe {
+        if features & 2 != 0 {
+            core::arch::asm!("clwb [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        } else if features & 4 != 0 {
+            core::arch::asm!("clflushopt [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        } else {
+            core::arch::asm!("clflush [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Waits for the flushes issued so far to complete.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn fence() {
+    // SAFETY: `sfence` only orders stores.
+    unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// The smallest data cache line, from `CTR_EL0`.
+#[cfg(target_arch = "aarch64")]
+fn line_size() -> usize {
+    let ctr: usize;
+    // SAFETY: Linux lets userspace read `CTR_EL0`.
+    unsafe { core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Cleans a cache line to the point of persistence with `dc cvap`, or to
+/// the point of coherency with `dc cvac` on CPUs without it.
+///
+/// # SAFETY
+///
+/// `ptr` must be mapped.
+#[cfg(target_arch = "aarch64")]
+unsafe fn write_back(ptr: *mut u8) {
+    /// `HWCAP_DCPOP`, for `dc cvap`.
+    const DCPOP: usize = 1 << 16;
+    unsafe {
+        if rustix::param::linux_hwcap().0 & DCPOP != 0 {
+            // `dc cvap`, spelled out for assemblers without ARMv8.2.
+            core::arch::asm!("sys #3, c7, c12, #1, {}", in(reg) ptr, options(nostack, preserves_flags));
+        } else {
+            core::arch::asm!("dc cvac, {}", in(reg) ptr, options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Waits for the cache maintenance issued so far to complete.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn fence() {
+    // SAFETY: `dsb` only orders memory accesses.
+    unsafe { core::arch::asm!("dsb ish", options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,306 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::NonNull,
+};
+
+use rustix::{
+    io::Errno,
+    mm::{self, Advice},
+};
+
+use crate::{
+    arenas::{self, Arenas},
+    core::{Alloc, Tag},
+    debug::Debugged,
+    emergency::Emergency,
+    fit::Fit,
+    mmap::{HUGE_PAGE, Mmap, MmapConfig, Retain, Revive},
+    tracked::Tracked,
+};
+
+/// The chunk size of the arenas of a [`SmallFastHeap`].
+const SMALL_FAST_CHUNK: usize = 4 << 20;
+
+/// The chunk size of the arenas of a heap built for
+/// [`Profile::LowMemory`].
+const LOW_MEMORY_CHUNK: usize = 256 << 10;
+
+/// The chunk size of the arenas of a heap built for
+/// [`Profile::LowLatency`], a whole number of huge pages.
+const LOW_LATENCY_CHUNK: usize = 4 * HUGE_PAGE;
+
+/// The resident memory a [`Profile::LowLatency`] backend keeps for reuse.
+const LOW_LATENCY_RETAIN: usize = 256 << 20;
+
+/// Where a [`Profile::Deterministic`] backend places its first mapping,
+/// before the seed's offset: far from where the kernel puts the program,
+/// its heap and its other mappings.
+const DETERMINISTIC_BASE: usize = 0x2000_0000_0000;
+
+/// The bytes heaps set aside when created for allocations made once their
+/// backend is exhausted. See [`Emergency`].
+const EMERGENCY_RESERVE: usize = 64 << 10;
+
+/// The bytes of freed memory a [`DebugHeap`] holds in quarantine.
+const DEBUG_QUARANTINE: usize = 64 << 20;
+
+/// A general-purpose heap for many small, short-lived objects: threads are
+/// spread across arenas to keep them from contending, and each arena packs
+/// objects into large chunks from the mmap backend rather than mapping each
+/// one. A small reserve is set aside when the heap is created, for error
+/// handling and logging to run once `parent` is exhausted.
+///
+/// The crate has no thread cache or slab layer, so this is not a
+/// tcache-over-slab-over-chunk stack: each arena is a single [`Fit`]
+/// allocator over its free extents, behind a lock, and freed objects go
+/// straight back to it.
+pub(crate) type SmallFastHeap<'p> = Emergency<Arenas<Fit<'p, Mmap>, 8>>;
+
+/// Creates a [`SmallFastHeap`] drawing its chunks from `parent`, with an
+/// arena per allowed CPU.
+pub(crate) fn small_fast_heap(parent: &Mmap) -> SmallFastHeap<'_> {
+    Profile::Balanced.small_fast_heap(parent)
+}
+
+/// Trade-offs between memory use and speed, picked when a heap and its
+/// backend are built.
+///
+/// A profile only picks settings of existing layers: the backend's
+/// [`MmapConfig`], from [`Profile::mmap_config`], and the chunk size, arena
+/// count and emergency reserve of a [`SmallFastHeap`] built with
+/// [`Profile::small_fast_heap`]. Anything else is configured by hand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Profile {
+    /// The defaults of each layer: a backend that unmaps freed memory at
+    /// once and requests no huge pages, and heaps with an arena per allowed
+    /// CPU, up to eight, 4 MiB chunks and a 64 KiB emergency reserve.
+    #[default]
+    Balanced,
+    /// For memory-constrained deployments such as CI runners and edge
+    /// devices: heaps use a single arena of 256 KiB chunks, and a 16 KiB
+    /// emergency reserve. The backend is configured as by default, keeping
+    /// no freed memory and requesting no huge pages, but explicitly, so that
+    /// the profile does not follow changes to the defaults.
+    LowMemory,
+    /// Trades memory for tail latency: the backend prefaults fresh mappings
+    /// and the purged extents it hands out again, keeps up to 256 MiB of
+    /// freed memory resident for reuse with no dirty watermark, and advises
+    /// allocations of a huge page or more for transparent huge pages. Heaps
+    /// use an arena per allowed CPU, up to eight, with chunks of four huge
+    /// pages. Lock the heap's own structures with [`lock`] once it is in
+    /// its final place.
+    LowLatency,
+    /// For reproducing heisenbugs: the backend places mappings back to back
+    /// from an address picked by `seed`, rather than by address space layout
+    /// randomization, and heaps use a single arena of 4 MiB chunks, so that
+    /// a single-threaded program allocates at the same addresses from run
+    /// to run. Canaries derive from addresses, so they repeat too. Seed any
+    /// [`Sampler`](crate::sampler::Sampler) with the same `seed`.
+    Deterministic { seed: u64 },
+}
+
+/// The environment variable [`Profile::from_env`] reads.
+#[cfg(feature = "std")]
+const PROFILE_VAR: &str = "MOZ_PROFILE";
+
+impl Profile {
+    /// Parses a profile from its [`Profile::name`], with
+    /// `deterministic:<seed>` for a seed other than zero, so that operators
+    /// can pick one from a config file, command line or environment without
+    /// a rebuild. The profile applies to heaps and backends built with it
+    /// afterwards; one already built cannot be switched.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "balanced" => Profile::Balanced,
+            "low-memory" => Profile::LowMemory,
+            "low-latency" => Profile::LowLatency,
+            "deterministic" => Profile::Deterministic { seed: 0 },
+            _ => {
+                let seed = name.strip_prefix("deterministic:")?.parse().ok()?;
+                Profile::Deterministic { seed }
+            }
+        })
+    }
+
+    /// The profile named by the `MOZ_PROFILE` environment variable, as
+    /// [`Profile::from_name`] reads it, or `None` if it is unset or names no
+    /// profile. The variable is read on each call, so set it before the
+    /// heap is built.
+    #[cfg(feature = "std")]
+    pub(crate) fn from_env() -> Option<Self> {
+        Self::from_name(&std::env::var(PROFILE_VAR).ok()?)
+    }
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Profile::Balanced => "balanced",
+            Profile::LowMemory => "low-memory",
+            Profile::LowLatency => "low-latency",
+            Profile::Deterministic { .. } => "deterministic",
+        }
+    }
+
+    pub(crate) fn mmap_config(self) -> MmapConfig {
+        match self {
+            Profile::Balanced => MmapConfig::default(),
+            Profile::LowMemory => MmapConfig {
+                retain: Retain::None,
+                huge_threshold: None,
+                ..Default::default()
+            },
+            Profile::Deterministic { seed } => MmapConfig {
+                // Up to 16 TiB of 4 GiB steps above the base.
+                base: Some(DETERMINISTIC_BASE + ((seed % 4096) as usize) * (1 << 32)),
+                ..Default::default()
+            },
+            Profile::LowLatency => MmapConfig {
+                retain: Retain::Dirty {
+                    max: LOW_LATENCY_RETAIN,
+                },
+                dirty_watermark: None,
+                huge_threshold: Some(HUGE_PAGE),
+                probe: true,
+                revive: Revive::Populate,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A backend configured for the profile.
+    pub(crate) fn mmap(self) -> Mmap {
+        Mmap::with_config(self.mmap_config())
+    }
+
+    /// The bytes heaps request from their backend at a time.
+    pub(crate) fn chunk_size(self) -> usize {
+        match self {
+            Profile::Balanced | Profile::Deterministic { .. } => SMALL_FAST_CHUNK,
+            Profile::LowMemory => LOW_MEMORY_CHUNK,
+            Profile::LowLatency => LOW_LATENCY_CHUNK,
+        }
+    }
+
+    /// The most arenas heaps spread threads across, before capping to the
+    /// allowed CPUs.
+    pub(crate) fn max_arenas(self) -> usize {
+        match self {
+            Profile::Balanced | Profile::LowLatency => usize::MAX,
+            Profile::LowMemory | Profile::Deterministic { .. } => 1,
+        }
+    }
+
+    /// The bytes heaps set aside for when their backend is exhausted.
+    pub(crate) fn emergency_reserve(self) -> usize {
+        match self {
+            Profile::LowMemory => EMERGENCY_RESERVE / 4,
+            _ => EMERGENCY_RESERVE,
+        }
+    }
+
+    /// Creates a [`SmallFastHeap`] configured for the profile, drawing its
+    /// chunks from `parent`, which is usually [`Profile::mmap`].
+    pub(crate) fn small_fast_heap(self, parent: &Mmap) -> SmallFastHeap<'_> {
+        let deterministic = matches!(self, Profile::Deterministic { .. });
+        let heap = Arenas::new().with_deterministic(deterministic);
+        let n = arenas::allowed_cpus().min(self.max_arenas());
+        let chunk = self.chunk_size();
+        for _ in 0..n {
+            if heap.create(Fit::new(parent, chunk)).is_err() {
+                break;
+            }
+        }
+        Emergency::new(heap, self.emergency_reserve())
+    }
+}
+
+/// Locks the pages under `value` in memory, so that touching it never
+/// takes a major fault. Meant for structures on the hot path, such as a
+/// heap's arena table; the pages stay locked until the process unlocks or
+/// unmaps them, so `value` should not move afterwards.
+pub(crate) fn lock<T>(value: &T) -> Result<(), Errno> {
+    let pagesize = rustix::param::page_size();
+    let addr = core::ptr::from_ref(value).addr();
+    let start = addr & !(pagesize - 1);
+    let end = (addr + size_of::<T>().max(1)).next_multiple_of(pagesize);
+    let ptr = core::ptr::from_ref(value).cast::<u8>().with_addr(start);
+    // SAFETY: Locking only pins the pages, which are mapped since `value`
+    // lies on them.
+    unsafe { mm::mlock(ptr.cast_mut().cast(), end - start) }
+}
+
+/// A heap for finding memory bugs: every allocation gets mappings of its
+/// own, freed allocations are held inaccessible in quarantine so that a use
+/// after free faults rather than reading whatever reuses the memory, memory
+/// is filled and guarded by canaries, frees are checked, and the call site
+/// of every live allocation is recorded for leak reports and overlap checks.
+/// A small reserve is set aside when the heap is created, so that a heap
+/// that runs out of memory can still report. Slow and wasteful by design.
+pub(crate) type DebugHeap = Tracked<Emergency<Debugged<Mmap>>>;
+
+pub(crate) fn debug_heap() -> DebugHeap {
+    let mmap = Mmap::with_config(MmapConfig {
+        quarantine: Some(DEBUG_QUARANTINE),
+        ..Default::default()
+    });
+    // Tracked checks frees against its table before Debugged reads the
+    // trailer, which quarantine has made inaccessible after a free.
+    Tracked::new(Emergency::new(Debugged::new(mmap), EMERGENCY_RESERVE)).with_strict()
+}
+
+/// A heap for keys and other secrets: allocations are locked in memory so
+/// they are never written to swap, left out of core dumps, and wiped before
+/// they are freed.
+///
+/// Every allocation gets pages of its own, so locking one never pins its
+/// neighbours. Allocations fail if they would exceed `RLIMIT_MEMLOCK`.
+pub(crate) struct SecretHeap {
+    inner: Mmap,
+    pagesize: usize,
+}
+
+impl SecretHeap {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Mmap::new(),
+            pagesize: rustix::param::page_size(),
+        }
+    }
+
+    /// The whole pages under an allocation, which it owns.
+    fn pages(&self, tag: &Tag) -> usize {
+        tag.layout().size().max(1).next_multiple_of(self.pagesize)
+    }
+}
+
+impl Alloc for SecretHeap {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        let (ptr, len) = (tag.ptr().as_ptr().cast(), self.pages(&tag));
+        // SAFETY: The pages belong to the allocation alone.
+        let locked = unsafe { mm::mlock(ptr, len) };
+        if locked.is_err() {
+            // SAFETY: The allocation was just made and is unused.
+            unsafe { self.inner.free(tag) };
+            return Err(AllocError);
+        }
+        // SAFETY: As above. This is only a hint, so failure is fine.
+        let _ = unsafe { mm::madvise(ptr, len, Advice::LinuxDontDump) };
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let len = self.pages(&tag);
+        let words = tag.ptr().cast::<u64>();
+        for i in 0..len / 8 {
+            // SAFETY: The pages are page-aligned and belong to the
+            // allocation. Volatile, so that the wipe is not optimized away
+            // as a dead store.
+            unsafe { words.add(i).write_volatile(0) };
+        }
+        // SAFETY: As above.
+        let _ = unsafe { mm::munlock(tag.ptr().as_ptr().cast(), len) };
+        unsafe { self.inner.free(tag) }
+    }
+}
@@ -0,0 +1,108 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ffi::c_void,
+    fmt, ptr,
+};
+use std::{collections::HashMap, sync::Mutex, vec::Vec};
+
+use crate::{
+    core::{Alloc, Tag},
+    sampler::Sampler,
+};
+
+/// Frames deeper than this are dropped from the leaf end of a stack.
+const MAX_DEPTH: usize = 64;
+
+/// Captures stack traces for a byte-sampled subset of allocations made
+/// through `A`, aggregated by stack.
+///
+/// The aggregation table lives in the global allocator. Do not wrap the
+/// allocator that is installed as `#[global_allocator]`.
+pub(crate) struct Profiled<A> {
+    inner: A,
+    sampler: Sampler,
+    stacks: Mutex<HashMap<Vec<usize>, usize>>,
+}
+
+impl<A> Profiled<A> {
+    pub(crate) fn new(inner: A, sampler: Sampler) -> Self {
+        Self {
+            inner,
+            sampler,
+            stacks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, bytes: usize) {
+        let mut ips = Vec::with_capacity(MAX_DEPTH);
+        backtrace::trace(|frame| {
+            ips.push(frame.ip().addr());
+            ips.len() < MAX_DEPTH
+        });
+        let mut stacks = self.stacks.lock().unwrap_or_else(|e| e.into_inner());
+        let total = stacks.entry(ips).or_default();
+        *total = total.saturating_add(bytes);
+    }
+
+    /// Returns the profile in the folded-stack format consumed by
+    /// `flamegraph.pl` and `inferno`. Each line is a `;`-separated stack,
+    /// root first, followed by the estimated number of bytes allocated from
+    /// it.
+    pub(crate) fn folded(&self) -> Folded<'_, A> {
+        Folded(self)
+    }
+}
+
+impl<A: Alloc> Alloc for Profiled<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        if self.sampler.sample(layout.size()) {
+            // A sample stands for roughly one interval's worth of bytes, or
+            // for the allocation itself if it is larger than that.
+            self.record(self.sampler.interval().max(layout.size()));
+        }
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        unsafe { self.inner.free(tag) }
+    }
+}
+
+pub(crate) struct Folded<'a, A>(&'a Profiled<A>);
+
+impl<A> fmt::Display for Folded<'_, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stacks = self.0.stacks.lock().unwrap_or_else(|e| e.into_inner());
+        for (ips, bytes) in stacks.iter() {
+            for (i, ip) in ips.iter().rev().enumerate() {
+                if i > 0 {
+                    f.write_str(";")?;
+                }
+                write_frame(f, *ip)?;
+            }
+            writeln!(f, " {bytes}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_frame(f: &mut fmt::Formatter<'_>, ip: usize) -> fmt::Result {
+    let mut res = Ok(());
+    let mut found = false;
+    backtrace::resolve(ptr::without_provenance_mut::<c_void>(ip), |sym| {
+        if found {
+            return;
+        }
+        if let Some(name) = sym.name() {
+            found = true;
+            res = write!(f, "{name:#}");
+        }
+    });
+    if !found {
+        res = write!(f, "{ip:#x}");
+    }
+    res
+}
@@ -0,0 +1,80 @@
+#![allow(unused)]
+
+use core::fmt;
+
+use crate::stats::{Stats, Syscall};
+
+type Metric = (&'static str, &'static str, fn(&Stats) -> usize);
+
+const METRICS: &[Metric] = &[
+    (
+        "moz_allocated_bytes",
+        "Bytes currently handed out to callers.",
+        Stats::allocated,
+    ),
+    (
+        "moz_allocated_peak_bytes",
+        "High-water mark of allocated bytes.",
+        Stats::allocated_peak,
+    ),
+    (
+        "moz_mapped_bytes",
+        "Bytes currently mapped from the OS.",
+        Stats::mapped,
+    ),
+    (
+        "moz_mapped_peak_bytes",
+        "High-water mark of mapped bytes.",
+        Stats::mapped_peak,
+    ),
+    (
+        "moz_committed_bytes",
+        "Mapped bytes charged against the commit limit.",
+        Stats::committed,
+    ),
+    (
+        "moz_mappings",
+        "Distinct mappings owned, towards vm.max_map_count.",
+        Stats::mappings,
+    ),
+    (
+        "moz_fragmented_bytes",
+        "Bytes mapped but not allocated.",
+        Stats::fragmented,
+    ),
+    #[cfg(feature = "std")]
+    (
+        "moz_process_rss_bytes",
+        "Resident set size of the process at the last sample.",
+        Stats::rss,
+    ),
+];
+
+/// Renders stats in the Prometheus text exposition format.
+///
+/// Each element of the slice is labeled with its index as `arena="<i>"`.
+/// Every metric family is written once, with one sample per arena, so the
+/// output can be served from a metrics endpoint as-is.
+pub(crate) struct Prometheus<'a>(pub(crate) &'a [&'a Stats]);
+
+impl fmt::Display for Prometheus<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, help, get) in METRICS {
+            writeln!(f, "# HELP {name} {help}")?;
+            writeln!(f, "# TYPE {name} gauge")?;
+            for (arena, stats) in self.0.iter().enumerate() {
+                writeln!(f, "{name}{{arena=\"{arena}\"}} {}", get(stats))?;
+            }
+        }
+        let name = "moz_syscalls_total";
+        writeln!(f, "# HELP {name} Memory-management system calls made.")?;
+        writeln!(f, "# TYPE {name} counter")?;
+        for (arena, stats) in self.0.iter().enumerate() {
+            for syscall in Syscall::ALL {
+                let (syscall, n) = (syscall.name(), stats.syscalls(syscall));
+                writeln!(f, "{name}{{arena=\"{arena}\",syscall=\"{syscall}\"}} {n}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    hint,
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+struct Bucket {
+    tokens: u64,
+    /// The tick up to which tokens have been added.
+    last: u64,
+}
+
+/// Smooths out bursts of allocations from `A` with a token bucket, for
+/// backends like [`Mmap`](crate::mmap::Mmap) where every allocation is a
+/// system call. Bursts of mappings from many threads contend on the
+/// process-wide lock over the address space, stalling every thread that
+/// faults a page meanwhile.
+///
+/// One token is added every `interval` ticks of `clock`, up to `burst`
+/// tokens, and each allocation takes one, spinning until one is available.
+/// Frees are not limited, since holding on to memory does not help. The
+/// crate has no clock of its own, so the caller picks the source and the
+/// unit, such as a monotonic clock in microseconds.
+pub(crate) struct RateLimited<A> {
+    inner: A,
+    clock: fn() -> u64,
+    interval: u64,
+    burst: u64,
+    bucket: SpinLock<Bucket>,
+}
+
+impl<A> RateLimited<A> {
+    /// # Panics
+    ///
+    /// Panics if `interval` or `burst` is zero.
+    pub(crate) fn new(inner: A, clock: fn() -> u64, interval: u64, burst: u64) -> Self {
+        assert!(interval != 0 && burst != 0);
+        Self {
+            inner,
+            clock,
+            interval,
+            burst,
+            bucket: SpinLock::new(Bucket {
+                tokens: burst,
+                last: clock(),
+            }),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Takes a token if one is available.
+    fn try_take(&self) -> bool {
+        let now = (self.clock)();
+        let mut bucket = self.bucket.lock();
+        let added = now.saturating_sub(bucket.last) / self.interval;
+        if added != 0 {
+            bucket.tokens = bucket.tokens.saturating_add(added).min(self.burst);
+            // Keep the remainder of a partial interval, unless the bucket
+            // is full, which forfeits it.
+            bucket.last = if bucket.tokens == self.burst {
+                now
+            } else {
+                bucket.last + added * self.interval
+            };
+        }
+        let Some(tokens) = bucket.tokens.checked_sub(1) else {
+            return false;
+        };
+        bucket.tokens = tokens;
+        true
+    }
+
+    fn take(&self) {
+        while !self.try_take() {
+            hint::spin_loop();
+        }
+    }
+}
+
+impl<A: Alloc> Alloc for RateLimited<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.take();
+        self.inner.alloc(layout)
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.take();
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        unsafe { self.inner.free(tag) }
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        self.take();
+        unsafe { self.inner.grow(tag, new) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.inner.shrink(tag, new) }
+    }
+}
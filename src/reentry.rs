@@ -0,0 +1,151 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    cell::Cell,
+};
+
+use rustix::fd::BorrowedFd;
+
+use crate::{
+    core::{Alloc, Owns, Tag},
+    crash,
+    reserve::Reserve,
+};
+
+/// How many calls into [`NoReentry`] allocators the current thread is
+/// inside.
+#[thread_local]
+static DEPTH: Cell<u32> = Cell::new(0);
+
+/// How many nested allocators [`ACTIVE`] records.
+const NESTING: usize = 8;
+
+/// The addresses of the [`NoReentry`] allocators the current thread is
+/// inside, outermost first, so that one layer calling into another, such
+/// as a heap into its parent, is not mistaken for re-entry. Calls nested
+/// deeper than [`NESTING`] are counted but not recorded.
+#[thread_local]
+static ACTIVE: [Cell<usize>; NESTING] = [const { Cell::new(0) }; NESTING];
+
+/// Counts the current thread as inside the allocator at `owner` until
+/// dropped, which also happens if the inner allocator unwinds.
+struct Enter;
+
+impl Enter {
+    fn new(owner: usize) -> Self {
+        let depth = DEPTH.get();
+        if let Some(x) = ACTIVE.get(depth as usize) {
+            x.set(owner);
+        }
+        DEPTH.set(depth + 1);
+        Self
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        DEPTH.set(DEPTH.get() - 1);
+    }
+}
+
+/// Whether the current thread is inside the allocator at `owner`.
+fn inside(owner: usize) -> bool {
+    let depth = (DEPTH.get() as usize).min(NESTING);
+    ACTIVE[..depth].iter().any(|x| x.get() == owner)
+}
+
+/// Catches `A` being called into from inside itself on the same thread,
+/// such as by a hook, by logging that allocates, or by a panic raised
+/// while one of its locks is held. Left alone, that deadlocks on the lock
+/// or recurses until the stack overflows.
+///
+/// A re-entrant allocation is served from the emergency reserve, if one was
+/// given with [`NoReentry::with_reserve`] and it has room, and otherwise
+/// aborts the process with a message on standard error. A re-entrant free
+/// is leaked, since `A` may be in the middle of updating what it would
+/// touch. Allocations from the reserve are never reused.
+///
+/// Only calls back into the same instance count: `A` may itself call into
+/// another `NoReentry`, such as one around its parent.
+pub(crate) struct NoReentry<'r, A> {
+    inner: A,
+    reserve: Option<&'r Reserve>,
+}
+
+impl<'r, A> NoReentry<'r, A> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            reserve: None,
+        }
+    }
+
+    /// Serves re-entrant allocations from `reserve`, such as one made with
+    /// [`Mmap::signal_safe`](crate::mmap::Mmap::signal_safe), which needs no
+    /// locks.
+    pub(crate) const fn with_reserve(mut self, reserve: &'r Reserve) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Identifies this allocator while it is borrowed, which it is for the
+    /// whole of any call into it.
+    #[inline]
+    fn owner(&self) -> usize {
+        (self as *const Self).addr()
+    }
+}
+
+/// Whether the current thread is inside any [`NoReentry`] allocator, so
+/// that hooks can avoid work that allocates.
+#[inline]
+pub(crate) fn in_allocator() -> bool {
+    DEPTH.get() != 0
+}
+
+#[cold]
+fn abort(layout: Layout) -> ! {
+    // SAFETY: Standard error stays open for the life of the process, or
+    // the write fails harmlessly.
+    let stderr = unsafe { BorrowedFd::borrow_raw(2) };
+    crash::emit(stderr, |buf| {
+        writeln!(
+            buf,
+            "moz: allocator re-entered for {} bytes with no emergency reserve left, aborting",
+            layout.size()
+        )
+    });
+    #[cfg(feature = "std")]
+    std::process::abort();
+    // Without `std`, panicking from inside the allocator re-enters it, and
+    // a panic during a panic aborts.
+    #[cfg(not(feature = "std"))]
+    panic!("allocator re-entered");
+}
+
+impl<A: Alloc> Alloc for NoReentry<'_, A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        if inside(self.owner()) {
+            return match self.reserve.map(|x| x.alloc(layout)) {
+                Some(Ok(tag)) => Ok(tag),
+                _ => abort(layout),
+            };
+        }
+        let _enter = Enter::new(self.owner());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        if self.reserve.is_some_and(|x| x.owns(tag.ptr())) || inside(self.owner()) {
+            return;
+        }
+        let _enter = Enter::new(self.owner());
+        unsafe { self.inner.free(tag) }
+    }
+}
@@ -0,0 +1,550 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    cell::Cell,
+    fmt,
+    marker::PhantomPinned,
+    mem,
+    pin::Pin,
+    ptr::NonNull,
+    slice,
+};
+
+use rustix::{fd::BorrowedFd, io::Errno};
+
+use crate::{
+    base::Base,
+    brk::Brk,
+    core::{Alloc, Grind, GrindReport, Tag},
+    mmap::Mmap,
+    stats::{Snapshot, Stats, Syscall},
+    sync::SpinLock,
+};
+
+/// A heap that process-wide operations can reach through the registry.
+pub(crate) trait Member: Sync {
+    /// A short name for reports.
+    fn name(&self) -> &'static str;
+
+    /// The heap's counters, if it keeps any.
+    fn stats(&self) -> Option<&Stats> {
+        None
+    }
+
+    /// Releases what memory the heap can spare, as [`Grind::grind`] does.
+    fn grind(&self) -> GrindReport {
+        GrindReport::default()
+    }
+
+    /// Like [`Member::grind`], but also unmaps memory kept for reuse until at
+    /// most `keep` bytes of it remain.
+    fn trim(&self, keep: usize) -> GrindReport {
+        self.grind()
+    }
+
+    /// Shrinks the heap right before the process is checkpointed, as
+    /// [`Grind::compact`] does.
+    fn pre_dump(&self) -> GrindReport {
+        self.grind()
+    }
+
+    /// Checks that the heap came through a restore from a checkpoint intact,
+    /// and redoes what the checkpoint did not carry over. Returns an error
+    /// if the heap cannot be used as restored.
+    fn post_restore(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Describes the heap's live allocations in more detail than its stats,
+    /// such as by call site or category, if it keeps track of them.
+    fn leaks(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        Ok(())
+    }
+
+    /// Explains a fault at `addr` if it lies in memory the heap manages but
+    /// has left inaccessible. This may be called from a signal handler, so
+    /// it must not allocate or block.
+    fn fault(&self, addr: usize) -> Option<Fault> {
+        None
+    }
+
+    /// Calls `f` on each region of address space the heap has mapped, in no
+    /// particular order, so that tools can draw the heap's address space and
+    /// spot leaked virtual memory. `f` may be called with heap locks held,
+    /// so it must not allocate through the heap.
+    fn regions(&self, f: &mut dyn FnMut(Region)) {}
+}
+
+/// What a [`Region`] of a heap's address space is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RegionState {
+    /// Handed out, or holding the heap's own metadata.
+    Active,
+    /// Free, but possibly still resident.
+    Dirty,
+    /// Free and purged, kept mapped for reuse.
+    Retained,
+    /// Reserved address space that has not been committed.
+    Reserved,
+    /// An inaccessible guard page.
+    Guard,
+    /// A freed allocation held inaccessible in quarantine.
+    Quarantined,
+}
+
+impl RegionState {
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            RegionState::Active => "active",
+            RegionState::Dirty => "dirty",
+            RegionState::Retained => "retained",
+            RegionState::Reserved => "reserved",
+            RegionState::Guard => "guard",
+            RegionState::Quarantined => "quarantined",
+        }
+    }
+}
+
+/// A run of address space reported by [`Member::regions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub(crate) addr: usize,
+    pub(crate) len: usize,
+    pub(crate) state: RegionState,
+}
+
+/// One `start-end len state` line, in the spirit of `/proc/self/maps`.
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let end = self.addr + self.len;
+        write!(
+            f,
+            "{:012x}-{end:012x} {:>15} {}",
+            self.addr,
+            self.len,
+            self.state.name()
+        )
+    }
+}
+
+/// What a faulting address was, as far as the heap owning it knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FaultKind {
+    /// A guard page next to an allocation.
+    Guard,
+    /// Reserved address space that has not been committed.
+    Uncommitted,
+    /// A freed allocation held inaccessible in quarantine.
+    Quarantined,
+}
+
+/// A fault explained by [`Member::fault`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Fault {
+    pub(crate) kind: FaultKind,
+    /// The region the address lies in: the allocation a guard protects, or
+    /// the reservation.
+    pub(crate) base: usize,
+    pub(crate) len: usize,
+    pub(crate) addr: usize,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            FaultKind::Guard => "guard page of the allocation",
+            FaultKind::Uncommitted => "uncommitted part of the reservation",
+            FaultKind::Quarantined => "quarantined freed allocation",
+        };
+        let end = self.base + self.len;
+        write!(
+            f,
+            "{:#x} is in the {what} at {:#x}..{end:#x}",
+            self.addr, self.base
+        )
+    }
+}
+
+impl Member for Mmap {
+    fn name(&self) -> &'static str {
+        "mmap"
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        Some(Mmap::stats(self))
+    }
+
+    fn grind(&self) -> GrindReport {
+        Grind::grind(self)
+    }
+
+    fn trim(&self, keep: usize) -> GrindReport {
+        self.trim_retained(keep)
+    }
+
+    fn pre_dump(&self) -> GrindReport {
+        Grind::compact(self)
+    }
+
+    /// Only retained and quarantined extents: the backend does not keep track of live
+    /// allocations. Wrap it in a `Tracked` to list those as well.
+    fn regions(&self, f: &mut dyn FnMut(Region)) {
+        Mmap::regions(self, f)
+    }
+
+    fn fault(&self, addr: usize) -> Option<Fault> {
+        Mmap::fault(self, addr)
+    }
+}
+
+impl Member for Brk {
+    fn name(&self) -> &'static str {
+        "brk"
+    }
+
+    fn grind(&self) -> GrindReport {
+        Grind::grind(self)
+    }
+
+    fn fault(&self, addr: usize) -> Option<Fault> {
+        let base = self.base().addr().get();
+        let offset = addr.checked_sub(base).filter(|&x| x < self.capacity())?;
+        // The lock may be held by the thread that faulted.
+        let committed = self.try_committed()?;
+        (offset >= committed).then_some(Fault {
+            kind: FaultKind::Uncommitted,
+            base,
+            len: self.capacity(),
+            addr,
+        })
+    }
+
+    fn regions(&self, f: &mut dyn FnMut(Region)) {
+        Brk::regions(self, f)
+    }
+}
+
+impl Member for Base {
+    fn name(&self) -> &'static str {
+        "base"
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        Some(Base::stats(self))
+    }
+
+    fn regions(&self, f: &mut dyn FnMut(Region)) {
+        Base::regions(self, f)
+    }
+}
+
+/// The link embedded in every [`Registered`] heap.
+struct Link {
+    next: Cell<Option<NonNull<Link>>>,
+    member: Cell<NonNull<dyn Member>>,
+}
+
+struct List {
+    head: Option<NonNull<Link>>,
+}
+
+// SAFETY: The links belong to pinned `Registered` values, which unlink
+// themselves with the lock held before they are dropped, and members are
+// `Sync`.
+unsafe impl Send for List {}
+
+static REGISTRY: SpinLock<List> = SpinLock::new(List { head: None });
+
+/// A heap that can be added to the process-wide registry, so that stats
+/// aggregation, purging under memory pressure and crash dumps can reach it
+/// without the application wiring heaps together.
+///
+/// The registry is an intrusive list: each registered heap holds its own
+/// link, so registering never allocates. A heap must be pinned to be
+/// registered, and removes itself when dropped.
+pub(crate) struct Registered<A> {
+    inner: A,
+    link: Link,
+    _pin: PhantomPinned,
+}
+
+// SAFETY: The link is only read or written with the registry locked.
+unsafe impl<A: Send> Send for Registered<A> {}
+unsafe impl<A: Sync> Sync for Registered<A> {}
+
+impl<A: Member + 'static> Registered<A> {
+    pub(crate) fn new(inner: A) -> Self {
+        Self {
+            inner,
+            link: Link {
+                next: Cell::new(None),
+                member: Cell::new(NonNull::<A>::dangling()),
+            },
+            _pin: PhantomPinned,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Adds the heap to the registry, if it is not there already.
+    pub(crate) fn register(self: Pin<&Self>) {
+        let mut list = REGISTRY.lock();
+        let link = NonNull::from(&self.link);
+        if contains(&list, link) {
+            return;
+        }
+        // The heap is pinned, so these addresses stay valid until it is
+        // dropped, which unlinks it first.
+        self.link.member.set(NonNull::from(&self.inner));
+        self.link.next.set(list.head);
+        list.head = Some(link);
+    }
+}
+
+impl<A> Drop for Registered<A> {
+    fn drop(&mut self) {
+        let mut list = REGISTRY.lock();
+        let link = NonNull::from(&self.link);
+        if list.head == Some(link) {
+            list.head = self.link.next.get();
+            return;
+        }
+        let mut prev = list.head;
+        while let Some(p) = prev {
+            // SAFETY: Every link in the list belongs to a live heap.
+            let p = unsafe { p.as_ref() };
+            if p.next.get() == Some(link) {
+                p.next.set(self.link.next.get());
+                return;
+            }
+            prev = p.next.get();
+        }
+    }
+}
+
+impl<A: Alloc> Alloc for Registered<A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        unsafe { self.inner.free(tag) }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.inner.grow(tag, new) }
+    }
+
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        unsafe { self.inner.shrink(tag, new) }
+    }
+}
+
+fn contains(list: &List, link: NonNull<Link>) -> bool {
+    let mut next = list.head;
+    while let Some(l) = next {
+        if l == link {
+            return true;
+        }
+        // SAFETY: Every link in the list belongs to a live heap.
+        next = unsafe { l.as_ref() }.next.get();
+    }
+    false
+}
+
+/// Like [`for_each`], but returns `false` without calling `f` if the registry
+/// is locked, e.g. by the thread that is now in a signal handler.
+pub(crate) fn try_for_each(mut f: impl FnMut(&dyn Member)) -> bool {
+    let Some(list) = REGISTRY.try_lock() else {
+        return false;
+    };
+    let mut next = list.head;
+    while let Some(link) = next {
+        // SAFETY: As in `for_each`.
+        let link = unsafe { link.as_ref() };
+        f(unsafe { link.member.get().as_ref() });
+        next = link.next.get();
+    }
+    true
+}
+
+/// Calls `f` on every registered heap, most recently registered first.
+///
+/// The registry is locked while `f` runs, so `f` must not register or drop
+/// a registered heap.
+pub(crate) fn for_each(mut f: impl FnMut(&dyn Member)) {
+    let list = REGISTRY.lock();
+    let mut next = list.head;
+    while let Some(link) = next {
+        // SAFETY: Every link in the list belongs to a live, pinned heap,
+        // which cannot be dropped while we hold the lock.
+        let link = unsafe { link.as_ref() };
+        f(unsafe { link.member.get().as_ref() });
+        next = link.next.get();
+    }
+}
+
+/// Calls `f` on every region of every registered heap, with the heap's name,
+/// under the rules of [`for_each`] and [`Member::regions`].
+pub(crate) fn for_each_region(mut f: impl FnMut(&'static str, Region)) {
+    for_each(|heap| heap.regions(&mut |region| f(heap.name(), region)));
+}
+
+/// A range of address space laid out as a `struct iovec`, the form
+/// `process_madvise(2)` takes ranges in.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct IoVec {
+    pub(crate) base: usize,
+    pub(crate) len: usize,
+}
+
+/// Fills `out` with the whole pages of every dirty region of every
+/// registered heap: memory that is free but may still be resident. An
+/// external memory manager can pass them to `process_madvise(2)` with
+/// `MADV_PAGEOUT` or `MADV_COLD` to reclaim memory from many processes
+/// centrally, without their cooperation beyond this export.
+///
+/// Returns how many ranges there were, which may exceed `out.len()`, in
+/// which case the rest are left out. The kernel takes at most `IOV_MAX`
+/// ranges per call.
+pub(crate) fn reclaimable(out: &mut [IoVec]) -> usize {
+    let pagesize = rustix::param::page_size();
+    let mut n = 0;
+    for_each_region(|_, region| {
+        if region.state != RegionState::Dirty {
+            return;
+        }
+        let base = region.addr.next_multiple_of(pagesize);
+        let end = (region.addr + region.len) & !(pagesize - 1);
+        if base >= end {
+            return;
+        }
+        if let Some(slot) = out.get_mut(n) {
+            *slot = IoVec {
+                base,
+                len: end - base,
+            };
+        }
+        n += 1;
+    });
+    n
+}
+
+/// Writes the ranges [`reclaimable`] finds to `fd` as raw `struct iovec`s,
+/// such as to a pipe or socket read by the memory manager, using `buf` to
+/// collect them. Returns how many were written.
+pub(crate) fn write_reclaimable(fd: BorrowedFd<'_>, buf: &mut [IoVec]) -> Result<usize, Errno> {
+    let n = reclaimable(buf).min(buf.len());
+    // SAFETY: `IoVec` is plain old data without padding.
+    let mut bytes =
+        unsafe { slice::from_raw_parts(buf.as_ptr().cast::<u8>(), n * mem::size_of::<IoVec>()) };
+    while !bytes.is_empty() {
+        match rustix::io::write(fd, bytes) {
+            Ok(written) => bytes = &bytes[written..],
+            Err(Errno::INTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Grinds every registered heap, e.g. in response to memory pressure, and
+/// returns the combined report.
+pub(crate) fn grind_all() -> GrindReport {
+    let mut total = GrindReport::default();
+    for_each(|heap| {
+        let report = heap.grind();
+        total.purged_bytes += report.purged_bytes;
+        total.unmapped_bytes += report.unmapped_bytes;
+        total.duration_hint += report.duration_hint;
+    });
+    total
+}
+
+/// Prepares every registered heap for a checkpoint, such as by CRIU's
+/// `dump` or `pre-dump`, so that the image is as small as possible: frees
+/// every thread's cached scratch buffer, then calls each heap's
+/// [`Member::pre_dump`]. Returns the combined report.
+///
+/// Private anonymous mappings, which is all the backends make, come back
+/// from a restore as they were, protections and advice included. CRIU only
+/// restores onto a kernel with the same page size, and restores the
+/// auxiliary vector the page size is read from, so cached page sizes stay
+/// valid. Mappings of memfds and hugetlb pages, and userfaultfds, need more
+/// care: see [`Intern`], [`MirroredBuf`], [`PinnedRegion`] and [`Uffd`].
+///
+/// [`Intern`]: crate::intern::Intern
+/// [`MirroredBuf`]: crate::mirror::MirroredBuf
+/// [`PinnedRegion`]: crate::pinned::PinnedRegion
+/// [`Uffd`]: crate::uffd::Uffd
+pub(crate) fn pre_dump() -> GrindReport {
+    crate::heap::reclaim_idle_scratch(0);
+    let mut total = GrindReport::default();
+    for_each(|heap| {
+        let report = heap.pre_dump();
+        total.purged_bytes += report.purged_bytes;
+        total.unmapped_bytes += report.unmapped_bytes;
+        total.duration_hint += report.duration_hint;
+    });
+    total
+}
+
+/// Calls every registered heap's [`Member::post_restore`] once the process
+/// has been restored from a checkpoint, before it allocates again. Returns
+/// the first error, after calling the rest.
+pub(crate) fn post_restore() -> Result<(), Errno> {
+    let mut res = Ok(());
+    for_each(|heap| {
+        let r = heap.post_restore();
+        if res.is_ok() {
+            res = r;
+        }
+    });
+    res
+}
+
+/// Gives memory back to the system now, in the spirit of `malloc_trim`,
+/// such as from an admin endpoint when the operator wants the resident set
+/// size down: frees every thread's cached scratch buffer, then trims every
+/// registered heap down to `keep` bytes of memory kept for reuse each.
+pub(crate) fn trim(keep: usize) -> GrindReport {
+    crate::heap::reclaim_idle_scratch(0);
+    let mut total = GrindReport::default();
+    for_each(|heap| {
+        let report = heap.trim(keep);
+        total.purged_bytes += report.purged_bytes;
+        total.unmapped_bytes += report.unmapped_bytes;
+        total.duration_hint += report.duration_hint;
+    });
+    total
+}
+
+/// The counters of every registered heap that keeps them, added up. Peaks
+/// are the sum of each heap's peak, which bounds the combined peak from
+/// above.
+pub(crate) fn snapshot_all() -> Snapshot {
+    let mut total = Snapshot::default();
+    for_each(|heap| {
+        let Some(stats) = heap.stats() else { return };
+        let s = stats.snapshot();
+        total.allocated += s.allocated;
+        total.allocated_peak += s.allocated_peak;
+        total.mapped += s.mapped;
+        total.mapped_peak += s.mapped_peak;
+        total.committed += s.committed;
+        total.mappings += s.mappings;
+        for syscall in Syscall::ALL {
+            total.syscalls[syscall as usize] += s.syscalls(syscall);
+        }
+    });
+    total
+}
@@ -0,0 +1,200 @@
+#![allow(unused)]
+
+use core::alloc::{AllocError, Layout};
+
+use thiserror::Error;
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+const ALLOC: u8 = 0;
+const FREE: u8 = 1;
+
+/// The longest encoding of a single event: a kind byte, two LEB128 `u64`s and
+/// one byte of `log2(align)`.
+pub(crate) const MAX_EVENT_LEN: usize = 1 + 10 + 10 + 1;
+
+/// An allocator event. Allocations are identified by their address at record
+/// time, which is unique among live allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    Alloc { id: u64, size: usize, align: usize },
+    Free { id: u64 },
+}
+
+impl Event {
+    /// Encodes `self` into `buf` and returns the encoded bytes.
+    pub(crate) fn encode<'a>(&self, buf: &'a mut [u8; MAX_EVENT_LEN]) -> &'a [u8] {
+        let n = match *self {
+            Event::Alloc { id, size, align } => {
+                buf[0] = ALLOC;
+                let mut n = 1;
+                n += put_varint(&mut buf[n..], id);
+                n += put_varint(&mut buf[n..], size as u64);
+                buf[n] = align.trailing_zeros() as u8;
+                n + 1
+            }
+            Event::Free { id } => {
+                buf[0] = FREE;
+                1 + put_varint(&mut buf[1..], id)
+            }
+        };
+        &buf[..n]
+    }
+
+    /// Decodes one event from the front of `bytes`, returning it along with
+    /// the number of bytes consumed, or `None` if `bytes` does not begin with
+    /// a complete, valid event.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (&kind, rest) = bytes.split_first()?;
+        let mut n = 1;
+        let (id, len) = get_varint(rest)?;
+        n += len;
+        match kind {
+            ALLOC => {
+                let (size, len) = get_varint(&bytes[n..])?;
+                n += len;
+                let shift = *bytes.get(n)?;
+                n += 1;
+                let size = usize::try_from(size).ok()?;
+                let align = 1usize.checked_shl(shift.into())?;
+                Some((Event::Alloc { id, size, align }, n))
+            }
+            FREE => Some((Event::Free { id }, n)),
+            _ => None,
+        }
+    }
+}
+
+fn put_varint(buf: &mut [u8], mut x: u64) -> usize {
+    let mut n = 0;
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            buf[n] = byte;
+            return n + 1;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+}
+
+fn get_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut x = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        x |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            return Some((x, i + 1));
+        }
+    }
+    None
+}
+
+/// Records every allocation and free made through `A` as a compact binary
+/// log, passing each encoded event to `sink`.
+///
+/// The sink is called with the lock held, so events arrive in the order they
+/// took effect. It must not allocate through this allocator.
+pub(crate) struct Recorder<A, F> {
+    inner: A,
+    sink: SpinLock<F>,
+}
+
+impl<A, F: FnMut(&[u8])> Recorder<A, F> {
+    pub(crate) const fn new(inner: A, sink: F) -> Self {
+        Self {
+            inner,
+            sink: SpinLock::new(sink),
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        let mut buf = [0; MAX_EVENT_LEN];
+        (self.sink.lock())(event.encode(&mut buf));
+    }
+}
+
+impl<A: Alloc, F: FnMut(&[u8])> Alloc for Recorder<A, F> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        self.emit(Event::Alloc {
+            id: tag.ptr().addr().get() as u64,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let id = tag.ptr().addr().get() as u64;
+        // Emit before freeing so that the address cannot be reused, and
+        // recorded, by another thread first.
+        self.emit(Event::Free { id });
+        unsafe { self.inner.free(tag) }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ReplayErr {
+    #[error("malformed event at offset {0}")]
+    Malformed(usize),
+    #[error("event at offset {0} refers to unknown allocation {1:#x}")]
+    UnknownId(usize, u64),
+    #[error("allocation at offset {0} failed")]
+    Alloc(usize),
+}
+
+/// Feeds a log produced by [`Recorder`] into `alloc`. Allocations still live
+/// at the end of the log are freed. Returns the number of events replayed.
+#[cfg(feature = "std")]
+pub(crate) fn replay<A: Alloc>(log: &[u8], alloc: &A) -> Result<usize, ReplayErr> {
+    use std::collections::HashMap;
+
+    let mut live = HashMap::new();
+    let res = replay_into(log, alloc, &mut live);
+    for (_, tag) in live.drain() {
+        // SAFETY: Every tag in `live` was returned by `alloc` and has not
+        // been freed.
+        unsafe { alloc.free(tag) };
+    }
+    res
+}
+
+#[cfg(feature = "std")]
+fn replay_into<A: Alloc>(
+    log: &[u8],
+    alloc: &A,
+    live: &mut std::collections::HashMap<u64, Tag>,
+) -> Result<usize, ReplayErr> {
+    let mut ost = 0;
+    let mut count = 0;
+    while ost < log.len() {
+        let (event, len) = Event::decode(&log[ost..]).ok_or(ReplayErr::Malformed(ost))?;
+        match event {
+            Event::Alloc { id, size, align } => {
+                let layout =
+                    Layout::from_size_align(size, align).map_err(|_| ReplayErr::Malformed(ost))?;
+                let tag = alloc.alloc(layout).map_err(|_| ReplayErr::Alloc(ost))?;
+                if let Some(old) = live.insert(id, tag) {
+                    // The log reused a live id, so it cannot have come from a
+                    // well-behaved `Recorder`. Don't leak the old allocation.
+                    // SAFETY: `old` was returned by `alloc` and not freed.
+                    unsafe { alloc.free(old) };
+                    return Err(ReplayErr::Malformed(ost));
+                }
+            }
+            Event::Free { id } => {
+                let tag = live.remove(&id).ok_or(ReplayErr::UnknownId(ost, id))?;
+                // SAFETY: `tag` was returned by `alloc` and removed from
+                // `live`, so it is freed exactly once.
+                unsafe { alloc.free(tag) };
+            }
+        }
+        ost += len;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,92 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use crate::core::{Alloc, Owns, Tag};
+
+/// A lock-free bump allocator over a fixed, pre-committed region.
+///
+/// Allocation is a single compare-and-swap loop: no locks, no syscalls, and
+/// no state shared with any other allocator. That makes it safe to use from
+/// signal handlers, e.g. for crash reporters capturing state. Memory is
+/// never reused; `free` is a no-op. Use [`Mmap::signal_safe`] to obtain one.
+///
+/// [`Mmap::signal_safe`]: crate::mmap::Mmap::signal_safe
+pub(crate) struct Reserve {
+    base: NonNull<u8>,
+    cap: usize,
+    used: AtomicUsize,
+}
+
+// SAFETY: The region is only handed out in disjoint pieces, claimed with
+// atomic operations.
+unsafe impl Send for Reserve {}
+unsafe impl Sync for Reserve {}
+
+impl Reserve {
+    /// # SAFETY
+    ///
+    /// `base` must be valid for reads and writes of `cap` bytes for the
+    /// lifetime of the returned value, and the memory must be committed, so
+    /// that touching it cannot fault.
+    pub(crate) const unsafe fn new(base: NonNull<u8>, cap: usize) -> Self {
+        Self {
+            base,
+            cap,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn base(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Bytes handed out so far, including alignment padding.
+    #[inline]
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Relaxed)
+    }
+}
+
+impl Owns for Reserve {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let base = self.base.addr().get();
+        (base..base + self.cap).contains(&ptr.addr().get())
+    }
+}
+
+impl Alloc for Reserve {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let base = self.base.addr().get();
+        let mut used = self.used.load(Relaxed);
+        loop {
+            let start = base
+                .checked_add(used)
+                .and_then(|x| x.checked_next_multiple_of(layout.align()))
+                .ok_or(AllocError)?
+                - base;
+            let end = start.checked_add(layout.size()).ok_or(AllocError)?;
+            if end > self.cap {
+                return Err(AllocError);
+            }
+            match self.used.compare_exchange_weak(used, end, Relaxed, Relaxed) {
+                // SAFETY: `start <= end <= cap` and the range was claimed by
+                // the successful exchange, so no one else will hand it out.
+                Ok(_) => return Ok(unsafe { Tag::new(self.base.add(start), layout) }),
+                Err(x) => used = x,
+            }
+        }
+    }
+
+    unsafe fn free(&self, tag: Tag) {}
+}
@@ -0,0 +1,215 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    fmt,
+    panic::Location,
+    ptr,
+    sync::atomic::{
+        AtomicPtr, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::thread_id,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Alloc,
+    Free,
+}
+
+/// One slot of the ring. `seq` is zero while the slot is being written, and
+/// otherwise one more than the index of the event it holds, with the low bit
+/// of the shifted value recording the kind.
+struct Slot {
+    seq: AtomicUsize,
+    addr: AtomicUsize,
+    size: AtomicUsize,
+    thread: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            addr: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            thread: AtomicUsize::new(0),
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// A recorded event, as read back from an [`EventRing`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Event {
+    pub(crate) index: usize,
+    pub(crate) kind: Kind,
+    pub(crate) addr: usize,
+    pub(crate) size: usize,
+    pub(crate) thread: usize,
+    pub(crate) site: Option<&'static Location<'static>>,
+}
+
+/// A lock-free ring of the last `N` allocation and free events.
+///
+/// Writers claim a slot with a single `fetch_add` and never wait. Readers
+/// skip slots that are being overwritten while they read, so a dump is a
+/// best-effort view that never blocks the heap. This keeps enough history to
+/// answer "what was allocated just before the corruption" without the cost
+/// of full tracing.
+pub(crate) struct EventRing<const N: usize> {
+    next: AtomicUsize,
+    slots: [Slot; N],
+}
+
+impl<const N: usize> EventRing<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        kind: Kind,
+        addr: usize,
+        size: usize,
+        site: Option<&'static Location<'static>>,
+    ) {
+        if N == 0 {
+            return;
+        }
+        let index = self.next.fetch_add(1, Relaxed);
+        let slot = &self.slots[index % N];
+        slot.seq.store(0, Relaxed);
+        // Order the invalidation before the field writes below.
+        core::sync::atomic::fence(Release);
+        slot.addr.store(addr, Relaxed);
+        slot.size.store(size, Relaxed);
+        slot.thread.store(thread_id(), Relaxed);
+        let site = site.map_or(ptr::null_mut(), |x| ptr::from_ref(x).cast_mut());
+        slot.site.store(site, Relaxed);
+        slot.seq.store(encode(index, kind), Release);
+    }
+
+    /// Calls `f` on each event still in the ring, oldest first.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(Event)) {
+        let end = self.next.load(Acquire);
+        for index in end.saturating_sub(N)..end {
+            let slot = &self.slots[index % N];
+            let seq = slot.seq.load(Acquire);
+            let Some(kind) = decode(seq, index) else {
+                continue;
+            };
+            let addr = slot.addr.load(Relaxed);
+            let size = slot.size.load(Relaxed);
+            let thread = slot.thread.load(Relaxed);
+            let site = slot.site.load(Relaxed);
+            // Discard the event if a writer started overwriting the slot
+            // while we were reading it.
+            core::sync::atomic::fence(Acquire);
+            if slot.seq.load(Relaxed) != seq {
+                continue;
+            }
+            f(Event {
+                index,
+                kind,
+                addr,
+                size,
+                thread,
+                // SAFETY: Non-null sites are `&'static Location`s.
+                site: unsafe { site.as_ref() },
+            });
+        }
+    }
+}
+
+fn encode(index: usize, kind: Kind) -> usize {
+    let seq = index.wrapping_add(1) << 1;
+    seq | (kind == Kind::Free) as usize
+}
+
+fn decode(seq: usize, index: usize) -> Option<Kind> {
+    if seq >> 1 != index.wrapping_add(1) & (usize::MAX >> 1) {
+        return None;
+    }
+    Some(if seq & 1 == 0 {
+        Kind::Alloc
+    } else {
+        Kind::Free
+    })
+}
+
+/// One event per line: `index kind addr size thread site`.
+impl<const N: usize> fmt::Display for EventRing<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = Ok(());
+        self.for_each(|e| {
+            if res.is_err() {
+                return;
+            }
+            let kind = match e.kind {
+                Kind::Alloc => "alloc",
+                Kind::Free => "free",
+            };
+            res = write!(
+                f,
+                "{} {kind} {:#x} {} {:#x}",
+                e.index, e.addr, e.size, e.thread
+            );
+            res = res.and_then(|_| match e.site {
+                Some(site) => writeln!(f, " {site}"),
+                None => writeln!(f, " -"),
+            });
+        });
+        res
+    }
+}
+
+/// Records every allocation and free made through `A` in an [`EventRing`].
+pub(crate) struct Recent<A, const N: usize = 256> {
+    inner: A,
+    ring: EventRing<N>,
+}
+
+impl<A, const N: usize> Recent<A, N> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            ring: EventRing::new(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn ring(&self) -> &EventRing<N> {
+        &self.ring
+    }
+}
+
+impl<A: Alloc, const N: usize> Alloc for Recent<A, N> {
+    #[track_caller]
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        let (addr, size) = (tag.ptr().addr().get(), tag.layout().size());
+        self.ring
+            .record(Kind::Alloc, addr, size, Some(Location::caller()));
+        Ok(tag)
+    }
+
+    #[track_caller]
+    unsafe fn free(&self, tag: Tag) {
+        let (addr, size) = (tag.ptr().addr().get(), tag.layout().size());
+        // Record before freeing, so that the free is ordered before any
+        // reuse of the address.
+        self.ring
+            .record(Kind::Free, addr, size, Some(Location::caller()));
+        unsafe { self.inner.free(tag) }
+    }
+}
@@ -0,0 +1,77 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicIsize, AtomicU64, Ordering::Relaxed};
+
+/// Decides which allocations to sample, on average one per `interval` bytes
+/// allocated.
+///
+/// Sampling by bytes rather than by count makes large allocations
+/// proportionally more likely to be seen. Each interval is jittered
+/// uniformly within `[interval / 2, 3 * interval / 2)` to avoid aliasing
+/// with periodic allocation patterns. Concurrent callers may occasionally
+/// both observe a crossing or skip one; the sampler only needs to be right on
+/// average.
+pub(crate) struct Sampler {
+    interval: usize,
+    remaining: AtomicIsize,
+    seed: AtomicU64,
+}
+
+impl Sampler {
+    /// Returns a sampler that samples on average once every `interval`
+    /// bytes. An `interval` of zero samples every allocation.
+    pub(crate) const fn new(interval: usize, seed: u64) -> Self {
+        // xorshift has a fixed point at zero.
+        let seed = if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        };
+        let interval = if interval > isize::MAX as usize {
+            isize::MAX as usize
+        } else {
+            interval
+        };
+        Self {
+            interval,
+            remaining: AtomicIsize::new(interval as isize),
+            seed: AtomicU64::new(seed),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// Accounts for an allocation of `size` bytes and returns whether it
+    /// should be sampled.
+    #[inline]
+    pub(crate) fn sample(&self, size: usize) -> bool {
+        if self.interval == 0 {
+            return true;
+        }
+        // Layout sizes never exceed `isize::MAX`.
+        let size = size as isize;
+        let prev = self.remaining.fetch_sub(size, Relaxed);
+        if prev > 0 && prev <= size {
+            self.remaining.store(self.next_interval(), Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_interval(&self) -> isize {
+        let mut x = self.seed.load(Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.store(x, Relaxed);
+        let half = self.interval / 2;
+        // `half + (x % interval) < 3 * isize::MAX / 2 + 1`, which does not
+        // overflow `usize`. Clamp back into `isize` for the countdown.
+        let next = half + (x % self.interval as u64) as usize;
+        next.min(isize::MAX as usize).max(1) as isize
+    }
+}
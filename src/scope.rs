@@ -0,0 +1,284 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+/// Written at the start of every chunk, linking the chunks of a scope.
+struct Chunk {
+    next: Option<NonNull<Chunk>>,
+    layout: Layout,
+    /// The user bits of the parent's tag, restored when the chunk is freed.
+    user: u32,
+}
+
+const HEADER: usize = mem::size_of::<Chunk>();
+
+struct State {
+    /// The most recently allocated chunk.
+    head: Option<NonNull<Chunk>>,
+    /// Bytes of `head` in use, including the header.
+    used: usize,
+    /// Whether the bytes of `head` past `used` are known to be zero.
+    zeroed: bool,
+}
+
+// SAFETY: The chunks are owned by the scope and only accessed with the lock
+// held or through `&mut Scope`.
+unsafe impl Send for State {}
+
+/// A bump region for allocations that die together, such as those made while
+/// serving one request.
+///
+/// Handlers share the scope by reference. Allocations are carved from chunks
+/// of `chunk_size` bytes obtained from the parent, `free` is a no-op, and all
+/// chunks are returned to the parent when the scope is dropped. Allocations
+/// that must outlive the scope should be made with [`Scope::parent`], or
+/// copied out with [`Scope::promote`].
+pub(crate) struct Scope<'p, A: Alloc> {
+    parent: &'p A,
+    chunk_size: usize,
+    state: SpinLock<State>,
+}
+
+impl<'p, A: Alloc> Scope<'p, A> {
+    pub(crate) const fn new(parent: &'p A, chunk_size: usize) -> Self {
+        Self {
+            parent,
+            chunk_size,
+            state: SpinLock::new(State {
+                head: None,
+                used: 0,
+                zeroed: false,
+            }),
+        }
+    }
+
+    /// The allocator that outlives this scope.
+    #[inline]
+    pub(crate) fn parent(&self) -> &'p A {
+        self.parent
+    }
+
+    /// Copies a scoped allocation into the parent so that it outlives the
+    /// scope. The scoped copy is released with the rest of the scope.
+    ///
+    /// # SAFETY
+    ///
+    /// `tag` must have been returned by `self.alloc`.
+    pub(crate) unsafe fn promote(&self, tag: Tag) -> Result<Tag, AllocError> {
+        let new = self.parent.alloc(tag.layout())?;
+        // SAFETY: Both allocations are valid for `tag.layout().size()` bytes,
+        // and they cannot overlap since `tag` lives in a chunk owned by this
+        // scope while `new` was just handed out by the parent.
+        unsafe {
+            ptr::copy_nonoverlapping(tag.ptr().as_ptr(), new.ptr().as_ptr(), tag.layout().size())
+        };
+        Ok(new)
+    }
+
+    /// Tries to carve `layout` out of the current chunk.
+    fn bump(state: &mut State, layout: Layout) -> Option<NonNull<u8>> {
+        let head = state.head?;
+        // SAFETY: `head` points to the header of a live chunk.
+        let cap = unsafe { head.as_ref() }.layout.size();
+        let base = head.cast::<u8>();
+        let start = base
+            .addr()
+            .get()
+            .checked_add(state.used)?
+            .checked_next_multiple_of(layout.align())?
+            - base.addr().get();
+        let end = start.checked_add(layout.size())?;
+        if end > cap {
+            return None;
+        }
+        state.used = end;
+        // SAFETY: `start <= end <= cap`, and the chunk is valid for `cap`
+        // bytes.
+        Some(unsafe { base.add(start) })
+    }
+
+    /// Carves `layout` out of the current chunk, or a new one if it does not
+    /// fit. A new chunk is zeroed by the parent if `zero` is set. Returns
+    /// whether the memory is known to be zero.
+    fn carve(&self, layout: Layout, zero: bool) -> Result<(NonNull<u8>, bool), AllocError> {
+        let mut state = self.state.lock();
+        let ptr = match Self::bump(&mut state, layout) {
+            Some(ptr) => ptr,
+            None => {
+                self.grow(&mut state, layout, zero)?;
+                Self::bump(&mut state, layout).ok_or(AllocError)?
+            }
+        };
+        Ok((ptr, state.zeroed))
+    }
+
+    /// Allocates a new chunk large enough for `layout` and makes it current.
+    fn grow(&self, state: &mut State, layout: Layout, zero: bool) -> Result<(), AllocError> {
+        let (chunk, _) = Layout::new::<Chunk>()
+            .extend(layout)
+            .map_err(|_| AllocError)?;
+        let size = chunk.size().max(self.chunk_size);
+        let chunk = Layout::from_size_align(size, chunk.align()).map_err(|_| AllocError)?;
+        let tag = if zero {
+            self.parent.alloc_zeroed(chunk)?
+        } else {
+            self.parent.alloc(chunk)?
+        };
+        let head = tag.ptr().cast::<Chunk>();
+        // SAFETY: The chunk is valid for at least `HEADER` bytes and aligned
+        // for `Chunk`.
+        unsafe {
+            head.write(Chunk {
+                next: state.head,
+                layout: tag.layout(),
+                user: tag.user(),
+            })
+        };
+        state.head = Some(head);
+        state.used = HEADER;
+        state.zeroed = zero;
+        Ok(())
+    }
+
+    /// Extends `tag` to `new` in place, if it is the last allocation in the
+    /// current chunk and the chunk has room.
+    fn extend(&self, tag: &Tag, new: Layout) -> Option<Tag> {
+        if !tag.ptr().is_aligned_to(new.align()) {
+            return None;
+        }
+        let mut state = self.state.lock();
+        let head = state.head?;
+        // SAFETY: `head` points to the header of a live chunk.
+        let cap = unsafe { head.as_ref() }.layout.size();
+        let start = tag.ptr().addr().get().checked_sub(head.addr().get())?;
+        if start.checked_add(tag.layout().size()) != Some(state.used) {
+            return None;
+        }
+        let end = start.checked_add(new.size()).filter(|&x| x <= cap)?;
+        state.used = end;
+        // SAFETY: The block is aligned to `new.align()`, and the chunk is
+        // valid for `cap` bytes, past the end of the grown block.
+        Some(unsafe { Tag::new(tag.ptr(), new) })
+    }
+}
+
+impl<A: Alloc> Alloc for Scope<'_, A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let (ptr, _) = self.carve(layout, false)?;
+        // SAFETY: `carve` returns a pointer aligned to `layout.align()` and
+        // valid for `layout.size()` bytes.
+        Ok(unsafe { Tag::new(ptr, layout) })
+    }
+
+    /// Scoped allocations are released all at once when the scope is dropped.
+    unsafe fn free(&self, tag: Tag) {}
+
+    /// Chunks taken for zeroed allocations come zeroed from the parent, and
+    /// the rest of such a chunk is handed out without zeroing it again.
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let (ptr, zeroed) = self.carve(layout, true)?;
+        if !zeroed {
+            // SAFETY: As in `alloc`.
+            unsafe { ptr.write_bytes(0, layout.size()) };
+        }
+        // SAFETY: As in `alloc`.
+        Ok(unsafe { Tag::new(ptr, layout) })
+    }
+
+    /// The last allocation grows in place while its chunk has room. Others
+    /// are copied, and the old block is released with the scope.
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if let Some(grown) = self.extend(tag, new) {
+            return Ok(grown);
+        }
+        let moved = self.alloc(new)?;
+        // SAFETY: Both allocations are valid for the old size and distinct.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                tag.ptr().as_ptr(),
+                moved.ptr().as_ptr(),
+                tag.layout().size(),
+            )
+        };
+        Ok(moved)
+    }
+
+    /// Shrinks in place when aligned, as the tail is released with the scope
+    /// anyway.
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        if tag.ptr().is_aligned_to(new.align()) {
+            // SAFETY: The block is valid for at least `new.size()` bytes and
+            // aligned to `new.align()`.
+            return Ok(unsafe { Tag::new(tag.ptr(), new) });
+        }
+        let moved = self.alloc(new)?;
+        // SAFETY: Both allocations are valid for `new.size()` bytes and
+        // distinct.
+        unsafe { ptr::copy_nonoverlapping(tag.ptr().as_ptr(), moved.ptr().as_ptr(), new.size()) };
+        Ok(moved)
+    }
+}
+
+impl<A: Alloc> Drop for Scope<'_, A> {
+    fn drop(&mut self) {
+        let mut next = self.state.get_mut().head.take();
+        while let Some(chunk) = next {
+            // SAFETY: Every chunk in the list is live and starts with its
+            // header.
+            let Chunk {
+                next: n,
+                layout,
+                user,
+            } = unsafe { chunk.read() };
+            next = n;
+            // SAFETY: `chunk`, `layout` and `user` describe a tag returned by
+            // the parent, which is freed exactly once here.
+            unsafe {
+                self.parent
+                    .free(Tag::new(chunk.cast(), layout).with_user(user))
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::Mmap;
+
+    #[test]
+    fn grow_last_in_place() {
+        let parent = Mmap::new();
+        let scope = Scope::new(&parent, 1 << 16);
+        let a = scope.alloc(Layout::new::<[u64; 8]>()).unwrap();
+        let b = unsafe { Alloc::grow(&scope, &a, Layout::new::<[u64; 64]>()) }.unwrap();
+        assert_eq!(a.ptr(), b.ptr());
+        let c = scope.alloc(Layout::new::<u64>()).unwrap();
+        // `b` is no longer last, so it moves.
+        let d = unsafe { Alloc::grow(&scope, &b, Layout::new::<[u64; 128]>()) }.unwrap();
+        assert_ne!(b.ptr(), d.ptr());
+        assert!(d.ptr() > c.ptr());
+    }
+
+    #[test]
+    fn zeroed_chunk() {
+        let parent = Mmap::new();
+        let scope = Scope::new(&parent, 1 << 16);
+        let layout = Layout::new::<[u8; 256]>();
+        let a = scope.alloc_zeroed(layout).unwrap();
+        let b = scope.alloc_zeroed(layout).unwrap();
+        for tag in [a, b] {
+            let bytes = unsafe { core::slice::from_raw_parts(tag.ptr().as_ptr(), 256) };
+            assert!(bytes.iter().all(|&x| x == 0));
+        }
+    }
+}
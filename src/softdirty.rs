@@ -0,0 +1,96 @@
+#![allow(unused)]
+
+use core::ops::Range;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+};
+
+use crate::{
+    pagemap,
+    registry::{Member, RegionState},
+};
+
+/// The pagemap bit set for pages written since the soft-dirty bits were
+/// last cleared. See the kernel's `soft-dirty.rst`.
+const SOFT_DIRTY: u64 = 1 << 55;
+
+/// Finds the pages written since the last [`SoftDirty::clear`], from the
+/// kernel's soft-dirty bits, so that incremental checkpoints can copy only
+/// what changed without instrumenting writes.
+///
+/// The bits belong to the process, not the tracker: clearing them through
+/// any tracker, or by writing to `/proc/self/clear_refs` directly, resets
+/// what every tracker sees. Needs a kernel built with
+/// `CONFIG_MEM_SOFT_DIRTY`; without it, no page is ever reported dirty.
+pub(crate) struct SoftDirty {
+    pagemap: File,
+    pagesize: usize,
+}
+
+impl SoftDirty {
+    pub(crate) fn open() -> io::Result<Self> {
+        Ok(Self {
+            pagemap: File::open("/proc/self/pagemap")?,
+            pagesize: rustix::param::page_size(),
+        })
+    }
+
+    /// Clears the soft-dirty bit of every page in the process, starting a new
+    /// interval to track writes over.
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        // Writing 4 clears the soft-dirty bits. See proc(5).
+        OpenOptions::new()
+            .write(true)
+            .open("/proc/self/clear_refs")?
+            .write_all(b"4")
+    }
+
+    /// Calls `f` on every run of pages overlapping `range` that was written
+    /// since the last [`clear`](Self::clear), in address order.
+    pub(crate) fn dirty(
+        &self,
+        range: Range<usize>,
+        f: &mut dyn FnMut(Range<usize>),
+    ) -> io::Result<()> {
+        let pagesize = self.pagesize;
+        let mut run: Option<usize> = None;
+        pagemap::for_each_entry(
+            &self.pagemap,
+            range.clone(),
+            pagesize,
+            &mut |page, entry| match (entry & SOFT_DIRTY != 0, run) {
+                (true, None) => run = Some(page),
+                (false, Some(start)) => {
+                    f(start * pagesize..page * pagesize);
+                    run = None;
+                }
+                _ => {}
+            },
+        )?;
+        if let Some(start) = run {
+            f(start * pagesize..range.end.next_multiple_of(pagesize));
+        }
+        Ok(())
+    }
+
+    /// Like [`dirty`](Self::dirty), for every region `heap` reports as
+    /// active. Regions it keeps for reuse are not checked, since their
+    /// contents do not matter to a checkpoint.
+    ///
+    /// `f` is called with the heap's locks held, as for
+    /// [`Member::regions`], so it must not allocate through the heap.
+    pub(crate) fn dirty_regions(
+        &self,
+        heap: &dyn Member,
+        f: &mut dyn FnMut(Range<usize>),
+    ) -> io::Result<()> {
+        let mut res = Ok(());
+        heap.regions(&mut |region| {
+            if region.state == RegionState::Active && res.is_ok() {
+                res = self.dirty(region.addr..region.addr + region.len, f);
+            }
+        });
+        res
+    }
+}
@@ -0,0 +1,411 @@
+#![allow(unused)]
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use crate::sync::thread_id;
+
+/// The number of shards the allocated byte count is spread over.
+const SHARDS: usize = 16;
+
+/// How far a shard may drift from zero before it is folded into the total.
+const FOLD_BYTES: usize = 1 << 20;
+
+/// A share of the allocated byte count, as a wrapping delta from the folded
+/// total. Each shard has a cache line of its own, so threads counting
+/// allocations rarely contend.
+///
+/// Threads are hashed onto the shards, so these are not per-thread caches:
+/// threads that hash alike share a shard and update it atomically.
+#[repr(align(64))]
+struct Shard(AtomicUsize);
+
+/// The memory-management system calls counted by [`Stats`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Syscall {
+    Mmap,
+    Munmap,
+    Mprotect,
+    Madvise,
+    Mremap,
+    Mbind,
+}
+
+impl Syscall {
+    pub(crate) const ALL: [Syscall; 6] = [
+        Syscall::Mmap,
+        Syscall::Munmap,
+        Syscall::Mprotect,
+        Syscall::Madvise,
+        Syscall::Mremap,
+        Syscall::Mbind,
+    ];
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Syscall::Mmap => "mmap",
+            Syscall::Munmap => "munmap",
+            Syscall::Mprotect => "mprotect",
+            Syscall::Madvise => "madvise",
+            Syscall::Mremap => "mremap",
+            Syscall::Mbind => "mbind",
+        }
+    }
+}
+
+/// Byte and system call counters for a heap.
+///
+/// All counters are updated with relaxed atomics. They are intended for
+/// monitoring, and readers may observe values from slightly different points
+/// in time.
+///
+/// Allocations and frees, which happen on every hot path, are counted in a
+/// shard picked by hashing the current thread, and folded into the shared
+/// total lazily: when the shard drifts by more than a megabyte, or on
+/// [`Stats::fold`]. Reads add up the shards. The allocated peak is only raised when shards are folded, so
+/// it may miss short spikes of up to a megabyte per shard.
+pub(crate) struct Stats {
+    allocated: AtomicUsize,
+    shards: [Shard; SHARDS],
+    allocated_peak: AtomicUsize,
+    mapped: AtomicUsize,
+    mapped_peak: AtomicUsize,
+    committed: AtomicUsize,
+    mappings: AtomicUsize,
+    syscalls: [AtomicUsize; Syscall::ALL.len()],
+    #[cfg(feature = "std")]
+    rss: AtomicUsize,
+}
+
+impl Stats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            allocated: AtomicUsize::new(0),
+            shards: [const { Shard(AtomicUsize::new(0)) }; SHARDS],
+            allocated_peak: AtomicUsize::new(0),
+            mapped: AtomicUsize::new(0),
+            mapped_peak: AtomicUsize::new(0),
+            committed: AtomicUsize::new(0),
+            mappings: AtomicUsize::new(0),
+            syscalls: [const { AtomicUsize::new(0) }; Syscall::ALL.len()],
+            #[cfg(feature = "std")]
+            rss: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes currently handed out to callers.
+    #[inline]
+    pub(crate) fn allocated(&self) -> usize {
+        let n = self
+            .shards
+            .iter()
+            .fold(self.allocated.load(Relaxed), |n, x| {
+                n.wrapping_add(x.0.load(Relaxed))
+            });
+        // A shard of net frees being folded may briefly take the sum below
+        // zero.
+        n.cast_signed().max(0).cast_unsigned()
+    }
+
+    /// The largest value `allocated` has reached.
+    #[inline]
+    pub(crate) fn allocated_peak(&self) -> usize {
+        self.allocated_peak.load(Relaxed)
+    }
+
+    /// Bytes currently mapped from the OS.
+    #[inline]
+    pub(crate) fn mapped(&self) -> usize {
+        self.mapped.load(Relaxed)
+    }
+
+    /// The largest value `mapped` has reached.
+    #[inline]
+    pub(crate) fn mapped_peak(&self) -> usize {
+        self.mapped_peak.load(Relaxed)
+    }
+
+    /// Bytes of mapped memory charged against the system's commit limit:
+    /// writable private mappings not made with `MAP_NORESERVE`. The rest of
+    /// [`Stats::mapped`] is address space that is only reserved. Purging
+    /// pages does not uncommit them.
+    #[inline]
+    pub(crate) fn committed(&self) -> usize {
+        self.committed.load(Relaxed)
+    }
+
+    /// The number of distinct mappings the heap owns, which count towards
+    /// the `vm.max_map_count` limit on the process. The kernel may merge
+    /// adjacent mappings, so this bounds the number of VMAs from above.
+    #[inline]
+    pub(crate) fn mappings(&self) -> usize {
+        self.mappings.load(Relaxed)
+    }
+
+    /// The number of calls made to `syscall`, including failed ones.
+    #[inline]
+    pub(crate) fn syscalls(&self, syscall: Syscall) -> usize {
+        self.syscalls[syscall as usize].load(Relaxed)
+    }
+
+    /// Bytes mapped but not handed out to callers: external fragmentation
+    /// plus whatever memory the heap is holding on to for reuse.
+    #[inline]
+    pub(crate) fn fragmented(&self) -> usize {
+        self.mapped().saturating_sub(self.allocated())
+    }
+
+    /// [`Stats::fragmented`] as parts per thousand of mapped bytes.
+    pub(crate) fn fragmented_permille(&self) -> usize {
+        let mapped = self.mapped();
+        if mapped == 0 {
+            return 0;
+        }
+        let frag = mapped.saturating_sub(self.allocated());
+        // Compute in `u128` since `frag * 1000` can overflow `usize`.
+        (frag as u128 * 1000 / mapped as u128) as usize
+    }
+
+    /// The resident set size of the whole process as of the last call to
+    /// [`Stats::sample_rss`], or zero if it has never been sampled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub(crate) fn rss(&self) -> usize {
+        self.rss.load(Relaxed)
+    }
+
+    /// Reads every counter into a plain struct. Each counter is read once
+    /// with a relaxed load, so taking a snapshot never waits for or slows
+    /// down the allocation paths. The counters are not read together, so a
+    /// snapshot taken while the heap is busy mixes values from slightly
+    /// different points in time, and values derived from it, such as
+    /// [`Snapshot::fragmented`], are approximate.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        let allocated = self.allocated();
+        let mapped = self.mapped();
+        Snapshot {
+            allocated,
+            // A peak is raised after its counter, so it may briefly lag.
+            allocated_peak: self.allocated_peak().max(allocated),
+            mapped,
+            mapped_peak: self.mapped_peak().max(mapped),
+            committed: self.committed(),
+            mappings: self.mappings(),
+            syscalls: Syscall::ALL.map(|x| self.syscalls(x)),
+            #[cfg(feature = "std")]
+            rss: self.rss(),
+        }
+    }
+
+    /// Folds every shard into the shared total and raises the allocated
+    /// peak. Callers that want an up-to-date peak, such as a periodic
+    /// metrics scrape, call this first.
+    pub(crate) fn fold(&self) {
+        for shard in &self.shards {
+            self.fold_shard(shard);
+        }
+    }
+
+    fn fold_shard(&self, shard: &Shard) {
+        // Add to the total before taking from the shard, so that a reader
+        // in between counts a net allocation twice rather than not at all.
+        // Subtracting with `fetch_sub` rather than swapping keeps whatever
+        // the shard gained meanwhile.
+        let delta = shard.0.load(Relaxed);
+        self.allocated.fetch_add(delta, Relaxed);
+        shard.0.fetch_sub(delta, Relaxed);
+        self.allocated_peak.fetch_max(self.allocated(), Relaxed);
+    }
+
+    #[inline]
+    fn shard(&self) -> &Shard {
+        let hash = thread_id().wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize);
+        &self.shards[hash >> (usize::BITS - SHARDS.ilog2())]
+    }
+
+    /// Adds `delta` to the current thread's shard, folding it once it has
+    /// drifted far enough.
+    #[inline]
+    fn record_delta(&self, delta: usize) {
+        let shard = self.shard();
+        let now = shard.0.fetch_add(delta, Relaxed).wrapping_add(delta);
+        if now.wrapping_add(FOLD_BYTES) > 2 * FOLD_BYTES {
+            self.fold_shard(shard);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record_alloc(&self, size: usize) {
+        self.record_delta(size);
+    }
+
+    #[inline]
+    pub(crate) fn record_free(&self, size: usize) {
+        self.record_delta(size.wrapping_neg());
+    }
+
+    #[inline]
+    pub(crate) fn record_map(&self, len: usize) {
+        bump(&self.mapped, &self.mapped_peak, len);
+    }
+
+    #[inline]
+    pub(crate) fn record_unmap(&self, len: usize) {
+        self.mapped.fetch_sub(len, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn record_commit(&self, len: usize) {
+        self.committed.fetch_add(len, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn record_decommit(&self, len: usize) {
+        self.committed.fetch_sub(len, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn record_mapping(&self) {
+        self.mappings.fetch_add(1, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn record_unmapping(&self) {
+        self.mappings.fetch_sub(1, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn record_syscall(&self, syscall: Syscall) {
+        self.syscalls[syscall as usize].fetch_add(1, Relaxed);
+    }
+
+    /// Reads the resident set size of the process from `/proc/self/statm`
+    /// and stores it alongside the heap's own counters, so the two can be
+    /// compared. Returns the sampled value in bytes.
+    #[cfg(feature = "std")]
+    pub(crate) fn sample_rss(&self, pagesize: usize) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let statm = std::fs::read_to_string("/proc/self/statm")?;
+        // The second field is the number of resident pages. See proc(5).
+        let pages: usize = statm
+            .split_ascii_whitespace()
+            .nth(1)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+        let rss = pages.saturating_mul(pagesize);
+        self.rss.store(rss, Relaxed);
+        Ok(rss)
+    }
+}
+
+/// The counters of a [`Stats`] at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename = "Stats"))]
+pub(crate) struct Snapshot {
+    pub(crate) allocated: usize,
+    pub(crate) allocated_peak: usize,
+    pub(crate) mapped: usize,
+    pub(crate) mapped_peak: usize,
+    pub(crate) committed: usize,
+    pub(crate) mappings: usize,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "syscall_map"))]
+    pub(crate) syscalls: [usize; Syscall::ALL.len()],
+    #[cfg(feature = "std")]
+    pub(crate) rss: usize,
+}
+
+impl Snapshot {
+    #[inline]
+    pub(crate) fn syscalls(&self, syscall: Syscall) -> usize {
+        self.syscalls[syscall as usize]
+    }
+
+    /// See [`Stats::fragmented`].
+    #[inline]
+    pub(crate) fn fragmented(&self) -> usize {
+        self.mapped.saturating_sub(self.allocated)
+    }
+
+    /// See [`Stats::fragmented_permille`].
+    pub(crate) fn fragmented_permille(&self) -> usize {
+        if self.mapped == 0 {
+            return 0;
+        }
+        // Compute in `u128` since `frag * 1000` can overflow `usize`.
+        (self.fragmented() as u128 * 1000 / self.mapped as u128) as usize
+    }
+}
+
+/// A compact, column-formatted report in the spirit of `malloc_stats_print`.
+///
+/// ```text
+///                    current            peak
+/// allocated          1048576         2097152
+/// mapped             1052672         2101248
+/// committed          1052672               -
+/// mappings                 2               -
+/// fragmented            4096            0.3%
+/// syscalls   mmap=2 munmap=1 mprotect=0 madvise=0 mremap=0
+/// ```
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10} {:>15} {:>15}", "", "current", "peak")?;
+        let rows = [
+            ("allocated", self.allocated, self.allocated_peak),
+            ("mapped", self.mapped, self.mapped_peak),
+        ];
+        for (name, now, peak) in rows {
+            writeln!(f, "{name:<10} {now:>15} {peak:>15}")?;
+        }
+        writeln!(f, "{:<10} {:>15} {:>15}", "committed", self.committed, "-")?;
+        writeln!(f, "{:<10} {:>15} {:>15}", "mappings", self.mappings, "-")?;
+        let (frag, permille) = (self.fragmented(), self.fragmented_permille());
+        writeln!(
+            f,
+            "{:<10} {frag:>15} {:>12}.{}%",
+            "fragmented",
+            permille / 10,
+            permille % 10
+        )?;
+        #[cfg(feature = "std")]
+        writeln!(f, "{:<10} {:>15} {:>15}", "rss", self.rss, "-")?;
+        write!(f, "syscalls  ")?;
+        for syscall in Syscall::ALL {
+            write!(f, " {}={}", syscall.name(), self.syscalls(syscall))?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+// Serde implements `Serialize` for atomics only with `std`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Stats {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(s)
+    }
+}
+
+/// Serializes syscall counts as a map from name to count.
+#[cfg(feature = "serde")]
+fn syscall_map<S: serde::Serializer>(
+    syscalls: &[usize; Syscall::ALL.len()],
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_map(Syscall::ALL.map(|x| (x.name(), syscalls[x as usize])))
+}
+
+/// Adds `n` to `ctr` and raises `peak` to the new value if necessary.
+#[inline]
+fn bump(ctr: &AtomicUsize, peak: &AtomicUsize, n: usize) {
+    let now = ctr.fetch_add(n, Relaxed) + n;
+    peak.fetch_max(now, Relaxed);
+}
@@ -0,0 +1,100 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use crate::sync::SpinLock;
+
+/// The condition a [`Thresholds`] callback waits for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Crossing {
+    /// The watched value rises above the given number of bytes.
+    Above(usize),
+    /// The watched value drops below the given number of bytes.
+    Below(usize),
+}
+
+impl Crossing {
+    fn holds(self, value: usize) -> bool {
+        match self {
+            Crossing::Above(x) => value > x,
+            Crossing::Below(x) => value < x,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Watch {
+    crossing: Crossing,
+    callback: fn(usize),
+    /// Whether the condition held at the last poll. Callbacks fire on the
+    /// transition from `false` to `true`.
+    held: bool,
+}
+
+/// Up to `N` callbacks fired when a byte count crosses a threshold.
+///
+/// Each callback fires once per crossing, with the value that triggered it,
+/// and re-arms once the condition stops holding. Callbacks run on whichever
+/// thread polls, after the internal lock has been released, so they may
+/// register or unregister thresholds themselves.
+pub(crate) struct Thresholds<const N: usize> {
+    watches: SpinLock<[Option<Watch>; N]>,
+    /// The number of registered watches, so that polling is a single load
+    /// when there are none.
+    len: AtomicUsize,
+}
+
+impl<const N: usize> Thresholds<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            watches: SpinLock::new([None; N]),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Registers `callback` to fire on `crossing`, given the current `value`
+    /// of the watched count. Returns an id for [`Thresholds::unregister`], or
+    /// `None` if all `N` slots are taken.
+    pub(crate) fn register(
+        &self,
+        crossing: Crossing,
+        callback: fn(usize),
+        value: usize,
+    ) -> Option<usize> {
+        let mut watches = self.watches.lock();
+        let (id, slot) = watches.iter_mut().enumerate().find(|(_, x)| x.is_none())?;
+        *slot = Some(Watch {
+            crossing,
+            callback,
+            held: crossing.holds(value),
+        });
+        self.len.fetch_add(1, Relaxed);
+        Some(id)
+    }
+
+    pub(crate) fn unregister(&self, id: usize) {
+        if self.watches.lock()[id].take().is_some() {
+            self.len.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Evaluates every registered threshold against `value` and runs the
+    /// callbacks of those that were crossed.
+    pub(crate) fn poll(&self, value: usize) {
+        if self.len.load(Relaxed) == 0 {
+            return;
+        }
+        let mut fired: [Option<fn(usize)>; N] = [None; N];
+        {
+            let mut watches = self.watches.lock();
+            for (watch, fired) in watches.iter_mut().flatten().zip(&mut fired) {
+                let holds = watch.crossing.holds(value);
+                if holds && !watch.held {
+                    *fired = Some(watch.callback);
+                }
+                watch.held = holds;
+            }
+        }
+        fired.into_iter().flatten().for_each(|f| f(value));
+    }
+}
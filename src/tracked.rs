@@ -0,0 +1,576 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    fmt,
+    panic::Location,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use rustix::io::Errno;
+
+use crate::{
+    check::{self, Check, Violation},
+    core::{Alloc, FreeAll, GrindReport, Tag},
+    registry::{Fault, Member, Region, RegionState},
+    stats::Stats,
+    sync::SpinLock,
+};
+
+#[derive(Clone, Copy)]
+struct Live {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    /// The tag's [`Tag::user`] bits, which the inner allocator may need to
+    /// route the free.
+    user: u32,
+    site: &'static Location<'static>,
+    /// The tick at which the allocation was made, or zero without a clock.
+    born: u64,
+    /// The tick after which the allocation counts as expired, or
+    /// `u64::MAX` if it has no TTL.
+    expires: u64,
+}
+
+// SAFETY: `Live` records an allocation owned by the caller of `Tracked`. The
+// pointer is only used to free the allocation in `FreeAll::free_all`, whose
+// caller guarantees that the allocation is no longer in use on any thread.
+unsafe impl Send for Live {}
+
+impl Live {
+    #[inline]
+    fn addr(&self) -> usize {
+        self.ptr.addr().get()
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+/// Records the call site of every live allocation made through `A`.
+///
+/// Up to `N` live allocations are tracked in a fixed table, so this layer
+/// never allocates. Allocations made while the table is full still succeed,
+/// but are only counted in [`Tracked::untracked`]. Lookups are linear, so this
+/// is a debugging aid rather than something to leave on in production.
+///
+/// Growing or shrinking an allocation attributes it to the call site that
+/// resized it.
+pub(crate) struct Tracked<A, const N: usize = 1024> {
+    inner: A,
+    live: SpinLock<[Option<Live>; N]>,
+    untracked: AtomicUsize,
+    clock: Option<fn() -> u64>,
+    strict: bool,
+}
+
+impl<A, const N: usize> Tracked<A, N> {
+    pub(crate) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: SpinLock::new([None; N]),
+            untracked: AtomicUsize::new(0),
+            clock: None,
+            strict: false,
+        }
+    }
+
+    /// Panics on a free of a pointer that is not a live allocation, such as
+    /// a double free, before the inner allocator sees it. The memory is not
+    /// touched, so this works even if the inner allocator has unmapped or
+    /// protected it. While allocations made with the table full may be live,
+    /// a free of an unknown pointer is taken to be one of them.
+    pub(crate) const fn with_strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Stamps every allocation with the tick returned by `clock`, so that
+    /// [`Tracked::ages`] can tell how long live memory has been held. The
+    /// crate has no clock of its own, so the caller picks the source and
+    /// the unit, such as a monotonic clock in milliseconds or an epoch
+    /// counter.
+    pub(crate) const fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Live bytes grouped by age, or `None` if there is no clock.
+    pub(crate) fn ages(&self) -> Option<Ages> {
+        let now = self.clock?();
+        let mut ages = Ages { bytes: [0; 65] };
+        for a in self.live.lock().iter().flatten() {
+            let age = now.saturating_sub(a.born);
+            ages.bytes[(u64::BITS - age.leading_zeros()) as usize] += a.size();
+        }
+        Some(ages)
+    }
+
+    /// The number of live allocations that were not recorded because the
+    /// table was full. A free of a pointer with no record is taken to be one
+    /// of them.
+    pub(crate) fn untracked(&self) -> usize {
+        self.untracked.load(Relaxed)
+    }
+
+    /// Calls `f(site, bytes, count)` once for each call site with live
+    /// allocations.
+    pub(crate) fn for_each_site(
+        &self,
+        mut f: impl FnMut(&'static Location<'static>, usize, usize),
+    ) {
+        let live = self.live.lock();
+        for (i, a) in live.iter().enumerate() {
+            let Some(a) = a else { continue };
+            // Only report a site at its first occurrence in the table.
+            if live[..i].iter().flatten().any(|b| b.site == a.site) {
+                continue;
+            }
+            let (bytes, count) = live[i..]
+                .iter()
+                .flatten()
+                .filter(|b| b.site == a.site)
+                .fold((0, 0), |(bytes, count), b| (bytes + b.size(), count + 1));
+            f(a.site, bytes, count);
+        }
+    }
+
+    /// Returns a report of live bytes grouped by call site.
+    pub(crate) fn report(&self) -> Report<'_, A, N> {
+        Report(self)
+    }
+}
+
+/// An allocation found by [`Tracked::for_each_expired`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Expired {
+    pub(crate) ptr: NonNull<u8>,
+    pub(crate) layout: Layout,
+    pub(crate) site: &'static Location<'static>,
+    /// Ticks since the allocation expired.
+    pub(crate) overdue: u64,
+}
+
+impl<A: Alloc, const N: usize> Tracked<A, N> {
+    /// Like [`Alloc::alloc`], but the allocation expires `ttl` ticks from
+    /// now, after which [`Tracked::for_each_expired`] reports it and
+    /// [`Tracked::free_expired`] may free it. Useful for finding buffers a
+    /// long-running service forgot, or for cache entries that should not
+    /// outlive their freshness. Without a clock, nothing ever expires.
+    ///
+    /// An allocation that could not be recorded, as counted in
+    /// [`Tracked::untracked`], never expires either.
+    #[track_caller]
+    pub(crate) fn alloc_with_ttl(&self, layout: Layout, ttl: u64) -> Result<Tag, AllocError> {
+        let site = Location::caller();
+        let tag = self.inner.alloc(layout)?;
+        self.record(&tag, site, Some(ttl));
+        Ok(tag)
+    }
+
+    /// Records a new allocation made at `site`.
+    fn record(&self, tag: &Tag, site: &'static Location<'static>, ttl: Option<u64>) {
+        let born = self.clock.map_or(0, |x| x());
+        self.insert(Live {
+            ptr: tag.ptr(),
+            layout: tag.layout(),
+            user: tag.user(),
+            site,
+            born,
+            expires: match (self.clock, ttl) {
+                (Some(_), Some(ttl)) => born.saturating_add(ttl),
+                _ => u64::MAX,
+            },
+        });
+    }
+
+    /// Puts `live` in a free slot, or counts it in `untracked` if there is
+    /// none.
+    fn insert(&self, live: Live) {
+        match self.live.lock().iter_mut().find(|x| x.is_none()) {
+            Some(slot) => *slot = Some(live),
+            None => {
+                self.untracked.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    /// Takes the record of `ptr` out of the table, before the inner
+    /// allocator frees or moves it. Without a record, `ptr` is taken to be
+    /// one of the allocations counted in `untracked`, and with `strict`
+    /// there must be one left.
+    fn forget(&self, ptr: NonNull<u8>) -> Option<Live> {
+        let live = self
+            .live
+            .lock()
+            .iter_mut()
+            .find(|x| x.is_some_and(|x| x.ptr == ptr))
+            .and_then(Option::take);
+        if live.is_none() {
+            let res = self
+                .untracked
+                .fetch_update(Relaxed, Relaxed, |x| x.checked_sub(1));
+            assert!(
+                res.is_ok() || !self.strict,
+                "double free of {ptr:p}, or free of a pointer never allocated"
+            );
+        }
+        live
+    }
+
+    /// Grows or shrinks `tag` with `f`, moving its record to the new
+    /// allocation and attributing it to `site`. Its age and TTL carry over.
+    fn resize(
+        &self,
+        tag: &Tag,
+        site: &'static Location<'static>,
+        f: impl FnOnce() -> Result<Tag, AllocError>,
+    ) -> Result<Tag, AllocError> {
+        let old = self.forget(tag.ptr());
+        let moved = match f() {
+            Ok(x) => x,
+            Err(e) => {
+                match old {
+                    Some(live) => self.insert(live),
+                    None => {
+                        self.untracked.fetch_add(1, Relaxed);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        match old {
+            Some(live) => self.insert(Live {
+                ptr: moved.ptr(),
+                layout: moved.layout(),
+                user: moved.user(),
+                site,
+                ..live
+            }),
+            None => self.record(&moved, site, None),
+        }
+        Ok(moved)
+    }
+
+    /// Calls `f` on every allocation that has outlived its TTL, with the
+    /// table locked. Returns how many there were, or `None` if there is no
+    /// clock.
+    pub(crate) fn for_each_expired(&self, mut f: impl FnMut(Expired)) -> Option<usize> {
+        let now = self.clock?();
+        let mut n = 0;
+        for a in self.live.lock().iter().flatten() {
+            if now > a.expires {
+                n += 1;
+                f(Expired {
+                    ptr: a.ptr,
+                    layout: a.layout,
+                    site: a.site,
+                    overdue: now - a.expires,
+                });
+            }
+        }
+        Some(n)
+    }
+
+    /// Frees every allocation that has outlived its TTL, for cache-like
+    /// uses, calling `evict` on each first so that its owner can forget it.
+    /// `evict` runs without the table locked, so it may allocate through
+    /// this heap. Returns how many were freed, or `None` if there is no
+    /// clock.
+    ///
+    /// # SAFETY
+    ///
+    /// No allocation made with a TTL may be used once it has expired, other
+    /// than by `evict`.
+    pub(crate) unsafe fn free_expired(&self, mut evict: impl FnMut(&Tag)) -> Option<usize> {
+        let now = self.clock?();
+        let mut n = 0;
+        loop {
+            let expired = self
+                .live
+                .lock()
+                .iter_mut()
+                .find(|x| x.is_some_and(|x| now > x.expires))
+                .and_then(Option::take);
+            let Some(a) = expired else { break };
+            // SAFETY: `a` was recorded from a tag returned by `self.inner`,
+            // and removed from the table so it is freed once. The caller
+            // guarantees it is no longer in use.
+            let tag = unsafe { Tag::new(a.ptr, a.layout) }.with_user(a.user);
+            evict(&tag);
+            unsafe { self.inner.free(tag) };
+            n += 1;
+        }
+        Some(n)
+    }
+}
+
+impl<A: Alloc, const N: usize> Alloc for Tracked<A, N> {
+    #[track_caller]
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let site = Location::caller();
+        let tag = self.inner.alloc(layout)?;
+        self.record(&tag, site, None);
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        self.forget(tag.ptr());
+        unsafe { self.inner.free(tag) }
+    }
+
+    #[track_caller]
+    fn alloc_zeroed(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let site = Location::caller();
+        let tag = self.inner.alloc_zeroed(layout)?;
+        self.record(&tag, site, None);
+        Ok(tag)
+    }
+
+    #[track_caller]
+    unsafe fn grow(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        let site = Location::caller();
+        // SAFETY: The caller upholds the contract of `grow`.
+        self.resize(tag, site, || unsafe { self.inner.grow(tag, new) })
+    }
+
+    #[track_caller]
+    unsafe fn shrink(&self, tag: &Tag, new: Layout) -> Result<Tag, AllocError> {
+        let site = Location::caller();
+        // SAFETY: The caller upholds the contract of `shrink`.
+        self.resize(tag, site, || unsafe { self.inner.shrink(tag, new) })
+    }
+}
+
+impl<A: Alloc + FreeAll, const N: usize> FreeAll for Tracked<A, N> {
+    /// Frees every tracked allocation, then everything held by the inner
+    /// allocator. Allocations counted in [`Tracked::untracked`] are not known
+    /// to this layer and leak unless the inner allocator frees them.
+    unsafe fn free_all(&self) {
+        loop {
+            let live = self.live.lock().iter_mut().find_map(Option::take);
+            let Some(live) = live else { break };
+            // SAFETY: `live` was recorded from a tag returned by
+            // `self.inner`, and removed from the table so it is freed once.
+            // The caller guarantees that it is no longer in use.
+            let tag = unsafe { Tag::new(live.ptr, live.layout) }.with_user(live.user);
+            unsafe { self.inner.free(tag) };
+        }
+        unsafe { self.inner.free_all() };
+        self.untracked.store(0, Relaxed);
+    }
+}
+
+impl<A: Check, const N: usize> Check for Tracked<A, N> {
+    fn check(&self, report: &mut check::Report) {
+        {
+            let live = self.live.lock();
+            for (i, a) in live.iter().flatten().enumerate() {
+                report.checked += 1;
+                // Zero-sized allocations may share a (dangling) address.
+                if a.size() == 0 {
+                    continue;
+                }
+                for b in live.iter().flatten().skip(i + 1) {
+                    let (x, y) = (a.addr(), b.addr());
+                    if b.size() != 0 && x < y + b.size() && y < x + a.size() {
+                        report.violation(Violation::Overlap { a: x, b: y });
+                    }
+                }
+            }
+        }
+        self.inner.check(report);
+    }
+}
+
+impl<A: Member + Send, const N: usize> Member for Tracked<A, N> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.inner.stats()
+    }
+
+    fn grind(&self) -> GrindReport {
+        self.inner.grind()
+    }
+
+    fn trim(&self, keep: usize) -> GrindReport {
+        self.inner.trim(keep)
+    }
+
+    fn pre_dump(&self) -> GrindReport {
+        self.inner.pre_dump()
+    }
+
+    fn post_restore(&self) -> Result<(), Errno> {
+        self.inner.post_restore()
+    }
+
+    /// Live bytes by call site, followed by those of the inner heap.
+    fn leaks(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.report())?;
+        self.inner.leaks(out)
+    }
+
+    fn fault(&self, addr: usize) -> Option<Fault> {
+        self.inner.fault(addr)
+    }
+
+    /// Every tracked allocation as an active region, followed by the
+    /// regions of the inner heap.
+    fn regions(&self, f: &mut dyn FnMut(Region)) {
+        for a in self.live.lock().iter().flatten() {
+            f(Region {
+                addr: a.addr(),
+                len: a.size(),
+                state: RegionState::Active,
+            });
+        }
+        self.inner.regions(f)
+    }
+}
+
+/// Live bytes grouped by call site, one `file:line:col bytes count` line
+/// per site.
+pub(crate) struct Report<'a, A, const N: usize>(&'a Tracked<A, N>);
+
+impl<A, const N: usize> fmt::Display for Report<'_, A, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = Ok(());
+        self.0.for_each_site(|site, bytes, count| {
+            if res.is_ok() {
+                res = writeln!(f, "{site} {bytes} {count}");
+            }
+        });
+        res?;
+        match self.0.untracked() {
+            0 => Ok(()),
+            n => writeln!(f, "<untracked> - {n}"),
+        }
+    }
+}
+
+/// Live bytes grouped by age in ticks, in power-of-two buckets.
+pub(crate) struct Ages {
+    /// Bucket `0` holds allocations made on the current tick, and bucket `i`
+    /// those between `2^(i-1)` and `2^i - 1` ticks old.
+    bytes: [usize; 65],
+}
+
+impl Ages {
+    /// Calls `f(min, max, bytes)` for each bucket with live bytes, youngest
+    /// first, where `min..=max` is the range of ages in ticks.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(u64, u64, usize)) {
+        for (i, &bytes) in self.bytes.iter().enumerate() {
+            if bytes == 0 {
+                continue;
+            }
+            let (min, max) = match i {
+                0 => (0, 0),
+                _ => (1 << (i - 1), u64::MAX >> (64 - i)),
+            };
+            f(min, max, bytes);
+        }
+    }
+}
+
+/// One `min..=max bytes` line per non-empty bucket, youngest first.
+impl fmt::Display for Ages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut res = Ok(());
+        self.for_each(|min, max, bytes| {
+            if res.is_ok() {
+                res = writeln!(f, "{min}..={max} {bytes}");
+            }
+        });
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicU64;
+
+    use super::*;
+    use crate::mmap::Mmap;
+
+    const SMALL: Layout = Layout::new::<[u64; 8]>();
+
+    #[test]
+    fn untracked_frees() {
+        let heap = Tracked::<_, 1>::new(Mmap::new());
+        let a = heap.alloc(SMALL).unwrap();
+        let b = heap.alloc(SMALL).unwrap();
+        assert_eq!(heap.untracked(), 1);
+        unsafe { heap.free(b) };
+        assert_eq!(heap.untracked(), 0);
+        unsafe { heap.free(a) };
+        assert_eq!(heap.untracked(), 0);
+    }
+
+    #[test]
+    fn grow_moves_site() {
+        let heap = Tracked::<_, 4>::new(Mmap::new());
+        let a = heap.alloc(SMALL).unwrap();
+        let line = line!() + 1;
+        let a = unsafe { heap.grow(&a, Layout::from_size_align(1 << 16, 8).unwrap()) }.unwrap();
+        let mut sites = 0;
+        heap.for_each_site(|site, bytes, count| {
+            assert_eq!(site.line(), line);
+            assert_eq!((bytes, count), (a.layout().size(), 1));
+            sites += 1;
+        });
+        assert_eq!(sites, 1);
+        unsafe { heap.free(a) };
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        let heap = Tracked::<_, 4>::new(Mmap::new()).with_clock(|| NOW.load(Relaxed));
+        let a = heap.alloc_with_ttl(SMALL, 10).unwrap();
+        let b = heap.alloc_with_ttl(SMALL, 20).unwrap();
+        let c = heap.alloc(SMALL).unwrap();
+        let mapped = heap.inner.stats().mapped();
+        let expired = |heap: &Tracked<Mmap, 4>| {
+            let mut found = None;
+            let n = heap.for_each_expired(|x| found = Some((x.ptr, x.overdue)));
+            (n, found)
+        };
+        // Expiry is strictly after the TTL.
+        NOW.store(10, Relaxed);
+        assert_eq!(expired(&heap), (Some(0), None));
+        NOW.store(15, Relaxed);
+        assert_eq!(expired(&heap), (Some(1), Some((a.ptr(), 5))));
+        let mut evicted = None;
+        let n = unsafe { heap.free_expired(|x| evicted = Some(x.ptr())) };
+        assert_eq!((n, evicted), (Some(1), Some(a.ptr())));
+        assert_eq!(heap.inner.stats().mapped(), mapped - a.layout().size());
+        // Allocations without a TTL never expire.
+        NOW.store(u64::MAX, Relaxed);
+        assert_eq!(expired(&heap), (Some(1), Some((b.ptr(), u64::MAX - 20))));
+        unsafe { heap.free(b) };
+        unsafe { heap.free(c) };
+        // Without a clock, nothing expires.
+        let heap = Tracked::<_, 4>::new(Mmap::new());
+        let d = heap.alloc_with_ttl(SMALL, 0).unwrap();
+        assert_eq!(expired(&heap), (None, None));
+        assert_eq!(unsafe { heap.free_expired(|_| {}) }, None);
+        unsafe { heap.free(d) };
+    }
+
+    #[test]
+    #[should_panic = "double free"]
+    fn strict_double_free() {
+        let heap = Tracked::<_, 4>::new(Mmap::new()).with_strict();
+        let a = heap.alloc(SMALL).unwrap();
+        let copy = unsafe { Tag::new(a.ptr(), a.layout()) };
+        unsafe { heap.free(a) };
+        unsafe { heap.free(copy) };
+    }
+}
@@ -0,0 +1,231 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout},
+    ptr::NonNull,
+};
+
+use rustix::{
+    fd::OwnedFd,
+    io::{self, Errno},
+    ioctl::{self, Setter, Updater, opcode},
+    mm::{self, Advice, MapFlags, ProtFlags, UserfaultfdFlags},
+};
+
+use crate::{
+    core::{Alloc, Tag},
+    sync::SpinLock,
+};
+
+/// `UFFD_API`, and the ioctl group of the `UFFDIO_*` requests.
+const UFFD_API: u8 = 0xAA;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+const UFFDIO_API: ioctl::Opcode = opcode::read_write::<UffdioApi>(UFFD_API, 0x3F);
+const UFFDIO_REGISTER: ioctl::Opcode = opcode::read_write::<UffdioRegister>(UFFD_API, 0x00);
+const UFFDIO_UNREGISTER: ioctl::Opcode = opcode::read::<UffdioRange>(UFFD_API, 0x01);
+const UFFDIO_COPY: ioctl::Opcode = opcode::read_write::<UffdioCopy>(UFFD_API, 0x03);
+
+/// A scratch page the contents of faulting pages are prepared in.
+struct Scratch(NonNull<u8>);
+
+// SAFETY: The page is owned by the `Uffd` and only accessed under its lock.
+unsafe impl Send for Scratch {}
+
+/// A userfaultfd through which missing pages of registered ranges are
+/// materialized on first touch by a user callback.
+///
+/// Faults block the touching thread until some thread calls
+/// [`Uffd::serve`], so a dedicated thread should serve the fd in a loop.
+/// Unprivileged processes may need the `vm.unprivileged_userfaultfd` sysctl.
+///
+/// CRIU does not checkpoint userfaultfds, so drop the `Uffd`, which
+/// unregisters its ranges, before a dump, and make a new one after the
+/// restore. Pages the callback has not filled yet are restored as zeroes.
+pub(crate) struct Uffd {
+    fd: OwnedFd,
+    pagesize: usize,
+    scratch: SpinLock<Scratch>,
+}
+
+impl Uffd {
+    pub(crate) fn new() -> Result<Self, Errno> {
+        // SAFETY: The fd only affects ranges we register ourselves.
+        let fd = unsafe { mm::userfaultfd(UserfaultfdFlags::CLOEXEC) }?;
+        let mut api = UffdioApi {
+            api: UFFD_API as u64,
+            features: 0,
+            ioctls: 0,
+        };
+        // SAFETY: `UFFDIO_API` takes a `struct uffdio_api`.
+        unsafe { ioctl::ioctl(&fd, Updater::<UFFDIO_API, _>::new(&mut api)) }?;
+        let pagesize = rustix::param::page_size();
+        let rw = ProtFlags::READ | ProtFlags::WRITE;
+        // SAFETY: The kernel chooses the address.
+        let page =
+            unsafe { mm::mmap_anonymous(core::ptr::null_mut(), pagesize, rw, MapFlags::PRIVATE) }?;
+        Ok(Self {
+            fd,
+            pagesize,
+            scratch: SpinLock::new(Scratch(NonNull::new(page.cast()).unwrap())),
+        })
+    }
+
+    /// Registers `len` bytes at `ptr` so that touching a missing page faults
+    /// to [`Uffd::serve`]. Pages already present in the range are released
+    /// first, so that every page is materialized by the callback.
+    ///
+    /// # SAFETY
+    ///
+    /// `ptr` and `len` must be page-aligned, and the range must be a private
+    /// anonymous mapping whose contents are no longer needed.
+    pub(crate) unsafe fn register(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        unsafe { mm::madvise(ptr.as_ptr().cast(), len, Advice::LinuxDontNeed) }?;
+        let mut reg = UffdioRegister {
+            range: UffdioRange {
+                start: ptr.addr().get() as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // SAFETY: `UFFDIO_REGISTER` takes a `struct uffdio_register`.
+        unsafe { ioctl::ioctl(&self.fd, Updater::<UFFDIO_REGISTER, _>::new(&mut reg)) }
+    }
+
+    /// # SAFETY
+    ///
+    /// `ptr` and `len` must describe a range registered with `self`.
+    pub(crate) unsafe fn unregister(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Errno> {
+        let range = UffdioRange {
+            start: ptr.addr().get() as u64,
+            len: len as u64,
+        };
+        // SAFETY: `UFFDIO_UNREGISTER` takes a `struct uffdio_range`.
+        unsafe { ioctl::ioctl(&self.fd, Setter::<UFFDIO_UNREGISTER, _>::new(range)) }
+    }
+
+    /// Blocks until a registered page is touched, calls `fill` with the
+    /// address of the page and a page-sized buffer to write its contents to,
+    /// and installs the page, waking the faulting thread.
+    ///
+    /// The address comes from the kernel and carries no provenance, so it is
+    /// passed as an integer, for the callback to locate the page within the
+    /// allocations it knows about.
+    pub(crate) fn serve(&self, fill: impl FnOnce(usize, &mut [u8])) -> Result<(), Errno> {
+        // The size of `struct uffd_msg`.
+        let mut msg = [0u8; 32];
+        loop {
+            match io::read(&self.fd, &mut msg) {
+                Ok(n) if n == msg.len() => break,
+                Ok(_) => return Err(Errno::IO),
+                Err(Errno::INTR) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        if msg[0] != UFFD_EVENT_PAGEFAULT {
+            return Ok(());
+        }
+        let addr = u64::from_ne_bytes(msg[16..24].try_into().unwrap()) as usize;
+        let page = addr & !(self.pagesize - 1);
+        let scratch = self.scratch.lock();
+        // SAFETY: The scratch page is valid for `pagesize` bytes and only
+        // accessed with the lock held.
+        let buf = unsafe { core::slice::from_raw_parts_mut(scratch.0.as_ptr(), self.pagesize) };
+        buf.fill(0);
+        fill(page, buf);
+        let mut copy = UffdioCopy {
+            dst: page as u64,
+            src: scratch.0.addr().get() as u64,
+            len: self.pagesize as u64,
+            mode: 0,
+            copy: 0,
+        };
+        // SAFETY: `UFFDIO_COPY` takes a `struct uffdio_copy`.
+        match unsafe { ioctl::ioctl(&self.fd, Updater::<UFFDIO_COPY, _>::new(&mut copy)) } {
+            // Another thread resolved the fault first.
+            Err(Errno::EXIST) => Ok(()),
+            res => res,
+        }
+    }
+}
+
+impl Drop for Uffd {
+    fn drop(&mut self) {
+        let page = self.scratch.get_mut().0;
+        // SAFETY: The scratch page was mapped in `new`.
+        let _ = unsafe { mm::munmap(page.as_ptr().cast(), self.pagesize) };
+    }
+}
+
+/// Registers allocations of at least `min` bytes with a [`Uffd`], so that
+/// their pages are materialized on first touch by whoever serves it.
+pub(crate) struct Lazy<'u, A> {
+    inner: A,
+    uffd: &'u Uffd,
+    min: usize,
+}
+
+impl<'u, A> Lazy<'u, A> {
+    pub(crate) const fn new(inner: A, uffd: &'u Uffd, min: usize) -> Self {
+        Self { inner, uffd, min }
+    }
+
+    fn is_lazy(&self, tag: &Tag) -> bool {
+        let (ptr, len) = (tag.ptr(), tag.layout().size());
+        len >= self.min && ptr.is_aligned_to(self.uffd.pagesize) && len % self.uffd.pagesize == 0
+    }
+}
+
+impl<A: Alloc> Alloc for Lazy<'_, A> {
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        let tag = self.inner.alloc(layout)?;
+        if self.is_lazy(&tag) {
+            // SAFETY: The allocation is fresh and page-aligned.
+            if unsafe { self.uffd.register(tag.ptr(), tag.layout().size()) }.is_err() {
+                unsafe { self.inner.free(tag) };
+                return Err(AllocError);
+            }
+        }
+        Ok(tag)
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        if self.is_lazy(&tag) {
+            // SAFETY: Lazy allocations were registered in `alloc`. Unregister
+            // them so that the inner allocator may reuse the range eagerly.
+            let _ = unsafe { self.uffd.unregister(tag.ptr(), tag.layout().size()) };
+        }
+        unsafe { self.inner.free(tag) }
+    }
+}
@@ -0,0 +1,215 @@
+#![allow(unused)]
+
+use core::{
+    alloc::{AllocError, Layout, LayoutError},
+    ffi::c_void,
+    ptr::{self, NonNull},
+};
+
+use rustix::{io::Errno, mm};
+use thiserror::Error;
+
+use crate::{
+    core::{Alloc, Owns, Tag},
+    sync::SpinLock,
+};
+
+/// The `struct iovec` expected by `io_uring_register_buffers`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Iovec {
+    pub(crate) base: *mut c_void,
+    pub(crate) len: usize,
+}
+
+impl Iovec {
+    const EMPTY: Self = Self {
+        base: ptr::null_mut(),
+        len: 0,
+    };
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum FixedErr {
+    #[error("failed to allocate a region")]
+    Alloc,
+    #[error("failed to lock a region with {0}")]
+    Os(#[from] Errno),
+    #[error("invalid buffer layout: {0}")]
+    Layout(#[from] LayoutError),
+    #[error("more than the maximum number of regions")]
+    TooMany,
+}
+
+/// Written at the start of each free buffer, linking the free list of its
+/// region.
+struct Free {
+    next: Option<NonNull<Free>>,
+}
+
+/// The heads of the per-region free lists.
+struct State<const R: usize> {
+    free: [Option<NonNull<Free>>; R],
+}
+
+// SAFETY: The free lists point into regions owned by `FixedBufs` and are
+// only accessed with the lock held or through `&mut FixedBufs`.
+unsafe impl<const R: usize> Send for State<R> {}
+
+/// Fixed-size I/O buffers carved from up to `R` large, mlocked regions that
+/// are registered with io_uring once, up front.
+///
+/// [`FixedBufs::iovecs`] is the array to pass to `io_uring_register_buffers`.
+/// Every buffer lies entirely within one region, and [`FixedBufs::index`]
+/// gives the `buf_index` to use for it in `READ_FIXED` and `WRITE_FIXED`
+/// operations. Regions are never grown or moved, so the registration stays
+/// valid for the lifetime of the pool.
+pub(crate) struct FixedBufs<'p, A: Alloc, const R: usize = 4> {
+    parent: &'p A,
+    buf_len: usize,
+    regions: [Option<Tag>; R],
+    iovecs: [Iovec; R],
+    len: usize,
+    state: SpinLock<State<R>>,
+}
+
+// SAFETY: The regions are owned by the pool; buffers are handed out under
+// the lock.
+unsafe impl<A: Alloc + Sync, const R: usize> Send for FixedBufs<'_, A, R> {}
+unsafe impl<A: Alloc + Sync, const R: usize> Sync for FixedBufs<'_, A, R> {}
+
+impl<'p, A: Alloc, const R: usize> FixedBufs<'p, A, R> {
+    /// Allocates `regions` regions of `bufs` buffers of `buf_len` bytes each
+    /// from `parent` and locks them in memory. `buf_len` is rounded up to a
+    /// multiple of the page size so that buffers are page-aligned.
+    pub(crate) fn new(
+        parent: &'p A,
+        buf_len: usize,
+        bufs: usize,
+        regions: usize,
+    ) -> Result<Self, FixedErr> {
+        if regions > R {
+            return Err(FixedErr::TooMany);
+        }
+        let pagesize = rustix::param::page_size();
+        let buf = Layout::from_size_align(buf_len.max(1), pagesize)?.pad_to_align();
+        let size = buf.size().checked_mul(bufs).ok_or(FixedErr::Alloc)?;
+        let region = Layout::from_size_align(size, pagesize)?;
+        let mut this = Self {
+            parent,
+            buf_len: buf.size(),
+            regions: [const { None }; R],
+            iovecs: [Iovec::EMPTY; R],
+            len: 0,
+            state: SpinLock::new(State { free: [None; R] }),
+        };
+        for i in 0..regions {
+            let tag = parent.alloc(region).map_err(|_| FixedErr::Alloc)?;
+            let (base, len) = (tag.ptr(), tag.layout().size());
+            // SAFETY: `tag` is valid for `len` bytes.
+            if let Err(err) = unsafe { mm::mlock(base.as_ptr().cast(), len) } {
+                // SAFETY: `tag` was just allocated by `parent`.
+                unsafe { parent.free(tag) };
+                return Err(err.into());
+            }
+            let mut head = None;
+            for k in (0..bufs).rev() {
+                // SAFETY: `k * buf.size()` is within the region, and each
+                // buffer is aligned for and large enough to hold a `Free`.
+                let free = unsafe { base.add(k * buf.size()) }.cast::<Free>();
+                unsafe { free.write(Free { next: head }) };
+                head = Some(free);
+            }
+            this.state.get_mut().free[i] = head;
+            this.iovecs[i] = Iovec {
+                base: base.as_ptr().cast(),
+                len,
+            };
+            this.regions[i] = Some(tag);
+            this.len += 1;
+        }
+        Ok(this)
+    }
+
+    /// The usable length of every buffer.
+    #[inline]
+    pub(crate) fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// The regions, in the shape `io_uring_register_buffers` expects.
+    #[inline]
+    pub(crate) fn iovecs(&self) -> &[Iovec] {
+        &self.iovecs[..self.len]
+    }
+
+    /// The index of the registered region containing `ptr`, for use as the
+    /// `buf_index` of a fixed-buffer operation.
+    pub(crate) fn index(&self, ptr: NonNull<u8>) -> Option<u16> {
+        let addr = ptr.addr().get();
+        self.iovecs()
+            .iter()
+            .position(|x| (x.base.addr()..x.base.addr() + x.len).contains(&addr))
+            .and_then(|x| x.try_into().ok())
+    }
+}
+
+impl<A: Alloc, const R: usize> Alloc for FixedBufs<'_, A, R> {
+    /// Hands out a whole buffer. Fails for layouts that do not fit in one,
+    /// or when every buffer is in use; the pool never grows.
+    fn alloc(&self, layout: Layout) -> Result<Tag, AllocError> {
+        if layout.size() > self.buf_len || !self.buf_len.is_multiple_of(layout.align()) {
+            return Err(AllocError);
+        }
+        let mut state = self.state.lock();
+        let head = state.free[..self.len]
+            .iter_mut()
+            .find(|x| x.is_some())
+            .ok_or(AllocError)?;
+        let free = head.take().unwrap();
+        // SAFETY: Every node in a free list is the header of a free buffer.
+        *head = unsafe { free.read() }.next;
+        let layout = Layout::from_size_align(self.buf_len, layout.align()).unwrap();
+        // SAFETY: Buffers are `buf_len` bytes long and start at a multiple
+        // of `buf_len`, which is a multiple of `layout.align()`, from a
+        // page-aligned base.
+        Ok(unsafe { Tag::new(free.cast(), layout) })
+    }
+
+    unsafe fn free(&self, tag: Tag) {
+        let Some(i) = self.index(tag.ptr()) else {
+            debug_assert!(false, "buffer not from this pool");
+            return;
+        };
+        let mut state = self.state.lock();
+        let free = tag.ptr().cast::<Free>();
+        // SAFETY: The buffer was handed out by `alloc` and is no longer in
+        // use, so its header can be overwritten.
+        unsafe {
+            free.write(Free {
+                next: state.free[i as usize],
+            })
+        };
+        state.free[i as usize] = Some(free);
+    }
+}
+
+impl<A: Alloc, const R: usize> Owns for FixedBufs<'_, A, R> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.index(ptr).is_some()
+    }
+}
+
+impl<A: Alloc, const R: usize> Drop for FixedBufs<'_, A, R> {
+    fn drop(&mut self) {
+        for tag in self.regions.iter_mut().filter_map(Option::take) {
+            let (base, len) = (tag.ptr(), tag.layout().size());
+            // SAFETY: The region was locked in `new` and is valid for `len`
+            // bytes; it is returned to the parent exactly once here.
+            unsafe {
+                let _ = mm::munlock(base.as_ptr().cast(), len);
+                self.parent.free(tag);
+            }
+        }
+    }
+}